@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+use crate::session::SessionId;
+
+// RFC 6464 client-to-mixer audio level.
+pub const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+
+// Weight of each new packet's level; about 100ms of history at 20ms packets.
+const SMOOTHING: f64 = 0.2;
+// A level not refreshed for this long counts as silence.
+const STALE_AFTER: Duration = Duration::from_millis(500);
+// Anything quieter is nobody speaking.
+pub const SPEECH_THRESHOLD_DBOV: f64 = -60.0;
+// A new speaker has to be this much louder than the current one to take over.
+const SWITCH_MARGIN_DB: f64 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioLevel {
+    // 0 (loudest) to 127 (silence), in -dBov.
+    pub level: u8,
+    // Only meaningful when the sender negotiated vad=on.
+    pub voice: bool,
+}
+
+pub fn parse_audio_level(element: &[u8]) -> Option<AudioLevel> {
+    let &byte = element.first()?;
+    Some(AudioLevel { level: byte & 0x7F, voice: byte & 0x80 != 0 })
+}
+
+#[derive(Debug, Default)]
+pub struct AudioLevelMeter {
+    smoothed_dbov: f64,
+    voice: bool,
+    updated: Option<Instant>,
+}
+
+impl AudioLevelMeter {
+    pub fn observe(&mut self, level: AudioLevel, now: Instant) {
+        let dbov = -(level.level as f64);
+        self.smoothed_dbov = match self.updated {
+            Some(updated) if now.saturating_duration_since(updated) < STALE_AFTER => {
+                self.smoothed_dbov + SMOOTHING * (dbov - self.smoothed_dbov)
+            }
+            _ => dbov,
+        };
+        self.voice = level.voice;
+        self.updated = Some(now);
+    }
+
+    // Smoothed level in dBov; None when the source hasn't sent one lately.
+    pub fn level_dbov(&self, now: Instant) -> Option<f64> {
+        let updated = self.updated?;
+        (now.saturating_duration_since(updated) < STALE_AFTER).then_some(self.smoothed_dbov)
+    }
+
+    pub fn voice(&self) -> bool {
+        self.voice
+    }
+}
+
+// Loudest session of a group, held until someone is clearly louder or the
+// speaker goes quiet so the highlight doesn't flicker between talkers.
+#[derive(Debug, Default)]
+pub struct SpeakerSelector {
+    current: Option<SessionId>,
+}
+
+impl SpeakerSelector {
+    pub fn current(&self) -> Option<SessionId> {
+        self.current
+    }
+
+    // Returns the new speaker when it changed.
+    pub fn select(&mut self, levels: &[(SessionId, f64)]) -> Option<Option<SessionId>> {
+        let speaking = levels.iter().filter(|(_, level)| *level > SPEECH_THRESHOLD_DBOV);
+        let loudest = speaking.clone().max_by(|a, b| a.1.total_cmp(&b.1));
+        let current = speaking.clone().find(|(id, _)| Some(*id) == self.current);
+
+        let next = match (current, loudest) {
+            (Some(&(id, level)), Some(&(_, loudest))) if loudest < level + SWITCH_MARGIN_DB => Some(id),
+            (_, loudest) => loudest.map(|(id, _)| *id),
+        };
+        if next == self.current {
+            return None;
+        }
+        self.current = next;
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_smoothing_and_staleness() {
+        assert_eq!(parse_audio_level(&[0x9E]), Some(AudioLevel { level: 30, voice: true }));
+
+        let start = Instant::now();
+        let mut meter = AudioLevelMeter::default();
+        assert_eq!(meter.level_dbov(start), None);
+        meter.observe(AudioLevel { level: 30, voice: true }, start);
+        meter.observe(AudioLevel { level: 80, voice: false }, start + Duration::from_millis(20));
+        assert_eq!(meter.level_dbov(start + Duration::from_millis(20)), Some(-40.0));
+        assert_eq!(meter.level_dbov(start + Duration::from_millis(600)), None);
+    }
+
+    #[test]
+    fn test_speaker_hysteresis() {
+        let (alice, bob) = (SessionId::new(), SessionId::new());
+        let mut selector = SpeakerSelector::default();
+
+        assert_eq!(selector.select(&[(alice, -30.0), (bob, -70.0)]), Some(Some(alice)));
+        // Slightly louder isn't enough to take over
+        assert_eq!(selector.select(&[(alice, -30.0), (bob, -28.0)]), None);
+        assert_eq!(selector.select(&[(alice, -30.0), (bob, -20.0)]), Some(Some(bob)));
+        assert_eq!(selector.select(&[(alice, -90.0), (bob, -65.0)]), Some(None));
+        assert_eq!(selector.current(), None);
+    }
+}
@@ -0,0 +1,406 @@
+// Transport-wide congestion control (draft-holmer-rmcat-transport-wide-cc-
+// extensions-01): egress packets are numbered per subscriber, subscribers
+// report arrival times, and a delay- and loss-based estimator turns those
+// reports into an available bitrate.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use crate::rtp::RtpExtension;
+use crate::RtpPacket;
+
+pub const TRANSPORT_CC_URI: &str =
+    "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+
+const RTCP_RTPFB: u8 = 205;
+const TWCC_FMT: u8 = 15;
+const DELTA_UNIT_US: i64 = 250;
+const REFERENCE_UNIT_US: i64 = 64_000;
+
+const HISTORY_LEN: usize = 4096;
+const TRENDLINE_WINDOW: usize = 20;
+const TRENDLINE_SMOOTHING: f64 = 0.9;
+const TRENDLINE_GAIN: f64 = 4.0;
+const OVERUSE_THRESHOLD_MS: f64 = 12.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BweConfig {
+    pub start_bps: u64,
+    pub min_bps: u64,
+    pub max_bps: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TwccFeedback {
+    pub sender_ssrc: u32,
+    pub media_ssrc: u32,
+    // (transport sequence, arrival time in microseconds) per reported
+    // packet; None when the packet was not received.
+    pub packets: Vec<(u16, Option<i64>)>,
+}
+
+impl TwccFeedback {
+    // Every transport-cc feedback in a (possibly compound) RTCP packet.
+    pub fn parse_compound(data: &[u8]) -> Vec<TwccFeedback> {
+        let mut feedback = Vec::new();
+        let mut offset = 0;
+
+        while offset + 4 <= data.len() {
+            let length = (u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize + 1) * 4;
+            let Some(packet) = data.get(offset..offset + length) else {
+                break;
+            };
+            if packet[1] == RTCP_RTPFB && packet[0] & 0x1F == TWCC_FMT {
+                if let Some(parsed) = Self::parse(packet) {
+                    feedback.push(parsed);
+                }
+            }
+            offset += length;
+        }
+
+        feedback
+    }
+
+    fn parse(packet: &[u8]) -> Option<TwccFeedback> {
+        if packet.len() < 20 {
+            return None;
+        }
+        let sender_ssrc = u32::from_be_bytes(packet[4..8].try_into().ok()?);
+        let media_ssrc = u32::from_be_bytes(packet[8..12].try_into().ok()?);
+        let base_seq = u16::from_be_bytes([packet[12], packet[13]]);
+        let count = u16::from_be_bytes([packet[14], packet[15]]) as usize;
+        let reference = i32::from_be_bytes([packet[16], packet[17], packet[18], 0]) >> 8;
+
+        // Packet status chunks: one status symbol per reported packet.
+        let mut symbols = Vec::with_capacity(count);
+        let mut i = 20;
+        while symbols.len() < count {
+            let chunk = u16::from_be_bytes([*packet.get(i)?, *packet.get(i + 1)?]);
+            i += 2;
+            if chunk & 0x8000 == 0 {
+                let symbol = ((chunk >> 13) & 0x03) as u8;
+                let run = (chunk & 0x1FFF) as usize;
+                symbols.extend(std::iter::repeat_n(symbol, run.min(count - symbols.len())));
+            } else if chunk & 0x4000 == 0 {
+                symbols.extend((0..14).rev().map(|bit| ((chunk >> bit) & 0x01) as u8));
+            } else {
+                symbols.extend((0..7).rev().map(|pair| ((chunk >> (pair * 2)) & 0x03) as u8));
+            }
+        }
+        symbols.truncate(count);
+
+        let mut arrival = reference as i64 * REFERENCE_UNIT_US;
+        let mut packets = Vec::with_capacity(count);
+        for (n, symbol) in symbols.into_iter().enumerate() {
+            let seq = base_seq.wrapping_add(n as u16);
+            let delta = match symbol {
+                1 => {
+                    let delta = *packet.get(i)? as i64;
+                    i += 1;
+                    delta
+                }
+                2 => {
+                    let delta = i16::from_be_bytes([*packet.get(i)?, *packet.get(i + 1)?]) as i64;
+                    i += 2;
+                    delta
+                }
+                _ => {
+                    packets.push((seq, None));
+                    continue;
+                }
+            };
+            arrival += delta * DELTA_UNIT_US;
+            packets.push((seq, Some(arrival)));
+        }
+
+        Some(TwccFeedback {
+            sender_ssrc,
+            media_ssrc,
+            packets,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DelayState {
+    Normal,
+    Overusing,
+    Underusing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandwidthEstimate {
+    pub estimate_bps: u64,
+    pub acked_bps: u64,
+    pub loss_fraction: f64,
+}
+
+// Per-subscriber sender side of transport-cc.
+#[derive(Debug)]
+pub struct CongestionController {
+    config: BweConfig,
+    next_seq: u16,
+    sent: HashMap<u16, (Instant, usize)>,
+    sent_order: VecDeque<u16>,
+    // Last received packet of the previous feedback: (send time, arrival us).
+    last_received: Option<(Instant, i64)>,
+    accumulated_delay_ms: f64,
+    smoothed_delay_ms: f64,
+    trendline: VecDeque<(f64, f64)>,
+    first_arrival_ms: Option<f64>,
+    delay_state: DelayState,
+    delay_bps: f64,
+    loss_bps: f64,
+    acked_bps: f64,
+    loss_fraction: f64,
+    last_update: Option<Instant>,
+    pub last_activity: Instant,
+}
+
+impl CongestionController {
+    pub fn new(config: BweConfig) -> Self {
+        Self {
+            config,
+            next_seq: 0,
+            sent: HashMap::new(),
+            sent_order: VecDeque::new(),
+            last_received: None,
+            accumulated_delay_ms: 0.0,
+            smoothed_delay_ms: 0.0,
+            trendline: VecDeque::new(),
+            first_arrival_ms: None,
+            delay_state: DelayState::Normal,
+            delay_bps: config.start_bps as f64,
+            loss_bps: config.start_bps as f64,
+            acked_bps: 0.0,
+            loss_fraction: 0.0,
+            last_update: None,
+            last_activity: Instant::now(),
+        }
+    }
+
+    // Writes the next transport sequence number into the packet's extension
+    // and remembers when it left. Packets that wait in a pacer are re-timed by
+    // `on_sent` once they actually leave.
+    pub fn stamp(&mut self, packet: &mut RtpPacket, extension_id: u8, now: Instant) -> u16 {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+
+        let mut extension = packet.extension.take().unwrap_or_else(|| RtpExtension::from_elements(&[]));
+        extension.set_element(extension_id, &seq.to_be_bytes());
+        packet.extension = Some(extension);
+        packet.raw = None;
+
+        self.sent.insert(seq, (now, 12 + packet.payload.len()));
+        self.sent_order.push_back(seq);
+        if self.sent_order.len() > HISTORY_LEN {
+            if let Some(old) = self.sent_order.pop_front() {
+                self.sent.remove(&old);
+            }
+        }
+        self.last_activity = now;
+        seq
+    }
+
+    pub fn on_sent(&mut self, seq: u16, now: Instant) {
+        if let Some((sent_at, _)) = self.sent.get_mut(&seq) {
+            *sent_at = now;
+        }
+    }
+
+    pub fn on_feedback(&mut self, feedback: &TwccFeedback, now: Instant) {
+        let mut lost = 0usize;
+        let mut matched = 0usize;
+        let mut received_bytes = 0usize;
+        let mut arrival_span: Option<(i64, i64)> = None;
+
+        for &(seq, arrival) in &feedback.packets {
+            let Some(&(sent_at, size)) = self.sent.get(&seq) else {
+                continue;
+            };
+            matched += 1;
+            let Some(arrival) = arrival else {
+                lost += 1;
+                continue;
+            };
+
+            received_bytes += size;
+            arrival_span = Some(match arrival_span {
+                Some((first, last)) => (first.min(arrival), last.max(arrival)),
+                None => (arrival, arrival),
+            });
+
+            if let Some((prev_sent, prev_arrival)) = self.last_received {
+                let send_delta_ms = if sent_at >= prev_sent {
+                    sent_at.duration_since(prev_sent).as_secs_f64() * 1000.0
+                } else {
+                    -(prev_sent.duration_since(sent_at).as_secs_f64() * 1000.0)
+                };
+                let recv_delta_ms = (arrival - prev_arrival) as f64 / 1000.0;
+                self.update_trendline(recv_delta_ms - send_delta_ms, arrival as f64 / 1000.0);
+            }
+            self.last_received = Some((sent_at, arrival));
+        }
+
+        if matched > 0 {
+            self.loss_fraction = lost as f64 / matched as f64;
+        }
+        if let Some((first, last)) = arrival_span {
+            if last > first {
+                let bps = received_bytes as f64 * 8.0 * 1_000_000.0 / (last - first) as f64;
+                self.acked_bps = if self.acked_bps == 0.0 { bps } else { 0.8 * self.acked_bps + 0.2 * bps };
+            }
+        }
+
+        self.update_estimate(now);
+        self.last_activity = now;
+    }
+
+    fn update_trendline(&mut self, delay_variation_ms: f64, arrival_ms: f64) {
+        self.accumulated_delay_ms += delay_variation_ms;
+        self.smoothed_delay_ms = TRENDLINE_SMOOTHING * self.smoothed_delay_ms
+            + (1.0 - TRENDLINE_SMOOTHING) * self.accumulated_delay_ms;
+
+        let first = *self.first_arrival_ms.get_or_insert(arrival_ms);
+        self.trendline.push_back((arrival_ms - first, self.smoothed_delay_ms));
+        if self.trendline.len() > TRENDLINE_WINDOW {
+            self.trendline.pop_front();
+        }
+        if self.trendline.len() < TRENDLINE_WINDOW {
+            return;
+        }
+
+        // Least-squares slope of smoothed delay over arrival time.
+        let n = self.trendline.len() as f64;
+        let mean_x = self.trendline.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = self.trendline.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let (num, den) = self.trendline.iter().fold((0.0, 0.0), |(num, den), (x, y)| {
+            (num + (x - mean_x) * (y - mean_y), den + (x - mean_x) * (x - mean_x))
+        });
+        let slope = if den > 0.0 { num / den } else { 0.0 };
+
+        let modified_trend = slope * n * TRENDLINE_GAIN;
+        self.delay_state = if modified_trend > OVERUSE_THRESHOLD_MS {
+            DelayState::Overusing
+        } else if modified_trend < -OVERUSE_THRESHOLD_MS {
+            DelayState::Underusing
+        } else {
+            DelayState::Normal
+        };
+    }
+
+    fn update_estimate(&mut self, now: Instant) {
+        let elapsed = self
+            .last_update
+            .map(|last| now.saturating_duration_since(last).as_secs_f64().min(1.0))
+            .unwrap_or(0.0);
+        self.last_update = Some(now);
+
+        // Delay-based: back off below what actually got through when queues
+        // build, otherwise grow ~8%/s without running far ahead of it.
+        match self.delay_state {
+            DelayState::Overusing => {
+                let base = if self.acked_bps > 0.0 { self.acked_bps } else { self.delay_bps };
+                self.delay_bps = self.delay_bps.min(0.85 * base);
+            }
+            DelayState::Normal => {
+                self.delay_bps *= 1.08f64.powf(elapsed);
+                if self.acked_bps > 0.0 {
+                    self.delay_bps = self.delay_bps.min(1.5 * self.acked_bps + 10_000.0);
+                }
+            }
+            DelayState::Underusing => {}
+        }
+
+        // Loss-based, as in draft-ietf-rmcat-gcc.
+        if self.loss_fraction > 0.10 {
+            self.loss_bps *= 1.0 - 0.5 * self.loss_fraction;
+        } else if self.loss_fraction < 0.02 {
+            self.loss_bps = 1.05 * self.loss_bps + 1000.0;
+        }
+
+        let (min, max) = (self.config.min_bps as f64, self.config.max_bps as f64);
+        self.delay_bps = self.delay_bps.clamp(min, max);
+        self.loss_bps = self.loss_bps.clamp(min, max);
+    }
+
+    pub fn estimate_bps(&self) -> u64 {
+        self.delay_bps.min(self.loss_bps) as u64
+    }
+
+    pub fn estimate(&self) -> BandwidthEstimate {
+        BandwidthEstimate {
+            estimate_bps: self.estimate_bps(),
+            acked_bps: self.acked_bps as u64,
+            loss_fraction: self.loss_fraction,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> BweConfig {
+        BweConfig {
+            start_bps: 1_000_000,
+            min_bps: 100_000,
+            max_bps: 10_000_000,
+        }
+    }
+
+    #[test]
+    fn test_parse_feedback() {
+        let mut rtcp = vec![0x8F, 205, 0x00, 0x06];
+        rtcp.extend_from_slice(&1u32.to_be_bytes());
+        rtcp.extend_from_slice(&2u32.to_be_bytes());
+        // base seq 10, 3 packets, reference time 1 (64ms), fb count 0
+        rtcp.extend_from_slice(&[0x00, 0x0A, 0x00, 0x03, 0x00, 0x00, 0x01, 0x00]);
+        // status vector, two-bit symbols: small, not received, large
+        rtcp.extend_from_slice(&[0xD2, 0x00]);
+        // deltas: 4 (1ms), then -4 (-1ms), padding
+        rtcp.extend_from_slice(&[0x04, 0xFF, 0xFC, 0x00, 0x00, 0x00]);
+        rtcp[3] = (rtcp.len() / 4 - 1) as u8;
+
+        let feedback = TwccFeedback::parse_compound(&rtcp);
+        assert_eq!(feedback.len(), 1);
+        assert_eq!(feedback[0].media_ssrc, 2);
+        assert_eq!(
+            feedback[0].packets,
+            vec![(10, Some(65_000)), (11, None), (12, Some(64_000))]
+        );
+    }
+
+    #[test]
+    fn test_loss_reduces_estimate() {
+        let mut controller = CongestionController::new(config());
+        let start = Instant::now();
+        let mut packet = RtpPacket {
+            payload: vec![0; 1000],
+            timestamp: 0,
+            sequence: 0,
+            ssrc: 1,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: None,
+            received_at: start,
+            raw: None,
+        };
+
+        let mut packets = Vec::new();
+        for n in 0..20u16 {
+            controller.stamp(&mut packet, 5, start + Duration::from_millis(n as u64));
+            let arrival = (n % 2 == 0).then_some(n as i64 * 1000);
+            packets.push((n, arrival));
+        }
+        assert_eq!(packet.extension.as_ref().unwrap().element(5), Some(&19u16.to_be_bytes()[..]));
+
+        let feedback = TwccFeedback { sender_ssrc: 1, media_ssrc: 1, packets };
+        controller.on_feedback(&feedback, start + Duration::from_millis(100));
+        let estimate = controller.estimate();
+        assert_eq!(estimate.loss_fraction, 0.5);
+        assert!(estimate.estimate_bps < 1_000_000);
+    }
+}
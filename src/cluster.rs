@@ -0,0 +1,436 @@
+// Cluster mode: servers share a directory of session definitions so sessions
+// outlive the node serving them. Every node heartbeats into the directory and
+// serves the sessions it owns; sessions of nodes that stop heartbeating are
+// handed to the least loaded live node. The directory is pluggable: anything
+// implementing `SessionDirectory` can back it. The built-in stores, in memory
+// and a JSON file, only span the nodes of one host (or of one shared
+// filesystem); spreading a cluster over hosts needs a replicated backend.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::codec::MediaType;
+use crate::fec::FecConfig;
+use crate::filter::SubscriberFilter;
+use crate::jitter::ReorderMode;
+use crate::policer::RateLimit;
+use crate::sdp::SessionDescription;
+use crate::session::{Session, SessionId, SessionOptions, SubscriberTransport};
+
+#[derive(Debug, Error)]
+pub enum DirectoryError {
+    #[error("session directory I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("corrupt session directory: {0}")]
+    Corrupt(#[from] serde_json::Error),
+    #[error("no live cluster nodes")]
+    NoLiveNodes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeRecord {
+    pub id: Uuid,
+    // Where sources send media for the sessions this node owns.
+    pub rtp_address: SocketAddr,
+    // Unix milliseconds.
+    pub heartbeat_ms: u64,
+}
+
+// A UDP subscriber as last seen by the session's owner. Subscribers over
+// TCP, RTSP interleaving or the relay tunnel are tied to their connection and
+// have to come back on their own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriberRecord {
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub filter: SubscriberFilter,
+}
+
+impl SubscriberRecord {
+    // Sorted, so snapshots of an unchanged session compare equal.
+    pub fn snapshot(session: &Session) -> Vec<Self> {
+        let mut subscribers: Vec<_> = session
+            .subscribers
+            .iter()
+            .filter(|s| matches!(s.transport, SubscriberTransport::Udp))
+            .map(|s| Self { addr: s.addr, filter: s.filter.clone() })
+            .collect();
+        subscribers.sort_by_key(|s| s.addr);
+        subscribers
+    }
+}
+
+// What's needed to recreate a session on another node. Simulcast layers come
+// back from the SDP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub id: SessionId,
+    pub owner: Uuid,
+    pub source_addr: SocketAddr,
+    pub ssrc: u32,
+    pub ssrcs: Vec<u32>,
+    pub mids: Vec<String>,
+    pub demux_by_source: bool,
+    pub sdp: Option<String>,
+    pub media_type: Option<String>,
+    pub created_ms: u64,
+    // Per-session overrides from CreateSession.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub fec: Option<FecConfig>,
+    // 0 disables reordering.
+    #[serde(default)]
+    pub reorder_latency_ms: Option<u64>,
+    // Refreshed by the owner on every sync.
+    #[serde(default)]
+    pub subscribers: Vec<SubscriberRecord>,
+}
+
+impl SessionRecord {
+    pub fn options(&self) -> SessionOptions {
+        let description = self.sdp.as_deref().and_then(|sdp| SessionDescription::parse(sdp).ok());
+        let mut options = description.map(SessionOptions::from_description).unwrap_or_default();
+        options.id = Some(self.id);
+        options.ssrcs = self.ssrcs.clone();
+        options.mids = self.mids.clone();
+        options.demux_by_source = self.demux_by_source;
+        options.media_type = self.media_type.as_deref().and_then(MediaType::parse);
+        options.rate_limit = self.rate_limit;
+        options.fec = self.fec;
+        options.reorder = self.reorder_latency_ms.map(ReorderMode::from_latency_ms);
+        options
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+// Shared state of the cluster. Implementations must apply each call
+// atomically with respect to the other nodes. Calls may block; the server
+// makes them from blocking threads.
+pub trait SessionDirectory: Send + Sync {
+    fn heartbeat(&self, node: &NodeRecord) -> Result<(), DirectoryError>;
+    fn nodes(&self) -> Result<Vec<NodeRecord>, DirectoryError>;
+    fn put_session(&self, record: &SessionRecord) -> Result<(), DirectoryError>;
+    fn remove_session(&self, id: &SessionId) -> Result<bool, DirectoryError>;
+    fn sessions(&self) -> Result<Vec<SessionRecord>, DirectoryError>;
+    // Moves the session to `to` only if `from` still owns it.
+    fn reassign(&self, id: &SessionId, from: Uuid, to: Uuid) -> Result<bool, DirectoryError>;
+    // Replaces the session's subscribers only if `owner` still owns it.
+    fn set_subscribers(&self, id: &SessionId, owner: Uuid, subscribers: &[SubscriberRecord]) -> Result<bool, DirectoryError>;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct DirectoryState {
+    nodes: Vec<NodeRecord>,
+    sessions: Vec<SessionRecord>,
+}
+
+// Backends that hold the whole directory and update it under a lock.
+trait StateStore: Send + Sync {
+    fn update<T>(&self, f: impl FnOnce(&mut DirectoryState) -> T) -> Result<T, DirectoryError>;
+}
+
+impl<S: StateStore> SessionDirectory for S {
+    fn heartbeat(&self, node: &NodeRecord) -> Result<(), DirectoryError> {
+        self.update(|state| {
+            state.nodes.retain(|n| n.id != node.id);
+            state.nodes.push(node.clone());
+        })
+    }
+
+    fn nodes(&self) -> Result<Vec<NodeRecord>, DirectoryError> {
+        self.update(|state| state.nodes.clone())
+    }
+
+    fn put_session(&self, record: &SessionRecord) -> Result<(), DirectoryError> {
+        self.update(|state| {
+            state.sessions.retain(|s| s.id != record.id);
+            state.sessions.push(record.clone());
+        })
+    }
+
+    fn remove_session(&self, id: &SessionId) -> Result<bool, DirectoryError> {
+        self.update(|state| {
+            let before = state.sessions.len();
+            state.sessions.retain(|s| s.id != *id);
+            state.sessions.len() != before
+        })
+    }
+
+    fn sessions(&self) -> Result<Vec<SessionRecord>, DirectoryError> {
+        self.update(|state| state.sessions.clone())
+    }
+
+    fn reassign(&self, id: &SessionId, from: Uuid, to: Uuid) -> Result<bool, DirectoryError> {
+        self.update(|state| match state.sessions.iter_mut().find(|s| s.id == *id) {
+            Some(record) if record.owner == from => {
+                record.owner = to;
+                true
+            }
+            _ => false,
+        })
+    }
+
+    fn set_subscribers(&self, id: &SessionId, owner: Uuid, subscribers: &[SubscriberRecord]) -> Result<bool, DirectoryError> {
+        self.update(|state| match state.sessions.iter_mut().find(|s| s.id == *id) {
+            Some(record) if record.owner == owner => {
+                record.subscribers = subscribers.to_vec();
+                true
+            }
+            _ => false,
+        })
+    }
+}
+
+// Nodes of one process; for tests and single-process setups.
+#[derive(Debug, Default)]
+pub struct MemoryDirectory {
+    state: Mutex<DirectoryState>,
+}
+
+impl StateStore for MemoryDirectory {
+    fn update<T>(&self, f: impl FnOnce(&mut DirectoryState) -> T) -> Result<T, DirectoryError> {
+        Ok(f(&mut self.state.lock()))
+    }
+}
+
+// A JSON file shared by the nodes of one host, guarded by an advisory lock on
+// a file next to it and replaced atomically on every change. The OS drops the
+// lock when its holder exits, so a crashed node can't leave it behind.
+#[derive(Debug)]
+pub struct FileDirectory {
+    path: PathBuf,
+    lock_path: PathBuf,
+}
+
+impl FileDirectory {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        Self { path, lock_path: lock_path.into() }
+    }
+
+    // Held until the returned file is dropped.
+    fn lock(&self) -> Result<File, DirectoryError> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(&self.lock_path)?;
+        file.lock()?;
+        Ok(file)
+    }
+}
+
+impl StateStore for FileDirectory {
+    fn update<T>(&self, f: impl FnOnce(&mut DirectoryState) -> T) -> Result<T, DirectoryError> {
+        let _lock = self.lock()?;
+        let mut state: DirectoryState = match fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => DirectoryState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let value = f(&mut state);
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(&state)?)?;
+        fs::rename(&temp, &self.path)?;
+        Ok(value)
+    }
+}
+
+// "memory", or the path of a shared JSON file.
+pub fn open(spec: &str) -> Arc<dyn SessionDirectory> {
+    match spec {
+        "memory" => Arc::new(MemoryDirectory::default()),
+        path => Arc::new(FileDirectory::new(path.strip_prefix("file:").unwrap_or(path))),
+    }
+}
+
+pub struct Cluster {
+    node_id: Uuid,
+    rtp_address: SocketAddr,
+    directory: Arc<dyn SessionDirectory>,
+    node_timeout: Duration,
+}
+
+impl Cluster {
+    pub fn new(node_id: Uuid, rtp_address: SocketAddr, directory: Arc<dyn SessionDirectory>, node_timeout: Duration) -> Self {
+        Self { node_id, rtp_address, directory, node_timeout }
+    }
+
+    pub fn node_id(&self) -> Uuid {
+        self.node_id
+    }
+
+    pub fn heartbeat(&self) -> Result<(), DirectoryError> {
+        self.directory.heartbeat(&NodeRecord { id: self.node_id, rtp_address: self.rtp_address, heartbeat_ms: now_ms() })
+    }
+
+    pub fn nodes(&self) -> Result<Vec<NodeRecord>, DirectoryError> {
+        self.directory.nodes()
+    }
+
+    pub fn live_nodes(&self) -> Result<Vec<NodeRecord>, DirectoryError> {
+        let cutoff = now_ms().saturating_sub(self.node_timeout.as_millis() as u64);
+        Ok(self.directory.nodes()?.into_iter().filter(|n| n.heartbeat_ms >= cutoff).collect())
+    }
+
+    pub fn sessions(&self) -> Result<Vec<SessionRecord>, DirectoryError> {
+        self.directory.sessions()
+    }
+
+    pub fn session(&self, id: &SessionId) -> Result<Option<SessionRecord>, DirectoryError> {
+        Ok(self.directory.sessions()?.into_iter().find(|s| s.id == *id))
+    }
+
+    pub fn remove_session(&self, id: &SessionId) -> Result<bool, DirectoryError> {
+        self.directory.remove_session(id)
+    }
+
+    // Least loaded live node, ties going to the lowest id so every node
+    // picks the same one.
+    fn choose_owner(live: &[NodeRecord], sessions: &[SessionRecord]) -> Option<Uuid> {
+        live.iter()
+            .map(|n| (sessions.iter().filter(|s| s.owner == n.id).count(), n.id))
+            .min()
+            .map(|(_, id)| id)
+    }
+
+    // Records a new session and picks the node that serves it.
+    pub fn place(&self, source_addr: SocketAddr, ssrc: u32, options: &SessionOptions) -> Result<SessionRecord, DirectoryError> {
+        let owner = Self::choose_owner(&self.live_nodes()?, &self.directory.sessions()?).ok_or(DirectoryError::NoLiveNodes)?;
+        let record = SessionRecord {
+            id: options.id.unwrap_or_default(),
+            owner,
+            source_addr,
+            ssrc,
+            ssrcs: options.ssrcs.clone(),
+            mids: options.mids.clone(),
+            demux_by_source: options.demux_by_source,
+            sdp: options.description.as_ref().map(|d| d.to_string()),
+            media_type: options.media_type.map(|t| t.as_str().to_string()),
+            created_ms: now_ms(),
+            rate_limit: options.rate_limit,
+            fec: options.fec,
+            reorder_latency_ms: options.reorder.map(|mode| match mode {
+                ReorderMode::Bypass => 0,
+                ReorderMode::Buffer(latency) => latency.as_millis() as u64,
+            }),
+            subscribers: Vec::new(),
+        };
+        self.directory.put_session(&record)?;
+        info!("Placed session {} on node {}", record.id.0, owner);
+        Ok(record)
+    }
+
+    // Takes over sessions of dead nodes and returns every session this node
+    // should be serving.
+    pub fn owned_sessions(&self) -> Result<Vec<SessionRecord>, DirectoryError> {
+        let live = self.live_nodes()?;
+        let mut sessions = self.directory.sessions()?;
+        for index in 0..sessions.len() {
+            let record = &sessions[index];
+            if live.iter().any(|n| n.id == record.owner) {
+                continue;
+            }
+            let Some(owner) = Self::choose_owner(&live, &sessions) else {
+                break;
+            };
+            let (id, previous) = (record.id, record.owner);
+            if self.directory.reassign(&id, previous, owner)? {
+                info!("Session {} moved from dead node {} to {}", id.0, previous, owner);
+                sessions[index].owner = owner;
+            }
+        }
+        Ok(sessions.into_iter().filter(|s| s.owner == self.node_id).collect())
+    }
+
+    // Stores the current subscribers of an owned session so a node taking it
+    // over can restore them.
+    pub fn save_subscribers(&self, record: &SessionRecord, session: &Session) -> Result<(), DirectoryError> {
+        let subscribers = SubscriberRecord::snapshot(session);
+        if subscribers != record.subscribers {
+            self.directory.set_subscribers(&record.id, self.node_id, &subscribers)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(directory: &Arc<dyn SessionDirectory>, port: u16) -> Cluster {
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        Cluster::new(Uuid::new_v4(), address, directory.clone(), Duration::from_secs(10))
+    }
+
+    #[test]
+    fn test_placement_and_failover() {
+        let directory: Arc<dyn SessionDirectory> = Arc::new(MemoryDirectory::default());
+        let (a, b) = (node(&directory, 5004), node(&directory, 6004));
+        a.heartbeat().unwrap();
+        b.heartbeat().unwrap();
+
+        let source = SocketAddr::from(([10, 0, 0, 1], 5004));
+        let first = a.place(source, 1, &SessionOptions::default()).unwrap();
+        let second = a.place(source, 2, &SessionOptions::default()).unwrap();
+        assert_ne!(first.owner, second.owner);
+        assert_eq!(a.owned_sessions().unwrap().len() + b.owned_sessions().unwrap().len(), 2);
+
+        // b stops heartbeating
+        let mut stale = directory.nodes().unwrap().into_iter().find(|n| n.id == b.node_id()).unwrap();
+        stale.heartbeat_ms -= 60_000;
+        directory.heartbeat(&stale).unwrap();
+        assert_eq!(a.owned_sessions().unwrap().len(), 2);
+        assert!(b.owned_sessions().unwrap().is_empty());
+        assert_eq!(a.session(&second.id).unwrap().unwrap().owner, a.node_id());
+    }
+
+    #[test]
+    fn test_file_directory_round_trip() {
+        let path = std::env::temp_dir().join(format!("rtp-fanout-cluster-{}.json", Uuid::new_v4()));
+        let directory: Arc<dyn SessionDirectory> = Arc::new(FileDirectory::new(&path));
+        let cluster = node(&directory, 5004);
+        cluster.heartbeat().unwrap();
+
+        let mut options = SessionOptions {
+            ssrcs: vec![7, 8],
+            media_type: Some(MediaType::Audio),
+            reorder: Some(ReorderMode::Buffer(Duration::from_millis(40))),
+            ..Default::default()
+        };
+        options.mids.push("audio".to_string());
+        let record = cluster.place(SocketAddr::from(([10, 0, 0, 1], 5004)), 7, &options).unwrap();
+
+        // A second handle on the file sees the same directory
+        let reopened = open(&format!("file:{}", path.display()));
+        assert_eq!(reopened.sessions().unwrap(), vec![record.clone()]);
+        let restored = record.options();
+        assert_eq!(restored.id, Some(record.id));
+        assert_eq!(restored.media_type, Some(MediaType::Audio));
+        assert_eq!(restored.mids, vec!["audio".to_string()]);
+        assert_eq!(restored.reorder, Some(ReorderMode::Buffer(Duration::from_millis(40))));
+
+        let subscriber = SubscriberRecord { addr: SocketAddr::from(([10, 0, 0, 2], 6000)), filter: SubscriberFilter::default() };
+        assert!(reopened.set_subscribers(&record.id, cluster.node_id(), std::slice::from_ref(&subscriber)).unwrap());
+        assert!(!reopened.set_subscribers(&record.id, Uuid::new_v4(), &[]).unwrap());
+        assert_eq!(cluster.session(&record.id).unwrap().unwrap().subscribers, vec![subscriber]);
+
+        assert!(reopened.remove_session(&record.id).unwrap());
+        assert!(cluster.sessions().unwrap().is_empty());
+        fs::remove_file(&path).ok();
+        fs::remove_file(path.with_extension("json.lock")).ok();
+    }
+}
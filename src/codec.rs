@@ -0,0 +1,1144 @@
+// Just enough payload parsing to tell where a decoder can start: layer
+// switches are only safe at the first packet of a keyframe.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::RtpPacket;
+
+// Packets looked at before guessing the codec of a stream without an SDP.
+const SNIFF_PACKETS: usize = 32;
+// Opus frame sizes from 2.5 to 60 ms, in 48 kHz ticks.
+const OPUS_FRAME_TICKS: [u32; 6] = [120, 240, 480, 960, 1920, 2880];
+// Larger timestamp jumps restart the frame rate measurement.
+const MAX_RATE_WINDOW_SECS: u32 = 5;
+// Frame size statistics cover this many recent frames.
+const FRAME_SIZE_WINDOW: usize = 300;
+// Streams that sent nothing for this long are forgotten.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// Redundancy, FEC, comfort noise and DTMF share an SSRC with the media they
+// accompany but carry no frames of their own.
+const AUXILIARY_ENCODINGS: [&str; 6] = ["RED", "ULPFEC", "FLEXFEC", "FLEXFEC-03", "CN", "TELEPHONE-EVENT"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+    Opus,
+    Unknown,
+}
+
+impl Codec {
+    pub fn from_encoding(encoding: &str) -> Self {
+        match encoding.to_ascii_uppercase().as_str() {
+            "H264" => Codec::H264,
+            "H265" | "HEVC" => Codec::H265,
+            "VP8" => Codec::Vp8,
+            "VP9" => Codec::Vp9,
+            "AV1" | "AV1X" => Codec::Av1,
+            "OPUS" => Codec::Opus,
+            _ => Codec::Unknown,
+        }
+    }
+
+    pub fn is_auxiliary_encoding(encoding: &str) -> bool {
+        AUXILIARY_ENCODINGS.iter().any(|e| e.eq_ignore_ascii_case(encoding))
+    }
+
+    pub fn is_video(&self) -> bool {
+        matches!(self, Codec::H264 | Codec::H265 | Codec::Vp8 | Codec::Vp9 | Codec::Av1)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Codec::H264 => "H264",
+            Codec::H265 => "H265",
+            Codec::Vp8 => "VP8",
+            Codec::Vp9 => "VP9",
+            Codec::Av1 => "AV1",
+            Codec::Opus => "opus",
+            Codec::Unknown => "unknown",
+        }
+    }
+
+    pub fn media_type(&self) -> Option<MediaType> {
+        match self {
+            Codec::Opus => Some(MediaType::Audio),
+            Codec::Unknown => None,
+            _ => Some(MediaType::Video),
+        }
+    }
+
+    pub fn clock_rate(&self) -> u32 {
+        match self {
+            Codec::Opus => 48000,
+            _ => 90000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Audio,
+    Video,
+    Data,
+}
+
+impl MediaType {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "audio" => Some(MediaType::Audio),
+            "video" => Some(MediaType::Video),
+            "data" | "application" => Some(MediaType::Data),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Audio => "audio",
+            MediaType::Video => "video",
+            MediaType::Data => "data",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaStreamStats {
+    pub ssrc: u32,
+    pub payload_type: u8,
+    pub codec: Codec,
+    // Guessed from the payloads rather than signalled.
+    pub sniffed: bool,
+    pub frames: u64,
+    pub keyframes: u64,
+    pub since_keyframe: Option<Duration>,
+    pub resolution: Option<(u32, u32)>,
+    // Measured from RTP timestamps; 0 until a second has been seen.
+    pub frame_rate: f64,
+    // Average time between keyframes.
+    pub keyframe_interval: Option<Duration>,
+    pub frame_size: Option<FrameSizeStats>,
+    pub frozen: bool,
+    pub freezes: u64,
+}
+
+// Payload bytes per frame over the last FRAME_SIZE_WINDOW frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSizeStats {
+    pub min: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub max: u64,
+}
+
+impl FrameSizeStats {
+    fn from_sizes(sizes: &VecDeque<u64>) -> Option<Self> {
+        let mut sorted: Vec<u64> = sizes.iter().copied().collect();
+        sorted.sort_unstable();
+        let percentile = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+        Some(Self {
+            min: *sorted.first()?,
+            mean: sorted.iter().sum::<u64>() / sorted.len() as u64,
+            p50: percentile(50),
+            p95: percentile(95),
+            max: *sorted.last()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreezeChange {
+    // No complete frame for the threshold; `stalled` is the time since the last one.
+    Frozen { ssrc: u32, stalled: Duration },
+    Resumed { ssrc: u32, frozen_for: Duration },
+}
+
+// Guesses the codec of a stream from payload structure. Video candidates are
+// tried in order; the first whose header rules hold for nearly every packet
+// wins. Streams that never repeat a timestamp and step by Opus frame sizes are
+// taken for Opus.
+#[derive(Debug, Default)]
+struct CodecSniffer {
+    packets: usize,
+    shared_timestamps: usize,
+    last_timestamp: Option<u32>,
+    deltas: Vec<u32>,
+    plausible: [usize; 5],
+}
+
+const SNIFF_CANDIDATES: [Codec; 5] = [Codec::H264, Codec::Vp8, Codec::H265, Codec::Av1, Codec::Vp9];
+
+impl CodecSniffer {
+    fn observe(&mut self, packet: &RtpPacket) -> Option<Codec> {
+        let new_frame = self.last_timestamp != Some(packet.timestamp);
+        match self.last_timestamp {
+            Some(_) if !new_frame => self.shared_timestamps += 1,
+            Some(last) => self.deltas.push(packet.timestamp.wrapping_sub(last)),
+            None => {}
+        }
+        self.last_timestamp = Some(packet.timestamp);
+        self.packets += 1;
+        for (count, codec) in self.plausible.iter_mut().zip(SNIFF_CANDIDATES) {
+            if plausible(codec, &packet.payload, new_frame) {
+                *count += 1;
+            }
+        }
+
+        (self.packets >= SNIFF_PACKETS).then(|| self.verdict())
+    }
+
+    fn verdict(&self) -> Codec {
+        let mut deltas = self.deltas.clone();
+        deltas.sort_unstable();
+        let median = deltas.get(deltas.len() / 2);
+        if self.shared_timestamps == 0 && median.is_some_and(|d| OPUS_FRAME_TICKS.contains(d)) {
+            return Codec::Opus;
+        }
+        SNIFF_CANDIDATES
+            .into_iter()
+            .zip(self.plausible)
+            .find(|(_, count)| count * 10 >= self.packets * 9)
+            .map_or(Codec::Unknown, |(codec, _)| codec)
+    }
+}
+
+// Header rules of each payload format; `new_frame` packets must start one.
+fn plausible(codec: Codec, payload: &[u8], new_frame: bool) -> bool {
+    let Some(&first) = payload.first() else {
+        return false;
+    };
+    match codec {
+        Codec::H264 => {
+            first & 0x80 == 0
+                && match first & 0x1F {
+                    1 | 5..=12 | 24 => true,
+                    28 => payload.get(1).is_some_and(|&fu| {
+                        fu & 0x20 == 0 && fu & 0xC0 != 0xC0 && (!new_frame || fu & 0x80 != 0)
+                    }),
+                    _ => false,
+                }
+        }
+        Codec::H265 => {
+            let Some(&second) = payload.get(1) else {
+                return false;
+            };
+            // forbidden bit, nuh_layer_id 0, nuh_temporal_id_plus1 non-zero
+            if first & 0x81 != 0 || second & 0xF8 != 0 || second & 0x07 == 0 {
+                return false;
+            }
+            match (first >> 1) & 0x3F {
+                0..=9 | 16..=21 | 32..=40 | 48 => true,
+                49 => payload.get(2).is_some_and(|&fu| fu & 0xC0 != 0xC0 && (!new_frame || fu & 0x80 != 0)),
+                _ => false,
+            }
+        }
+        // Reserved bits zero; frames start with S set on partition 0
+        Codec::Vp8 => {
+            first & 0x48 == 0
+                && (first & 0x80 == 0 || payload.get(1).is_some_and(|ext| ext & 0x0F == 0))
+                && (!new_frame || (first & 0x10 != 0 && first & 0x07 == 0))
+        }
+        Codec::Vp9 => !new_frame || first & 0x08 != 0,
+        Codec::Av1 => {
+            if first & 0x07 != 0 || (new_frame && first & 0x80 != 0) {
+                return false;
+            }
+            if first & 0x80 != 0 {
+                return true;
+            }
+            // The first OBU header, behind a LEB128 length unless W is 1
+            let mut i = 1;
+            if (first >> 4) & 0x03 != 1 {
+                while payload.get(i).is_some_and(|b| b & 0x80 != 0) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            payload
+                .get(i)
+                .is_some_and(|&obu| obu & 0x81 == 0 && matches!((obu >> 3) & 0x0F, 1..=8 | 15))
+        }
+        Codec::Opus | Codec::Unknown => false,
+    }
+}
+
+// Picture size signalled in a packet: an SPS for H.264/H.265, the keyframe
+// header for VP8, the scalability structure for VP9.
+pub fn resolution(codec: Codec, payload: &[u8]) -> Option<(u32, u32)> {
+    match codec {
+        Codec::H264 | Codec::H265 => {
+            let (header_len, sps_type, aggregate) = match codec {
+                Codec::H264 => (1, 7, 24),
+                _ => (2, 33, 48),
+            };
+            let nal_type = |unit: &[u8]| match codec {
+                Codec::H264 => unit.first().map(|h| h & 0x1F),
+                _ => unit.first().map(|h| (h >> 1) & 0x3F),
+            };
+            let mut units = Vec::new();
+            if nal_type(payload) == Some(aggregate) {
+                let mut data = payload.get(header_len..)?;
+                while data.len() > 2 {
+                    let size = u16::from_be_bytes([data[0], data[1]]) as usize;
+                    let Some(unit) = data.get(2..2 + size) else {
+                        break;
+                    };
+                    units.push(unit);
+                    data = &data[2 + size..];
+                }
+            } else {
+                units.push(payload);
+            }
+            units
+                .into_iter()
+                .filter(|unit| nal_type(unit) == Some(sps_type))
+                .find_map(|unit| parse_sps(codec, unit))
+                .map(|info| (info.width, info.height))
+        }
+        Codec::Vp8 => {
+            if !vp8_keyframe_start(payload) {
+                return None;
+            }
+            let i = vp8_payload_offset(payload)?;
+            let header = payload.get(i..i + 10)?;
+            if header[3..6] != [0x9D, 0x01, 0x2A] {
+                return None;
+            }
+            let width = u16::from_le_bytes([header[6], header[7]]) & 0x3FFF;
+            let height = u16::from_le_bytes([header[8], header[9]]) & 0x3FFF;
+            Some((width as u32, height as u32))
+        }
+        Codec::Vp9 => vp9_resolution(payload),
+        _ => None,
+    }
+}
+
+fn vp9_resolution(payload: &[u8]) -> Option<(u32, u32)> {
+    let first = *payload.first()?;
+    if first & 0x02 == 0 {
+        return None;
+    }
+    let mut i = 1;
+    if first & 0x80 != 0 {
+        i += if payload.get(i)? & 0x80 != 0 { 2 } else { 1 };
+    }
+    // Layer indices, plus TL0PICIDX in non-flexible mode
+    if first & 0x20 != 0 {
+        i += if first & 0x10 != 0 { 1 } else { 2 };
+    }
+    // Reference indices in flexible mode
+    if first & 0x50 == 0x50 {
+        while payload.get(i)? & 0x01 != 0 {
+            i += 1;
+        }
+        i += 1;
+    }
+    let structure = *payload.get(i)?;
+    if structure & 0x10 == 0 {
+        return None;
+    }
+    // The highest spatial layer's size
+    let top = i + 1 + (structure >> 5) as usize * 4;
+    let size = payload.get(top..top + 4)?;
+    Some((u16::from_be_bytes([size[0], size[1]]) as u32, u16::from_be_bytes([size[2], size[3]]) as u32))
+}
+
+#[derive(Debug)]
+struct StreamInspection {
+    payload_type: u8,
+    format: Option<(Codec, u32)>,
+    sniffed: bool,
+    sniffer: CodecSniffer,
+    frames: u64,
+    keyframes: u64,
+    timestamp: Option<u32>,
+    keyframe_timestamp: Option<u32>,
+    last_keyframe: Option<Instant>,
+    resolution: Option<(u32, u32)>,
+    // RTP timestamp and frame count since the start of the measurement.
+    rate_window: Option<(u32, u64)>,
+    frame_rate: f64,
+    first_keyframe: Option<Instant>,
+    frame_bytes: u64,
+    frame_sizes: VecDeque<u64>,
+    last_frame: Option<Instant>,
+    next_sequence: Option<u16>,
+    // A packet of the current frame may have been lost.
+    frame_gap: bool,
+    // Time of the last frame before the stream froze.
+    frozen_since: Option<Instant>,
+    freezes: u64,
+    last_seen: Instant,
+}
+
+impl StreamInspection {
+    fn new(payload_type: u8, now: Instant) -> Self {
+        Self {
+            payload_type,
+            format: None,
+            sniffed: false,
+            sniffer: CodecSniffer::default(),
+            frames: 0,
+            keyframes: 0,
+            timestamp: None,
+            keyframe_timestamp: None,
+            last_keyframe: None,
+            resolution: None,
+            rate_window: None,
+            frame_rate: 0.0,
+            first_keyframe: None,
+            frame_bytes: 0,
+            frame_sizes: VecDeque::new(),
+            last_frame: None,
+            next_sequence: None,
+            frame_gap: false,
+            frozen_since: None,
+            freezes: 0,
+            last_seen: now,
+        }
+    }
+
+    fn is_video(&self) -> bool {
+        self.format.is_some_and(|(codec, _)| codec.is_video())
+    }
+
+    // Frames missing packets don't count as delivered.
+    fn on_frame_complete(&mut self, now: Instant) {
+        let bytes = std::mem::take(&mut self.frame_bytes);
+        if std::mem::take(&mut self.frame_gap) {
+            return;
+        }
+        if self.frame_sizes.len() == FRAME_SIZE_WINDOW {
+            self.frame_sizes.pop_front();
+        }
+        self.frame_sizes.push_back(bytes);
+        self.last_frame = Some(now);
+    }
+
+    fn keyframe_interval(&self) -> Option<Duration> {
+        let span = self.last_keyframe?.saturating_duration_since(self.first_keyframe?);
+        (self.keyframes > 1).then(|| span / (self.keyframes - 1) as u32)
+    }
+
+    fn on_frame_start(&mut self, timestamp: u32, clock_rate: u32) {
+        self.frames += 1;
+        let Some((start, frames)) = self.rate_window.as_mut() else {
+            self.rate_window = Some((timestamp, 0));
+            return;
+        };
+        *frames += 1;
+        let span = timestamp.wrapping_sub(*start);
+        if span > clock_rate * MAX_RATE_WINDOW_SECS {
+            self.rate_window = Some((timestamp, 0));
+        } else if span >= clock_rate {
+            self.frame_rate = *frames as f64 * clock_rate as f64 / span as f64;
+            self.rate_window = Some((timestamp, 0));
+        }
+    }
+}
+
+// Per-SSRC and payload type view of what a session carries: codec, frame
+// boundaries, keyframes, picture size and frame rate.
+#[derive(Debug, Default)]
+pub struct MediaInspector {
+    streams: HashMap<(u32, u8), StreamInspection>,
+}
+
+impl MediaInspector {
+    // `declared` is the payload type's format from the SDP, if there is one.
+    // Returns the format the packet is handled as: declared, else sniffed,
+    // else H.264.
+    pub fn observe(&mut self, packet: &RtpPacket, declared: Option<(Codec, u32)>, now: Instant) -> (Codec, u32) {
+        let key = (packet.ssrc, packet.payload_type);
+        if !self.streams.contains_key(&key) {
+            self.prune(now);
+        }
+        let stream = self
+            .streams
+            .entry(key)
+            .or_insert_with(|| StreamInspection::new(packet.payload_type, now));
+        stream.last_seen = now;
+
+        match declared {
+            Some(format) => stream.format = Some(format),
+            None if stream.format.is_none() => {
+                if let Some(codec) = stream.sniffer.observe(packet) {
+                    stream.format = Some((codec, codec.clock_rate()));
+                    stream.sniffed = true;
+                }
+            }
+            None => {}
+        }
+        let (codec, clock_rate) = stream.format.unwrap_or((Codec::H264, 90000));
+
+        // Late packets don't move the expected sequence back.
+        let ahead = stream.next_sequence.map(|expected| packet.sequence.wrapping_sub(expected));
+        let lost = codec.is_video() && ahead.is_some_and(|a| a != 0 && a < 0x8000);
+        if ahead.is_none_or(|a| a < 0x8000) {
+            stream.next_sequence = Some(packet.sequence.wrapping_add(1));
+        }
+
+        if stream.timestamp != Some(packet.timestamp) || !codec.is_video() {
+            // The previous frame ended without a marker, maybe because its
+            // tail was lost.
+            if stream.frame_bytes > 0 {
+                stream.frame_gap |= lost;
+                stream.on_frame_complete(now);
+            }
+            stream.timestamp = Some(packet.timestamp);
+            stream.on_frame_start(packet.timestamp, clock_rate);
+        }
+        stream.frame_gap |= lost;
+        stream.frame_bytes += packet.payload.len() as u64;
+        if packet.marker || !codec.is_video() {
+            stream.on_frame_complete(now);
+        }
+        if codec.is_video() && stream.keyframe_timestamp != Some(packet.timestamp) && is_keyframe_start(codec, &packet.payload) {
+            stream.keyframe_timestamp = Some(packet.timestamp);
+            stream.keyframes += 1;
+            stream.first_keyframe.get_or_insert(now);
+            stream.last_keyframe = Some(now);
+        }
+        if let Some(resolution) = resolution(codec, &packet.payload) {
+            stream.resolution = Some(resolution);
+        }
+        (codec, clock_rate)
+    }
+
+    // Codec sniffed for a payload type, for sessions without an SDP.
+    pub fn detected(&self, payload_type: u8) -> Option<(Codec, u32)> {
+        self.streams
+            .values()
+            .filter(|s| s.sniffed && s.payload_type == payload_type)
+            .find_map(|s| s.format)
+    }
+
+    // Video if any stream carries video, audio if any carries audio.
+    pub fn media_type(&self) -> Option<MediaType> {
+        let types: Vec<MediaType> = self.streams.values().filter_map(|s| s.format?.0.media_type()).collect();
+        [MediaType::Video, MediaType::Audio].into_iter().find(|t| types.contains(t))
+    }
+
+    pub fn stats(&self, now: Instant) -> Vec<MediaStreamStats> {
+        let mut stats: Vec<MediaStreamStats> = self
+            .streams
+            .iter()
+            .map(|(&(ssrc, _), stream)| MediaStreamStats {
+                ssrc,
+                payload_type: stream.payload_type,
+                codec: stream.format.map_or(Codec::Unknown, |(codec, _)| codec),
+                sniffed: stream.sniffed,
+                frames: stream.frames,
+                keyframes: stream.keyframes,
+                since_keyframe: stream.last_keyframe.map(|at| now.saturating_duration_since(at)),
+                resolution: stream.resolution,
+                frame_rate: stream.frame_rate,
+                keyframe_interval: stream.keyframe_interval(),
+                frame_size: FrameSizeStats::from_sizes(&stream.frame_sizes),
+                frozen: stream.frozen_since.is_some(),
+                freezes: stream.freezes,
+            })
+            .collect();
+        stats.sort_by_key(|s| (s.ssrc, s.payload_type));
+        stats
+    }
+
+    // Video streams that went `threshold` without a complete frame, and
+    // frozen ones that have delivered one since.
+    pub fn check_freezes(&mut self, now: Instant, threshold: Duration) -> Vec<FreezeChange> {
+        self.prune(now);
+        // A payload type the SSRC has switched away from isn't frozen.
+        let mut latest: HashMap<u32, Instant> = HashMap::new();
+        for (&(ssrc, _), stream) in self.streams.iter().filter(|(_, s)| s.is_video()) {
+            if let Some(last_frame) = stream.last_frame {
+                let entry = latest.entry(ssrc).or_insert(last_frame);
+                *entry = (*entry).max(last_frame);
+            }
+        }
+
+        let mut changes = Vec::new();
+        for (&(ssrc, _), stream) in self.streams.iter_mut().filter(|(_, s)| s.is_video()) {
+            let Some(last_frame) = stream.last_frame else {
+                continue;
+            };
+            if stream.frozen_since.is_none() && latest.get(&ssrc).is_some_and(|&l| l > last_frame) {
+                continue;
+            }
+            match stream.frozen_since {
+                Some(since) if last_frame > since => {
+                    stream.frozen_since = None;
+                    changes.push(FreezeChange::Resumed { ssrc, frozen_for: last_frame - since });
+                }
+                None if now.saturating_duration_since(last_frame) >= threshold => {
+                    stream.frozen_since = Some(last_frame);
+                    stream.freezes += 1;
+                    changes.push(FreezeChange::Frozen { ssrc, stalled: now - last_frame });
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+
+    pub fn frozen_streams(&self) -> usize {
+        self.streams.values().filter(|s| s.frozen_since.is_some()).count()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.streams
+            .retain(|_, s| now.saturating_duration_since(s.last_seen) < STREAM_IDLE_TIMEOUT);
+    }
+}
+
+// True for the first packet of a frame a decoder can start from. Audio and
+// unknown codecs have no such notion, so every packet qualifies.
+pub fn is_keyframe_start(codec: Codec, payload: &[u8]) -> bool {
+    match codec {
+        Codec::H264 => h264_keyframe_start(payload),
+        Codec::H265 => h265_keyframe_start(payload),
+        Codec::Vp8 => vp8_keyframe_start(payload),
+        Codec::Vp9 => vp9_keyframe_start(payload),
+        Codec::Av1 => av1_keyframe_start(payload),
+        Codec::Opus | Codec::Unknown => true,
+    }
+}
+
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub(crate) fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u32;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    pub(crate) fn skip(&mut self, bits: usize) -> Option<()> {
+        self.pos += bits;
+        (self.pos <= self.data.len() * 8).then_some(())
+    }
+
+    // Exp-Golomb, as used throughout H.264/H.265 parameter sets.
+    pub(crate) fn read_ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.read(1)? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.read(zeros)?)
+    }
+
+    pub(crate) fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()?;
+        Some(if value % 2 == 1 { value.div_ceil(2) as i32 } else { -((value / 2) as i32) })
+    }
+}
+
+// Picture format from a sequence parameter set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpsInfo {
+    pub width: u32,
+    pub height: u32,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+}
+
+// Strips the emulation prevention bytes (00 00 03) from a NAL unit.
+pub fn nal_to_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+// Parses an SPS NAL unit (with its header) into the cropped picture size.
+pub fn parse_sps(codec: Codec, nal: &[u8]) -> Option<SpsInfo> {
+    let rbsp = nal_to_rbsp(nal);
+    match codec {
+        Codec::H264 => parse_h264_sps(rbsp.get(1..)?),
+        Codec::H265 => parse_h265_sps(rbsp.get(2..)?),
+        _ => None,
+    }
+}
+
+fn parse_h264_sps(rbsp: &[u8]) -> Option<SpsInfo> {
+    let mut reader = BitReader::new(rbsp);
+    let profile_idc = reader.read(8)?;
+    // constraint flags, level_idc
+    reader.skip(16)?;
+    reader.read_ue()?;
+
+    let (mut chroma_format_idc, mut bit_depth_luma, mut bit_depth_chroma) = (1, 8, 8);
+    let mut separate_planes = false;
+    if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+        chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc == 3 {
+            separate_planes = reader.read(1)? == 1;
+        }
+        bit_depth_luma = reader.read_ue()?.checked_add(8)?;
+        bit_depth_chroma = reader.read_ue()?.checked_add(8)?;
+        reader.skip(1)?;
+        if reader.read(1)? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if reader.read(1)? == 1 {
+                    skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    reader.read_ue()?;
+    match reader.read_ue()? {
+        0 => {
+            reader.read_ue()?;
+        }
+        1 => {
+            reader.skip(1)?;
+            reader.read_se()?;
+            reader.read_se()?;
+            for _ in 0..reader.read_ue()? {
+                reader.read_se()?;
+            }
+        }
+        _ => {}
+    }
+    reader.read_ue()?;
+    reader.skip(1)?;
+
+    let width_mbs = reader.read_ue()? + 1;
+    let height_units = reader.read_ue()? + 1;
+    let frame_mbs_only = reader.read(1)?;
+    if frame_mbs_only == 0 {
+        reader.skip(1)?;
+    }
+    reader.skip(1)?;
+
+    let mut width = width_mbs.checked_mul(16)?;
+    let mut height = height_units.checked_mul(16 * (2 - frame_mbs_only))?;
+    if reader.read(1)? == 1 {
+        let (left, right, top, bottom) = (reader.read_ue()?, reader.read_ue()?, reader.read_ue()?, reader.read_ue()?);
+        let (crop_x, crop_y) = match chroma_format_idc {
+            _ if separate_planes => (1, 2 - frame_mbs_only),
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        width = crop(width, crop_x, left, right)?;
+        height = crop(height, crop_y, top, bottom)?;
+    }
+
+    Some(SpsInfo {
+        width,
+        height,
+        chroma_format_idc: u8::try_from(chroma_format_idc).ok()?,
+        bit_depth_luma: u8::try_from(bit_depth_luma).ok()?,
+        bit_depth_chroma: u8::try_from(bit_depth_chroma).ok()?,
+    })
+}
+
+// The size left after cropping `start` and `end` units off a dimension. The
+// offsets come straight from the bitstream, so nothing here may overflow.
+fn crop(size: u32, unit: u32, start: u32, end: u32) -> Option<u32> {
+    size.checked_sub(start.checked_add(end)?.checked_mul(unit)?)
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let (mut last, mut next) = (8i32, 8i32);
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + reader.read_se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+fn parse_h265_sps(rbsp: &[u8]) -> Option<SpsInfo> {
+    let mut reader = BitReader::new(rbsp);
+    // sps_video_parameter_set_id
+    reader.skip(4)?;
+    let sub_layers = reader.read(3)? as usize;
+    reader.skip(1)?;
+
+    // profile_tier_level: general profile and level, then the sub-layers
+    reader.skip(96)?;
+    let mut present = Vec::with_capacity(sub_layers);
+    for _ in 0..sub_layers {
+        present.push((reader.read(1)? == 1, reader.read(1)? == 1));
+    }
+    if sub_layers > 0 {
+        reader.skip(2 * (8 - sub_layers))?;
+    }
+    for (profile, level) in present {
+        if profile {
+            reader.skip(88)?;
+        }
+        if level {
+            reader.skip(8)?;
+        }
+    }
+
+    reader.read_ue()?;
+    let chroma_format_idc = reader.read_ue()?;
+    if chroma_format_idc == 3 {
+        reader.skip(1)?;
+    }
+    let mut width = reader.read_ue()?;
+    let mut height = reader.read_ue()?;
+    if reader.read(1)? == 1 {
+        let (left, right, top, bottom) = (reader.read_ue()?, reader.read_ue()?, reader.read_ue()?, reader.read_ue()?);
+        let sub_width = if matches!(chroma_format_idc, 1 | 2) { 2 } else { 1 };
+        let sub_height = if chroma_format_idc == 1 { 2 } else { 1 };
+        width = crop(width, sub_width, left, right)?;
+        height = crop(height, sub_height, top, bottom)?;
+    }
+
+    Some(SpsInfo {
+        width,
+        height,
+        chroma_format_idc: u8::try_from(chroma_format_idc).ok()?,
+        bit_depth_luma: u8::try_from(reader.read_ue()?.checked_add(8)?).ok()?,
+        bit_depth_chroma: u8::try_from(reader.read_ue()?.checked_add(8)?).ok()?,
+    })
+}
+
+fn h264_keyframe_start(payload: &[u8]) -> bool {
+    let Some(&header) = payload.first() else {
+        return false;
+    };
+    match header & 0x1F {
+        // IDR slice or SPS (which precedes the IDR in a keyframe)
+        5 | 7 => true,
+        // STAP-A: any aggregated unit being an SPS/IDR
+        24 => {
+            let mut i = 1;
+            while i + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[i], payload[i + 1]]) as usize;
+                if matches!(payload[i + 2] & 0x1F, 5 | 7) {
+                    return true;
+                }
+                i += 2 + size;
+            }
+            false
+        }
+        // FU-A: start fragment of an IDR slice
+        28 => payload.len() > 1 && payload[1] & 0x80 != 0 && payload[1] & 0x1F == 5,
+        _ => false,
+    }
+}
+
+fn h265_nal_is_keyframe(nal_type: u8) -> bool {
+    // IRAP pictures (16-21) and parameter sets (32-34)
+    (16..=21).contains(&nal_type) || (32..=34).contains(&nal_type)
+}
+
+fn h265_keyframe_start(payload: &[u8]) -> bool {
+    if payload.len() < 2 {
+        return false;
+    }
+    match (payload[0] >> 1) & 0x3F {
+        // Aggregation packet
+        48 => {
+            let mut i = 2;
+            while i + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[i], payload[i + 1]]) as usize;
+                if h265_nal_is_keyframe((payload[i + 2] >> 1) & 0x3F) {
+                    return true;
+                }
+                i += 2 + size;
+            }
+            false
+        }
+        // Fragmentation unit
+        49 => payload.len() > 2 && payload[2] & 0x80 != 0 && h265_nal_is_keyframe(payload[2] & 0x3F),
+        nal_type => h265_nal_is_keyframe(nal_type),
+    }
+}
+
+fn vp8_keyframe_start(payload: &[u8]) -> bool {
+    // RFC 7741 payload descriptor
+    let Some(&first) = payload.first() else {
+        return false;
+    };
+    let start = first & 0x10 != 0;
+    let partition = first & 0x07;
+    if !start || partition != 0 {
+        return false;
+    }
+
+    // P bit of the VP8 payload header is 0 for keyframes
+    vp8_payload_offset(payload)
+        .and_then(|i| payload.get(i))
+        .is_some_and(|header| header & 0x01 == 0)
+}
+
+// Length of the RFC 7741 payload descriptor.
+fn vp8_payload_offset(payload: &[u8]) -> Option<usize> {
+    let first = *payload.first()?;
+    let mut i = 1;
+    if first & 0x80 != 0 {
+        let ext = *payload.get(i)?;
+        i += 1;
+        if ext & 0x80 != 0 {
+            // PictureID, one or two bytes
+            i += if payload.get(i)? & 0x80 != 0 { 2 } else { 1 };
+        }
+        if ext & 0x40 != 0 {
+            i += 1;
+        }
+        if ext & 0x30 != 0 {
+            i += 1;
+        }
+    }
+    Some(i)
+}
+
+fn vp9_keyframe_start(payload: &[u8]) -> bool {
+    // RFC 9628 descriptor: I|P|L|F|B|E|V|Z. A keyframe is a non-inter-predicted
+    // picture; the B bit marks the start of the frame.
+    let Some(&first) = payload.first() else {
+        return false;
+    };
+    first & 0x40 == 0 && first & 0x08 != 0
+}
+
+fn av1_keyframe_start(payload: &[u8]) -> bool {
+    // Aggregation header Z|Y|W|W|N|-|-|-: N starts a new coded video sequence
+    // and Z continues an OBU from the previous packet.
+    let Some(&header) = payload.first() else {
+        return false;
+    };
+    header & 0x80 == 0 && header & 0x08 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_h264_keyframe_start() {
+        assert!(is_keyframe_start(Codec::H264, &[0x67, 0x42]));
+        assert!(is_keyframe_start(Codec::H264, &[0x7C, 0x85, 0x00]));
+        assert!(!is_keyframe_start(Codec::H264, &[0x7C, 0x05, 0x00]));
+        assert!(!is_keyframe_start(Codec::H264, &[0x41, 0x9A]));
+        assert!(is_keyframe_start(Codec::H264, &[0x78, 0x00, 0x02, 0x67, 0x42, 0x00, 0x01, 0x65]));
+    }
+
+    #[test]
+    fn test_vp8_keyframe_start() {
+        assert!(is_keyframe_start(Codec::Vp8, &[0x10, 0x00]));
+        assert!(!is_keyframe_start(Codec::Vp8, &[0x10, 0x01]));
+        assert!(!is_keyframe_start(Codec::Vp8, &[0x00, 0x00]));
+        // X, I with a 15-bit PictureID
+        assert!(is_keyframe_start(Codec::Vp8, &[0x90, 0x80, 0x81, 0x23, 0x00]));
+    }
+
+    fn packet(ssrc: u32, timestamp: u32, payload: &[u8]) -> RtpPacket {
+        RtpPacket {
+            payload: payload.to_vec(),
+            timestamp,
+            sequence: 0,
+            ssrc,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: None,
+            received_at: Instant::now(),
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn test_sniffs_codec_without_sdp() {
+        let now = Instant::now();
+        let mut inspector = MediaInspector::default();
+        // Two-packet VP8 frames at 30 fps; the keyframe after the first 32
+        // packets is 320x240
+        let keyframe = [0x10, 0x00, 0x00, 0x00, 0x9D, 0x01, 0x2A, 0x40, 0x01, 0xF0, 0x00];
+        for frame in 0..40u32 {
+            let first: &[u8] = if frame % 20 == 0 { &keyframe } else { &[0x10, 0x01] };
+            inspector.observe(&packet(1, frame * 3000, first), None, now);
+            inspector.observe(&packet(1, frame * 3000, &[0x00, 0x01]), None, now);
+        }
+        // 20ms Opus frames
+        for frame in 0..40u32 {
+            let opus = RtpPacket { payload_type: 111, ..packet(2, frame * 960, &[0xFC, 0xFF, 0xFE]) };
+            inspector.observe(&opus, None, now);
+        }
+
+        assert_eq!(inspector.detected(96), Some((Codec::Vp8, 90000)));
+        let stats = inspector.stats(now);
+        assert_eq!((stats[0].codec, stats[0].sniffed), (Codec::Vp8, true));
+        assert_eq!((stats[0].frames, stats[0].keyframes), (40, 1));
+        assert_eq!(stats[0].resolution, Some((320, 240)));
+        assert_eq!(stats[0].frame_rate, 30.0);
+        assert_eq!(stats[1].codec, Codec::Opus);
+        assert_eq!(inspector.media_type(), Some(MediaType::Video));
+    }
+
+    #[test]
+    fn test_declared_h264_resolution() {
+        let now = Instant::now();
+        let mut inspector = MediaInspector::default();
+        // STAP-A with a 1280x720 SPS and a PPS
+        let sps = [
+            0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00, 0x00, 0x03, 0x00, 0x10,
+            0x00, 0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19,
+        ];
+        let mut stap = vec![0x78, 0x00, sps.len() as u8];
+        stap.extend(sps);
+        stap.extend([0x00, 0x02, 0x68, 0xEB]);
+
+        let format = inspector.observe(&packet(1, 0, &stap), Some((Codec::H264, 90000)), now);
+        assert_eq!(format, (Codec::H264, 90000));
+        inspector.observe(&packet(1, 0, &[0x65, 0x88]), Some((Codec::H264, 90000)), now);
+
+        let stats = inspector.stats(now);
+        assert_eq!((stats[0].frames, stats[0].keyframes, stats[0].sniffed), (1, 1, false));
+        assert_eq!(stats[0].resolution, Some((1280, 720)));
+        assert_eq!(inspector.detected(96), None);
+    }
+
+    #[test]
+    fn test_sps_overflow_is_rejected() {
+        // Baseline SPS header fields, each Exp-Golomb value 0
+        let mut bits = String::from("01000010");
+        bits.push_str(&"0".repeat(16));
+        bits.push_str("111110");
+        // pic_width_in_mbs_minus1 = 2^32 - 3, so the width overflows
+        bits.push_str(&"0".repeat(31));
+        bits.push('1');
+        bits.push_str(&"1".repeat(30));
+        bits.push('0');
+        bits.push_str("1100");
+        while bits.len() % 8 != 0 {
+            bits.push('0');
+        }
+        let mut nal = vec![0x67];
+        for byte in bits.as_bytes().chunks(8) {
+            nal.push(u8::from_str_radix(std::str::from_utf8(byte).unwrap(), 2).unwrap());
+        }
+        assert_eq!(parse_sps(Codec::H264, &nal), None);
+
+        // A crop larger than the picture
+        assert_eq!(crop(64, 2, u32::MAX, 1), None);
+        assert_eq!(crop(64, 2, 4, 4), Some(48));
+    }
+
+    #[test]
+    fn test_frame_stats_and_freeze() {
+        let start = Instant::now();
+        let h264 = Some((Codec::H264, 90000));
+        let threshold = Duration::from_millis(500);
+        let mut inspector = MediaInspector::default();
+
+        // Keyframes every 10 frames of 100ms; each frame is two packets, the
+        // second with the marker
+        for frame in 0..30u32 {
+            let now = start + Duration::from_millis(100 * frame as u64);
+            let first: &[u8] = if frame % 10 == 0 { &[0x65, 0, 0, 0] } else { &[0x41, 0] };
+            inspector.observe(&packet(1, frame * 9000, first), h264, now);
+            let last = RtpPacket { marker: true, ..packet(1, frame * 9000, &[0x41, 0, 0]) };
+            inspector.observe(&last, h264, now);
+        }
+        let last_frame = start + Duration::from_millis(2900);
+        assert!(inspector.check_freezes(last_frame + Duration::from_millis(400), threshold).is_empty());
+
+        let stats = &inspector.stats(last_frame)[0];
+        assert_eq!(stats.keyframe_interval, Some(Duration::from_secs(1)));
+        let sizes = stats.frame_size.unwrap();
+        assert_eq!((sizes.min, sizes.p50, sizes.max), (5, 5, 7));
+
+        // Nothing for 600ms, then frames again
+        let frozen = inspector.check_freezes(last_frame + Duration::from_millis(600), threshold);
+        assert_eq!(frozen, vec![FreezeChange::Frozen { ssrc: 1, stalled: Duration::from_millis(600) }]);
+        assert_eq!(inspector.frozen_streams(), 1);
+
+        let resumed_at = last_frame + Duration::from_millis(1000);
+        let resumed = RtpPacket { marker: true, ..packet(1, 39 * 9000, &[0x41]) };
+        inspector.observe(&resumed, h264, resumed_at);
+        let changes = inspector.check_freezes(resumed_at, threshold);
+        assert_eq!(changes, vec![FreezeChange::Resumed { ssrc: 1, frozen_for: Duration::from_millis(1000) }]);
+        assert_eq!(inspector.stats(resumed_at)[0].freezes, 1);
+    }
+
+    #[test]
+    fn test_frames_with_losses_are_not_delivered() {
+        let start = Instant::now();
+        let h264 = Some((Codec::H264, 90000));
+        let threshold = Duration::from_millis(500);
+        let mut inspector = MediaInspector::default();
+        let mut sequence = 0u16;
+        let mut send = |inspector: &mut MediaInspector, frame: u32, lose_first: bool| {
+            let now = start + Duration::from_millis(100 * frame as u64);
+            if !lose_first {
+                let first = RtpPacket { sequence, ..packet(1, frame * 9000, &[0x41, 0]) };
+                inspector.observe(&first, h264, now);
+            }
+            let last = RtpPacket { sequence: sequence + 1, marker: true, ..packet(1, frame * 9000, &[0x41]) };
+            inspector.observe(&last, h264, now);
+            sequence += 2;
+        };
+
+        send(&mut inspector, 0, false);
+        // Every later frame arrives missing its first packet
+        for frame in 1..10 {
+            send(&mut inspector, frame, true);
+        }
+        let frozen = inspector.check_freezes(start + Duration::from_millis(900), threshold);
+        assert_eq!(frozen, vec![FreezeChange::Frozen { ssrc: 1, stalled: Duration::from_millis(900) }]);
+        assert_eq!(inspector.stats(start)[0].frame_size.unwrap().max, 3);
+    }
+
+    #[test]
+    fn test_payload_types_tracked_apart_and_pruned() {
+        let start = Instant::now();
+        let h264 = Some((Codec::H264, 90000));
+        let mut inspector = MediaInspector::default();
+
+        // Redundancy packets on another payload type leave the video's stats
+        for frame in 0..5u32 {
+            let video = RtpPacket { marker: true, ..packet(1, frame * 3000, &[0x41, 0]) };
+            inspector.observe(&video, h264, start);
+            let red = RtpPacket { payload_type: 97, ..packet(1, frame * 3000, &[0x60]) };
+            inspector.observe(&red, None, start);
+        }
+        let stats = inspector.stats(start);
+        assert_eq!((stats[0].payload_type, stats[0].frames), (96, 5));
+        assert_eq!(stats[1].payload_type, 97);
+
+        // A departed SSRC is forgotten, frozen or not
+        let later = start + STREAM_IDLE_TIMEOUT;
+        inspector.check_freezes(later, Duration::from_millis(500));
+        assert!(inspector.stats(later).is_empty());
+        assert_eq!(inspector.frozen_streams(), 0);
+    }
+}
@@ -22,6 +22,147 @@ pub struct ServerConfig {
     
     #[serde(default = "default_metrics_bind_address")]
     pub metrics_bind_address: String,
+    
+    #[serde(default = "default_enable_rtsp")]
+    pub enable_rtsp: bool,
+    
+    #[serde(default = "default_rtsp_bind_address")]
+    pub rtsp_bind_address: String,
+    
+    #[serde(default = "default_rtsp_session_timeout_secs")]
+    pub rtsp_session_timeout_secs: u64,
+    
+    #[serde(default = "default_rtsp_multicast_address")]
+    pub rtsp_multicast_address: String,
+    
+    #[serde(default = "default_enable_tcp")]
+    pub enable_tcp: bool,
+    
+    #[serde(default = "default_tcp_bind_address")]
+    pub tcp_bind_address: String,
+    
+    #[serde(default = "default_tcp_send_queue_depth")]
+    pub tcp_send_queue_depth: usize,
+    
+    #[serde(default = "default_multicast_ttl")]
+    pub multicast_ttl: u32,
+    
+    #[serde(default = "default_multicast_interface")]
+    pub multicast_interface: String,
+    
+    #[serde(default = "default_enable_session_ports")]
+    pub enable_session_ports: bool,
+    
+    #[serde(default = "default_session_port_range_start")]
+    pub session_port_range_start: u16,
+    
+    #[serde(default = "default_session_port_range_end")]
+    pub session_port_range_end: u16,
+    
+    #[serde(default = "default_session_port_pairs")]
+    pub session_port_pairs: bool,
+    
+    #[serde(default = "default_enable_twcc")]
+    pub enable_twcc: bool,
+    
+    #[serde(default = "default_twcc_extension_id")]
+    pub twcc_extension_id: u8,
+    
+    #[serde(default = "default_bwe_start_bitrate_bps")]
+    pub bwe_start_bitrate_bps: u64,
+    
+    #[serde(default = "default_bwe_min_bitrate_bps")]
+    pub bwe_min_bitrate_bps: u64,
+    
+    #[serde(default = "default_bwe_max_bitrate_bps")]
+    pub bwe_max_bitrate_bps: u64,
+    
+    #[serde(default = "default_pacing_mode")]
+    pub pacing_mode: String,
+    
+    #[serde(default = "default_pacing_rate_bps")]
+    pub pacing_rate_bps: u64,
+    
+    #[serde(default = "default_pacing_bwe_multiplier")]
+    pub pacing_bwe_multiplier: f64,
+    
+    #[serde(default = "default_pacing_queue_depth")]
+    pub pacing_queue_depth: usize,
+    
+    #[serde(default = "default_source_rate_limit_pps")]
+    pub source_rate_limit_pps: u64,
+    
+    #[serde(default = "default_source_rate_limit_bps")]
+    pub source_rate_limit_bps: u64,
+    
+    #[serde(default = "default_session_rate_limit_pps")]
+    pub session_rate_limit_pps: u64,
+    
+    #[serde(default = "default_session_rate_limit_bps")]
+    pub session_rate_limit_bps: u64,
+    
+    #[serde(default = "default_policing_burst_secs")]
+    pub policing_burst_secs: f64,
+    
+    #[serde(default = "default_policing_action")]
+    pub policing_action: String,
+    
+    #[serde(default = "default_policing_suspend_secs")]
+    pub policing_suspend_secs: u64,
+    
+    #[serde(default = "default_fec_mode")]
+    pub fec_mode: String,
+    
+    #[serde(default = "default_fec_protection_percent")]
+    pub fec_protection_percent: u32,
+    
+    #[serde(default = "default_fec_payload_type")]
+    pub fec_payload_type: u8,
+    
+    #[serde(default = "default_fec_red_payload_type")]
+    pub fec_red_payload_type: u8,
+    
+    #[serde(default = "default_reorder_latency_ms")]
+    pub reorder_latency_ms: u64,
+    
+    #[serde(default = "default_reorder_max_packets")]
+    pub reorder_max_packets: usize,
+    
+    #[serde(default = "default_recording_directory")]
+    pub recording_directory: String,
+    
+    #[serde(default = "default_recording_max_file_bytes")]
+    pub recording_max_file_bytes: u64,
+    
+    #[serde(default = "default_recording_max_file_secs")]
+    pub recording_max_file_secs: u64,
+    
+    #[serde(default = "default_recording_queue_depth")]
+    pub recording_queue_depth: usize,
+    
+    #[serde(default = "default_video_freeze_threshold_ms")]
+    pub video_freeze_threshold_ms: u64,
+    
+    #[serde(default = "default_tap_max_packets_per_second")]
+    pub tap_max_packets_per_second: u32,
+    
+    #[serde(default = "default_tap_max_duration_secs")]
+    pub tap_max_duration_secs: u64,
+    
+    #[serde(default = "default_relay_bind_address")]
+    pub relay_bind_address: String,
+    
+    #[serde(default = "default_server_id")]
+    pub server_id: String,
+    
+    #[serde(default = "default_cluster_directory")]
+    pub cluster_directory: String,
+    
+    #[serde(default = "default_cluster_advertise_address")]
+    pub cluster_advertise_address: String,
+    
+    #[serde(default = "default_cluster_node_timeout_secs")]
+    pub cluster_node_timeout_secs: u64,
 }
 
 impl Default for ServerConfig {
@@ -34,6 +175,53 @@ impl Default for ServerConfig {
             session_timeout_secs: default_session_timeout_secs(),
             enable_metrics: default_enable_metrics(),
             metrics_bind_address: default_metrics_bind_address(),
+            enable_rtsp: default_enable_rtsp(),
+            rtsp_bind_address: default_rtsp_bind_address(),
+            rtsp_session_timeout_secs: default_rtsp_session_timeout_secs(),
+            rtsp_multicast_address: default_rtsp_multicast_address(),
+            enable_tcp: default_enable_tcp(),
+            tcp_bind_address: default_tcp_bind_address(),
+            tcp_send_queue_depth: default_tcp_send_queue_depth(),
+            multicast_ttl: default_multicast_ttl(),
+            multicast_interface: default_multicast_interface(),
+            enable_session_ports: default_enable_session_ports(),
+            session_port_range_start: default_session_port_range_start(),
+            session_port_range_end: default_session_port_range_end(),
+            session_port_pairs: default_session_port_pairs(),
+            enable_twcc: default_enable_twcc(),
+            twcc_extension_id: default_twcc_extension_id(),
+            bwe_start_bitrate_bps: default_bwe_start_bitrate_bps(),
+            bwe_min_bitrate_bps: default_bwe_min_bitrate_bps(),
+            bwe_max_bitrate_bps: default_bwe_max_bitrate_bps(),
+            pacing_mode: default_pacing_mode(),
+            pacing_rate_bps: default_pacing_rate_bps(),
+            pacing_bwe_multiplier: default_pacing_bwe_multiplier(),
+            pacing_queue_depth: default_pacing_queue_depth(),
+            source_rate_limit_pps: default_source_rate_limit_pps(),
+            source_rate_limit_bps: default_source_rate_limit_bps(),
+            session_rate_limit_pps: default_session_rate_limit_pps(),
+            session_rate_limit_bps: default_session_rate_limit_bps(),
+            policing_burst_secs: default_policing_burst_secs(),
+            policing_action: default_policing_action(),
+            policing_suspend_secs: default_policing_suspend_secs(),
+            fec_mode: default_fec_mode(),
+            fec_protection_percent: default_fec_protection_percent(),
+            fec_payload_type: default_fec_payload_type(),
+            fec_red_payload_type: default_fec_red_payload_type(),
+            reorder_latency_ms: default_reorder_latency_ms(),
+            reorder_max_packets: default_reorder_max_packets(),
+            recording_directory: default_recording_directory(),
+            recording_max_file_bytes: default_recording_max_file_bytes(),
+            recording_max_file_secs: default_recording_max_file_secs(),
+            recording_queue_depth: default_recording_queue_depth(),
+            video_freeze_threshold_ms: default_video_freeze_threshold_ms(),
+            tap_max_packets_per_second: default_tap_max_packets_per_second(),
+            tap_max_duration_secs: default_tap_max_duration_secs(),
+            relay_bind_address: default_relay_bind_address(),
+            server_id: default_server_id(),
+            cluster_directory: default_cluster_directory(),
+            cluster_advertise_address: default_cluster_advertise_address(),
+            cluster_node_timeout_secs: default_cluster_node_timeout_secs(),
         }
     }
 }
@@ -76,3 +264,191 @@ fn default_enable_metrics() -> bool {
 fn default_metrics_bind_address() -> String {
     "0.0.0.0:9090".to_string()
 }
+
+fn default_enable_rtsp() -> bool {
+    false
+}
+
+fn default_rtsp_bind_address() -> String {
+    "0.0.0.0:8554".to_string()
+}
+
+fn default_rtsp_session_timeout_secs() -> u64 {
+    60
+}
+
+fn default_rtsp_multicast_address() -> String {
+    "239.255.42.1:16000".to_string()
+}
+
+fn default_enable_tcp() -> bool {
+    false
+}
+
+fn default_tcp_bind_address() -> String {
+    "0.0.0.0:5005".to_string()
+}
+
+fn default_tcp_send_queue_depth() -> usize {
+    256
+}
+
+fn default_multicast_ttl() -> u32 {
+    16
+}
+
+fn default_multicast_interface() -> String {
+    String::new()
+}
+
+fn default_enable_session_ports() -> bool {
+    false
+}
+
+fn default_session_port_range_start() -> u16 {
+    20000
+}
+
+fn default_session_port_range_end() -> u16 {
+    29999
+}
+
+fn default_session_port_pairs() -> bool {
+    true
+}
+
+fn default_enable_twcc() -> bool {
+    false
+}
+
+fn default_twcc_extension_id() -> u8 {
+    5
+}
+
+fn default_bwe_start_bitrate_bps() -> u64 {
+    1_000_000
+}
+
+fn default_bwe_min_bitrate_bps() -> u64 {
+    100_000
+}
+
+fn default_bwe_max_bitrate_bps() -> u64 {
+    20_000_000
+}
+
+fn default_pacing_mode() -> String {
+    "off".to_string()
+}
+
+fn default_pacing_rate_bps() -> u64 {
+    10_000_000
+}
+
+fn default_pacing_bwe_multiplier() -> f64 {
+    2.5
+}
+
+fn default_pacing_queue_depth() -> usize {
+    512
+}
+
+fn default_source_rate_limit_pps() -> u64 {
+    0
+}
+
+fn default_source_rate_limit_bps() -> u64 {
+    0
+}
+
+fn default_session_rate_limit_pps() -> u64 {
+    0
+}
+
+fn default_session_rate_limit_bps() -> u64 {
+    0
+}
+
+fn default_policing_burst_secs() -> f64 {
+    1.0
+}
+
+fn default_policing_action() -> String {
+    "drop".to_string()
+}
+
+fn default_policing_suspend_secs() -> u64 {
+    10
+}
+
+fn default_fec_mode() -> String {
+    "off".to_string()
+}
+
+fn default_fec_protection_percent() -> u32 {
+    20
+}
+
+fn default_fec_payload_type() -> u8 {
+    118
+}
+
+fn default_fec_red_payload_type() -> u8 {
+    116
+}
+
+fn default_reorder_latency_ms() -> u64 {
+    0
+}
+
+fn default_reorder_max_packets() -> usize {
+    512
+}
+
+fn default_recording_directory() -> String {
+    "recordings".to_string()
+}
+
+fn default_recording_max_file_bytes() -> u64 {
+    268_435_456
+}
+
+fn default_recording_max_file_secs() -> u64 {
+    600
+}
+
+fn default_recording_queue_depth() -> usize {
+    4096
+}
+
+fn default_video_freeze_threshold_ms() -> u64 {
+    2000
+}
+
+fn default_tap_max_packets_per_second() -> u32 {
+    2000
+}
+
+fn default_tap_max_duration_secs() -> u64 {
+    300
+}
+
+fn default_relay_bind_address() -> String {
+    String::new()
+}
+
+fn default_server_id() -> String {
+    String::new()
+}
+
+fn default_cluster_directory() -> String {
+    String::new()
+}
+
+fn default_cluster_advertise_address() -> String {
+    String::new()
+}
+
+fn default_cluster_node_timeout_secs() -> u64 {
+    10
+}
@@ -0,0 +1,765 @@
+// Frame-level recording: RTP is depacketized and muxed into fragmented MP4 or
+// Matroska, so recordings open in ordinary players. Fragments are cut at video
+// keyframes, or every second for audio-only sessions.
+
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime};
+
+use crate::codec::{self, Codec, SpsInfo};
+use crate::depacketize::{Depacketizer, Frame};
+use crate::recording::{PacketWriter, RecordedPacket};
+use crate::RtpFanoutServer;
+
+const FRAGMENT_DURATION: Duration = Duration::from_secs(1);
+// Bounds memory when keyframes are rare.
+const MAX_FRAGMENT_DURATION: Duration = Duration::from_secs(10);
+// Larger timestamp jumps, or any step backwards, mean the source restarted.
+const MAX_TIMESTAMP_JUMP_SECS: u32 = 5;
+// Tracks that haven't produced a decodable frame by then are left out.
+const HEADER_WAIT: Duration = Duration::from_secs(5);
+const OPUS_PRE_SKIP: u16 = 312;
+const MUXING_APP: &[u8] = b"rtp-fanout-server";
+
+const MP4_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerFormat {
+    Mp4,
+    Matroska,
+}
+
+// A payload type from the session's SDP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadFormat {
+    pub payload_type: u8,
+    pub codec: Codec,
+    pub clock_rate: u32,
+    pub channels: u16,
+}
+
+// One video track (the first H.264 or H.265 format) and one Opus track. Without
+// an SDP the session is assumed to carry H.264, as in `Session::media_format`.
+pub fn recordable_codecs(formats: &[PayloadFormat]) -> Vec<Codec> {
+    if formats.is_empty() {
+        return vec![Codec::H264];
+    }
+    let video = formats.iter().map(|f| f.codec).find(|c| matches!(c, Codec::H264 | Codec::H265));
+    let audio = formats.iter().map(|f| f.codec).find(|c| *c == Codec::Opus);
+    video.into_iter().chain(audio).collect()
+}
+
+#[derive(Debug)]
+struct Sample {
+    decode_time: u64,
+    duration: u32,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Track {
+    id: u32,
+    codec: Codec,
+    timescale: u32,
+    channels: u16,
+    ssrc: Option<u32>,
+    depacketizer: Option<Depacketizer>,
+    vps: Option<Vec<u8>>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    info: Option<SpsInfo>,
+    // The latest frame and its decode time; its duration is known once the
+    // next one arrives.
+    pending: Option<(Frame, u64)>,
+    last_duration: u32,
+    samples: Vec<Sample>,
+}
+
+impl Track {
+    fn new(codec: Codec, formats: &[PayloadFormat]) -> Self {
+        let format = formats.iter().find(|f| f.codec == codec);
+        // Opus RTP always runs at 48 kHz
+        let timescale = match codec {
+            Codec::Opus => 48000,
+            _ => format.map_or(90000, |f| f.clock_rate.max(1)),
+        };
+        Self {
+            id: 0,
+            codec,
+            timescale,
+            channels: format.map_or(2, |f| f.channels.clamp(1, 2)),
+            ssrc: None,
+            depacketizer: Depacketizer::new(codec),
+            vps: None,
+            sps: None,
+            pps: None,
+            info: None,
+            pending: None,
+            last_duration: if codec.is_video() { timescale / 30 } else { timescale / 50 },
+            samples: Vec::new(),
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        match self.codec {
+            Codec::H264 => self.info.is_some() && self.pps.is_some(),
+            Codec::H265 => self.info.is_some() && self.pps.is_some() && self.vps.is_some(),
+            _ => true,
+        }
+    }
+
+    // Parameter sets go to the sample description rather than the samples.
+    fn update_parameter_sets(&mut self, frame: &Frame) {
+        for unit in &frame.units {
+            let Some(&header) = unit.first() else {
+                continue;
+            };
+            let kind = match self.codec {
+                Codec::H264 => header & 0x1F,
+                _ => (header >> 1) & 0x3F,
+            };
+            match (self.codec, kind) {
+                (Codec::H265, 32) => self.vps = Some(unit.clone()),
+                (Codec::H264, 7) | (Codec::H265, 33) => {
+                    if let Some(info) = codec::parse_sps(self.codec, unit) {
+                        self.info = Some(info);
+                        self.sps = Some(unit.clone());
+                    }
+                }
+                (Codec::H264, 8) | (Codec::H265, 34) => self.pps = Some(unit.clone()),
+                _ => {}
+            }
+        }
+    }
+
+    // Length-prefixed NAL units for video, the packet as is for Opus.
+    fn sample_data(&self, frame: Frame) -> Vec<u8> {
+        if !self.codec.is_video() {
+            return frame.units.into_iter().next().unwrap_or_default();
+        }
+        let mut data = Vec::new();
+        for unit in frame.units {
+            let Some(&header) = unit.first() else {
+                continue;
+            };
+            // Parameter sets and access unit delimiters
+            let skip = match self.codec {
+                Codec::H264 => matches!(header & 0x1F, 7..=9),
+                _ => matches!((header >> 1) & 0x3F, 32..=35),
+            };
+            if !skip {
+                data.extend_from_slice(&(unit.len() as u32).to_be_bytes());
+                data.extend_from_slice(&unit);
+            }
+        }
+        data
+    }
+
+    // Queues a frame and completes the previous one. Decode times follow RTP
+    // timestamps; after a discontinuity they pick up from the arrival time.
+    fn push(&mut self, frame: Frame, wall_time: u64) {
+        let decode_time = match self.pending.take() {
+            Some((previous, previous_time)) => {
+                let delta = frame.timestamp.wrapping_sub(previous.timestamp);
+                let duration = if delta == 0 || delta > self.timescale * MAX_TIMESTAMP_JUMP_SECS {
+                    wall_time.saturating_sub(previous_time).clamp(1, u32::MAX as u64) as u32
+                } else {
+                    self.last_duration = delta;
+                    delta
+                };
+                self.complete(previous, previous_time, duration);
+                previous_time + duration as u64
+            }
+            None => wall_time,
+        };
+        self.pending = Some((frame, decode_time));
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some((frame, decode_time)) = self.pending.take() {
+            self.complete(frame, decode_time, self.last_duration);
+        }
+    }
+
+    fn complete(&mut self, frame: Frame, decode_time: u64, duration: u32) {
+        let keyframe = frame.keyframe;
+        let data = self.sample_data(frame);
+        if !data.is_empty() {
+            self.samples.push(Sample { decode_time, duration, keyframe, data });
+        }
+    }
+
+    fn buffered(&self) -> Duration {
+        let ticks: u64 = self.samples.iter().map(|s| s.duration as u64).sum();
+        Duration::from_secs_f64(ticks as f64 / self.timescale as f64)
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        self.info.map_or((0, 0), |info| (info.width, info.height))
+    }
+
+    // avcC / hvcC for video, OpusHead for audio.
+    fn codec_private(&self) -> Vec<u8> {
+        match self.codec {
+            Codec::H264 => {
+                let sps = self.sps.as_deref().unwrap_or_default();
+                let pps = self.pps.as_deref().unwrap_or_default();
+                let mut config = vec![1, byte(sps, 1), byte(sps, 2), byte(sps, 3), 0xFF, 0xE1];
+                config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+                config.extend_from_slice(sps);
+                config.push(1);
+                config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+                config.extend_from_slice(pps);
+                config
+            }
+            Codec::H265 => {
+                let sps = self.sps.as_deref().unwrap_or_default();
+                let info = self.info.unwrap_or(SpsInfo {
+                    width: 0,
+                    height: 0,
+                    chroma_format_idc: 1,
+                    bit_depth_luma: 8,
+                    bit_depth_chroma: 8,
+                });
+                // General profile, compatibility flags, constraint flags and level
+                let rbsp = codec::nal_to_rbsp(sps);
+                let mut profile = rbsp.get(3..15).map(<[u8]>::to_vec).unwrap_or_default();
+                profile.resize(12, 0);
+
+                let mut config = vec![1];
+                config.extend_from_slice(&profile);
+                config.extend_from_slice(&[
+                    0xF0,
+                    0x00,
+                    0xFC,
+                    0xFC | info.chroma_format_idc,
+                    0xF8 | info.bit_depth_luma.saturating_sub(8),
+                    0xF8 | info.bit_depth_chroma.saturating_sub(8),
+                    0,
+                    0,
+                    // one temporal layer, nested, 4-byte lengths
+                    0x0F,
+                    3,
+                ]);
+                for (kind, unit) in [(32, &self.vps), (33, &self.sps), (34, &self.pps)] {
+                    let unit = unit.as_deref().unwrap_or_default();
+                    config.push(0x80 | kind);
+                    config.extend_from_slice(&1u16.to_be_bytes());
+                    config.extend_from_slice(&(unit.len() as u16).to_be_bytes());
+                    config.extend_from_slice(unit);
+                }
+                config
+            }
+            _ => {
+                let mut head = b"OpusHead".to_vec();
+                head.extend_from_slice(&[1, self.channels as u8]);
+                head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+                head.extend_from_slice(&48000u32.to_le_bytes());
+                head.extend_from_slice(&[0, 0, 0]);
+                head
+            }
+        }
+    }
+}
+
+fn byte(data: &[u8], index: usize) -> u8 {
+    data.get(index).copied().unwrap_or_default()
+}
+
+pub struct ContainerWriter<W: Write> {
+    out: W,
+    format: ContainerFormat,
+    formats: Vec<PayloadFormat>,
+    tracks: Vec<Track>,
+    first_arrival: Option<SystemTime>,
+    // Wall clock at decode time zero, set once the header is written.
+    origin: Option<SystemTime>,
+    fragments: u32,
+}
+
+impl<W: Write> ContainerWriter<W> {
+    pub fn new(out: W, format: ContainerFormat, formats: Vec<PayloadFormat>) -> Self {
+        let tracks = recordable_codecs(&formats).into_iter().map(|c| Track::new(c, &formats)).collect();
+        Self {
+            out,
+            format,
+            formats,
+            tracks,
+            first_arrival: None,
+            origin: None,
+            fragments: 0,
+        }
+    }
+
+    fn track_for(&self, payload_type: u8) -> Option<usize> {
+        let codec = if self.formats.is_empty() {
+            Codec::H264
+        } else {
+            self.formats.iter().find(|f| f.payload_type == payload_type)?.codec
+        };
+        self.tracks.iter().position(|t| t.codec == codec)
+    }
+
+    fn has_video(&self) -> bool {
+        self.tracks.iter().any(|t| t.codec.is_video())
+    }
+
+    fn add_frame(&mut self, index: usize, frame: Frame, arrival: SystemTime) -> io::Result<usize> {
+        if self.tracks[index].codec.is_video() {
+            self.tracks[index].update_parameter_sets(&frame);
+        }
+
+        let mut written = 0;
+        let origin = match self.origin {
+            Some(origin) => origin,
+            None => {
+                let waited = self
+                    .first_arrival
+                    .and_then(|first| arrival.duration_since(first).ok())
+                    .unwrap_or_default();
+                if !self.tracks[index].is_ready() {
+                    return Ok(0);
+                }
+                if !self.tracks.iter().all(Track::is_ready) && waited < HEADER_WAIT {
+                    return Ok(0);
+                }
+                let codec = self.tracks[index].codec;
+                self.tracks.retain(Track::is_ready);
+                for (id, track) in self.tracks.iter_mut().enumerate() {
+                    track.id = id as u32 + 1;
+                }
+                let Some(position) = self.tracks.iter().position(|t| t.codec == codec) else {
+                    return Ok(0);
+                };
+
+                let header = match self.format {
+                    ContainerFormat::Mp4 => mp4_header(&self.tracks),
+                    ContainerFormat::Matroska => mkv_header(&self.tracks),
+                };
+                self.out.write_all(&header)?;
+                written += header.len();
+                self.origin = Some(arrival);
+                return Ok(written + self.add_frame(position, frame, arrival)?);
+            }
+        };
+
+        let track = &self.tracks[index];
+        let elapsed = arrival.duration_since(origin).unwrap_or_default();
+        let wall_time = (elapsed.as_secs_f64() * track.timescale as f64).round() as u64;
+        let keyframe = frame.keyframe && track.codec.is_video();
+        self.tracks[index].push(frame, wall_time);
+
+        let buffered = self.tracks.iter().map(Track::buffered).max().unwrap_or_default();
+        if keyframe
+            || (!self.has_video() && buffered >= FRAGMENT_DURATION)
+            || buffered >= MAX_FRAGMENT_DURATION
+        {
+            written += self.write_fragment()?;
+        }
+        Ok(written)
+    }
+
+    fn write_fragment(&mut self) -> io::Result<usize> {
+        if self.tracks.iter().all(|t| t.samples.is_empty()) {
+            return Ok(0);
+        }
+        self.fragments += 1;
+        let fragment = match self.format {
+            ContainerFormat::Mp4 => mp4_fragment(self.fragments, &self.tracks),
+            ContainerFormat::Matroska => mkv_cluster(&self.tracks),
+        };
+        for track in &mut self.tracks {
+            track.samples.clear();
+        }
+        self.out.write_all(&fragment)?;
+        Ok(fragment.len())
+    }
+}
+
+impl<W: Write + Send> PacketWriter for ContainerWriter<W> {
+    fn write_packet(&mut self, packet: &RecordedPacket) -> io::Result<usize> {
+        let Some(rtp) = RtpFanoutServer::parse_rtp_packet(&packet.data) else {
+            return Ok(0);
+        };
+        let Some(index) = self.track_for(rtp.payload_type) else {
+            return Ok(0);
+        };
+        self.first_arrival.get_or_insert(packet.arrival);
+
+        // One stream per track; simulcast layers or a second source are ignored.
+        let track = &mut self.tracks[index];
+        if *track.ssrc.get_or_insert(rtp.ssrc) != rtp.ssrc {
+            return Ok(0);
+        }
+        let Some(depacketizer) = track.depacketizer.as_mut() else {
+            return Ok(0);
+        };
+        let frames = depacketizer.push(rtp.sequence, rtp.timestamp, rtp.marker, &rtp.payload);
+
+        let mut written = 0;
+        for frame in frames {
+            written += self.add_frame(index, frame, packet.arrival)?;
+        }
+        Ok(written)
+    }
+
+    // New files start at a video keyframe so each one plays on its own.
+    fn can_split_before(&self, packet: &RecordedPacket) -> bool {
+        if !self.has_video() {
+            return true;
+        }
+        let Some(rtp) = RtpFanoutServer::parse_rtp_packet(&packet.data) else {
+            return false;
+        };
+        self.track_for(rtp.payload_type)
+            .map(|index| &self.tracks[index])
+            .is_some_and(|track| track.codec.is_video() && codec::is_keyframe_start(track.codec, &rtp.payload))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.origin.is_some() {
+            for track in &mut self.tracks {
+                track.flush_pending();
+            }
+            self.write_fragment()?;
+        }
+        self.out.flush()
+    }
+}
+
+fn be32(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+fn be16(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + content.len());
+    out.extend_from_slice(&(8 + content.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(content);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, content: &[u8]) -> Vec<u8> {
+    mp4_box(kind, &[&(((version as u32) << 24) | flags).to_be_bytes(), content].concat())
+}
+
+fn mp4_header(tracks: &[Track]) -> Vec<u8> {
+    let ftyp = mp4_box(b"ftyp", &[b"iso5".as_slice(), &512u32.to_be_bytes(), b"iso5", b"iso6", b"mp41"].concat());
+
+    let mut mvhd = be32(&[0, 0, 1000, 0, 0x0001_0000]);
+    mvhd.extend(be16(&[0x0100]));
+    mvhd.extend([0; 10]);
+    mvhd.extend(be32(&MP4_MATRIX));
+    mvhd.extend([0; 24]);
+    mvhd.extend(be32(&[tracks.len() as u32 + 1]));
+
+    let mut moov = full_box(b"mvhd", 0, 0, &mvhd);
+    let mut mvex = Vec::new();
+    for track in tracks {
+        moov.extend(mp4_trak(track));
+        mvex.extend(full_box(b"trex", 0, 0, &be32(&[track.id, 1, 0, 0, 0])));
+    }
+    moov.extend(mp4_box(b"mvex", &mvex));
+    [ftyp, mp4_box(b"moov", &moov)].concat()
+}
+
+fn mp4_trak(track: &Track) -> Vec<u8> {
+    let video = track.codec.is_video();
+    let (width, height) = track.dimensions();
+
+    let mut tkhd = be32(&[0, 0, track.id, 0, 0, 0, 0]);
+    tkhd.extend(be16(&[0, 0, if video { 0 } else { 0x0100 }, 0]));
+    tkhd.extend(be32(&MP4_MATRIX));
+    tkhd.extend(be32(&[width << 16, height << 16]));
+
+    let mut mdhd = be32(&[0, 0, track.timescale, 0]);
+    // language "und"
+    mdhd.extend(be16(&[0x55C4, 0]));
+
+    let (handler, name) = if video { (b"vide", "VideoHandler") } else { (b"soun", "SoundHandler") };
+    let mut hdlr = vec![0; 4];
+    hdlr.extend(handler);
+    hdlr.extend([0; 12]);
+    hdlr.extend(name.as_bytes());
+    hdlr.push(0);
+
+    let media_header = if video { full_box(b"vmhd", 0, 1, &[0; 8]) } else { full_box(b"smhd", 0, 0, &[0; 4]) };
+    let dref = full_box(b"dref", 0, 0, &[be32(&[1]), full_box(b"url ", 0, 1, &[])].concat());
+    let stsd = full_box(b"stsd", 0, 0, &[be32(&[1]), mp4_sample_entry(track)].concat());
+    // Samples are all described by the fragments
+    let stbl = [
+        stsd,
+        full_box(b"stts", 0, 0, &be32(&[0])),
+        full_box(b"stsc", 0, 0, &be32(&[0])),
+        full_box(b"stsz", 0, 0, &be32(&[0, 0])),
+        full_box(b"stco", 0, 0, &be32(&[0])),
+    ]
+    .concat();
+    let minf = [media_header, mp4_box(b"dinf", &dref), mp4_box(b"stbl", &stbl)].concat();
+    let mdia = [full_box(b"mdhd", 0, 0, &mdhd), full_box(b"hdlr", 0, 0, &hdlr), mp4_box(b"minf", &minf)].concat();
+    mp4_box(b"trak", &[full_box(b"tkhd", 0, 3, &tkhd), mp4_box(b"mdia", &mdia)].concat())
+}
+
+fn mp4_sample_entry(track: &Track) -> Vec<u8> {
+    let mut entry = vec![0; 6];
+    // data reference index
+    entry.extend(be16(&[1]));
+    match track.codec {
+        Codec::H264 | Codec::H265 => {
+            let (width, height) = track.dimensions();
+            entry.extend([0; 16]);
+            entry.extend(be16(&[width as u16, height as u16]));
+            entry.extend(be32(&[0x0048_0000, 0x0048_0000, 0]));
+            entry.extend(be16(&[1]));
+            entry.extend([0; 32]);
+            entry.extend(be16(&[0x0018, 0xFFFF]));
+            if track.codec == Codec::H264 {
+                entry.extend(mp4_box(b"avcC", &track.codec_private()));
+                mp4_box(b"avc1", &entry)
+            } else {
+                entry.extend(mp4_box(b"hvcC", &track.codec_private()));
+                mp4_box(b"hvc1", &entry)
+            }
+        }
+        _ => {
+            entry.extend([0; 8]);
+            entry.extend(be16(&[track.channels, 16, 0, 0]));
+            entry.extend(be32(&[48000 << 16]));
+            let mut dops = vec![0, track.channels as u8];
+            dops.extend(be16(&[OPUS_PRE_SKIP]));
+            dops.extend(be32(&[48000]));
+            dops.extend([0, 0, 0]);
+            entry.extend(mp4_box(b"dOps", &dops));
+            mp4_box(b"Opus", &entry)
+        }
+    }
+}
+
+fn mp4_fragment(sequence: u32, tracks: &[Track]) -> Vec<u8> {
+    let tracks: Vec<&Track> = tracks.iter().filter(|t| !t.samples.is_empty()).collect();
+    let moof = |data_offsets: &[u32]| {
+        let mut moof = full_box(b"mfhd", 0, 0, &be32(&[sequence]));
+        for (track, offset) in tracks.iter().zip(data_offsets) {
+            // default-base-is-moof
+            let tfhd = full_box(b"tfhd", 0, 0x02_0000, &be32(&[track.id]));
+            let decode_time = track.samples[0].decode_time;
+            let tfdt = full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes());
+            // data offset, then duration, size and flags per sample
+            let mut trun = be32(&[track.samples.len() as u32, *offset]);
+            for sample in &track.samples {
+                let flags = if sample.keyframe { 0x0200_0000 } else { 0x0101_0000 };
+                trun.extend(be32(&[sample.duration, sample.data.len() as u32, flags]));
+            }
+            let trun = full_box(b"trun", 0, 0x0701, &trun);
+            moof.extend(mp4_box(b"traf", &[tfhd, tfdt, trun].concat()));
+        }
+        mp4_box(b"moof", &moof)
+    };
+
+    // The offsets don't change the moof's size, so measure it first.
+    let moof_size = moof(&vec![0; tracks.len()]).len() as u32;
+    let mut offsets = Vec::with_capacity(tracks.len());
+    let mut mdat = Vec::new();
+    for track in &tracks {
+        offsets.push(moof_size + 8 + mdat.len() as u32);
+        for sample in &track.samples {
+            mdat.extend_from_slice(&sample.data);
+        }
+    }
+    [moof(&offsets), mp4_box(b"mdat", &mdat)].concat()
+}
+
+// EBML variable-length size; all ones is reserved for "unknown".
+fn ebml_size(size: u64) -> Vec<u8> {
+    let mut len = 1;
+    while len < 8 && size >= (1 << (7 * len)) - 1 {
+        len += 1;
+    }
+    (size | (1 << (7 * len))).to_be_bytes()[8 - len..].to_vec()
+}
+
+fn ebml(id: &[u8], body: &[u8]) -> Vec<u8> {
+    [id, &ebml_size(body.len() as u64), body].concat()
+}
+
+fn ebml_uint(id: &[u8], value: u64) -> Vec<u8> {
+    let skip = (value.leading_zeros() / 8).min(7) as usize;
+    ebml(id, &value.to_be_bytes()[skip..])
+}
+
+fn mkv_header(tracks: &[Track]) -> Vec<u8> {
+    let header = ebml(
+        &[0x1A, 0x45, 0xDF, 0xA3],
+        &[
+            ebml_uint(&[0x42, 0x86], 1),
+            ebml_uint(&[0x42, 0xF7], 1),
+            ebml_uint(&[0x42, 0xF2], 4),
+            ebml_uint(&[0x42, 0xF3], 8),
+            ebml(&[0x42, 0x82], b"matroska"),
+            ebml_uint(&[0x42, 0x87], 4),
+            ebml_uint(&[0x42, 0x85], 2),
+        ]
+        .concat(),
+    );
+    // Millisecond timestamps
+    let info = ebml(
+        &[0x15, 0x49, 0xA9, 0x66],
+        &[ebml_uint(&[0x2A, 0xD7, 0xB1], 1_000_000), ebml(&[0x4D, 0x80], MUXING_APP), ebml(&[0x57, 0x41], MUXING_APP)]
+            .concat(),
+    );
+
+    let mut entries = Vec::new();
+    for track in tracks {
+        let video = track.codec.is_video();
+        let codec_id: &[u8] = match track.codec {
+            Codec::H264 => b"V_MPEG4/ISO/AVC",
+            Codec::H265 => b"V_MPEGH/ISO/HEVC",
+            _ => b"A_OPUS",
+        };
+        let mut entry = [
+            ebml_uint(&[0xD7], track.id as u64),
+            ebml_uint(&[0x73, 0xC5], track.id as u64),
+            ebml_uint(&[0x83], if video { 1 } else { 2 }),
+            ebml(&[0x86], codec_id),
+            ebml_uint(&[0x9C], 0),
+            ebml(&[0x63, 0xA2], &track.codec_private()),
+        ]
+        .concat();
+        if video {
+            let (width, height) = track.dimensions();
+            entry.extend(ebml(&[0xE0], &[ebml_uint(&[0xB0], width as u64), ebml_uint(&[0xBA], height as u64)].concat()));
+        } else {
+            // CodecDelay in nanoseconds
+            entry.extend(ebml_uint(&[0x56, 0xAA], OPUS_PRE_SKIP as u64 * 1_000_000_000 / 48000));
+            let frequency = ebml(&[0xB5], &48000f64.to_be_bytes());
+            entry.extend(ebml(&[0xE1], &[frequency, ebml_uint(&[0x9F], track.channels as u64)].concat()));
+        }
+        entries.extend(ebml(&[0xAE], &entry));
+    }
+
+    // The segment is left open-ended so the file is playable while it grows.
+    let segment = [0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+    [header, segment.to_vec(), info, ebml(&[0x16, 0x54, 0xAE, 0x6B], &entries)].concat()
+}
+
+fn mkv_cluster(tracks: &[Track]) -> Vec<u8> {
+    let mut blocks: Vec<(u64, &Track, &Sample)> = tracks
+        .iter()
+        .flat_map(|track| {
+            track
+                .samples
+                .iter()
+                .map(move |sample| (sample.decode_time * 1000 / track.timescale as u64, track, sample))
+        })
+        .collect();
+    blocks.sort_by_key(|(time, _, _)| *time);
+    let base = blocks.first().map_or(0, |(time, _, _)| *time);
+
+    let mut cluster = ebml_uint(&[0xE7], base);
+    for (time, track, sample) in blocks {
+        let relative = (time - base).min(i16::MAX as u64) as i16;
+        let mut block = ebml_size(track.id as u64);
+        block.extend(relative.to_be_bytes());
+        block.push(if sample.keyframe { 0x80 } else { 0 });
+        block.extend_from_slice(&sample.data);
+        cluster.extend(ebml(&[0xA3], &block));
+    }
+    ebml(&[0x1F, 0x43, 0xB6, 0x75], &cluster)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1280x720 High profile SPS
+    const SPS: [u8; 25] = [
+        0x67, 0x64, 0x00, 0x1F, 0xAC, 0xD9, 0x40, 0x50, 0x05, 0xBB, 0x01, 0x10, 0x00, 0x00, 0x03, 0x00, 0x10, 0x00,
+        0x00, 0x03, 0x03, 0xC0, 0xF1, 0x83, 0x19,
+    ];
+
+    fn rtp(sequence: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, 96 | if marker { 0x80 } else { 0 }];
+        packet.extend(sequence.to_be_bytes());
+        packet.extend(timestamp.to_be_bytes());
+        packet.extend(42u32.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn record(format: ContainerFormat) -> Vec<u8> {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut out = Vec::new();
+        let mut writer = ContainerWriter::new(&mut out, format, Vec::new());
+        let mut stap = vec![0x78, 0x00, SPS.len() as u8];
+        stap.extend(SPS);
+        stap.extend([0x00, 0x02, 0x68, 0xEB]);
+
+        let packets = [
+            // Not decodable yet
+            (1, 0, true, vec![0x41, 0x01]),
+            (2, 3000, false, stap),
+            (3, 3000, true, vec![0x65, 0x88, 0x80]),
+            (4, 6000, true, vec![0x41, 0x9A]),
+            // The encoder restarted with a new timestamp base
+            (5, 900_000_000, true, vec![0x41, 0x9B]),
+        ];
+        for (i, (sequence, timestamp, marker, payload)) in packets.into_iter().enumerate() {
+            let packet = RecordedPacket {
+                data: rtp(sequence, timestamp, marker, &payload).into(),
+                arrival: start + Duration::from_millis(33 * i as u64),
+                source: None,
+            };
+            writer.write_packet(&packet).unwrap();
+        }
+        writer.finish().unwrap();
+        out
+    }
+
+    fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+        data.windows(pattern.len()).position(|w| w == pattern)
+    }
+
+    #[test]
+    fn test_sps_resolution() {
+        let info = codec::parse_sps(Codec::H264, &SPS).unwrap();
+        assert_eq!((info.width, info.height), (1280, 720));
+    }
+
+    #[test]
+    fn test_fragmented_mp4_layout() {
+        let out = record(ContainerFormat::Mp4);
+        assert_eq!(&out[4..8], b"ftyp");
+        let moov = find(&out, b"moov").unwrap();
+        let avcc = find(&out, b"avcC").unwrap();
+        assert!(avcc > moov);
+        assert_eq!(&out[avcc + 4..avcc + 8], &[1, 0x64, 0x00, 0x1F]);
+
+        let moof = find(&out, b"moof").unwrap() - 4;
+        let trun = find(&out, b"trun").unwrap() + 4;
+        // IDR, then the P frames; nothing from before the keyframe
+        assert_eq!(u32::from_be_bytes(out[trun + 4..trun + 8].try_into().unwrap()), 3);
+        let durations: Vec<u32> = (0..3)
+            .map(|i| u32::from_be_bytes(out[trun + 12 + i * 12..trun + 16 + i * 12].try_into().unwrap()))
+            .collect();
+        // The jump falls back to the 33ms arrival gap, in 90 kHz ticks
+        assert_eq!(durations, vec![3000, 2940, 3000]);
+
+        let offset = u32::from_be_bytes(out[trun + 8..trun + 12].try_into().unwrap()) as usize;
+        assert_eq!(&out[moof + offset..moof + offset + 7], &[0, 0, 0, 3, 0x65, 0x88, 0x80]);
+    }
+
+    #[test]
+    fn test_matroska_layout() {
+        let out = record(ContainerFormat::Matroska);
+        assert_eq!(&out[0..4], &[0x1A, 0x45, 0xDF, 0xA3]);
+        assert!(find(&out, b"V_MPEG4/ISO/AVC").is_some());
+        let cluster = find(&out, &[0x1F, 0x43, 0xB6, 0x75]).unwrap();
+        // First block: track 1, keyframe, the IDR length-prefixed
+        let block = find(&out[cluster..], &[0xA3, 0x8B]).unwrap() + cluster;
+        assert_eq!(&out[block + 2..block + 13], &[0x81, 0, 0, 0x80, 0, 0, 0, 3, 0x65, 0x88, 0x80]);
+    }
+}
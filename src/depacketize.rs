@@ -0,0 +1,217 @@
+// Reassembles RTP payloads into whole frames: NAL units for H.264 (RFC 6184)
+// and H.265 (RFC 7798), non-interleaved modes only, and one packet per frame
+// for Opus.
+
+use crate::codec::Codec;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub timestamp: u32,
+    // NAL units without start codes, or the Opus packet.
+    pub units: Vec<Vec<u8>>,
+    pub keyframe: bool,
+}
+
+#[derive(Debug)]
+pub struct Depacketizer {
+    codec: Codec,
+    last_sequence: Option<u16>,
+    timestamp: u32,
+    units: Vec<Vec<u8>>,
+    fragment: Option<Vec<u8>>,
+    // The frame being assembled is missing a packet.
+    damaged: bool,
+    // A reference picture was lost; frames are dropped until the next keyframe.
+    awaiting_keyframe: bool,
+}
+
+impl Depacketizer {
+    pub fn new(codec: Codec) -> Option<Self> {
+        if !matches!(codec, Codec::H264 | Codec::H265 | Codec::Opus) {
+            return None;
+        }
+        Some(Self {
+            codec,
+            last_sequence: None,
+            timestamp: 0,
+            units: Vec::new(),
+            fragment: None,
+            damaged: false,
+            awaiting_keyframe: true,
+        })
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    // Returns the frames this packet completed, in order.
+    pub fn push(&mut self, sequence: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<Frame> {
+        let gap = self.last_sequence.is_some_and(|last| sequence != last.wrapping_add(1));
+        self.last_sequence = Some(sequence);
+
+        if self.codec == Codec::Opus {
+            if payload.is_empty() {
+                return Vec::new();
+            }
+            return vec![Frame { timestamp, units: vec![payload.to_vec()], keyframe: true }];
+        }
+
+        let mut frames = Vec::new();
+        if gap {
+            self.damaged = true;
+        }
+        // A new timestamp without a marker: the end of the last frame was lost.
+        if timestamp != self.timestamp && (!self.units.is_empty() || self.fragment.is_some() || self.damaged) {
+            frames.extend(self.finish());
+        }
+        // The missing packets may equally have started this frame.
+        if gap {
+            self.damaged = true;
+        }
+        self.timestamp = timestamp;
+
+        match self.codec {
+            Codec::H264 => self.push_h264(payload),
+            _ => self.push_h265(payload),
+        }
+        if marker {
+            frames.extend(self.finish());
+        }
+        frames
+    }
+
+    fn push_h264(&mut self, payload: &[u8]) {
+        let Some(&header) = payload.first() else {
+            return;
+        };
+        match header & 0x1F {
+            1..=23 => self.units.push(payload.to_vec()),
+            // STAP-A
+            24 => self.aggregated(&payload[1..]),
+            // FU-A
+            28 if payload.len() > 2 => {
+                let fu = payload[1];
+                self.fragmented(fu & 0x80 != 0, fu & 0x40 != 0, &[(header & 0xE0) | (fu & 0x1F)], &payload[2..]);
+            }
+            // STAP-B, MTAP and FU-B only occur in interleaved mode
+            _ => self.damaged = true,
+        }
+    }
+
+    fn push_h265(&mut self, payload: &[u8]) {
+        if payload.len() < 2 {
+            return;
+        }
+        match (payload[0] >> 1) & 0x3F {
+            0..=47 => self.units.push(payload.to_vec()),
+            // Aggregation packet
+            48 => self.aggregated(&payload[2..]),
+            // Fragmentation unit
+            49 if payload.len() > 3 => {
+                let fu = payload[2];
+                let header = [(payload[0] & 0x81) | ((fu & 0x3F) << 1), payload[1]];
+                self.fragmented(fu & 0x80 != 0, fu & 0x40 != 0, &header, &payload[3..]);
+            }
+            _ => self.damaged = true,
+        }
+    }
+
+    fn aggregated(&mut self, mut data: &[u8]) {
+        while data.len() > 2 {
+            let size = u16::from_be_bytes([data[0], data[1]]) as usize;
+            let Some(unit) = data.get(2..2 + size) else {
+                self.damaged = true;
+                return;
+            };
+            if !unit.is_empty() {
+                self.units.push(unit.to_vec());
+            }
+            data = &data[2 + size..];
+        }
+    }
+
+    fn fragmented(&mut self, start: bool, end: bool, header: &[u8], data: &[u8]) {
+        if start {
+            let mut unit = header.to_vec();
+            unit.extend_from_slice(data);
+            self.fragment = Some(unit);
+        } else if let Some(unit) = self.fragment.as_mut() {
+            unit.extend_from_slice(data);
+        } else {
+            self.damaged = true;
+            return;
+        }
+        if end {
+            self.units.extend(self.fragment.take());
+        }
+    }
+
+    fn finish(&mut self) -> Option<Frame> {
+        let units = std::mem::take(&mut self.units);
+        let damaged = std::mem::replace(&mut self.damaged, false) | self.fragment.take().is_some();
+        if damaged {
+            self.awaiting_keyframe = true;
+            return None;
+        }
+        if units.is_empty() {
+            return None;
+        }
+
+        let keyframe = units.iter().any(|unit| is_random_access(self.codec, unit));
+        if self.awaiting_keyframe && !keyframe {
+            return None;
+        }
+        self.awaiting_keyframe = false;
+        Some(Frame { timestamp: self.timestamp, units, keyframe })
+    }
+}
+
+// H.264 IDR slices and H.265 IRAP pictures.
+pub fn is_random_access(codec: Codec, unit: &[u8]) -> bool {
+    let Some(&header) = unit.first() else {
+        return false;
+    };
+    match codec {
+        Codec::H264 => header & 0x1F == 5,
+        Codec::H265 => (16..=21).contains(&((header >> 1) & 0x3F)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_h264_stap_and_fu_a() {
+        let mut depacketizer = Depacketizer::new(Codec::H264).unwrap();
+        // SPS + PPS aggregated, then an IDR slice in two fragments
+        assert!(depacketizer.push(1, 3000, false, &[0x78, 0, 2, 0x67, 0x42, 0, 2, 0x68, 0xCE]).is_empty());
+        assert!(depacketizer.push(2, 3000, false, &[0x7C, 0x85, 0xAA]).is_empty());
+        let frames = depacketizer.push(3, 3000, true, &[0x7C, 0x45, 0xBB]);
+
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].keyframe);
+        assert_eq!(frames[0].units, vec![vec![0x67, 0x42], vec![0x68, 0xCE], vec![0x65, 0xAA, 0xBB]]);
+
+        // A P frame arrives whole
+        let frames = depacketizer.push(4, 6000, true, &[0x41, 0x9A]);
+        assert_eq!(frames, vec![Frame { timestamp: 6000, units: vec![vec![0x41, 0x9A]], keyframe: false }]);
+    }
+
+    #[test]
+    fn test_loss_drops_until_keyframe() {
+        let mut depacketizer = Depacketizer::new(Codec::H264).unwrap();
+        // Not decodable without a keyframe first
+        assert!(depacketizer.push(1, 0, true, &[0x41, 0x01]).is_empty());
+        assert_eq!(depacketizer.push(2, 3000, true, &[0x65, 0x01]).len(), 1);
+
+        // The middle of a fragmented frame is lost
+        depacketizer.push(3, 6000, false, &[0x7C, 0x81, 0x01]);
+        assert!(depacketizer.push(5, 6000, true, &[0x7C, 0x41, 0x03]).is_empty());
+        // ...and so is everything depending on it
+        assert!(depacketizer.push(6, 9000, true, &[0x41, 0x02]).is_empty());
+        assert_eq!(depacketizer.push(7, 12000, true, &[0x65, 0x02]).len(), 1);
+    }
+}
@@ -0,0 +1,72 @@
+use std::net::SocketAddr;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::trace;
+
+use crate::group::SessionGroupId;
+use crate::policer::{PolicingAction, PolicingScope};
+use crate::session::SessionId;
+
+const EVENT_BUFFER: usize = 1024;
+
+// Notable things happening on the server, streamed to control-API watchers.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    RateLimitExceeded {
+        scope: PolicingScope,
+        session_id: Option<SessionId>,
+        source: Option<SocketAddr>,
+        action: PolicingAction,
+    },
+    SessionSuspended {
+        session_id: SessionId,
+        duration_secs: u64,
+    },
+    VideoFrozen {
+        session_id: SessionId,
+        ssrc: u32,
+        // Since the last complete frame.
+        stalled_ms: u64,
+    },
+    VideoResumed {
+        session_id: SessionId,
+        ssrc: u32,
+        frozen_ms: u64,
+    },
+    ActiveSpeakerChanged {
+        group_id: SessionGroupId,
+        // None when nobody in the group is speaking.
+        session_id: Option<SessionId>,
+        level_dbov: Option<f64>,
+    },
+}
+
+// Fan-out of server events. Slow watchers lose the oldest events rather than
+// holding up the data path.
+pub struct EventBus {
+    tx: broadcast::Sender<ServerEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        Self { tx }
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        trace!("Event: {:?}", event);
+        // No receivers is not an error: nobody is watching.
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
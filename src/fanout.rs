@@ -1,20 +1,53 @@
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock, Weak};
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
 use crossbeam::queue::SegQueue;
 use tracing::{debug, trace, warn};
 use dashmap::DashMap;
 
-use crate::session::{SessionManager, Session};
+use crate::bwe::{BandwidthEstimate, BweConfig, CongestionController, TwccFeedback};
+use crate::codec::{self, Codec};
+use crate::events::ServerEvent;
+use crate::fec::{FecEncoder, FecProtector, RepairPacket};
+use crate::filter::PacketKind;
+use crate::group::SessionGroupManager;
+use crate::session::{SessionManager, Session, SubscriberTransport};
+use crate::metrics::MetricsCollector;
+use crate::multicast::{self, MulticastOptions};
+use crate::pacer::{Pacer, PacingConfig, TransportStamp};
+use crate::relay::{self, RelayServer};
+use crate::policer::{Policer, PolicingAction, PolicingConfig, PolicingScope};
+use crate::tcp;
 use crate::RtpPacket;
 
 pub struct FanoutEngine {
     session_manager: Arc<SessionManager>,
     packet_queue: Arc<SegQueue<RtpPacket>>,
     socket: DashMap<SocketAddr, Arc<UdpSocket>>,
+    symmetric_socket: Option<Arc<UdpSocket>>,
+    twcc: Option<(u8, BweConfig)>,
+    congestion: DashMap<SocketAddr, Arc<Mutex<CongestionController>>>,
+    pacing: Option<PacingConfig>,
+    pacers: DashMap<SocketAddr, Pacer>,
+    policing: PolicingConfig,
+    source_policers: DashMap<SocketAddr, Mutex<Policer>>,
+    source_policers_pruned: Mutex<Instant>,
+    // Shared by new sources once `source_policers` is full.
+    overflow_policer: Mutex<Policer>,
+    relay: OnceLock<Weak<RelayServer>>,
+    groups: OnceLock<Weak<SessionGroupManager>>,
 }
 
+// Congestion state of subscribers that neither received packets nor sent
+// feedback for this long is dropped.
+const CONGESTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const SOURCE_POLICER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const SOURCE_POLICER_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_SOURCE_POLICERS: usize = 4096;
+const REORDER_TICK: Duration = Duration::from_millis(5);
+
 impl FanoutEngine {
     pub fn new(
         session_manager: Arc<SessionManager>,
@@ -24,7 +57,146 @@ impl FanoutEngine {
             session_manager,
             packet_queue,
             socket: DashMap::new(),
+            symmetric_socket: None,
+            twcc: None,
+            congestion: DashMap::new(),
+            pacing: None,
+            pacers: DashMap::new(),
+            policing: PolicingConfig::default(),
+            source_policers: DashMap::new(),
+            source_policers_pruned: Mutex::new(Instant::now()),
+            overflow_policer: Mutex::new(Policer::new(PolicingConfig::default().source, Instant::now())),
+            relay: OnceLock::new(),
+            groups: OnceLock::new(),
+        }
+    }
+
+    // UDP media leaves from the server's bind socket, so latched subscribers
+    // get it from the address their punch packet reached (matching the NAT
+    // mapping they opened) and subscriber RTCP comes back where the server
+    // reads it. Without one, each subscriber gets its own ephemeral socket.
+    pub fn with_symmetric_socket(mut self, socket: Arc<UdpSocket>) -> Self {
+        self.symmetric_socket = Some(socket);
+        self
+    }
+
+    // Stamps the transport-wide sequence number extension on packets to UDP
+    // subscribers and estimates their bandwidth from the feedback they send.
+    pub fn with_twcc(mut self, extension_id: u8, config: BweConfig) -> Self {
+        self.twcc = Some((extension_id, config));
+        self
+    }
+
+    // Spreads packets to UDP subscribers over time instead of sending each
+    // fanned-out burst back-to-back.
+    pub fn with_pacing(mut self, pacing: PacingConfig) -> Self {
+        self.pacing = Some(pacing);
+        self
+    }
+
+    // Keyframe requests for relayed sessions go through the relay.
+    pub fn attach_relay(&self, relay: &Arc<RelayServer>) {
+        self.relay.set(Arc::downgrade(relay)).ok();
+    }
+
+    // Sessions whose CNAME only shows up in RTCP join their group from here.
+    pub fn attach_groups(&self, groups: &Arc<SessionGroupManager>) {
+        self.groups.set(Arc::downgrade(groups)).ok();
+    }
+
+    pub fn with_policing(mut self, policing: PolicingConfig) -> Self {
+        self.overflow_policer = Mutex::new(Policer::new(policing.source, Instant::now()));
+        self.policing = policing;
+        self
+    }
+
+    fn congestion_controller(&self, addr: SocketAddr, config: BweConfig) -> Arc<Mutex<CongestionController>> {
+        if let Some(controller) = self.congestion.get(&addr) {
+            return controller.clone();
         }
+
+        self.congestion
+            .retain(|_, controller| controller.lock().last_activity.elapsed() < CONGESTION_IDLE_TIMEOUT);
+        self.congestion
+            .entry(addr)
+            .or_insert_with(|| Arc::new(Mutex::new(CongestionController::new(config))))
+            .clone()
+    }
+
+    // RTCP from subscribers; transport-cc feedback updates the sender's
+    // bandwidth estimate. Feedback may come from the RTCP port next to the
+    // subscriber's RTP port.
+    pub fn handle_rtcp(&self, data: &[u8], addr: SocketAddr) {
+        let feedback = TwccFeedback::parse_compound(data);
+        if feedback.is_empty() {
+            return;
+        }
+
+        let rtp_addr = SocketAddr::new(addr.ip(), addr.port().wrapping_sub(1));
+        let Some(controller) = self
+            .congestion
+            .get(&addr)
+            .or_else(|| self.congestion.get(&rtp_addr))
+            .map(|c| c.clone())
+        else {
+            trace!("Ignoring transport-cc feedback from unknown subscriber {}", addr);
+            return;
+        };
+
+        {
+            let mut controller = controller.lock();
+            for report in &feedback {
+                controller.on_feedback(report, Instant::now());
+            }
+            trace!("Bandwidth estimate for {}: {} bps", addr, controller.estimate_bps());
+        }
+        self.session_manager.on_transport_feedback(&addr, &feedback);
+        self.session_manager.on_transport_feedback(&rtp_addr, &feedback);
+    }
+
+    // RTCP from a session's source. Sender reports carry the NTP/RTP
+    // timestamp mapping players need for lip sync, so the compound packet
+    // goes to every subscriber as is; its SDES CNAME can group the session.
+    pub async fn handle_source_rtcp(&self, session: &Session, data: &[u8]) {
+        session.record_activity();
+        if let Some(cname) = tcp::sdes_cname(data) {
+            if session.learn_cname(cname) {
+                if let Some(groups) = self.groups.get().and_then(Weak::upgrade) {
+                    if groups.group_of(&session.id).is_none() {
+                        groups.join_by_cname(session);
+                    }
+                }
+            }
+        }
+
+        let subscribers: Vec<_> = session
+            .subscribers
+            .iter()
+            .filter(|entry| entry.is_ready())
+            .map(|entry| (*entry.key(), entry.transport.clone()))
+            .collect();
+        for (addr, transport) in subscribers {
+            match transport {
+                // RTCP goes on the channel after the RTP one
+                SubscriberTransport::Interleaved { channel, sink } => {
+                    if sink.try_send(Self::interleave(channel.wrapping_add(1), data)).is_err() {
+                        trace!("Interleaved sink for {} is full, dropping RTCP", addr);
+                    }
+                }
+                // Not worth a place in the pacer's queue
+                SubscriberTransport::Udp => self.send_to_subscriber(data, addr).await,
+                transport => self.send(addr, transport, data, None).await,
+            }
+        }
+    }
+
+    pub fn bandwidth_estimate(&self, addr: &SocketAddr) -> Option<BandwidthEstimate> {
+        self.congestion.get(addr).map(|c| c.lock().estimate())
+    }
+
+    pub async fn submit(&self, packet: RtpPacket) {
+        self.packet_queue.push(packet);
+        self.process_batch().await;
     }
 
     pub async fn process_batch(&self) {
@@ -32,97 +204,580 @@ impl FanoutEngine {
         
         for _ in 0..BATCH_SIZE {
             if let Some(packet) = self.packet_queue.pop() {
-                self.fanout_packet(&packet).await;
+                self.fanout_packet(packet, None).await;
             } else {
                 break;
             }
         }
     }
 
-    async fn fanout_packet(&self, packet: &RtpPacket) {
-        if let Some(session) = self.session_manager.get_session_by_ssrc(packet.ssrc) {
-            session.record_activity();
-            
-            session.packet_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            session.byte_count.fetch_add(
-                packet.payload.len() as u64, 
-                std::sync::atomic::Ordering::Relaxed
-            );
+    // Source policing comes before the session lookup, so a flooding address
+    // costs as little as possible and is limited even without a session.
+    async fn fanout_packet(&self, packet: RtpPacket, data: Option<&[u8]>) {
+        if !self.police_source(&packet, None) {
+            return;
+        }
+        if let Some(session) = self.session_manager.resolve(&packet) {
+            let packet = Self::keep_raw(&session, packet, data);
+            self.fanout_resolved(&session, &packet).await;
+        } else {
+            debug!("No session found for SSRC {}", packet.ssrc);
+        }
+    }
+
+    // A datagram as received, for the session its SSRC resolves to.
+    pub async fn ingest(&self, packet: RtpPacket, data: &[u8]) {
+        self.fanout_packet(packet, Some(data)).await;
+    }
 
-            let rtp_data = self.serialize_rtp_packet(packet);
-            
-            let subscribers: Vec<_> = session
-                .subscribers
-                .iter()
-                .map(|entry| *entry.key())
-                .collect();
+    // Like `fanout_to_session`, with the datagram the packet was parsed from.
+    pub async fn ingest_to_session(&self, session: &Session, packet: RtpPacket, data: &[u8]) {
+        if !self.police_source(&packet, Some(session)) {
+            return;
+        }
+        let packet = Self::keep_raw(session, packet, Some(data));
+        self.fanout_resolved(session, &packet).await;
+    }
 
-            for subscriber_addr in subscribers {
-                self.send_to_subscriber(&rtp_data, subscriber_addr).await;
+    // Copies the datagram only for sessions that tap or record it.
+    fn keep_raw(session: &Session, packet: RtpPacket, data: Option<&[u8]>) -> RtpPacket {
+        match data {
+            Some(data) if session.wants_raw() => packet.with_raw(data),
+            _ => packet,
+        }
+    }
+
+    // Packets that arrived on a session's own ingest port are already
+    // demultiplexed and skip the SSRC lookup.
+    pub async fn fanout_to_session(&self, session: &Session, packet: &RtpPacket) {
+        if !self.police_source(packet, Some(session)) {
+            return;
+        }
+        self.fanout_resolved(session, packet).await;
+    }
+
+    async fn fanout_resolved(&self, session: &Session, packet: &RtpPacket) {
+        if !session.taps.is_empty() {
+            session.mirror(packet, &self.received_bytes(packet));
+        }
+        // Packet captures keep everything the source sent, before policing.
+        // Container recordings are fed in `forward`, after the reorder buffer.
+        if let Some(recorder) = session.recorder.read().as_ref().filter(|r| !r.wants_reordered()) {
+            recorder.record(packet, self.received_bytes(packet));
+        }
+
+        if !self.police_session(session, packet) {
+            return;
+        }
+
+        let bypassed = {
+            let mut reorder = session.reorder.lock();
+            if reorder.is_bypassed() {
+                reorder.observe(packet);
             }
+            reorder.is_bypassed()
+        };
+        if bypassed {
+            self.forward(session, packet).await;
+            return;
+        }
 
-            trace!("Fanned out packet seq={} to {} subscribers", 
-                   packet.sequence, session.subscribers.len());
-        } else {
-            debug!("No session found for SSRC {}", packet.ssrc);
+        // Held until the released packets are out, so the reorder timer can't
+        // slip its releases in between them.
+        let _output = session.reorder_output.lock().await;
+        let released = {
+            let mut reorder = session.reorder.lock();
+            let discarded = reorder.stats().discarded;
+            let released = reorder.push(packet.clone(), Instant::now());
+            let discarded = reorder.stats().discarded - discarded;
+            if discarded > 0 {
+                MetricsCollector::record_reorder_discard(discarded);
+            }
+            released
+        };
+        for packet in &released {
+            self.forward(session, packet).await;
+        }
+    }
+
+    // Releases packets from reorder buffers whose latency budget ran out
+    // while waiting for a missing sequence number.
+    pub async fn run_reorder_timer(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REORDER_TICK);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            for session in self.session_manager.reordering_sessions() {
+                let _output = session.reorder_output.lock().await;
+                let released = session.reorder.lock().expire(Instant::now());
+                for packet in &released {
+                    self.forward(&session, packet).await;
+                }
+            }
+        }
+    }
+
+    async fn forward(&self, session: &Session, packet: &RtpPacket) {
+        session.record_activity();
+        
+        session.packet_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        session.byte_count.fetch_add(
+            packet.payload.len() as u64, 
+            std::sync::atomic::Ordering::Relaxed
+        );
+
+        let rtp_data = self.serialize_rtp_packet(packet);
+        if let Some(recorder) = session.recorder.read().as_ref().filter(|r| r.wants_reordered()) {
+            recorder.record(packet, self.received_bytes(packet));
+        }
+
+        // Simulcast sessions send each subscriber one layer, rewritten onto
+        // the session SSRC.
+        let simulcast = session.observe_layer(packet);
+        let (codec, clock_rate) = session.inspect(packet);
+        session.observe_audio_level(packet);
+
+        // Scalable VP9/AV1: each subscriber gets the layers under its ceiling.
+        let svc_layer = match codec {
+            Codec::Vp9 | Codec::Av1 if !simulcast => session.svc_layer(packet, codec),
+            _ => None,
+        };
+        let scalable = svc_layer.is_some() || (matches!(codec, Codec::Vp9 | Codec::Av1) && session.svc.is_scalable());
+        let keyframe = (simulcast || scalable) && codec::is_keyframe_start(codec, &packet.payload);
+        
+        let kind = session.packet_kind(packet, codec);
+        let subscribers: Vec<_> = session
+            .subscribers
+            .iter()
+            .filter(|entry| entry.is_ready() && entry.filter.accepts(packet.payload_type, packet.ssrc, kind))
+            .map(|entry| {
+                (
+                    *entry.key(),
+                    entry.transport.clone(),
+                    entry.layer_selector.clone(),
+                    entry.fec.clone(),
+                )
+            })
+            .collect();
+
+        for (subscriber_addr, transport, layer_selector, fec) in subscribers {
+            let congestion = match (&self.twcc, &transport) {
+                (Some((extension_id, config)), SubscriberTransport::Udp) => {
+                    let controller = self.congestion_controller(subscriber_addr, *config);
+                    layer_selector.lock().estimated_bps = Some(controller.lock().estimate_bps());
+                    Some((*extension_id, controller))
+                }
+                _ => None,
+            };
+
+            let rewritten = if simulcast || scalable {
+                let (selected, awaiting) = {
+                    let mut selector = layer_selector.lock();
+                    let selected = if simulcast {
+                        selector.select(packet, &session.simulcast, keyframe, clock_rate, session.ssrc)
+                    } else {
+                        selector.filter_layers(packet, svc_layer, &session.svc, keyframe)
+                    };
+                    (selected, selector.awaiting_keyframe())
+                };
+                // A layer switch waits for a keyframe; ask the source for one.
+                if let Some(ssrc) = awaiting.filter(|ssrc| session.keyframe_request_due(*ssrc, packet.received_at)) {
+                    self.request_keyframe(session, ssrc).await;
+                }
+                match selected {
+                    Some(selected) => Some(selected),
+                    None => continue,
+                }
+            } else {
+                None
+            };
+
+            // Retransmissions and the source's own repair streams aren't protected again.
+            if let Some(fec) = fec.filter(|_| !matches!(kind, Some(PacketKind::Rtx | PacketKind::Fec))) {
+                let packet = rewritten.unwrap_or_else(|| packet.clone());
+                let controller = congestion.as_ref().map(|(_, controller)| controller.clone());
+                for (data, seq) in self.protect(packet, &fec, congestion) {
+                    let stamp = seq.zip(controller.clone()).map(|(seq, controller)| TransportStamp { seq, controller });
+                    self.send(subscriber_addr, transport.clone(), &data, stamp).await;
+                }
+                continue;
+            }
+
+            match (congestion, rewritten) {
+                (Some((extension_id, controller)), rewritten) => {
+                    let mut stamped = rewritten.unwrap_or_else(|| packet.clone());
+                    let seq = controller.lock().stamp(&mut stamped, extension_id, Instant::now());
+                    let data = self.serialize_rtp_packet(&stamped);
+                    let stamp = TransportStamp { seq, controller };
+                    self.send(subscriber_addr, transport, &data, Some(stamp)).await;
+                }
+                (None, Some(rewritten)) => {
+                    let data = self.serialize_rtp_packet(&rewritten);
+                    self.send(subscriber_addr, transport, &data, None).await;
+                }
+                (None, None) => self.send(subscriber_addr, transport, &rtp_data, None).await,
+            }
+        }
+
+        trace!("Fanned out packet seq={} to {} subscribers", 
+               packet.sequence, session.subscribers.len());
+    }
+
+    // Runs a subscriber's packet through the FEC encoder of its SSRC. Repair
+    // packets are computed over the media packet exactly as sent, then follow
+    // it; a group the packet closed early goes out before it. Each datagram
+    // comes with its transport-wide sequence number, if stamped.
+    fn protect(
+        &self,
+        mut packet: RtpPacket,
+        fec: &Mutex<FecProtector>,
+        congestion: Option<(u8, Arc<Mutex<CongestionController>>)>,
+    ) -> Vec<(Vec<u8>, Option<u16>)> {
+        let stamp = |packet: &mut RtpPacket| {
+            congestion
+                .as_ref()
+                .map(|(extension_id, controller)| controller.lock().stamp(packet, *extension_id, Instant::now()))
+        };
+        let mut fec = fec.lock();
+        let Some(fec) = fec.encoder(packet.ssrc) else {
+            let transport_seq = stamp(&mut packet);
+            return vec![(self.serialize_rtp_packet(&packet), transport_seq)];
+        };
+
+        let closed = fec.sequence(&mut packet);
+        let repair_datagram = |fec: &mut FecEncoder, mut repair: RepairPacket| {
+            let transport_seq = stamp(&mut repair.packet);
+            if let Some(transport_seq) = transport_seq {
+                fec.track(transport_seq, repair.group, true);
+            }
+            (self.serialize_rtp_packet(&repair.packet), transport_seq)
+        };
+        let mut datagrams: Vec<_> = closed.into_iter().map(|repair| repair_datagram(fec, repair)).collect();
+
+        let transport_seq = stamp(&mut packet);
+        let repairs = fec.protect(&self.serialize_rtp_packet(&packet), transport_seq);
+        fec.wrap(&mut packet);
+        datagrams.push((self.serialize_rtp_packet(&packet), transport_seq));
+        datagrams.extend(repairs.into_iter().map(|repair| repair_datagram(fec, repair)));
+        datagrams
+    }
+
+    // Token-bucket policing per source address. Returns whether the packet
+    // may go on to its session; `session` is looked up only to suspend it.
+    fn police_source(&self, packet: &RtpPacket, session: Option<&Session>) -> bool {
+        let Some(source) = packet.source.filter(|_| !self.policing.source.is_unlimited()) else {
+            return true;
+        };
+        let now = packet.received_at;
+        let bytes = 12 + packet.payload.len();
+        let verdict = match self.source_policers.get(&source) {
+            Some(policer) => self.charge_source(&policer, bytes, now),
+            None => {
+                self.prune_source_policers(now);
+                if self.source_policers.len() >= MAX_SOURCE_POLICERS {
+                    self.charge_source(&self.overflow_policer, bytes, now)
+                } else {
+                    let policer = self
+                        .source_policers
+                        .entry(source)
+                        .or_insert_with(|| Mutex::new(Policer::new(self.policing.source, now)));
+                    self.charge_source(&policer, bytes, now)
+                }
+            }
+        };
+        let Some((report, suspend)) = verdict else {
+            return true;
+        };
+
+        // Only a session's own source can get it suspended; anyone can send
+        // with its SSRC.
+        let resolved = match session {
+            None if suspend => self.session_manager.resolve(packet),
+            _ => None,
+        };
+        let session = session.or(resolved.as_deref());
+        let suspend = suspend && session.is_some_and(|s| s.source_addr == source);
+        self.exceeded(PolicingScope::Source, session, packet.source, report, suspend, now)
+    }
+
+    // None when the packet conforms, else whether to report it and whether
+    // this starts a suspension.
+    fn charge_source(&self, policer: &Mutex<Policer>, bytes: usize, now: Instant) -> Option<(bool, bool)> {
+        let mut policer = policer.lock();
+        if policer.conforms(bytes, now) {
+            return None;
+        }
+        let suspend = self.policing.action == PolicingAction::Suspend
+            && policer.start_suspension(self.policing.suspend, now);
+        Some((policer.should_report(now), suspend))
+    }
+
+    fn prune_source_policers(&self, now: Instant) {
+        {
+            let mut pruned = self.source_policers_pruned.lock();
+            if now.saturating_duration_since(*pruned) < SOURCE_POLICER_PRUNE_INTERVAL {
+                return;
+            }
+            *pruned = now;
+        }
+        self.source_policers
+            .retain(|_, policer| now.saturating_duration_since(policer.lock().last_seen) < SOURCE_POLICER_IDLE_TIMEOUT);
+    }
+
+    // Token-bucket policing per session, after the source's own limit.
+    fn police_session(&self, session: &Session, packet: &RtpPacket) -> bool {
+        let now = packet.received_at;
+        if session.is_suspended(now) {
+            MetricsCollector::record_policed(PolicingScope::Session.as_str(), PolicingAction::Suspend.as_str());
+            return false;
+        }
+
+        if let Some(policer) = &session.policer {
+            let mut policer = policer.lock();
+            if !policer.conforms(12 + packet.payload.len(), now) {
+                let report = policer.should_report(now);
+                drop(policer);
+                return self.exceeded(PolicingScope::Session, Some(session), packet.source, report, true, now);
+            }
+        }
+        true
+    }
+
+    // Returns whether the packet is still forwarded. Under the suspend
+    // action `session` is suspended if `suspend` allows it.
+    fn exceeded(
+        &self,
+        scope: PolicingScope,
+        session: Option<&Session>,
+        source: Option<SocketAddr>,
+        report: bool,
+        suspend: bool,
+        now: Instant,
+    ) -> bool {
+        let action = self.policing.action;
+        MetricsCollector::record_policed(scope.as_str(), action.as_str());
+
+        let events = self.session_manager.events();
+        if report {
+            warn!("{} rate limit exceeded by {:?} in session {:?} ({})",
+                  scope.as_str(), source, session.map(|s| s.id.0), action.as_str());
+            events.publish(ServerEvent::RateLimitExceeded {
+                scope,
+                session_id: session.map(|s| s.id),
+                source,
+                action,
+            });
+        }
+
+        match (action, session) {
+            (PolicingAction::Drop, _) => false,
+            (PolicingAction::Mark, _) => true,
+            (PolicingAction::Suspend, Some(session)) if suspend => {
+                if session.suspend(self.policing.suspend, now) {
+                    events.publish(ServerEvent::SessionSuspended {
+                        session_id: session.id,
+                        duration_secs: self.policing.suspend.as_secs(),
+                    });
+                }
+                false
+            }
+            (PolicingAction::Suspend, _) => false,
+        }
+    }
+
+    async fn send(
+        &self,
+        subscriber_addr: SocketAddr,
+        transport: SubscriberTransport,
+        rtp_data: &[u8],
+        stamp: Option<TransportStamp>,
+    ) {
+        match transport {
+            SubscriberTransport::Interleaved { channel, sink } => {
+                if sink.try_send(Self::interleave(channel, rtp_data)).is_err() {
+                    trace!("Interleaved sink for {} is full, dropping packet", subscriber_addr);
+                }
+            }
+            SubscriberTransport::Tcp { sink } => {
+                if sink.try_send(Self::frame_rfc4571(rtp_data)).is_err() {
+                    MetricsCollector::record_tcp_egress_drop();
+                    trace!("TCP sink for {} is full, dropping packet", subscriber_addr);
+                }
+            }
+            SubscriberTransport::Multicast(options) => {
+                self.send_to_group(rtp_data, subscriber_addr, &options).await;
+            }
+            SubscriberTransport::Relay { header } => {
+                let mut data = Vec::with_capacity(header.len() + rtp_data.len());
+                data.extend_from_slice(&header);
+                data.extend_from_slice(rtp_data);
+                self.send_to_subscriber(&data, subscriber_addr).await;
+            }
+            SubscriberTransport::Udp if self.pacing.is_some() => {
+                self.send_paced(rtp_data, subscriber_addr, stamp);
+            }
+            SubscriberTransport::Udp => self.send_to_subscriber(rtp_data, subscriber_addr).await,
+        }
+    }
+
+    // Taps and recordings get the datagram as the source sent it, CSRCs and
+    // padding included.
+    fn received_bytes(&self, packet: &RtpPacket) -> Arc<[u8]> {
+        match &packet.raw {
+            Some(raw) => raw.clone(),
+            None => self.serialize_rtp_packet(packet).into(),
         }
     }
 
     fn serialize_rtp_packet(&self, packet: &RtpPacket) -> Vec<u8> {
         let mut data = Vec::with_capacity(12 + packet.payload.len());
         
-        data.push(0x80);
+        data.push(if packet.extension.is_some() { 0x90 } else { 0x80 });
         
-        let pt_byte = if packet.marker { 0x80 } else { 0x00 };
+        let pt_byte = if packet.marker { 0x80 } else { 0x00 } | packet.payload_type;
         data.push(pt_byte);
         
         data.extend_from_slice(&packet.sequence.to_be_bytes());
         data.extend_from_slice(&packet.timestamp.to_be_bytes());
         data.extend_from_slice(&packet.ssrc.to_be_bytes());
+
+        if let Some(extension) = &packet.extension {
+            data.extend_from_slice(&extension.profile.to_be_bytes());
+            data.extend_from_slice(&((extension.data.len() / 4) as u16).to_be_bytes());
+            data.extend_from_slice(&extension.data);
+        }
         
         data.extend_from_slice(&packet.payload);
         
         data
     }
 
-    async fn send_to_subscriber(&self, data: &[u8], addr: SocketAddr) {
-        let socket = self.socket
-            .entry(addr)
-            .or_insert_with(|| {
-                let local_addr = if addr.is_ipv4() {
-                    "0.0.0.0:0"
-                } else {
-                    "[::]:0"
-                };
-                
-                match std::net::UdpSocket::bind(local_addr) {
-                    Ok(udp_socket) => {
-                        udp_socket.set_nonblocking(true).ok();
-                        match UdpSocket::from_std(udp_socket) {
-                            Ok(tokio_socket) => Arc::new(tokio_socket),
-                            Err(_) => {
-                                warn!("Failed to convert socket to tokio for {}", addr);
-                                Arc::new(tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap())
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Failed to bind socket for {}: {}", addr, e);
-                        Arc::new(tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap())
-                    }
+    fn interleave(channel: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(4 + data.len());
+        frame.push(b'$');
+        frame.push(channel);
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    pub fn frame_rfc4571(data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(2 + data.len());
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    async fn send_to_group(&self, data: &[u8], group: SocketAddr, options: &MulticastOptions) {
+        let socket = match self.socket.get(&group) {
+            Some(socket) => socket.clone(),
+            None => match multicast::egress_socket(&group, options) {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    self.socket.insert(group, socket.clone());
+                    socket
                 }
-            });
+                Err(e) => {
+                    warn!("Failed to open multicast socket for {}: {}", group, e);
+                    return;
+                }
+            },
+        };
+
+        if let Err(e) = socket.send_to(data, group).await {
+            warn!("Failed to send packet to group {}: {}", group, e);
+        }
+    }
+
+    fn send_paced(&self, data: &[u8], addr: SocketAddr, stamp: Option<TransportStamp>) {
+        let Some(pacing) = &self.pacing else {
+            return;
+        };
+        let estimate = self.bandwidth_estimate(&addr).map(|e| e.estimate_bps);
+        let rate = pacing.rate_for(estimate);
+
+        if self.pacers.get(&addr).is_none_or(|pacer| pacer.is_closed()) {
+            let socket = match self.egress_socket(addr) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("Failed to bind socket for {}: {}", addr, e);
+                    return;
+                }
+            };
+            self.pacers.retain(|_, pacer| !pacer.is_closed());
+            self.pacers.insert(addr, Pacer::spawn(socket, addr, rate, pacing.queue_depth));
+        }
+
+        if let Some(pacer) = self.pacers.get(&addr) {
+            pacer.set_rate(rate);
+            if !pacer.enqueue(data.to_vec(), stamp) {
+                MetricsCollector::record_pacer_drop();
+                trace!("Pacer queue for {} is full, dropping packet", addr);
+            }
+        }
+    }
+
+    async fn send_to_subscriber(&self, data: &[u8], addr: SocketAddr) {
+        let socket = match self.egress_socket(addr) {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Failed to bind socket for {}: {}", addr, e);
+                return;
+            }
+        };
 
         if let Err(e) = socket.send_to(data, addr).await {
             warn!("Failed to send packet to {}: {}", addr, e);
         }
     }
+
+    // Sends a PLI for `ssrc` toward the session's source: up the relay chain
+    // for relayed sessions, else straight to the source address.
+    async fn request_keyframe(&self, session: &Session, ssrc: u32) {
+        debug!("Requesting a keyframe for SSRC {} of session {}", ssrc, session.id.0);
+        let pli = relay::build_pli(ssrc);
+        if let Some(relay) = self.relay.get().and_then(Weak::upgrade) {
+            relay.feedback_upstream(session, &pli).await;
+            return;
+        }
+        if session.source_addr.port() == 0 {
+            return;
+        }
+        let sent = match self.egress_socket(session.source_addr) {
+            Ok(socket) => socket.send_to(&pli, session.source_addr).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            warn!("Failed to send PLI to source {}: {}", session.source_addr, e);
+        }
+    }
+
+    fn egress_socket(&self, addr: SocketAddr) -> std::io::Result<Arc<UdpSocket>> {
+        match &self.symmetric_socket {
+            Some(socket) if socket.local_addr()?.is_ipv4() == addr.is_ipv4() => Ok(socket.clone()),
+            _ => self.subscriber_socket(addr),
+        }
+    }
+
+    fn subscriber_socket(&self, addr: SocketAddr) -> std::io::Result<Arc<UdpSocket>> {
+        if let Some(socket) = self.socket.get(&addr) {
+            return Ok(socket.clone());
+        }
+
+        let local_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let udp_socket = std::net::UdpSocket::bind(local_addr)?;
+        udp_socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(udp_socket)?);
+        Ok(self.socket.entry(addr).or_insert(socket).clone())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::ServerConfig;
+    use crate::policer::RateLimit;
+    use crate::tap::{TapOptions, TapTarget};
 
     #[tokio::test]
     async fn test_fanout_engine_creation() {
@@ -133,4 +788,107 @@ mod tests {
         let engine = FanoutEngine::new(session_manager, packet_queue);
         assert!(engine.socket.is_empty());
     }
+
+    fn policing_engine(action: PolicingAction) -> (Arc<SessionManager>, FanoutEngine) {
+        let session_manager = Arc::new(SessionManager::new(ServerConfig::default()));
+        let policing = PolicingConfig {
+            source: RateLimit { packets_per_sec: 1, bits_per_sec: 0, burst_secs: 1.0 },
+            action,
+            suspend: Duration::from_secs(10),
+        };
+        let engine = FanoutEngine::new(session_manager.clone(), Arc::new(SegQueue::new())).with_policing(policing);
+        (session_manager, engine)
+    }
+
+    fn packet_from(source: SocketAddr, received_at: Instant) -> RtpPacket {
+        RtpPacket {
+            payload: vec![0; 100],
+            timestamp: 0,
+            sequence: 1,
+            ssrc: 42,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: Some(source),
+            received_at,
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn test_source_suspends_its_session_once() {
+        let (session_manager, engine) = policing_engine(PolicingAction::Suspend);
+        let source: SocketAddr = "10.0.0.1:5004".parse().unwrap();
+        let session = session_manager.create_session(source, 42).unwrap();
+        let mut events = session_manager.events().subscribe();
+
+        let start = Instant::now();
+        assert!(engine.police_source(&packet_from(source, start), None));
+        assert!(!session.is_suspended(start));
+        assert!(!engine.police_source(&packet_from(source, start), None));
+        assert!(session.is_suspended(start));
+
+        // Continued excess neither extends the suspension nor repeats the event
+        for ms in 1..50 {
+            assert!(!engine.police_source(&packet_from(source, start + Duration::from_millis(ms)), None));
+        }
+        assert!(!session.is_suspended(start + Duration::from_secs(10)));
+        let suspensions = std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|e| matches!(e, ServerEvent::SessionSuspended { .. }))
+            .count();
+        assert_eq!(suspensions, 1);
+    }
+
+    #[test]
+    fn test_foreign_source_cannot_suspend_session() {
+        let (session_manager, engine) = policing_engine(PolicingAction::Suspend);
+        let session = session_manager.create_session("10.0.0.1:5004".parse().unwrap(), 42).unwrap();
+
+        let attacker: SocketAddr = "10.9.9.9:4000".parse().unwrap();
+        let now = Instant::now();
+        assert!(engine.police_source(&packet_from(attacker, now), None));
+        assert!(!engine.police_source(&packet_from(attacker, now), None));
+        assert!(!engine.police_source(&packet_from(attacker, now), Some(&session)));
+        assert!(!session.is_suspended(now));
+    }
+
+    #[test]
+    fn test_mark_forwards_and_source_policers_are_capped() {
+        let (_, engine) = policing_engine(PolicingAction::Mark);
+        let now = Instant::now();
+        let source: SocketAddr = "10.0.0.1:5004".parse().unwrap();
+        assert!(engine.police_source(&packet_from(source, now), None));
+        assert!(engine.police_source(&packet_from(source, now), None));
+
+        let (_, engine) = policing_engine(PolicingAction::Drop);
+        for port in 0..=MAX_SOURCE_POLICERS as u16 {
+            engine.police_source(&packet_from(SocketAddr::new(source.ip(), port), now), None);
+        }
+        assert_eq!(engine.source_policers.len(), MAX_SOURCE_POLICERS);
+        // New sources past the cap share one bucket
+        assert!(!engine.police_source(&packet_from("10.0.0.2:1".parse().unwrap(), now), None));
+    }
+
+    #[tokio::test]
+    async fn test_taps_get_the_datagram_as_received() {
+        let session_manager = Arc::new(SessionManager::new(ServerConfig::default()));
+        let engine = FanoutEngine::new(session_manager.clone(), Arc::new(SegQueue::new()));
+        let source: SocketAddr = "10.0.0.1:5004".parse().unwrap();
+        let session = session_manager.create_session(source, 42).unwrap();
+
+        // One CSRC, which the parsed packet doesn't keep
+        let mut datagram = vec![0x81, 96, 0x00, 0x01, 0, 0, 0, 0];
+        datagram.extend_from_slice(&42u32.to_be_bytes());
+        datagram.extend_from_slice(&7u32.to_be_bytes());
+        datagram.extend_from_slice(&[0xAB; 20]);
+        let packet = crate::RtpFanoutServer::parse_rtp_packet(&datagram).unwrap().with_source(source);
+        assert!(packet.raw.is_none());
+        assert!(!session.wants_raw());
+
+        let options = TapOptions { sample_rate: 1.0, max_packets_per_second: 0, duration: Duration::ZERO };
+        let (_, receiver) = session_manager.start_tap(&session.id, TapTarget::Stream, options).unwrap();
+        let mut receiver = receiver.unwrap();
+        engine.ingest(packet, &datagram).await;
+        assert_eq!(&receiver.recv().await.unwrap().data[..], &datagram[..]);
+    }
 }
@@ -0,0 +1,610 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::bwe::TwccFeedback;
+use crate::config::ServerConfig;
+use crate::RtpPacket;
+
+// FlexFEC repair streams use an SSRC derived from the media SSRC so the
+// subscriber SDP can announce it up front.
+const FLEXFEC_SSRC_MASK: u32 = 0x5FEC_0000;
+
+// Largest sequence number span of one group; fits the long ULPFEC mask and
+// the second FlexFEC mask chunk.
+const MAX_SPAN: u16 = 46;
+
+const TRACKING_LEN: usize = 2048;
+const MAX_TRACKED_GROUPS: usize = 256;
+// Media SSRCs one subscriber's FEC covers; further streams go unprotected.
+const MAX_PROTECTED_SSRCS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FecScheme {
+    // flexfec-03 (draft-ietf-payload-flexible-fec-scheme-03) repair packets
+    // on their own SSRC. Not RFC 8627: the header names the protected SSRC
+    // instead of listing it in the CSRCs.
+    FlexFec { payload_type: u8 },
+    // RFC 5109 ULPFEC carried in RFC 2198 RED, sharing the media SSRC and
+    // sequence space.
+    UlpfecRed { red_payload_type: u8, ulpfec_payload_type: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FecConfig {
+    pub scheme: FecScheme,
+    // Media packets covered by each repair packet.
+    pub group_size: usize,
+}
+
+impl FecConfig {
+    pub fn parse(mode: &str, config: &ServerConfig) -> Option<Self> {
+        let scheme = match mode {
+            "flexfec" => FecScheme::FlexFec { payload_type: config.fec_payload_type },
+            "ulpfec" => FecScheme::UlpfecRed {
+                red_payload_type: config.fec_red_payload_type,
+                ulpfec_payload_type: config.fec_payload_type,
+            },
+            "off" | "" => return None,
+            other => {
+                warn!("Unknown FEC mode {}, FEC disabled", other);
+                return None;
+            }
+        };
+
+        let percent = config.fec_protection_percent.clamp(1, 100) as usize;
+        Some(Self {
+            scheme,
+            group_size: (100 / percent).clamp(1, MAX_SPAN as usize),
+        })
+    }
+
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        Self::parse(&config.fec_mode, config)
+    }
+}
+
+pub fn flexfec_ssrc(media_ssrc: u32) -> u32 {
+    media_ssrc ^ FLEXFEC_SSRC_MASK
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FecStats {
+    pub media_packets: u64,
+    pub media_bytes: u64,
+    pub fec_packets: u64,
+    pub fec_bytes: u64,
+    // From transport-cc feedback: lost media packets the receiver could
+    // rebuild from their group's repair packet, and those it could not.
+    pub recoverable_losses: u64,
+    pub unrecoverable_losses: u64,
+}
+
+impl FecStats {
+    pub fn overhead(&self) -> f64 {
+        if self.media_bytes == 0 {
+            0.0
+        } else {
+            self.fec_bytes as f64 / self.media_bytes as f64
+        }
+    }
+
+    pub fn merge(&mut self, other: &FecStats) {
+        self.media_packets += other.media_packets;
+        self.media_bytes += other.media_bytes;
+        self.fec_packets += other.fec_packets;
+        self.fec_bytes += other.fec_bytes;
+        self.recoverable_losses += other.recoverable_losses;
+        self.unrecoverable_losses += other.unrecoverable_losses;
+    }
+}
+
+// A repair packet and the group it protects, for loss tracking once it has
+// been stamped.
+#[derive(Debug)]
+pub struct RepairPacket {
+    pub group: u64,
+    pub packet: RtpPacket,
+}
+
+// XOR of everything the repair packet needs to rebuild one lost packet.
+#[derive(Debug)]
+struct Group {
+    id: u64,
+    base: u16,
+    seqs: Vec<u16>,
+    first_byte: u8,
+    second_byte: u8,
+    timestamp: u32,
+    length: u16,
+    last_timestamp: u32,
+    payload: Vec<u8>,
+}
+
+impl Group {
+    fn new(id: u64, base: u16) -> Self {
+        Self {
+            id,
+            base,
+            seqs: Vec::new(),
+            first_byte: 0,
+            second_byte: 0,
+            timestamp: 0,
+            length: 0,
+            last_timestamp: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, data: &[u8], seq: u16, timestamp: u32) {
+        let body = &data[12..];
+        self.seqs.push(seq);
+        self.first_byte ^= data[0];
+        self.second_byte ^= data[1];
+        self.timestamp ^= timestamp;
+        self.length ^= body.len() as u16;
+        self.last_timestamp = timestamp;
+        if self.payload.len() < body.len() {
+            self.payload.resize(body.len(), 0);
+        }
+        for (out, byte) in self.payload.iter_mut().zip(body) {
+            *out ^= byte;
+        }
+    }
+
+    fn offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.seqs.iter().map(|seq| seq.wrapping_sub(self.base) as usize)
+    }
+
+    fn last_seq(&self) -> u16 {
+        self.seqs.iter().copied().max_by_key(|seq| seq.wrapping_sub(self.base)).unwrap_or(self.base)
+    }
+
+    // flexfec-03 section 4.2.2.1, flexible mask with a single SSRC.
+    fn flexfec_payload(&self, ssrc: u32) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24 + self.payload.len());
+        out.push(self.first_byte & 0x3F);
+        out.push(self.second_byte);
+        out.extend_from_slice(&self.length.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&[1, 0, 0, 0]);
+        out.extend_from_slice(&ssrc.to_be_bytes());
+        out.extend_from_slice(&self.base.to_be_bytes());
+
+        let long = self.offsets().any(|offset| offset >= 15);
+        let mut mask: u64 = 0;
+        for offset in self.offsets() {
+            mask |= 1 << (45 - offset);
+        }
+        if long {
+            // 15 bits + k=0, then 31 bits + k=1
+            let first = ((mask >> 31) as u16) & 0x7FFF;
+            let second = 0x8000_0000 | (mask as u32 & 0x7FFF_FFFF);
+            out.extend_from_slice(&first.to_be_bytes());
+            out.extend_from_slice(&second.to_be_bytes());
+        } else {
+            let first = 0x8000 | ((mask >> 31) as u16 & 0x7FFF);
+            out.extend_from_slice(&first.to_be_bytes());
+        }
+
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    // RFC 5109 section 7.3, a single protection level.
+    fn ulpfec_payload(&self) -> Vec<u8> {
+        let long = self.offsets().any(|offset| offset >= 16);
+        let mut out = Vec::with_capacity(18 + self.payload.len());
+        out.push((self.first_byte & 0x3F) | if long { 0x40 } else { 0x00 });
+        out.push(self.second_byte);
+        out.extend_from_slice(&self.base.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.length.to_be_bytes());
+
+        out.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        let mask_bits = if long { 48 } else { 16 };
+        let mut mask: u64 = 0;
+        for offset in self.offsets() {
+            mask |= 1 << (mask_bits - 1 - offset);
+        }
+        out.extend_from_slice(&mask.to_be_bytes()[8 - mask_bits / 8..]);
+
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+#[derive(Debug, Default)]
+struct GroupLosses {
+    media: usize,
+    reported_media: usize,
+    lost_media: usize,
+    closed: bool,
+    repair_reported: bool,
+    repair_received: bool,
+}
+
+// Per-subscriber FEC generator. Works on packets exactly as they are sent so
+// that receivers can XOR them back.
+#[derive(Debug)]
+pub struct FecEncoder {
+    config: FecConfig,
+    media_ssrc: u32,
+    group: Option<Group>,
+    next_group: u64,
+    // FlexFEC: repair stream sequence. ULPFEC/RED: how far media sequence
+    // numbers have been shifted by inserted repair packets.
+    repair_seq: u16,
+    seq_offset: u16,
+    stats: FecStats,
+    transport: HashMap<u16, (u64, bool)>,
+    transport_order: VecDeque<u16>,
+    losses: HashMap<u64, GroupLosses>,
+}
+
+impl FecEncoder {
+    pub fn new(config: FecConfig, media_ssrc: u32) -> Self {
+        Self {
+            config,
+            media_ssrc,
+            group: None,
+            next_group: 0,
+            repair_seq: uuid::Uuid::new_v4().as_u128() as u16,
+            seq_offset: 0,
+            stats: FecStats::default(),
+            transport: HashMap::new(),
+            transport_order: VecDeque::new(),
+            losses: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> FecConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> FecStats {
+        self.stats
+    }
+
+    // RED shares the media sequence space: media packets move up by the
+    // number of repair packets inserted before them. A packet that can't join
+    // the current group closes it first, so the repair packet is sent, and
+    // counted in the shift, ahead of the packet.
+    pub fn sequence(&mut self, packet: &mut RtpPacket) -> Option<RepairPacket> {
+        if packet.ssrc != self.media_ssrc {
+            return None;
+        }
+        let red = matches!(self.config.scheme, FecScheme::UlpfecRed { .. });
+        let original = packet.sequence;
+        let shifted = |offset: u16| if red { original.wrapping_add(offset) } else { original };
+
+        let seq = shifted(self.seq_offset);
+        let repair = match &self.group {
+            Some(group) if seq.wrapping_sub(group.base) >= MAX_SPAN || group.seqs.contains(&seq) => self.emit(),
+            _ => None,
+        };
+        packet.sequence = shifted(self.seq_offset);
+        repair
+    }
+
+    // Adds a sent media packet, already passed through `sequence`, to the
+    // current group and returns any repair packets that are due.
+    pub fn protect(&mut self, data: &[u8], transport_seq: Option<u16>) -> Vec<RepairPacket> {
+        let mut repairs = Vec::new();
+        if data.len() < 12 || u32::from_be_bytes([data[8], data[9], data[10], data[11]]) != self.media_ssrc {
+            return repairs;
+        }
+        let seq = u16::from_be_bytes([data[2], data[3]]);
+        let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let marker = data[1] & 0x80 != 0;
+
+        self.stats.media_packets += 1;
+        self.stats.media_bytes += data.len() as u64;
+
+        // Reordered or too far ahead for the mask: close the group first.
+        // `sequence` has normally done this already.
+        if let Some(group) = &self.group {
+            if seq.wrapping_sub(group.base) >= MAX_SPAN || group.seqs.contains(&seq) {
+                repairs.extend(self.emit());
+            }
+        }
+
+        let id = self.next_group;
+        let group = self.group.get_or_insert_with(|| Group::new(id, seq));
+        group.add(data, seq, timestamp);
+        let (group_id, full) = (group.id, group.seqs.len() >= self.config.group_size);
+        if let Some(transport_seq) = transport_seq {
+            self.track(transport_seq, group_id, false);
+        }
+
+        if full || marker {
+            repairs.extend(self.emit());
+        }
+        repairs
+    }
+
+    // RED-encapsulates a media packet after it has been protected.
+    pub fn wrap(&self, packet: &mut RtpPacket) {
+        if let FecScheme::UlpfecRed { red_payload_type, .. } = self.config.scheme {
+            if packet.ssrc == self.media_ssrc {
+                packet.payload.insert(0, packet.payload_type & 0x7F);
+                packet.payload_type = red_payload_type;
+            }
+        }
+    }
+
+    fn emit(&mut self) -> Option<RepairPacket> {
+        let group = self.group.take()?;
+        self.next_group += 1;
+
+        let packet = match self.config.scheme {
+            FecScheme::FlexFec { payload_type } => {
+                let seq = self.repair_seq;
+                self.repair_seq = seq.wrapping_add(1);
+                RtpPacket {
+                    payload: group.flexfec_payload(self.media_ssrc),
+                    timestamp: group.last_timestamp,
+                    sequence: seq,
+                    ssrc: flexfec_ssrc(self.media_ssrc),
+                    marker: false,
+                    payload_type,
+                    extension: None,
+                    source: None,
+                    received_at: std::time::Instant::now(),
+                    raw: None,
+                }
+            }
+            FecScheme::UlpfecRed { red_payload_type, ulpfec_payload_type } => {
+                let mut payload = vec![ulpfec_payload_type & 0x7F];
+                payload.extend_from_slice(&group.ulpfec_payload());
+                self.seq_offset = self.seq_offset.wrapping_add(1);
+                RtpPacket {
+                    payload,
+                    timestamp: group.last_timestamp,
+                    sequence: group.last_seq().wrapping_add(1),
+                    ssrc: self.media_ssrc,
+                    marker: false,
+                    payload_type: red_payload_type,
+                    extension: None,
+                    source: None,
+                    received_at: std::time::Instant::now(),
+                    raw: None,
+                }
+            }
+        };
+
+        self.stats.fec_packets += 1;
+        self.stats.fec_bytes += 12 + packet.payload.len() as u64;
+        Some(RepairPacket { group: group.id, packet })
+    }
+
+    // Remembers which group a transport-wide sequence number belongs to.
+    pub fn track(&mut self, transport_seq: u16, group: u64, repair: bool) {
+        let losses = self.losses.entry(group).or_default();
+        if repair {
+            losses.closed = true;
+        } else {
+            losses.media += 1;
+        }
+
+        self.transport.insert(transport_seq, (group, repair));
+        self.transport_order.push_back(transport_seq);
+        if self.transport_order.len() > TRACKING_LEN {
+            if let Some(old) = self.transport_order.pop_front() {
+                self.transport.remove(&old);
+            }
+        }
+        if self.losses.len() > MAX_TRACKED_GROUPS {
+            let oldest = self.next_group.saturating_sub(MAX_TRACKED_GROUPS as u64);
+            self.losses.retain(|id, _| *id >= oldest);
+        }
+    }
+
+    // Counts losses a receiver could and couldn't repair once feedback has
+    // covered a whole group. A single XOR repair packet rebuilds one loss.
+    pub fn on_feedback(&mut self, feedback: &TwccFeedback) {
+        for &(transport_seq, arrival) in &feedback.packets {
+            let Some((group, repair)) = self.transport.remove(&transport_seq) else {
+                continue;
+            };
+            let Some(losses) = self.losses.get_mut(&group) else {
+                continue;
+            };
+
+            if repair {
+                losses.repair_reported = true;
+                losses.repair_received = arrival.is_some();
+            } else {
+                losses.reported_media += 1;
+                if arrival.is_none() {
+                    losses.lost_media += 1;
+                }
+            }
+
+            if losses.closed && losses.repair_reported && losses.reported_media == losses.media {
+                if losses.lost_media == 1 && losses.repair_received {
+                    self.stats.recoverable_losses += 1;
+                } else {
+                    self.stats.unrecoverable_losses += losses.lost_media as u64;
+                }
+                self.losses.remove(&group);
+            }
+        }
+    }
+}
+
+// A subscriber's FEC: one encoder per media SSRC, created as streams appear.
+#[derive(Debug)]
+pub struct FecProtector {
+    config: FecConfig,
+    encoders: HashMap<u32, FecEncoder>,
+}
+
+impl FecProtector {
+    pub fn new(config: FecConfig) -> Self {
+        Self { config, encoders: HashMap::new() }
+    }
+
+    pub fn encoder(&mut self, ssrc: u32) -> Option<&mut FecEncoder> {
+        if !self.encoders.contains_key(&ssrc) && self.encoders.len() >= MAX_PROTECTED_SSRCS {
+            return None;
+        }
+        let config = self.config;
+        Some(self.encoders.entry(ssrc).or_insert_with(|| FecEncoder::new(config, ssrc)))
+    }
+
+    pub fn stats(&self) -> FecStats {
+        let mut stats = FecStats::default();
+        for encoder in self.encoders.values() {
+            stats.merge(&encoder.stats());
+        }
+        stats
+    }
+
+    // Transport-wide sequence numbers are unique per subscriber, so each
+    // encoder only matches the packets it tracked.
+    pub fn on_feedback(&mut self, feedback: &TwccFeedback) {
+        for encoder in self.encoders.values_mut() {
+            encoder.on_feedback(feedback);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn media(seq: u16, marker: bool, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x80, if marker { 0xE0 } else { 0x60 }];
+        data.extend_from_slice(&seq.to_be_bytes());
+        data.extend_from_slice(&(1000 + seq as u32).to_be_bytes());
+        data.extend_from_slice(&1234u32.to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn test_flexfec_recovers_lost_packet() {
+        let config = FecConfig { scheme: FecScheme::FlexFec { payload_type: 118 }, group_size: 3 };
+        let mut encoder = FecEncoder::new(config, 1234);
+        let packets = [media(10, false, b"first"), media(11, false, b"second!"), media(12, false, b"3")];
+
+        let mut repairs = Vec::new();
+        for packet in &packets {
+            repairs.extend(encoder.protect(packet, None));
+        }
+        assert_eq!(repairs.len(), 1);
+        let repair = &repairs[0].packet;
+        assert_eq!(repair.ssrc, flexfec_ssrc(1234));
+        let fec = &repair.payload;
+        assert_eq!(u16::from_be_bytes([fec[16], fec[17]]), 10);
+        assert_eq!(fec[18] & 0x80, 0x80);
+        assert_eq!(u16::from_be_bytes([fec[18], fec[19]]) & 0x7FFF, 0x7000);
+
+        // Rebuild packet 11 from 10, 12 and the repair packet
+        let mut length = u16::from_be_bytes([fec[2], fec[3]]);
+        let mut timestamp = u32::from_be_bytes([fec[4], fec[5], fec[6], fec[7]]);
+        let mut body = fec[20..].to_vec();
+        for other in [&packets[0], &packets[2]] {
+            length ^= (other.len() - 12) as u16;
+            timestamp ^= u32::from_be_bytes([other[4], other[5], other[6], other[7]]);
+            for (out, byte) in body.iter_mut().zip(&other[12..]) {
+                *out ^= byte;
+            }
+        }
+        body.truncate(length as usize);
+        assert_eq!(timestamp, 1011);
+        assert_eq!(body, b"second!");
+        assert_eq!(encoder.stats().fec_packets, 1);
+    }
+
+    #[test]
+    fn test_ulpfec_red_shares_sequence_space() {
+        let config = FecConfig {
+            scheme: FecScheme::UlpfecRed { red_payload_type: 116, ulpfec_payload_type: 117 },
+            group_size: 5,
+        };
+        let mut encoder = FecEncoder::new(config, 1234);
+
+        // The marker closes the group early
+        let first = encoder.protect(&media(100, false, b"aa"), Some(1));
+        assert!(first.is_empty());
+        let repairs = encoder.protect(&media(101, true, b"bbbb"), Some(2));
+        assert_eq!(repairs.len(), 1);
+        let repair = &repairs[0];
+        assert_eq!(repair.packet.sequence, 102);
+        assert_eq!(repair.packet.payload_type, 116);
+        assert_eq!(repair.packet.payload[0], 117);
+        assert_eq!(u16::from_be_bytes([repair.packet.payload[3], repair.packet.payload[4]]), 100);
+        assert_eq!(&repair.packet.payload[11..15], &[0x00, 0x04, 0xC0, 0x00]);
+
+        let mut next = RtpPacket {
+            payload: b"cc".to_vec(),
+            timestamp: 0,
+            sequence: 102,
+            ssrc: 1234,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: None,
+            received_at: Instant::now(),
+            raw: None,
+        };
+        encoder.sequence(&mut next);
+        assert_eq!(next.sequence, 103);
+        encoder.wrap(&mut next);
+        assert_eq!(next.payload, vec![96, b'c', b'c']);
+
+        // Packet 1 lost but the repair arrived: recoverable
+        encoder.track(3, repair.group, true);
+        encoder.on_feedback(&TwccFeedback {
+            sender_ssrc: 1,
+            media_ssrc: 1234,
+            packets: vec![(1, None), (2, Some(0)), (3, Some(1000))],
+        });
+        assert_eq!(encoder.stats().recoverable_losses, 1);
+        assert_eq!(encoder.stats().unrecoverable_losses, 0);
+    }
+
+    #[test]
+    fn test_early_close_keeps_sequence_contiguous() {
+        let config = FecConfig {
+            scheme: FecScheme::UlpfecRed { red_payload_type: 116, ulpfec_payload_type: 117 },
+            group_size: 5,
+        };
+        let mut protector = FecProtector::new(config);
+        let packet = |sequence: u16| RtpPacket {
+            payload: b"dd".to_vec(),
+            timestamp: 0,
+            sequence,
+            ssrc: 1234,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: None,
+            received_at: Instant::now(),
+            raw: None,
+        };
+
+        let encoder = protector.encoder(1234).unwrap();
+        for sequence in [100, 101] {
+            let mut sent = packet(sequence);
+            assert!(encoder.sequence(&mut sent).is_none());
+            encoder.protect(&media(sequence, false, b"dd"), None);
+        }
+        // Too far ahead for the group: its repair goes out first, and the
+        // packet after it is shifted past it
+        let mut jumped = packet(200);
+        let repair = encoder.sequence(&mut jumped).unwrap();
+        assert_eq!((repair.packet.sequence, jumped.sequence), (102, 201));
+        assert!(encoder.protect(&media(201, false, b"dd"), None).is_empty());
+        let mut next = packet(201);
+        encoder.sequence(&mut next);
+        assert_eq!(next.sequence, 202);
+
+        // Each SSRC gets its own encoder
+        assert_eq!(protector.encoder(5678).unwrap().stats().media_packets, 0);
+        assert_eq!(protector.stats().media_packets, 3);
+    }
+}
@@ -0,0 +1,120 @@
+// Per-subscriber packet filters, e.g. a subscriber that only wants the audio
+// of a mixed stream or only the RTX retransmissions.
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{Codec, MediaType};
+use crate::sdp::{MediaKind, SessionDescription};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PacketKind {
+    Audio,
+    Video,
+    Data,
+    // RFC 4588 retransmissions.
+    Rtx,
+    // ULPFEC and FlexFEC repair streams.
+    Fec,
+}
+
+impl PacketKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "audio" => Some(PacketKind::Audio),
+            "video" => Some(PacketKind::Video),
+            "data" | "application" => Some(PacketKind::Data),
+            "rtx" => Some(PacketKind::Rtx),
+            "fec" => Some(PacketKind::Fec),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PacketKind::Audio => "audio",
+            PacketKind::Video => "video",
+            PacketKind::Data => "data",
+            PacketKind::Rtx => "rtx",
+            PacketKind::Fec => "fec",
+        }
+    }
+
+    // By the SDP format the payload type maps to, else by the codec seen.
+    // RED carries the media itself, so it takes the kind of its m-line.
+    pub fn classify(description: Option<&SessionDescription>, payload_type: u8, codec: Codec) -> Option<Self> {
+        let declared = description.and_then(|d| {
+            d.media
+                .iter()
+                .find_map(|m| m.format(payload_type).map(|f| (m.kind, f.encoding.to_ascii_lowercase())))
+        });
+        match declared {
+            Some((_, encoding)) if encoding == "rtx" => Some(PacketKind::Rtx),
+            Some((_, encoding)) if matches!(encoding.as_str(), "ulpfec" | "flexfec" | "flexfec-03") => {
+                Some(PacketKind::Fec)
+            }
+            Some((MediaKind::Audio, _)) => Some(PacketKind::Audio),
+            Some((MediaKind::Video, _)) => Some(PacketKind::Video),
+            Some((MediaKind::Application, _)) => Some(PacketKind::Data),
+            None => match codec.media_type()? {
+                MediaType::Audio => Some(PacketKind::Audio),
+                MediaType::Video => Some(PacketKind::Video),
+                MediaType::Data => Some(PacketKind::Data),
+            },
+        }
+    }
+}
+
+// Each non-empty list must match; an empty filter accepts everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriberFilter {
+    pub payload_types: Vec<u8>,
+    pub ssrcs: Vec<u32>,
+    pub kinds: Vec<PacketKind>,
+}
+
+impl SubscriberFilter {
+    pub fn is_empty(&self) -> bool {
+        self.payload_types.is_empty() && self.ssrcs.is_empty() && self.kinds.is_empty()
+    }
+
+    // A packet of unknown kind never matches a kind filter.
+    pub fn accepts(&self, payload_type: u8, ssrc: u32, kind: Option<PacketKind>) -> bool {
+        (self.payload_types.is_empty() || self.payload_types.contains(&payload_type))
+            && (self.ssrcs.is_empty() || self.ssrcs.contains(&ssrc))
+            && (self.kinds.is_empty() || kind.is_some_and(|kind| self.kinds.contains(&kind)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_and_accept() {
+        let description = SessionDescription::parse(
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\nc=IN IP4 10.0.0.1\r\nt=0 0\r\n\
+             m=audio 5004 RTP/AVP 111 63\r\na=rtpmap:111 opus/48000/2\r\na=rtpmap:63 red/48000/2\r\n\
+             m=video 5006 RTP/AVP 96 97 98\r\na=rtpmap:96 H264/90000\r\na=rtpmap:97 rtx/90000\r\n\
+             a=rtpmap:98 ulpfec/90000\r\n",
+        )
+        .unwrap();
+        let kind = |pt| PacketKind::classify(Some(&description), pt, Codec::Unknown);
+        assert_eq!(kind(111), Some(PacketKind::Audio));
+        assert_eq!(kind(96), Some(PacketKind::Video));
+        assert_eq!(kind(97), Some(PacketKind::Rtx));
+        assert_eq!(kind(63), Some(PacketKind::Audio));
+        assert_eq!(kind(98), Some(PacketKind::Fec));
+        assert_eq!(PacketKind::classify(None, 100, Codec::Vp8), Some(PacketKind::Video));
+
+        let audio_only = SubscriberFilter { kinds: vec![PacketKind::Audio], ..Default::default() };
+        assert!(audio_only.accepts(111, 1, kind(111)));
+        assert!(!audio_only.accepts(96, 1, kind(96)));
+        assert!(!audio_only.accepts(100, 1, None));
+
+        let pinned = SubscriberFilter { payload_types: vec![96, 97], ssrcs: vec![7], ..Default::default() };
+        assert!(pinned.accepts(97, 7, None));
+        assert!(!pinned.accepts(97, 8, None));
+        assert!(SubscriberFilter::default().accepts(0, 0, None));
+    }
+}
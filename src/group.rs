@@ -0,0 +1,368 @@
+use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use dashmap::{DashMap, DashSet};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use tracing::{info, debug};
+
+use crate::audio_level::SpeakerSelector;
+use crate::events::ServerEvent;
+use crate::sdp::SessionDescription;
+use crate::session::{Session, SessionError, SessionId, SessionManager, SubscriberTransport};
+
+const SPEAKER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionGroupId(pub Uuid);
+
+impl SessionGroupId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Default for SessionGroupId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A bundle of sessions (e.g. several cameras plus audio from one vehicle)
+// that subscribers join as a unit. Sessions are tied together by their RTCP
+// CNAME.
+#[derive(Debug)]
+pub struct SessionGroup {
+    pub id: SessionGroupId,
+    pub name: String,
+    pub cname: Option<String>,
+    pub members: DashSet<SessionId>,
+    pub subscribers: DashMap<SocketAddr, SubscriberTransport>,
+    pub created_at: Instant,
+    pub speaker: Mutex<SpeakerSelector>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionGroupStats {
+    pub session_count: usize,
+    pub subscriber_count: usize,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub uptime_seconds: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActiveSpeaker {
+    pub session_id: Option<SessionId>,
+    // Members currently sending RFC 6464 levels, loudest first.
+    pub levels: Vec<(SessionId, f64)>,
+}
+
+pub struct SessionGroupManager {
+    session_manager: Arc<SessionManager>,
+    groups: DashMap<SessionGroupId, Arc<SessionGroup>>,
+}
+
+impl SessionGroupManager {
+    pub fn new(session_manager: Arc<SessionManager>) -> Self {
+        Self {
+            session_manager,
+            groups: DashMap::new(),
+        }
+    }
+
+    pub fn create_group(&self, name: &str, cname: Option<String>) -> Arc<SessionGroup> {
+        let group = Arc::new(SessionGroup {
+            id: SessionGroupId::new(),
+            name: name.to_string(),
+            cname,
+            members: DashSet::new(),
+            subscribers: DashMap::new(),
+            created_at: Instant::now(),
+            speaker: Mutex::new(SpeakerSelector::default()),
+        });
+
+        self.groups.insert(group.id, group.clone());
+        info!("Created session group {} ({})", group.id.0, name);
+
+        // Sessions created before the group share its CNAME too.
+        if let Some(cname) = &group.cname {
+            for session in self.session_manager.sessions_with_cname(cname) {
+                if self.group_of(&session.id).is_none() {
+                    let _ = self.add_session(&group.id, &session.id);
+                }
+            }
+        }
+        group
+    }
+
+    pub fn get_group(&self, id: &SessionGroupId) -> Option<Arc<SessionGroup>> {
+        self.groups.get(id).map(|g| g.clone())
+    }
+
+    pub fn group_of(&self, session_id: &SessionId) -> Option<Arc<SessionGroup>> {
+        self.groups
+            .iter()
+            .find(|g| g.members.contains(session_id))
+            .map(|g| g.clone())
+    }
+
+    // Live member sessions; members whose session has been removed are pruned.
+    pub fn sessions(&self, group: &SessionGroup) -> Vec<Arc<Session>> {
+        let mut sessions = Vec::with_capacity(group.members.len());
+        group.members.retain(|id| match self.session_manager.get_session(id) {
+            Some(session) => {
+                sessions.push(session);
+                true
+            }
+            None => false,
+        });
+        sessions
+    }
+
+    // A session joining a group immediately starts fanning out to the
+    // subscribers already in it.
+    pub fn add_session(&self, group_id: &SessionGroupId, session_id: &SessionId) -> Result<(), SessionError> {
+        let group = self.get_group(group_id).ok_or(SessionError::GroupNotFound(*group_id))?;
+        let session = self
+            .session_manager
+            .get_session(session_id)
+            .ok_or(SessionError::NotFound(*session_id))?;
+
+        if let Some(previous) = self.group_of(session_id) {
+            if previous.id != group.id {
+                self.remove_session(&previous.id, session_id);
+            }
+        }
+
+        group.members.insert(*session_id);
+        for entry in group.subscribers.iter() {
+            session.add_group_subscriber(*entry.key(), entry.value().clone());
+        }
+        debug!("Session {} joined group {}", session_id.0, group.id.0);
+        Ok(())
+    }
+
+    pub fn remove_session(&self, group_id: &SessionGroupId, session_id: &SessionId) -> bool {
+        let Some(group) = self.get_group(group_id) else {
+            return false;
+        };
+        if group.members.remove(session_id).is_none() {
+            return false;
+        }
+
+        if let Some(session) = self.session_manager.get_session(session_id) {
+            for entry in group.subscribers.iter() {
+                session.remove_group_subscriber(entry.key());
+            }
+        }
+        true
+    }
+
+    // Adds the session to the group whose CNAME matches the session's (from
+    // SDP or the source's RTCP), if any. Returns the group joined.
+    pub fn join_by_cname(&self, session: &Session) -> Option<SessionGroupId> {
+        let cname = session.cname()?;
+        let group_id = self
+            .groups
+            .iter()
+            .find(|g| g.cname.as_deref() == Some(cname.as_str()))
+            .map(|g| g.id)?;
+
+        self.add_session(&group_id, &session.id).ok()?;
+        Some(group_id)
+    }
+
+    pub fn add_subscriber(&self, group_id: &SessionGroupId, addr: SocketAddr, transport: SubscriberTransport) -> bool {
+        let Some(group) = self.get_group(group_id) else {
+            return false;
+        };
+
+        group.subscribers.insert(addr, transport.clone());
+        for session in self.sessions(&group) {
+            session.add_group_subscriber(addr, transport.clone());
+        }
+        info!("Added subscriber {} to group {} ({} sessions)", addr, group.id.0, group.members.len());
+        true
+    }
+
+    pub fn remove_subscriber(&self, group_id: &SessionGroupId, addr: &SocketAddr) -> bool {
+        let Some(group) = self.get_group(group_id) else {
+            return false;
+        };
+        if group.subscribers.remove(addr).is_none() {
+            return false;
+        }
+
+        for session in self.sessions(&group) {
+            session.remove_group_subscriber(addr);
+        }
+        true
+    }
+
+    pub fn stats(&self, group_id: &SessionGroupId) -> Option<SessionGroupStats> {
+        let group = self.get_group(group_id)?;
+        let sessions = self.sessions(&group);
+
+        Some(SessionGroupStats {
+            session_count: sessions.len(),
+            subscriber_count: group.subscribers.len(),
+            packets_received: sessions.iter().map(|s| s.packet_count.load(Ordering::Relaxed)).sum(),
+            bytes_received: sessions.iter().map(|s| s.byte_count.load(Ordering::Relaxed)).sum(),
+            uptime_seconds: group.created_at.elapsed().as_secs_f64(),
+        })
+    }
+
+    fn audio_levels(&self, group: &SessionGroup) -> Vec<(SessionId, f64)> {
+        let mut levels: Vec<_> = self
+            .sessions(group)
+            .iter()
+            .filter_map(|s| Some((s.id, s.audio_level_dbov()?)))
+            .collect();
+        levels.sort_by(|a, b| b.1.total_cmp(&a.1));
+        levels
+    }
+
+    // The speaker as of the last evaluation, with the live member levels.
+    pub fn active_speaker(&self, group_id: &SessionGroupId) -> Option<ActiveSpeaker> {
+        let group = self.get_group(group_id)?;
+        let session_id = group.speaker.lock().current();
+        Some(ActiveSpeaker { session_id, levels: self.audio_levels(&group) })
+    }
+
+    // Re-evaluates every group's speaker and publishes the changes.
+    pub fn update_active_speakers(&self) {
+        let groups: Vec<_> = self.groups.iter().map(|g| g.clone()).collect();
+        for group in groups {
+            let levels = self.audio_levels(&group);
+            let Some(session_id) = group.speaker.lock().select(&levels) else {
+                continue;
+            };
+            let level_dbov = levels.iter().find(|(id, _)| Some(*id) == session_id).map(|(_, level)| *level);
+            debug!("Active speaker of group {} is now {:?}", group.id.0, session_id.map(|id| id.0));
+            self.session_manager.events().publish(ServerEvent::ActiveSpeakerChanged {
+                group_id: group.id,
+                session_id,
+                level_dbov,
+            });
+        }
+    }
+
+    pub async fn run_speaker_detector(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(SPEAKER_CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            self.update_active_speakers();
+        }
+    }
+
+    // One SDP covering every member, with an a=group:LS line so receivers
+    // lip-sync the streams.
+    pub fn description(&self, group_id: &SessionGroupId, server_ip: IpAddr) -> Option<SessionDescription> {
+        let group = self.get_group(group_id)?;
+        let mut description = SessionDescription::new(&group.name, server_ip);
+
+        for (index, session) in self.sessions(&group).iter().enumerate() {
+            let member = self.session_manager.subscriber_description(session, server_ip);
+            description.connection = member.connection.clone();
+            for mut media in member.media {
+                if media.mid.is_none() {
+                    media.mid = Some(format!("{}-{}", index, description.media.len()));
+                }
+                if let Some(cname) = &group.cname {
+                    for ssrc in &mut media.ssrcs {
+                        ssrc.cname.get_or_insert_with(|| cname.clone());
+                    }
+                }
+                description.media.push(media);
+            }
+        }
+
+        let mids: Vec<&str> = description.media.iter().filter_map(|m| m.mid.as_deref()).collect();
+        description.attributes.push(format!("group:LS {}", mids.join(" ")));
+        Some(description)
+    }
+
+    // Tears the group down. With `remove_sessions` the member sessions are
+    // removed too; otherwise only the group's subscribers are detached.
+    pub fn remove_group(&self, group_id: &SessionGroupId, remove_sessions: bool) -> bool {
+        let Some((_, group)) = self.groups.remove(group_id) else {
+            return false;
+        };
+
+        for session in self.sessions(&group) {
+            if remove_sessions {
+                self.session_manager.remove_session(&session.id);
+            } else {
+                for entry in group.subscribers.iter() {
+                    session.remove_group_subscriber(entry.key());
+                }
+            }
+        }
+        info!("Removed session group {}", group_id.0);
+        true
+    }
+
+    pub fn group_count(&self) -> usize {
+        self.groups.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn test_group_subscribers_follow_members() {
+        let session_manager = Arc::new(SessionManager::new(ServerConfig::default()));
+        let groups = SessionGroupManager::new(session_manager.clone());
+        let source: SocketAddr = "10.0.0.1:5004".parse().unwrap();
+
+        let video = session_manager.create_session(source, 1).unwrap();
+        let group = groups.create_group("rover-17", Some("rover-17".to_string()));
+        groups.add_session(&group.id, &video.id).unwrap();
+
+        let viewer: SocketAddr = "192.0.2.10:7000".parse().unwrap();
+        assert!(groups.add_subscriber(&group.id, viewer, SubscriberTransport::Udp));
+        assert!(video.subscribers.contains_key(&viewer));
+
+        let audio = session_manager.create_session(source, 2).unwrap();
+        groups.add_session(&group.id, &audio.id).unwrap();
+        assert!(audio.subscribers.contains_key(&viewer));
+
+        let stats = groups.stats(&group.id).unwrap();
+        assert_eq!(stats.session_count, 2);
+        assert_eq!(stats.subscriber_count, 1);
+
+        assert!(groups.remove_group(&group.id, false));
+        assert!(video.subscribers.is_empty());
+        assert!(audio.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_group_adopts_cname_and_spares_direct_subscribers() {
+        let session_manager = Arc::new(SessionManager::new(ServerConfig::default()));
+        let groups = SessionGroupManager::new(session_manager.clone());
+        let offer = SessionDescription::parse(
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=cam\r\nt=0 0\r\n\
+             m=video 5004 RTP/AVP 96\r\na=rtpmap:96 VP8/90000\r\na=ssrc:5 cname:rover-9\r\n",
+        )
+        .unwrap();
+        let video = session_manager
+            .create_session_with_description("10.0.0.1:5004".parse().unwrap(), 0, offer)
+            .unwrap();
+
+        let group = groups.create_group("rover-9", Some("rover-9".to_string()));
+        assert!(group.members.contains(&video.id));
+
+        let viewer: SocketAddr = "192.0.2.10:7000".parse().unwrap();
+        video.add_subscriber(viewer);
+        groups.add_subscriber(&group.id, viewer, SubscriberTransport::Udp);
+        assert!(groups.remove_subscriber(&group.id, &viewer));
+        assert!(video.subscribers.contains_key(&viewer));
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::config::ServerConfig;
+use crate::RtpPacket;
+
+// This many late packets in a row means the source restarted or jumped its
+// sequence numbers, not that packets were reordered.
+const RESYNC_AFTER_LATE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReorderMode {
+    // Forward in arrival order; reordering is only counted.
+    Bypass,
+    // Hold packets up to this long waiting for earlier sequence numbers.
+    Buffer(Duration),
+}
+
+impl ReorderMode {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::from_latency_ms(config.reorder_latency_ms)
+    }
+
+    pub fn from_latency_ms(latency_ms: u64) -> Self {
+        if latency_ms == 0 {
+            ReorderMode::Bypass
+        } else {
+            ReorderMode::Buffer(Duration::from_millis(latency_ms))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    // Arrived after a higher sequence number.
+    pub reordered: u64,
+    // Arrived after their slot was released; discarded when buffering.
+    pub late: u64,
+    pub duplicates: u64,
+    pub discarded: u64,
+    // Sequence numbers given up on when the latency budget ran out.
+    pub skipped: u64,
+}
+
+#[derive(Debug)]
+struct StreamBuffer {
+    next: u64,
+    highest: u64,
+    held: BTreeMap<u64, RtpPacket>,
+    // Arrival times of the held packets; the oldest one bounds the wait.
+    arrivals: BTreeSet<(Instant, u64)>,
+    late_run: u32,
+}
+
+impl StreamBuffer {
+    // Start well above zero so early reordering doesn't clamp.
+    fn new(first: u16) -> Self {
+        let first = first as u64 + (1 << 16);
+        Self { next: first, highest: first, held: BTreeMap::new(), arrivals: BTreeSet::new(), late_run: 0 }
+    }
+
+    fn hold(&mut self, seq: u64, packet: RtpPacket) {
+        self.arrivals.insert((packet.received_at, seq));
+        self.held.insert(seq, packet);
+    }
+
+    fn release_first(&mut self) -> Option<(u64, RtpPacket)> {
+        let (seq, packet) = self.held.pop_first()?;
+        self.arrivals.remove(&(packet.received_at, seq));
+        Some((seq, packet))
+    }
+
+    // Unwraps a 16-bit sequence number relative to the highest one seen.
+    fn extend(&self, seq: u16) -> u64 {
+        let delta = seq.wrapping_sub(self.highest as u16) as i16 as i64;
+        (self.highest as i64 + delta).max(0) as u64
+    }
+}
+
+// Per-session ingest reorder stage, one sequence space per SSRC.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    mode: ReorderMode,
+    max_packets: usize,
+    streams: HashMap<u32, StreamBuffer>,
+    stats: ReorderStats,
+}
+
+impl ReorderBuffer {
+    pub fn new(mode: ReorderMode, max_packets: usize) -> Self {
+        Self {
+            mode,
+            max_packets: max_packets.max(1),
+            streams: HashMap::new(),
+            stats: ReorderStats::default(),
+        }
+    }
+
+    pub fn mode(&self) -> ReorderMode {
+        self.mode
+    }
+
+    pub fn is_bypassed(&self) -> bool {
+        self.mode == ReorderMode::Bypass
+    }
+
+    pub fn stats(&self) -> ReorderStats {
+        self.stats
+    }
+
+    // Bypass mode: the packet is forwarded as is, only counted.
+    pub fn observe(&mut self, packet: &RtpPacket) {
+        let stream = self.streams.entry(packet.ssrc).or_insert_with(|| StreamBuffer::new(packet.sequence));
+        let seq = stream.extend(packet.sequence);
+        if seq < stream.highest {
+            self.stats.reordered += 1;
+        }
+        stream.highest = stream.highest.max(seq);
+    }
+
+    // Buffers a packet and returns whatever is now ready, in order.
+    pub fn push(&mut self, packet: RtpPacket, now: Instant) -> Vec<RtpPacket> {
+        let ReorderMode::Buffer(latency) = self.mode else {
+            return vec![packet];
+        };
+
+        let stream = self.streams.entry(packet.ssrc).or_insert_with(|| StreamBuffer::new(packet.sequence));
+        let mut seq = stream.extend(packet.sequence);
+        let mut released = Vec::new();
+
+        if seq < stream.next {
+            stream.late_run += 1;
+            if stream.late_run < RESYNC_AFTER_LATE {
+                self.stats.late += 1;
+                self.stats.discarded += 1;
+                return Vec::new();
+            }
+            // Hand over what the old sequence space still holds and follow
+            // the new one.
+            debug!("Reorder buffer resyncing SSRC {} at sequence {}", packet.ssrc, packet.sequence);
+            while let Some((_, held)) = stream.release_first() {
+                released.push(held);
+            }
+            *stream = StreamBuffer::new(packet.sequence);
+            seq = stream.next;
+        }
+        stream.late_run = 0;
+        if stream.held.contains_key(&seq) {
+            self.stats.duplicates += 1;
+            self.stats.discarded += 1;
+            return Vec::new();
+        }
+        if seq < stream.highest {
+            self.stats.reordered += 1;
+        }
+        stream.highest = stream.highest.max(seq);
+        stream.hold(seq, packet);
+
+        Self::drain(stream, &mut self.stats, latency, self.max_packets, now, &mut released);
+        released
+    }
+
+    // Releases packets whose wait for a missing predecessor has run out.
+    pub fn expire(&mut self, now: Instant) -> Vec<RtpPacket> {
+        let mut released = Vec::new();
+        let ReorderMode::Buffer(latency) = self.mode else {
+            return released;
+        };
+        for stream in self.streams.values_mut() {
+            Self::drain(stream, &mut self.stats, latency, self.max_packets, now, &mut released);
+        }
+        released
+    }
+
+    fn drain(
+        stream: &mut StreamBuffer,
+        stats: &mut ReorderStats,
+        latency: Duration,
+        max_packets: usize,
+        now: Instant,
+        released: &mut Vec<RtpPacket>,
+    ) {
+        loop {
+            let overflow = stream.held.len() > max_packets;
+            let (Some(&seq), Some(&(oldest, _))) = (stream.held.keys().next(), stream.arrivals.first()) else {
+                break;
+            };
+            let expired = now.saturating_duration_since(oldest) >= latency;
+            if seq != stream.next && !expired && !overflow {
+                break;
+            }
+            stats.skipped += seq - stream.next;
+            stream.next = seq + 1;
+            released.extend(stream.release_first().map(|(_, packet)| packet));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: u16, received_at: Instant) -> RtpPacket {
+        RtpPacket {
+            payload: vec![0; 10],
+            timestamp: 0,
+            sequence: seq,
+            ssrc: 1234,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: None,
+            received_at,
+            raw: None,
+        }
+    }
+
+    fn sequences(packets: &[RtpPacket]) -> Vec<u16> {
+        packets.iter().map(|p| p.sequence).collect()
+    }
+
+    #[test]
+    fn test_reorder_releases_in_sequence() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(ReorderMode::Buffer(Duration::from_millis(50)), 64);
+
+        assert_eq!(sequences(&buffer.push(packet(65534, start), start)), vec![65534]);
+        assert!(buffer.push(packet(0, start), start).is_empty());
+        assert_eq!(sequences(&buffer.push(packet(65535, start), start)), vec![65535, 0]);
+
+        // 2 never arrives: 3 waits out the latency budget
+        assert!(buffer.push(packet(3, start), start).is_empty());
+        assert!(buffer.expire(start + Duration::from_millis(10)).is_empty());
+        assert_eq!(sequences(&buffer.expire(start + Duration::from_millis(50))), vec![3]);
+
+        assert_eq!(buffer.stats().reordered, 1);
+        assert_eq!(buffer.stats().skipped, 2);
+
+        // The wait is bounded by the oldest held packet, not the lowest sequence
+        let later = start + Duration::from_millis(30);
+        assert!(buffer.push(packet(6, start), start).is_empty());
+        assert!(buffer.push(packet(5, later), later).is_empty());
+        assert_eq!(sequences(&buffer.expire(start + Duration::from_millis(50))), vec![5, 6]);
+    }
+
+    #[test]
+    fn test_late_and_duplicate_packets_discarded() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(ReorderMode::Buffer(Duration::from_millis(50)), 64);
+
+        buffer.push(packet(10, start), start);
+        assert!(buffer.push(packet(12, start), start).is_empty());
+        assert!(buffer.push(packet(12, start), start).is_empty());
+        buffer.expire(start + Duration::from_millis(60));
+        assert!(buffer.push(packet(11, start), start).is_empty());
+
+        let stats = buffer.stats();
+        assert_eq!((stats.late, stats.duplicates, stats.discarded), (1, 1, 2));
+
+        // A source restart far back in sequence space is followed after a few packets
+        assert_eq!(sequences(&buffer.push(packet(13, start), start)), vec![13]);
+        let restart: Vec<u16> = (0..RESYNC_AFTER_LATE as u16).map(|i| 40000 + i).collect();
+        let released: Vec<_> = restart.iter().flat_map(|&seq| buffer.push(packet(seq, start), start)).collect();
+        assert_eq!(sequences(&released), vec![40000 + RESYNC_AFTER_LATE as u16 - 1]);
+        assert_eq!(sequences(&buffer.push(packet(40008, start), start)), vec![40008]);
+
+        let mut bypass = ReorderBuffer::new(ReorderMode::Bypass, 64);
+        bypass.observe(&packet(5, start));
+        bypass.observe(&packet(4, start));
+        assert_eq!(bypass.stats().reordered, 1);
+    }
+}
@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+use dashmap::DashMap;
+use uuid::Uuid;
+use tracing::debug;
+
+use crate::session::SessionId;
+
+pub const PUNCH_MAGIC: &[u8; 4] = b"RFLP";
+pub const PUNCH_LEN: usize = 4 + 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LatchToken(pub Uuid);
+
+impl LatchToken {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+
+    pub fn parse(token: &str) -> Option<Self> {
+        Uuid::parse_str(token).ok().map(Self)
+    }
+}
+
+impl Default for LatchToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for LatchToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// A punch packet is the 4-byte magic followed by the raw 16-byte token. The
+// first byte of the magic decodes as RTP version 1, so it can never be
+// mistaken for an RTP packet on the shared ingest socket.
+pub fn parse_punch(data: &[u8]) -> Option<LatchToken> {
+    if data.len() != PUNCH_LEN || &data[..4] != PUNCH_MAGIC {
+        return None;
+    }
+    Uuid::from_slice(&data[4..]).ok().map(LatchToken)
+}
+
+pub fn build_punch(token: LatchToken) -> Vec<u8> {
+    let mut data = Vec::with_capacity(PUNCH_LEN);
+    data.extend_from_slice(PUNCH_MAGIC);
+    data.extend_from_slice(token.0.as_bytes());
+    data
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatchBinding {
+    pub session_id: SessionId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug)]
+pub struct LatchRegistry {
+    bindings: DashMap<LatchToken, LatchBinding>,
+}
+
+impl LatchRegistry {
+    pub fn new() -> Self {
+        Self {
+            bindings: DashMap::new(),
+        }
+    }
+
+    pub fn register(&self, token: LatchToken, session_id: SessionId, addr: SocketAddr) {
+        self.bindings.insert(token, LatchBinding { session_id, addr });
+    }
+
+    pub fn lookup(&self, token: &LatchToken) -> Option<LatchBinding> {
+        self.bindings.get(token).map(|b| *b)
+    }
+
+    pub fn update(&self, token: &LatchToken, addr: SocketAddr) {
+        if let Some(mut binding) = self.bindings.get_mut(token) {
+            debug!("Latch {} moved from {} to {}", token, binding.addr, addr);
+            binding.addr = addr;
+        }
+    }
+
+    pub fn remove(&self, token: &LatchToken) -> bool {
+        self.bindings.remove(token).is_some()
+    }
+
+    pub fn remove_session(&self, session_id: &SessionId) {
+        self.bindings.retain(|_, binding| binding.session_id != *session_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+}
+
+impl Default for LatchRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punch_roundtrip() {
+        let token = LatchToken::new();
+        let punch = build_punch(token);
+        assert_eq!(punch.len(), PUNCH_LEN);
+        assert_eq!(parse_punch(&punch), Some(token));
+    }
+
+    #[test]
+    fn test_punch_rejects_rtp() {
+        let mut packet = vec![0u8; PUNCH_LEN];
+        packet[0] = 0x80;
+        assert_eq!(parse_punch(&packet), None);
+    }
+}
@@ -2,18 +2,57 @@ pub mod config;
 pub mod session;
 pub mod fanout;
 pub mod metrics;
+pub mod latch;
+pub mod rtsp;
+pub mod tcp;
+pub mod multicast;
+pub mod sdp;
+pub mod ports;
+pub mod rtp;
+pub mod group;
+pub mod codec;
+pub mod audio_level;
+pub mod container;
+pub mod depacketize;
+pub mod simulcast;
+pub mod svc;
+pub mod bwe;
+pub mod cluster;
+pub mod pacer;
+pub mod policer;
+pub mod events;
+pub mod fec;
+pub mod filter;
+pub mod jitter;
+pub mod recording;
+pub mod relay;
+pub mod replay;
+pub mod tap;
 
 use std::sync::Arc;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::sync::watch;
 use tracing::{info, warn, error, debug};
-use dashmap::DashMap;
+use dashmap::DashSet;
 use crossbeam::queue::SegQueue;
 
 use config::ServerConfig;
-use session::{SessionManager, SessionId};
+use session::{Session, SessionManager, SessionId, SessionOptions};
+use rtp::RtpExtension;
 use fanout::FanoutEngine;
+use rtsp::RtspServer;
+use tcp::TcpTransport;
+use multicast::MulticastOptions;
+use group::SessionGroupManager;
+use bwe::BweConfig;
+use pacer::PacingConfig;
+use policer::PolicingConfig;
+use relay::RelayServer;
+use cluster::{Cluster, DirectoryError, NodeRecord, SessionRecord};
+use events::ServerEvent;
+use replay::{Capture, ReplayOptions};
 
 #[derive(Debug, Clone)]
 pub struct RtpPacket {
@@ -22,6 +61,25 @@ pub struct RtpPacket {
     pub sequence: u16,
     pub ssrc: u32,
     pub marker: bool,
+    pub payload_type: u8,
+    pub extension: Option<RtpExtension>,
+    pub source: Option<SocketAddr>,
+    pub received_at: Instant,
+    // The datagram as it arrived, kept only for sessions with taps or a
+    // packet capture; None once the header has been rewritten.
+    pub raw: Option<Arc<[u8]>>,
+}
+
+impl RtpPacket {
+    pub fn with_source(mut self, source: SocketAddr) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn with_raw(mut self, data: &[u8]) -> Self {
+        self.raw = Some(Arc::from(data));
+        self
+    }
 }
 
 pub struct RtpFanoutServer {
@@ -29,9 +87,16 @@ pub struct RtpFanoutServer {
     socket: Arc<UdpSocket>,
     session_manager: Arc<SessionManager>,
     fanout_engine: Arc<FanoutEngine>,
-    packet_queue: Arc<SegQueue<RtpPacket>>,
+    session_groups: Arc<SessionGroupManager>,
+    relay: Option<Arc<RelayServer>>,
+    rtsp: Arc<RtspServer>,
+    cluster: Option<Arc<Cluster>>,
+    // Sessions this node serves on behalf of the cluster directory.
+    cluster_sessions: DashSet<SessionId>,
 }
 
+const CLUSTER_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
 impl RtpFanoutServer {
     pub async fn new(config: ServerConfig) -> anyhow::Result<Self> {
         let bind_addr: SocketAddr = config.bind_address.parse()?;
@@ -40,30 +105,477 @@ impl RtpFanoutServer {
 
         let session_manager = Arc::new(SessionManager::new(config.clone()));
         let packet_queue = Arc::new(SegQueue::new());
-        let fanout_engine = Arc::new(FanoutEngine::new(
-            session_manager.clone(),
-            packet_queue.clone(),
-        ));
+        let mut fanout_engine = FanoutEngine::new(session_manager.clone(), packet_queue)
+            .with_symmetric_socket(socket.clone())
+            .with_policing(PolicingConfig::from_config(&config));
+        if config.enable_twcc {
+            fanout_engine = fanout_engine.with_twcc(config.twcc_extension_id, BweConfig {
+                start_bps: config.bwe_start_bitrate_bps,
+                min_bps: config.bwe_min_bitrate_bps,
+                max_bps: config.bwe_max_bitrate_bps,
+            });
+        }
+        if let Some(pacing) = PacingConfig::from_config(&config) {
+            fanout_engine = fanout_engine.with_pacing(pacing);
+        }
+        let fanout_engine = Arc::new(fanout_engine);
+        let session_groups = Arc::new(SessionGroupManager::new(session_manager.clone()));
+        fanout_engine.attach_groups(&session_groups);
+        let server_id = match config.server_id.as_str() {
+            "" => uuid::Uuid::new_v4(),
+            id => uuid::Uuid::parse_str(id)?,
+        };
+        let relay = match config.relay_bind_address.as_str() {
+            "" => None,
+            _ => Some(Arc::new(
+                RelayServer::bind(&config, server_id, session_manager.clone(), fanout_engine.clone()).await?,
+            )),
+        };
+        if let Some(relay) = &relay {
+            fanout_engine.attach_relay(relay);
+        }
+        let rtsp = Arc::new(RtspServer::new(config.clone(), session_manager.clone(), socket.local_addr()?.port()));
+        let cluster = match config.cluster_directory.as_str() {
+            "" => None,
+            spec => {
+                let rtp_address = match config.cluster_advertise_address.as_str() {
+                    "" => socket.local_addr()?,
+                    address => address.parse()?,
+                };
+                let node_timeout = Duration::from_secs(config.cluster_node_timeout_secs);
+                info!("Cluster node {} advertising {} via {}", server_id, rtp_address, spec);
+                Some(Arc::new(Cluster::new(server_id, rtp_address, cluster::open(spec), node_timeout)))
+            }
+        };
 
         Ok(Self {
             config,
             socket,
             session_manager,
             fanout_engine,
-            packet_queue,
+            session_groups,
+            relay,
+            rtsp,
+            cluster,
+            cluster_sessions: DashSet::new(),
         })
     }
 
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn session_manager(&self) -> &Arc<SessionManager> {
+        &self.session_manager
+    }
+
+    pub fn session_groups(&self) -> &Arc<SessionGroupManager> {
+        &self.session_groups
+    }
+
+    pub fn relay(&self) -> Option<&Arc<RelayServer>> {
+        self.relay.as_ref()
+    }
+
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<ServerEvent> {
+        self.session_manager.events().subscribe()
+    }
+
+    pub fn bandwidth_estimate(&self, subscriber: &SocketAddr) -> Option<bwe::BandwidthEstimate> {
+        self.fanout_engine.bandwidth_estimate(subscriber)
+    }
+
+    pub fn create_session(
+        &self,
+        source_addr: SocketAddr,
+        ssrc: u32,
+        options: SessionOptions,
+    ) -> anyhow::Result<Arc<Session>> {
+        let session = self.session_manager.create_session_with_options(source_addr, ssrc, options)?;
+
+        if self.config.enable_session_ports {
+            if let Err(e) = self.bind_session_ports(&session) {
+                self.session_manager.remove_session(&session.id);
+                return Err(e);
+            }
+        }
+        self.session_groups.join_by_cname(&session);
+        Ok(session)
+    }
+
+    // A session fed by another server's session over the relay tunnel. The
+    // upstream address is the other server's relay_bind_address.
+    pub async fn create_relay_session(
+        &self,
+        upstream: SocketAddr,
+        upstream_session_id: SessionId,
+        options: SessionOptions,
+    ) -> anyhow::Result<Arc<Session>> {
+        let relay = self
+            .relay
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("relay is disabled (no relay_bind_address)"))?;
+        let session = self.session_manager.create_session_with_options(upstream, 0, options)?;
+        relay.attach(&session, upstream, upstream_session_id).await;
+        self.session_groups.join_by_cname(&session);
+        Ok(session)
+    }
+
+    // Runs a directory call on a blocking thread; directory backends do
+    // file or network I/O.
+    async fn with_cluster<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Cluster) -> Result<T, DirectoryError> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let cluster = self.cluster.clone().ok_or_else(|| anyhow::anyhow!("cluster mode is disabled"))?;
+        Ok(tokio::task::spawn_blocking(move || f(&cluster)).await??)
+    }
+
+    // Records the session in the cluster directory and starts it here if this
+    // node was picked to serve it. Sources send to the owner's rtp_address.
+    pub async fn create_cluster_session(
+        &self,
+        source_addr: SocketAddr,
+        ssrc: u32,
+        options: SessionOptions,
+    ) -> anyhow::Result<(SessionRecord, NodeRecord)> {
+        let (record, node_id) = self
+            .with_cluster(move |cluster| {
+                cluster.heartbeat()?;
+                Ok((cluster.place(source_addr, ssrc, &options)?, cluster.node_id()))
+            })
+            .await?;
+        if record.owner == node_id {
+            if let Err(e) = self.serve_cluster_session(&record) {
+                let id = record.id;
+                self.with_cluster(move |cluster| cluster.remove_session(&id)).await?;
+                return Err(e);
+            }
+        }
+        let owner = record.owner;
+        let owner = self
+            .with_cluster(move |cluster| Ok(cluster.nodes()?.into_iter().find(|n| n.id == owner)))
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("owner {} left the cluster", record.owner))?;
+        Ok((record, owner))
+    }
+
+    fn serve_cluster_session(&self, record: &SessionRecord) -> anyhow::Result<()> {
+        let session = self.create_session(record.source_addr, record.ssrc, record.options())?;
+        for subscriber in &record.subscribers {
+            session.add_subscriber(subscriber.addr);
+            session.set_subscriber_filter(&subscriber.addr, subscriber.filter.clone());
+        }
+        self.cluster_sessions.insert(record.id);
+        Ok(())
+    }
+
+    // Cluster-wide: sessions served by any node.
+    pub async fn cluster_sessions(&self) -> anyhow::Result<Vec<SessionRecord>> {
+        self.with_cluster(|cluster| cluster.sessions()).await
+    }
+
+    pub async fn cluster_session(&self, id: &SessionId) -> anyhow::Result<Option<SessionRecord>> {
+        let id = *id;
+        self.with_cluster(move |cluster| cluster.session(&id)).await
+    }
+
+    pub async fn cluster_nodes(&self) -> anyhow::Result<Vec<NodeRecord>> {
+        self.with_cluster(|cluster| cluster.live_nodes()).await
+    }
+
+    // Removes the session from the directory; its owner stops serving it on
+    // the next sync.
+    pub async fn delete_cluster_session(&self, id: &SessionId) -> anyhow::Result<bool> {
+        let id = *id;
+        let removed = self.with_cluster(move |cluster| cluster.remove_session(&id)).await?;
+        if self.cluster_sessions.remove(&id).is_some() {
+            self.session_manager.remove_session(&id);
+        }
+        Ok(removed)
+    }
+
+    // Heartbeats, saves the subscribers of the sessions served here and
+    // returns the sessions this node owns, taking over those of dead nodes.
+    async fn sync_directory(
+        cluster: Arc<Cluster>,
+        session_manager: Arc<SessionManager>,
+    ) -> Result<Vec<SessionRecord>, DirectoryError> {
+        tokio::task::spawn_blocking(move || {
+            cluster.heartbeat()?;
+            let owned = cluster.owned_sessions()?;
+            for record in &owned {
+                if let Some(session) = session_manager.get_session(&record.id) {
+                    cluster.save_subscribers(record, &session)?;
+                }
+            }
+            Ok(owned)
+        })
+        .await
+        .map_err(|e| DirectoryError::Io(std::io::Error::other(e)))?
+    }
+
+    // Keeps the directory work off the ingest loop; `run` applies each result.
+    async fn run_cluster_sync(
+        cluster: Arc<Cluster>,
+        session_manager: Arc<SessionManager>,
+        owned: watch::Sender<Option<Vec<SessionRecord>>>,
+    ) {
+        let mut interval = tokio::time::interval(CLUSTER_SYNC_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            match Self::sync_directory(cluster.clone(), session_manager.clone()).await {
+                Ok(records) => {
+                    if owned.send(Some(records)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("Cluster sync failed: {}", e),
+            }
+        }
+    }
+
+    // Starts the sessions this node now owns and stops the ones it no longer
+    // does.
+    fn apply_cluster_ownership(&self, owned: &[SessionRecord]) {
+        for record in owned {
+            if self.session_manager.get_session(&record.id).is_none() {
+                info!("Serving cluster session {}", record.id.0);
+                if let Err(e) = self.serve_cluster_session(record) {
+                    warn!("Failed to start cluster session {}: {}", record.id.0, e);
+                }
+            }
+        }
+        self.cluster_sessions.retain(|id| {
+            if owned.iter().any(|r| r.id == *id) {
+                return true;
+            }
+            info!("No longer serving cluster session {}", id.0);
+            self.session_manager.remove_session(id);
+            false
+        });
+    }
+
+    // One sync round, as the background task does every second.
+    pub async fn sync_cluster(&self) -> anyhow::Result<()> {
+        let Some(cluster) = self.cluster.clone() else {
+            return Ok(());
+        };
+        let owned = Self::sync_directory(cluster, self.session_manager.clone()).await?;
+        self.apply_cluster_ownership(&owned);
+        Ok(())
+    }
+
+    // Ports that turn out to be taken by another process are left at the back
+    // of the pool and the next allocation is tried instead.
+    fn bind_session_ports(&self, session: &Arc<Session>) -> anyhow::Result<()> {
+        const BIND_ATTEMPTS: usize = 8;
+        let bind_ip = self.config.bind_address.parse::<SocketAddr>()?.ip();
+
+        for _ in 0..BIND_ATTEMPTS {
+            let ports = self
+                .session_manager
+                .allocate_ingest_ports(session)
+                .ok_or_else(|| anyhow::anyhow!("ingest port pool exhausted"))?;
+
+            let sockets = std::net::UdpSocket::bind(SocketAddr::new(bind_ip, ports.rtp)).and_then(|rtp| {
+                let rtcp = ports
+                    .rtcp
+                    .map(|port| std::net::UdpSocket::bind(SocketAddr::new(bind_ip, port)))
+                    .transpose()?;
+                Ok((rtp, rtcp))
+            });
+
+            match sockets {
+                Ok((rtp, rtcp)) => {
+                    let handle = Self::spawn_session_ingest(session.clone(), rtp, self.fanout_engine.clone())?;
+                    session.attach_task(handle);
+                    if let Some(rtcp) = rtcp {
+                        let handle = Self::spawn_session_ingest(session.clone(), rtcp, self.fanout_engine.clone())?;
+                        session.attach_task(handle);
+                    }
+                    info!("Session {} ingest on port {} (rtcp {:?})", session.id.0, ports.rtp, ports.rtcp);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Failed to bind ingest port {}: {}", ports.rtp, e);
+                    self.session_manager.release_ingest_ports(session);
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!("no bindable ingest port after {} attempts", BIND_ATTEMPTS))
+    }
+
+    fn spawn_session_ingest(
+        session: Arc<Session>,
+        socket: std::net::UdpSocket,
+        fanout_engine: Arc<FanoutEngine>,
+    ) -> anyhow::Result<tokio::task::AbortHandle> {
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0u8; 65535];
+            loop {
+                match socket.recv_from(&mut buf).await {
+                    Ok((len, addr)) => {
+                        if tcp::is_rtcp(&buf[..len]) {
+                            if tcp::is_sender_report(&buf[..len]) {
+                                fanout_engine.handle_source_rtcp(&session, &buf[..len]).await;
+                            }
+                            continue;
+                        }
+                        if let Some(packet) = Self::parse_rtp_packet(&buf[..len]) {
+                            fanout_engine.ingest_to_session(&session, packet.with_source(addr), &buf[..len]).await;
+                        } else {
+                            debug!("Discarding non-RTP datagram from {} for session {}", addr, session.id.0);
+                        }
+                    }
+                    Err(e) if multicast::is_fatal_recv_error(&e) => {
+                        error!("Ingest for session {} stopped: {}", session.id.0, e);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Ingest receive error for session {}: {}", session.id.0, e);
+                        tokio::time::sleep(multicast::RECV_ERROR_BACKOFF).await;
+                    }
+                }
+            }
+        });
+        Ok(handle.abort_handle())
+    }
+
+    pub fn join_multicast_source(
+        &self,
+        session_id: &SessionId,
+        group: SocketAddr,
+        interface: Option<String>,
+    ) -> anyhow::Result<()> {
+        let session = self
+            .session_manager
+            .get_session(session_id)
+            .ok_or_else(|| anyhow::anyhow!("session {} not found", session_id.0))?;
+
+        let mut options = MulticastOptions::from_config(&self.config);
+        if interface.is_some() {
+            options.interface = interface;
+        }
+
+        let handle = multicast::spawn_ingest(session.clone(), group, &options, self.fanout_engine.clone())?;
+        session.attach_task(handle.abort_handle());
+        info!("Session {} receiving from multicast group {}", session_id.0, group);
+        Ok(())
+    }
+
+    // Injects an rtpdump/pcap capture into the session as if its source were
+    // sending it. A running replay is replaced.
+    pub fn start_replay(
+        &self,
+        session_id: &SessionId,
+        path: impl AsRef<std::path::Path>,
+        options: ReplayOptions,
+    ) -> anyhow::Result<()> {
+        let session = self
+            .session_manager
+            .get_session(session_id)
+            .ok_or_else(|| anyhow::anyhow!("session {} not found", session_id.0))?;
+
+        let capture = Capture::open(path.as_ref())?;
+        if capture.packets.is_empty() {
+            return Err(anyhow::anyhow!("no RTP packets in {}", path.as_ref().display()));
+        }
+
+        let handle = replay::spawn(capture, session.clone(), self.fanout_engine.clone(), options).abort_handle();
+        session.attach_task(handle.clone());
+        if let Some(previous) = session.replay.lock().replace(handle) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    pub fn stop_replay(&self, session_id: &SessionId) -> bool {
+        let Some(session) = self.session_manager.get_session(session_id) else {
+            return false;
+        };
+        let handle = session.replay.lock().take();
+        handle.map(|h| h.abort()).is_some()
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
         info!("Starting RTP Fanout Server v{}", env!("CARGO_PKG_VERSION"));
+
+        if self.config.enable_rtsp {
+            let rtsp = self.rtsp.clone();
+            tokio::spawn(async move {
+                if let Err(e) = rtsp.run().await {
+                    error!("RTSP server error: {}", e);
+                }
+            });
+        }
+
+        if self.config.enable_tcp {
+            let tcp = Arc::new(TcpTransport::new(
+                self.config.clone(),
+                self.session_manager.clone(),
+                self.fanout_engine.clone(),
+            ));
+            tokio::spawn(async move {
+                if let Err(e) = tcp.run().await {
+                    error!("RTP/TCP listener error: {}", e);
+                }
+            });
+        }
         
+        tokio::spawn(self.fanout_engine.clone().run_reorder_timer());
+        if self.config.video_freeze_threshold_ms > 0 {
+            let threshold = Duration::from_millis(self.config.video_freeze_threshold_ms);
+            tokio::spawn(self.session_manager.clone().run_freeze_detector(threshold));
+        }
+        tokio::spawn(self.session_groups.clone().run_speaker_detector());
+        if let Some(relay) = self.relay.clone() {
+            tokio::spawn(async move {
+                if let Err(e) = relay.run().await {
+                    error!("Relay error: {}", e);
+                }
+            });
+        }
+
+        let (owned_tx, mut owned) = watch::channel(None);
+        if let Some(cluster) = self.cluster.clone() {
+            tokio::spawn(Self::run_cluster_sync(cluster, self.session_manager.clone(), owned_tx));
+        }
+
         let mut buf = vec![0u8; 65535];
         
         loop {
-            match self.socket.recv_from(&mut buf).await {
+            let received = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => received,
+                Ok(()) = owned.changed() => {
+                    let records = owned.borrow_and_update().clone();
+                    if let Some(records) = records {
+                        self.apply_cluster_ownership(&records);
+                    }
+                    continue;
+                }
+            };
+            match received {
                 Ok((len, addr)) => {
-                    if let Some(packet) = Self::parse_rtp_packet(&buf[..len]) {
-                        self.handle_packet(packet, addr).await;
+                    if let Some(token) = latch::parse_punch(&buf[..len]) {
+                        self.handle_punch(token, addr).await;
+                    } else if tcp::is_rtcp(&buf[..len]) {
+                        if let Some(session) = self.session_manager.source_of_rtcp(&buf[..len], addr) {
+                            self.fanout_engine.handle_source_rtcp(&session, &buf[..len]).await;
+                            continue;
+                        }
+                        self.fanout_engine.handle_rtcp(&buf[..len], addr);
+                        self.rtsp.on_rtcp(addr);
+                        if let Some(relay) = &self.relay {
+                            relay.on_subscriber_feedback(&buf[..len]).await;
+                        }
+                    } else if let Some(packet) = Self::parse_rtp_packet(&buf[..len]) {
+                        self.handle_packet(packet, &buf[..len], addr).await;
                     }
                 }
                 Err(e) => {
@@ -73,7 +585,7 @@ impl RtpFanoutServer {
         }
     }
 
-    fn parse_rtp_packet(data: &[u8]) -> Option<RtpPacket> {
+    pub fn parse_rtp_packet(data: &[u8]) -> Option<RtpPacket> {
         if data.len() < 12 {
             return None;
         }
@@ -87,20 +599,33 @@ impl RtpFanoutServer {
         let extension = (data[0] >> 4) & 0x01;
         let csrc_count = data[0] & 0x0F;
         let marker = ((data[1] >> 7) & 0x01) != 0;
+        let payload_type = data[1] & 0x7F;
         
         let sequence = u16::from_be_bytes([data[2], data[3]]);
         let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
         let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
 
         let header_len = 12 + (csrc_count as usize * 4);
+        if header_len > data.len() {
+            return None;
+        }
         let mut payload_start = header_len;
+        let mut header_extension = None;
 
         if extension != 0 {
             if data.len() < header_len + 4 {
                 return None;
             }
+            let profile = u16::from_be_bytes([data[header_len], data[header_len + 1]]);
             let ext_len = u16::from_be_bytes([data[header_len + 2], data[header_len + 3]]) as usize;
             payload_start += 4 + (ext_len * 4);
+            if payload_start > data.len() {
+                return None;
+            }
+            header_extension = Some(RtpExtension {
+                profile,
+                data: data[header_len + 4..payload_start].to_vec(),
+            });
         }
 
         let mut payload_end = data.len();
@@ -110,6 +635,9 @@ impl RtpFanoutServer {
                 payload_end -= padding_len;
             }
         }
+        if payload_start > payload_end {
+            return None;
+        }
 
         Some(RtpPacket {
             payload: data[payload_start..payload_end].to_vec(),
@@ -117,14 +645,32 @@ impl RtpFanoutServer {
             sequence,
             ssrc,
             marker,
+            payload_type,
+            extension: header_extension,
+            source: None,
+            received_at: Instant::now(),
+            raw: None,
         })
     }
 
-    async fn handle_packet(&self, packet: RtpPacket, addr: SocketAddr) {
+    async fn handle_punch(&self, token: latch::LatchToken, addr: SocketAddr) {
+        match self.session_manager.latch_subscriber(&token, addr) {
+            Some(session_id) => {
+                debug!("Latched {} to session {}", addr, session_id.0);
+                if let Err(e) = self.socket.send_to(&latch::build_punch(token), addr).await {
+                    warn!("Failed to acknowledge punch from {}: {}", addr, e);
+                }
+            }
+            None => {
+                debug!("Ignoring punch with unknown token from {}", addr);
+            }
+        }
+    }
+
+    async fn handle_packet(&self, packet: RtpPacket, data: &[u8], addr: SocketAddr) {
         debug!("Received RTP packet from {}: ssrc={}, seq={}, ts={}", 
                addr, packet.ssrc, packet.sequence, packet.timestamp);
         
-        self.packet_queue.push(packet);
-        self.fanout_engine.process_batch().await;
+        self.fanout_engine.ingest(packet.with_source(addr), data).await;
     }
 }
@@ -5,7 +5,7 @@ use tracing::{info, error};
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_json()
+        .json()
         .init();
 
     let config = ServerConfig::from_env()?;
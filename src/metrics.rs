@@ -1,6 +1,4 @@
 use metrics::{counter, gauge, histogram};
-use std::time::Instant;
-use tracing::info;
 
 pub struct MetricsCollector;
 
@@ -21,6 +19,46 @@ impl MetricsCollector {
         counter!("rtp_packets_sent_total").increment(subscriber_count as u64);
     }
 
+    pub fn record_tcp_egress_drop() {
+        counter!("rtp_tcp_egress_dropped_total").increment(1);
+    }
+
+    pub fn record_port_pool_exhausted() {
+        counter!("ingest_port_pool_exhausted_total").increment(1);
+    }
+
+    pub fn update_ingest_ports_in_use(count: usize) {
+        gauge!("ingest_ports_in_use").set(count as f64);
+    }
+
+    pub fn record_policed(scope: &'static str, action: &'static str) {
+        counter!("rtp_policed_packets_total", "scope" => scope, "action" => action).increment(1);
+    }
+
+    pub fn record_reorder_discard(count: u64) {
+        counter!("rtp_reorder_discarded_total").increment(count);
+    }
+
+    pub fn record_recording_drop() {
+        counter!("recording_dropped_packets_total").increment(1);
+    }
+
+    pub fn record_video_freeze() {
+        counter!("video_freezes_total").increment(1);
+    }
+
+    pub fn update_frozen_streams(count: usize) {
+        gauge!("video_frozen_streams").set(count as f64);
+    }
+
+    pub fn record_pacer_drop() {
+        counter!("rtp_pacer_dropped_total").increment(1);
+    }
+
+    pub fn record_simulcast_switch() {
+        counter!("simulcast_layer_switches_total").increment(1);
+    }
+
     pub fn record_fanout_latency(latency_ms: f64) {
         histogram!("fanout_latency_ms").record(latency_ms);
     }
@@ -0,0 +1,177 @@
+use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{info, debug, error};
+
+use crate::config::ServerConfig;
+use crate::fanout::FanoutEngine;
+use crate::session::Session;
+use crate::tcp;
+use crate::RtpFanoutServer;
+
+// Pause after a failed receive so a persistent socket error doesn't spin.
+pub(crate) const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+// Receive errors that mean the socket itself is unusable. Anything else,
+// e.g. a reset caused by an ICMP error, is retried after the backoff.
+pub(crate) fn is_fatal_recv_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::NotConnected | ErrorKind::Unsupported)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MulticastOptions {
+    pub ttl: u32,
+    // IPv4: address of the local interface. IPv6: interface index.
+    pub interface: Option<String>,
+}
+
+impl MulticastOptions {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            ttl: config.multicast_ttl,
+            interface: Some(config.multicast_interface.clone()).filter(|i| !i.is_empty()),
+        }
+    }
+
+    fn interface_v4(&self) -> std::io::Result<Ipv4Addr> {
+        match &self.interface {
+            Some(iface) => iface.parse().map_err(|_| invalid_interface(iface)),
+            None => Ok(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+
+    fn interface_v6(&self) -> std::io::Result<u32> {
+        match &self.interface {
+            Some(iface) => iface.parse().map_err(|_| invalid_interface(iface)),
+            None => Ok(0),
+        }
+    }
+}
+
+fn invalid_interface(iface: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid multicast interface {}", iface),
+    )
+}
+
+pub fn egress_socket(group: &SocketAddr, options: &MulticastOptions) -> std::io::Result<UdpSocket> {
+    let socket = match group.ip() {
+        IpAddr::V4(_) => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_multicast_ttl_v4(options.ttl)?;
+            socket.set_multicast_if_v4(&options.interface_v4()?)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+            socket
+        }
+        IpAddr::V6(_) => {
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_multicast_hops_v6(options.ttl)?;
+            socket.set_multicast_if_v6(options.interface_v6()?)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+            socket
+        }
+    };
+
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Bound to the group address itself so unicast or other groups' traffic to
+// the same port isn't picked up.
+pub fn ingest_socket(group: &SocketAddr, options: &MulticastOptions) -> std::io::Result<UdpSocket> {
+    let socket = match group.ip() {
+        IpAddr::V4(addr) => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&(*group).into())?;
+            socket.join_multicast_v4(&addr, &options.interface_v4()?)?;
+            socket
+        }
+        IpAddr::V6(addr) => {
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            socket.set_only_v6(true)?;
+            socket.bind(&(*group).into())?;
+            socket.join_multicast_v6(&addr, options.interface_v6()?)?;
+            socket
+        }
+    };
+
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Joins `group` (IGMP/MLD is handled by the kernel) and fans every RTP
+// packet received on it out to `session`.
+pub fn spawn_ingest(
+    session: Arc<Session>,
+    group: SocketAddr,
+    options: &MulticastOptions,
+    fanout_engine: Arc<FanoutEngine>,
+) -> std::io::Result<JoinHandle<()>> {
+    if !group.ip().is_multicast() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a multicast group", group),
+        ));
+    }
+
+    let socket = ingest_socket(&group, options)?;
+    info!("Joined multicast group {} for ingest", group);
+
+    Ok(tokio::spawn(async move {
+        let mut buf = vec![0u8; 65535];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((len, _)) if tcp::is_rtcp(&buf[..len]) => {
+                    if tcp::is_sender_report(&buf[..len]) {
+                        fanout_engine.handle_source_rtcp(&session, &buf[..len]).await;
+                    }
+                }
+                Ok((len, addr)) => match RtpFanoutServer::parse_rtp_packet(&buf[..len]) {
+                    Some(packet) => {
+                        fanout_engine.ingest_to_session(&session, packet.with_source(addr), &buf[..len]).await
+                    }
+                    None => debug!("Discarding non-RTP datagram from {} on {}", addr, group),
+                },
+                Err(e) if is_fatal_recv_error(&e) => {
+                    error!("Multicast receive on {} failed, leaving the group: {}", group, e);
+                    break;
+                }
+                Err(e) => {
+                    error!("Multicast receive error on {}: {}", group, e);
+                    tokio::time::sleep(RECV_ERROR_BACKOFF).await;
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_from_config() {
+        let mut config = ServerConfig::default();
+        assert_eq!(MulticastOptions::from_config(&config).interface, None);
+
+        config.multicast_interface = "192.168.1.10".to_string();
+        let options = MulticastOptions::from_config(&config);
+        assert_eq!(options.interface_v4().unwrap(), Ipv4Addr::new(192, 168, 1, 10));
+        assert!(options.interface_v6().is_err());
+    }
+
+    #[test]
+    fn test_fatal_recv_errors() {
+        use std::io::{Error, ErrorKind};
+        assert!(!is_fatal_recv_error(&Error::from(ErrorKind::ConnectionReset)));
+        assert!(!is_fatal_recv_error(&Error::from(ErrorKind::Interrupted)));
+        assert!(is_fatal_recv_error(&Error::from(ErrorKind::NotConnected)));
+    }
+}
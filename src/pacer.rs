@@ -0,0 +1,172 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::bwe::CongestionController;
+use crate::config::ServerConfig;
+
+// A pacer whose queue stayed empty this long stops; the next packet for the
+// subscriber starts a new one.
+const PACER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacingRate {
+    Fixed(u64),
+    // Multiple of the subscriber's bandwidth estimate.
+    BandwidthMultiple(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacingConfig {
+    pub rate: PacingRate,
+    // Used in bandwidth mode until an estimate exists.
+    pub fallback_bps: u64,
+    pub queue_depth: usize,
+}
+
+impl PacingConfig {
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        let rate = match config.pacing_mode.as_str() {
+            "fixed" => PacingRate::Fixed(config.pacing_rate_bps),
+            "bwe" => PacingRate::BandwidthMultiple(config.pacing_bwe_multiplier),
+            "off" | "" => return None,
+            other => {
+                warn!("Unknown pacing_mode {}, pacing disabled", other);
+                return None;
+            }
+        };
+
+        Some(Self {
+            rate,
+            fallback_bps: config.pacing_rate_bps,
+            queue_depth: config.pacing_queue_depth,
+        })
+    }
+
+    pub fn rate_for(&self, estimate_bps: Option<u64>) -> u64 {
+        match (self.rate, estimate_bps) {
+            (PacingRate::Fixed(bps), _) => bps,
+            (PacingRate::BandwidthMultiple(multiple), Some(estimate)) => (estimate as f64 * multiple) as u64,
+            (PacingRate::BandwidthMultiple(_), None) => self.fallback_bps,
+        }
+    }
+}
+
+// Leaky bucket: each packet may leave once the previous ones have drained
+// at the pacing rate.
+#[derive(Debug)]
+pub struct LeakyBucket {
+    next_send: Instant,
+}
+
+impl LeakyBucket {
+    pub fn new(now: Instant) -> Self {
+        Self { next_send: now }
+    }
+
+    pub fn schedule(&mut self, now: Instant, bytes: usize, rate_bps: u64) -> Instant {
+        let send_at = self.next_send.max(now);
+        let drain = Duration::from_secs_f64(bytes as f64 * 8.0 / rate_bps.max(1) as f64);
+        self.next_send = send_at + drain;
+        send_at
+    }
+}
+
+// Transport-wide sequence number of a queued packet and the controller that
+// learns when it actually left, so queueing delay isn't read as network delay.
+pub struct TransportStamp {
+    pub seq: u16,
+    pub controller: Arc<Mutex<CongestionController>>,
+}
+
+type QueuedPacket = (Vec<u8>, Option<TransportStamp>);
+
+// Per-subscriber egress queue drained by its own task at the pacing rate.
+pub struct Pacer {
+    tx: mpsc::Sender<QueuedPacket>,
+    rate_bps: Arc<AtomicU64>,
+}
+
+impl Pacer {
+    pub fn spawn(socket: Arc<UdpSocket>, addr: SocketAddr, rate_bps: u64, queue_depth: usize) -> Self {
+        let (tx, rx) = mpsc::channel(queue_depth.max(1));
+        let rate = Arc::new(AtomicU64::new(rate_bps));
+        tokio::spawn(Self::run(socket, addr, rx, rate.clone()));
+        Self { tx, rate_bps: rate }
+    }
+
+    pub fn set_rate(&self, rate_bps: u64) {
+        self.rate_bps.store(rate_bps, Ordering::Relaxed);
+    }
+
+    // False when the queue is full and the packet was dropped.
+    pub fn enqueue(&self, data: Vec<u8>, stamp: Option<TransportStamp>) -> bool {
+        self.tx.try_send((data, stamp)).is_ok()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    async fn run(socket: Arc<UdpSocket>, addr: SocketAddr, mut rx: mpsc::Receiver<QueuedPacket>, rate: Arc<AtomicU64>) {
+        let mut bucket = LeakyBucket::new(Instant::now());
+
+        while let Ok(Some((data, stamp))) = tokio::time::timeout(PACER_IDLE_TIMEOUT, rx.recv()).await {
+            let send_at = bucket.schedule(Instant::now(), data.len(), rate.load(Ordering::Relaxed));
+            tokio::time::sleep_until(send_at.into()).await;
+            match socket.send_to(&data, addr).await {
+                Ok(_) => {
+                    if let Some(stamp) = stamp {
+                        stamp.controller.lock().on_sent(stamp.seq, Instant::now());
+                    }
+                }
+                Err(e) => warn!("Failed to send packet to {}: {}", addr, e),
+            }
+        }
+        debug!("Pacer for {} stopped", addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaky_bucket_spreads_burst() {
+        let start = Instant::now();
+        let mut bucket = LeakyBucket::new(start);
+
+        // 1250-byte packets at 1 Mbps leave 10ms apart
+        let times: Vec<Duration> = (0..3)
+            .map(|_| bucket.schedule(start, 1250, 1_000_000) - start)
+            .collect();
+        assert_eq!(times, vec![Duration::ZERO, Duration::from_millis(10), Duration::from_millis(20)]);
+
+        // After an idle gap the bucket doesn't let a backlog build up
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.schedule(later, 1250, 1_000_000), later);
+    }
+
+    #[test]
+    fn test_rate_for_bandwidth_multiple() {
+        let config = PacingConfig {
+            rate: PacingRate::BandwidthMultiple(2.5),
+            fallback_bps: 4_000_000,
+            queue_depth: 16,
+        };
+        assert_eq!(config.rate_for(Some(1_000_000)), 2_500_000);
+        assert_eq!(config.rate_for(None), 4_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_zero_queue_depth_is_clamped() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        Pacer::spawn(socket, addr, 1_000_000, 0);
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::ServerConfig;
+
+// Limit events are reported at most this often per policer.
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicingScope {
+    Source,
+    Session,
+}
+
+impl PolicingScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicingScope::Source => "source",
+            PolicingScope::Session => "session",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicingAction {
+    // Discard non-conforming packets.
+    Drop,
+    // Forward non-conforming packets, counted and reported as such.
+    Mark,
+    // Stop forwarding the session for `policing_suspend_secs`.
+    Suspend,
+}
+
+impl PolicingAction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "drop" => Some(PolicingAction::Drop),
+            "mark" => Some(PolicingAction::Mark),
+            "suspend" => Some(PolicingAction::Suspend),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicingAction::Drop => "drop",
+            PolicingAction::Mark => "mark",
+            PolicingAction::Suspend => "suspend",
+        }
+    }
+}
+
+// Packet and byte limits; 0 means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub packets_per_sec: u64,
+    pub bits_per_sec: u64,
+    // Bucket depth, in seconds of the rate.
+    pub burst_secs: f64,
+}
+
+impl RateLimit {
+    pub fn source(config: &ServerConfig) -> Self {
+        Self {
+            packets_per_sec: config.source_rate_limit_pps,
+            bits_per_sec: config.source_rate_limit_bps,
+            burst_secs: config.policing_burst_secs,
+        }
+    }
+
+    pub fn session(config: &ServerConfig) -> Self {
+        Self {
+            packets_per_sec: config.session_rate_limit_pps,
+            bits_per_sec: config.session_rate_limit_bps,
+            burst_secs: config.policing_burst_secs,
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.packets_per_sec == 0 && self.bits_per_sec == 0
+    }
+}
+
+pub fn action_from_config(config: &ServerConfig) -> PolicingAction {
+    PolicingAction::parse(&config.policing_action).unwrap_or_else(|| {
+        warn!("Unknown policing_action {}, dropping instead", config.policing_action);
+        PolicingAction::Drop
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolicingConfig {
+    pub source: RateLimit,
+    pub action: PolicingAction,
+    pub suspend: Duration,
+}
+
+impl PolicingConfig {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            source: RateLimit::source(config),
+            action: action_from_config(config),
+            suspend: Duration::from_secs(config.policing_suspend_secs),
+        }
+    }
+}
+
+impl Default for PolicingConfig {
+    fn default() -> Self {
+        Self {
+            source: RateLimit { packets_per_sec: 0, bits_per_sec: 0, burst_secs: 1.0 },
+            action: PolicingAction::Drop,
+            suspend: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate_per_sec: f64, burst_secs: f64, now: Instant) -> Self {
+        let capacity = (rate_per_sec * burst_secs).max(1.0);
+        Self {
+            capacity,
+            tokens: capacity,
+            rate: rate_per_sec,
+            last_refill: now,
+        }
+    }
+
+    pub fn try_consume(&mut self, amount: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Policer {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    last_event: Option<Instant>,
+    pub last_seen: Instant,
+    // Under the suspend action: the source's session has been dealt with
+    // until then, so it isn't looked up again for every excess packet.
+    pub suspended_until: Option<Instant>,
+}
+
+impl Policer {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        let bucket = |rate: u64| (rate > 0).then(|| TokenBucket::new(rate as f64, limit.burst_secs, now));
+        Self {
+            packets: bucket(limit.packets_per_sec),
+            bytes: bucket(limit.bits_per_sec / 8),
+            last_event: None,
+            last_seen: now,
+            suspended_until: None,
+        }
+    }
+
+    // True when the packet is within the limits. Both buckets are charged
+    // so that one limit can't be dodged by staying under the other.
+    pub fn conforms(&mut self, bytes: usize, now: Instant) -> bool {
+        self.last_seen = now;
+        let packets_ok = self.packets.as_mut().is_none_or(|b| b.try_consume(1.0, now));
+        let bytes_ok = self.bytes.as_mut().is_none_or(|b| b.try_consume(bytes as f64, now));
+        packets_ok && bytes_ok
+    }
+
+    // True once per suspension: when the policer isn't already in one.
+    pub fn start_suspension(&mut self, duration: Duration, now: Instant) -> bool {
+        if self.suspended_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        self.suspended_until = Some(now + duration);
+        true
+    }
+
+    // Rate-limits event reporting for this policer.
+    pub fn should_report(&mut self, now: Instant) -> bool {
+        match self.last_event {
+            Some(last) if now.saturating_duration_since(last) < EVENT_INTERVAL => false,
+            _ => {
+                self.last_event = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policer_packet_limit() {
+        let start = Instant::now();
+        let limit = RateLimit { packets_per_sec: 100, bits_per_sec: 0, burst_secs: 0.1 };
+        let mut policer = Policer::new(limit, start);
+
+        let conforming = (0..20).filter(|_| policer.conforms(1200, start)).count();
+        assert_eq!(conforming, 10);
+
+        // 50ms refills five tokens
+        let later = start + Duration::from_millis(50);
+        let conforming = (0..20).filter(|_| policer.conforms(1200, later)).count();
+        assert_eq!(conforming, 5);
+    }
+
+    #[test]
+    fn test_policer_byte_limit() {
+        let start = Instant::now();
+        let limit = RateLimit { packets_per_sec: 0, bits_per_sec: 80_000, burst_secs: 1.0 };
+        let mut policer = Policer::new(limit, start);
+
+        assert!(policer.conforms(6000, start));
+        assert!(policer.conforms(4000, start));
+        assert!(!policer.conforms(1, start));
+        assert!(policer.should_report(start));
+        assert!(!policer.should_report(start));
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use parking_lot::Mutex;
+use tracing::warn;
+
+use crate::metrics::MetricsCollector;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IngestPorts {
+    pub rtp: u16,
+    pub rtcp: Option<u16>,
+}
+
+// Hands out ingest ports from a fixed range. In pair mode every allocation
+// is an even RTP port plus the following odd port for RTCP.
+pub struct PortAllocator {
+    free: Mutex<VecDeque<u16>>,
+    pairs: bool,
+    capacity: usize,
+}
+
+impl PortAllocator {
+    pub fn new(start: u16, end: u16, pairs: bool) -> Self {
+        let free: VecDeque<u16> = if pairs {
+            // An odd 65535 has no even port (or RTCP port) after it.
+            match start.checked_add(start % 2) {
+                Some(first) => (first..end).step_by(2).collect(),
+                None => VecDeque::new(),
+            }
+        } else {
+            (start..=end).collect()
+        };
+        let capacity = free.len();
+
+        Self {
+            free: Mutex::new(free),
+            pairs,
+            capacity,
+        }
+    }
+
+    pub fn allocate(&self) -> Option<IngestPorts> {
+        let mut free = self.free.lock();
+        let Some(rtp) = free.pop_front() else {
+            warn!("Ingest port pool exhausted ({} allocations)", self.capacity);
+            MetricsCollector::record_port_pool_exhausted();
+            return None;
+        };
+        MetricsCollector::update_ingest_ports_in_use(self.capacity - free.len());
+
+        Some(IngestPorts {
+            rtp,
+            rtcp: self.pairs.then_some(rtp + 1),
+        })
+    }
+
+    pub fn release(&self, ports: IngestPorts) {
+        let mut free = self.free.lock();
+        free.push_back(ports.rtp);
+        MetricsCollector::update_ingest_ports_in_use(self.capacity - free.len());
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.lock().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pair_allocation() {
+        let allocator = PortAllocator::new(20001, 20006, true);
+        assert_eq!(allocator.capacity(), 2);
+
+        let first = allocator.allocate().unwrap();
+        assert_eq!(first, IngestPorts { rtp: 20002, rtcp: Some(20003) });
+        let second = allocator.allocate().unwrap();
+        assert_eq!(second.rtp, 20004);
+        assert!(allocator.allocate().is_none());
+
+        allocator.release(first);
+        assert_eq!(allocator.allocate(), Some(first));
+    }
+
+    #[test]
+    fn test_single_port_allocation() {
+        let allocator = PortAllocator::new(30000, 30002, false);
+        assert_eq!(allocator.capacity(), 3);
+        assert_eq!(allocator.allocate().unwrap().rtcp, None);
+    }
+
+    #[test]
+    fn test_range_at_top_of_port_space() {
+        assert_eq!(PortAllocator::new(65535, 65535, true).capacity(), 0);
+        assert_eq!(PortAllocator::new(65534, 65535, true).allocate(), Some(IngestPorts { rtp: 65534, rtcp: Some(65535) }));
+        assert_eq!(PortAllocator::new(65535, 65535, false).capacity(), 1);
+    }
+}
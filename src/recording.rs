@@ -0,0 +1,494 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::ServerConfig;
+use crate::container::{ContainerFormat, ContainerWriter, PayloadFormat};
+use crate::metrics::MetricsCollector;
+use crate::session::SessionId;
+use crate::RtpPacket;
+
+const PCAPNG_LINKTYPE_RAW: u16 = 101;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingFormat {
+    // rtptools `rtpdump -F dump`, playable with rtpplay.
+    Rtpdump,
+    // Synthesized IP/UDP packets; decode as RTP in Wireshark.
+    Pcapng,
+    // Depacketized H.264/H.265/Opus in a fragmented MP4 or Matroska file.
+    Mp4,
+    Matroska,
+}
+
+impl RecordingFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "rtpdump" => Some(RecordingFormat::Rtpdump),
+            "pcapng" | "pcap" => Some(RecordingFormat::Pcapng),
+            "mp4" | "fmp4" => Some(RecordingFormat::Mp4),
+            "mkv" | "matroska" => Some(RecordingFormat::Matroska),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Rtpdump => "rtpdump",
+            RecordingFormat::Pcapng => "pcapng",
+            RecordingFormat::Mp4 => "mp4",
+            RecordingFormat::Matroska => "mkv",
+        }
+    }
+
+    pub fn is_container(&self) -> bool {
+        matches!(self, RecordingFormat::Mp4 | RecordingFormat::Matroska)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingOptions {
+    pub format: RecordingFormat,
+    pub directory: PathBuf,
+    // A new file is started when either limit is reached; 0 disables it.
+    pub max_file_bytes: u64,
+    pub max_file_duration: Duration,
+    pub queue_depth: usize,
+    // What the container formats depacketize; empty assumes H.264.
+    pub payload_formats: Vec<PayloadFormat>,
+}
+
+impl RecordingOptions {
+    pub fn from_config(config: &ServerConfig, format: RecordingFormat) -> Self {
+        Self {
+            format,
+            directory: PathBuf::from(&config.recording_directory),
+            max_file_bytes: config.recording_max_file_bytes,
+            max_file_duration: Duration::from_secs(config.recording_max_file_secs),
+            queue_depth: config.recording_queue_depth,
+            payload_formats: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordedPacket {
+    pub data: Arc<[u8]>,
+    pub arrival: SystemTime,
+    pub source: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default)]
+pub struct RecordingStats {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    // Packets lost because the writer fell behind.
+    pub dropped: AtomicU64,
+    pub files: Mutex<Vec<PathBuf>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordingStatus {
+    pub format: RecordingFormat,
+    pub started_at: SystemTime,
+    pub packets: u64,
+    pub bytes: u64,
+    pub dropped: u64,
+    pub files: Vec<PathBuf>,
+}
+
+pub trait PacketWriter: Send {
+    fn write_packet(&mut self, packet: &RecordedPacket) -> io::Result<usize>;
+    // Whether a rotation may put this packet first in the next file.
+    fn can_split_before(&self, _packet: &RecordedPacket) -> bool {
+        true
+    }
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+// rtptools dump format: a text line, a binary file header, then each packet
+// behind an 8-byte header with its offset from the start in milliseconds.
+pub struct RtpdumpWriter<W: Write> {
+    out: W,
+    start: SystemTime,
+}
+
+impl<W: Write> RtpdumpWriter<W> {
+    pub fn new(mut out: W, start: SystemTime, source: SocketAddr) -> io::Result<Self> {
+        writeln!(out, "#!rtpplay1.0 {}/{}", source.ip(), source.port())?;
+        let since_epoch = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        out.write_all(&(since_epoch.as_secs() as u32).to_be_bytes())?;
+        out.write_all(&since_epoch.subsec_micros().to_be_bytes())?;
+        let address = match source.ip() {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => 0,
+        };
+        out.write_all(&address.to_be_bytes())?;
+        out.write_all(&source.port().to_be_bytes())?;
+        out.write_all(&[0, 0])?;
+        Ok(Self { out, start })
+    }
+}
+
+impl<W: Write + Send> PacketWriter for RtpdumpWriter<W> {
+    fn write_packet(&mut self, packet: &RecordedPacket) -> io::Result<usize> {
+        let offset = packet.arrival.duration_since(self.start).unwrap_or_default().as_millis() as u32;
+        self.out.write_all(&((packet.data.len() + 8) as u16).to_be_bytes())?;
+        self.out.write_all(&(packet.data.len() as u16).to_be_bytes())?;
+        self.out.write_all(&offset.to_be_bytes())?;
+        self.out.write_all(&packet.data)?;
+        Ok(packet.data.len() + 8)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// pcapng with one raw-IP interface. Each packet is wrapped in IP and UDP
+// headers from its source to the server's ingest address.
+pub struct PcapngWriter<W: Write> {
+    out: W,
+    local: SocketAddr,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(mut out: W, local: SocketAddr) -> io::Result<Self> {
+        // Section header block
+        out.write_all(&0x0A0D_0D0Au32.to_le_bytes())?;
+        out.write_all(&28u32.to_le_bytes())?;
+        out.write_all(&0x1A2B_3C4Du32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(&(-1i64).to_le_bytes())?;
+        out.write_all(&28u32.to_le_bytes())?;
+
+        // Interface description block; microsecond timestamps by default
+        out.write_all(&1u32.to_le_bytes())?;
+        out.write_all(&20u32.to_le_bytes())?;
+        out.write_all(&PCAPNG_LINKTYPE_RAW.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&20u32.to_le_bytes())?;
+        Ok(Self { out, local })
+    }
+}
+
+impl<W: Write + Send> PacketWriter for PcapngWriter<W> {
+    fn write_packet(&mut self, packet: &RecordedPacket) -> io::Result<usize> {
+        let datagram = udp_datagram(packet.source, self.local, &packet.data);
+        let padding = (4 - datagram.len() % 4) % 4;
+        let total = 32 + datagram.len() + padding;
+        let micros = packet.arrival.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        self.out.write_all(&6u32.to_le_bytes())?;
+        self.out.write_all(&(total as u32).to_le_bytes())?;
+        self.out.write_all(&0u32.to_le_bytes())?;
+        self.out.write_all(&((micros >> 32) as u32).to_le_bytes())?;
+        self.out.write_all(&(micros as u32).to_le_bytes())?;
+        self.out.write_all(&(datagram.len() as u32).to_le_bytes())?;
+        self.out.write_all(&(datagram.len() as u32).to_le_bytes())?;
+        self.out.write_all(&datagram)?;
+        self.out.write_all(&[0; 3][..padding])?;
+        self.out.write_all(&(total as u32).to_le_bytes())?;
+        Ok(total)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn udp_datagram(source: Option<SocketAddr>, local: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let source = source.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    let udp_len = 8 + payload.len();
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&local.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    match source.ip() {
+        IpAddr::V4(src) => {
+            let dst = match local.ip() {
+                IpAddr::V4(dst) => dst,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let sum = checksum(&header);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            // The UDP checksum is optional over IPv4.
+            header.extend_from_slice(&udp);
+            header
+        }
+        IpAddr::V6(src) => {
+            let dst = match local.ip() {
+                IpAddr::V6(dst) => dst,
+                IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+            };
+            let mut pseudo = Vec::with_capacity(40 + udp_len);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 17]);
+            pseudo.extend_from_slice(&udp);
+            let sum = match checksum(&pseudo) {
+                0 => 0xFFFF,
+                sum => sum,
+            };
+            udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            header.extend_from_slice(&[17, 64]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            header.extend_from_slice(&udp);
+            header
+        }
+    }
+}
+
+// Recording of one session. Packets are queued to a blocking writer task so
+// the fanout path never waits on the disk.
+#[derive(Debug)]
+pub struct Recorder {
+    tx: mpsc::Sender<RecordedPacket>,
+    stats: Arc<RecordingStats>,
+    format: RecordingFormat,
+    started_at: SystemTime,
+    task: JoinHandle<()>,
+}
+
+impl Recorder {
+    pub fn start(
+        session_id: SessionId,
+        source: SocketAddr,
+        local: SocketAddr,
+        options: RecordingOptions,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&options.directory)?;
+        let (tx, rx) = mpsc::channel(options.queue_depth.max(1));
+        let stats = Arc::new(RecordingStats::default());
+        let started_at = SystemTime::now();
+        let format = options.format;
+
+        let writer = FileRotator {
+            session_id,
+            source,
+            local,
+            options,
+            started_at,
+            stats: stats.clone(),
+        };
+        let task = tokio::task::spawn_blocking(move || writer.run(rx));
+        info!("Recording session {} as {}", session_id.0, format.extension());
+
+        Ok(Self { tx, stats, format, started_at, task })
+    }
+
+    pub fn record(&self, packet: &RtpPacket, data: Arc<[u8]>) {
+        let arrival = SystemTime::now()
+            .checked_sub(packet.received_at.elapsed())
+            .unwrap_or_else(SystemTime::now);
+        let recorded = RecordedPacket { data, arrival, source: packet.source };
+        if self.tx.try_send(recorded).is_err() {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            MetricsCollector::record_recording_drop();
+        }
+    }
+
+    // Containers are depacketized, so they need the stream in sequence order.
+    pub fn wants_reordered(&self) -> bool {
+        self.format.is_container()
+    }
+
+    pub fn status(&self) -> RecordingStatus {
+        RecordingStatus {
+            format: self.format,
+            started_at: self.started_at,
+            packets: self.stats.packets.load(Ordering::Relaxed),
+            bytes: self.stats.bytes.load(Ordering::Relaxed),
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+            files: self.stats.files.lock().clone(),
+        }
+    }
+
+    // Waits for queued packets to reach the disk.
+    pub async fn stop(self) -> RecordingStatus {
+        let Recorder { tx, stats, format, started_at, task } = self;
+        drop(tx);
+        if let Err(e) = task.await {
+            warn!("Recording writer failed: {}", e);
+        }
+        let files = stats.files.lock().clone();
+        RecordingStatus {
+            format,
+            started_at,
+            packets: stats.packets.load(Ordering::Relaxed),
+            bytes: stats.bytes.load(Ordering::Relaxed),
+            dropped: stats.dropped.load(Ordering::Relaxed),
+            files,
+        }
+    }
+}
+
+struct FileRotator {
+    session_id: SessionId,
+    source: SocketAddr,
+    local: SocketAddr,
+    options: RecordingOptions,
+    started_at: SystemTime,
+    stats: Arc<RecordingStats>,
+}
+
+impl FileRotator {
+    fn run(self, mut rx: mpsc::Receiver<RecordedPacket>) {
+        let mut current: Option<(Box<dyn PacketWriter>, Instant, u64)> = None;
+
+        while let Some(packet) = rx.blocking_recv() {
+            let rotate = current.as_ref().is_some_and(|(writer, opened, bytes)| {
+                ((self.options.max_file_bytes > 0 && *bytes >= self.options.max_file_bytes)
+                    || (!self.options.max_file_duration.is_zero()
+                        && opened.elapsed() >= self.options.max_file_duration))
+                    && writer.can_split_before(&packet)
+            });
+            if rotate {
+                if let Some((mut writer, _, _)) = current.take() {
+                    if let Err(e) = writer.finish() {
+                        warn!("Failed to finish recording file for session {}: {}", self.session_id.0, e);
+                    }
+                }
+            }
+
+            if current.is_none() {
+                match self.open(packet.arrival) {
+                    Ok(writer) => current = Some((writer, Instant::now(), 0)),
+                    Err(e) => {
+                        warn!("Stopping recording of session {}: {}", self.session_id.0, e);
+                        return;
+                    }
+                }
+            }
+
+            let Some((writer, _, bytes)) = current.as_mut() else {
+                continue;
+            };
+            match writer.write_packet(&packet) {
+                Ok(written) => {
+                    *bytes += written as u64;
+                    self.stats.packets.fetch_add(1, Ordering::Relaxed);
+                    self.stats.bytes.fetch_add(written as u64, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    warn!("Stopping recording of session {}: {}", self.session_id.0, e);
+                    return;
+                }
+            }
+        }
+
+        if let Some((mut writer, _, _)) = current {
+            if let Err(e) = writer.finish() {
+                warn!("Failed to finish recording file for session {}: {}", self.session_id.0, e);
+            }
+        }
+    }
+
+    fn open(&self, start: SystemTime) -> io::Result<Box<dyn PacketWriter>> {
+        let index = self.stats.files.lock().len();
+        let started = self.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let path = self.options.directory.join(format!(
+            "{}-{}-{:04}.{}",
+            self.session_id.0,
+            started,
+            index,
+            self.options.format.extension()
+        ));
+        let out = BufWriter::new(File::create(&path)?);
+        let writer: Box<dyn PacketWriter> = match self.options.format {
+            RecordingFormat::Rtpdump => Box::new(RtpdumpWriter::new(out, start, self.source)?),
+            RecordingFormat::Pcapng => Box::new(PcapngWriter::new(out, self.local)?),
+            RecordingFormat::Mp4 => {
+                Box::new(ContainerWriter::new(out, ContainerFormat::Mp4, self.options.payload_formats.clone()))
+            }
+            RecordingFormat::Matroska => {
+                Box::new(ContainerWriter::new(out, ContainerFormat::Matroska, self.options.payload_formats.clone()))
+            }
+        };
+        info!("Recording session {} to {}", self.session_id.0, path.display());
+        self.stats.files.lock().push(path);
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(data: &[u8], arrival: SystemTime) -> RecordedPacket {
+        RecordedPacket {
+            data: data.into(),
+            arrival,
+            source: Some("192.168.1.100:5004".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_rtpdump_layout() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut out = Vec::new();
+        let mut writer = RtpdumpWriter::new(&mut out, start, "192.168.1.100:5004".parse().unwrap()).unwrap();
+        writer.write_packet(&recorded(&[0x80; 12], start + Duration::from_millis(1500))).unwrap();
+        writer.finish().unwrap();
+
+        let header = b"#!rtpplay1.0 192.168.1.100/5004\n";
+        assert!(out.starts_with(header));
+        let binary = &out[header.len()..];
+        assert_eq!(&binary[0..4], &1000u32.to_be_bytes());
+        assert_eq!(&binary[8..12], &[192, 168, 1, 100]);
+        assert_eq!(&binary[16..24], &[0, 20, 0, 12, 0, 0, 0x05, 0xDC]);
+        assert_eq!(binary.len(), 16 + 8 + 12);
+    }
+
+    #[test]
+    fn test_pcapng_packet_block() {
+        let mut out = Vec::new();
+        let mut writer = PcapngWriter::new(&mut out, "10.0.0.1:5004".parse().unwrap()).unwrap();
+        let written = writer.write_packet(&recorded(&[0x80; 13], UNIX_EPOCH + Duration::from_micros(5))).unwrap();
+
+        // 20 IP + 8 UDP + 13 RTP, padded to 44
+        assert_eq!(written, 32 + 44);
+        let block = &out[48..];
+        assert_eq!(&block[0..4], &6u32.to_le_bytes());
+        assert_eq!(&block[16..20], &5u32.to_le_bytes());
+        assert_eq!(&block[20..24], &41u32.to_le_bytes());
+        let ip = &block[28..48];
+        assert_eq!(ip[9], 17);
+        assert_eq!(checksum(ip), 0);
+        assert_eq!(&block[48..52], &[0x13, 0x8C, 0x13, 0x8C]);
+    }
+}
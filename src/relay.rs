@@ -0,0 +1,504 @@
+// Server-to-server cascading. A downstream server pulls a session from an
+// upstream one by sending JOINs to the upstream's relay port; the upstream
+// then sends it the session's packets behind a tunnel header naming the
+// session and every server the packets have passed through. Subscriber
+// NACK/PLI/FIR for a relayed session travel back the same way. A server never
+// accepts packets that already passed through it, never serves a JOIN from a
+// server upstream of the session, and packets stop after MAX_HOPS servers.
+//
+// A JOIN only takes effect once it echoes a cookie the upstream handed out in
+// a CHALLENGE sent back to the JOIN's source address, so a spoofed JOIN can't
+// point a session's media at a third party. Cookies are stateless: a keyed
+// hash of the requester's address, the session and the current epoch.
+
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::{DashMap, DashSet};
+use parking_lot::RwLock;
+use tokio::net::UdpSocket;
+use tracing::{info, debug, trace, warn};
+use uuid::Uuid;
+
+use crate::config::ServerConfig;
+use crate::fanout::FanoutEngine;
+use crate::session::{Session, SessionId, SessionManager, SubscriberTransport};
+use crate::tcp;
+use crate::RtpFanoutServer;
+
+const RELAY_MAGIC: &[u8; 4] = b"RLAY";
+const HEADER_LEN: usize = 24;
+pub const MAX_HOPS: usize = 8;
+const RELAY_KEEPALIVE: Duration = Duration::from_secs(5);
+// Downstream servers that stop sending JOINs are dropped after this long.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(15);
+// Cookies from the current and the previous epoch are accepted.
+const COOKIE_EPOCH: Duration = Duration::from_secs(60);
+const COOKIE_LEN: usize = 8;
+
+const RTCP_RTPFB: u8 = 205;
+const RTCP_PSFB: u8 = 206;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayKind {
+    Media = 0,
+    Feedback = 1,
+    Join = 2,
+    Leave = 3,
+    Challenge = 4,
+}
+
+impl RelayKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(RelayKind::Media),
+            1 => Some(RelayKind::Feedback),
+            2 => Some(RelayKind::Join),
+            3 => Some(RelayKind::Leave),
+            4 => Some(RelayKind::Challenge),
+            _ => None,
+        }
+    }
+}
+
+// Tunnel header: "RLAY", kind, path length, two reserved bytes, the session
+// id on the upstream server, then the path as 16-byte server ids, origin
+// first. For JOIN, LEAVE and feedback the path is the sending server alone;
+// JOIN and LEAVE carry the cookie as payload. CHALLENGE has an empty path so
+// it is never larger than the JOIN that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayMessage<'a> {
+    pub kind: RelayKind,
+    pub session_id: SessionId,
+    pub path: Vec<Uuid>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RelayMessage<'a> {
+    pub fn header(kind: RelayKind, session_id: SessionId, path: &[Uuid]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + 16 * path.len());
+        out.extend_from_slice(RELAY_MAGIC);
+        out.extend_from_slice(&[kind as u8, path.len() as u8, 0, 0]);
+        out.extend_from_slice(session_id.0.as_bytes());
+        for server in path {
+            out.extend_from_slice(server.as_bytes());
+        }
+        out
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Self::header(self.kind, self.session_id, &self.path);
+        out.extend_from_slice(self.payload);
+        out
+    }
+
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || &data[..4] != RELAY_MAGIC {
+            return None;
+        }
+        let kind = RelayKind::from_u8(data[4])?;
+        let hops = data[5] as usize;
+        let session_id = SessionId(Uuid::from_slice(&data[8..24]).ok()?);
+        let end = HEADER_LEN + 16 * hops;
+        let path = data
+            .get(HEADER_LEN..end)?
+            .chunks_exact(16)
+            .map(|id| Uuid::from_slice(id).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { kind, session_id, path, payload: &data[end..] })
+    }
+}
+
+// True when packets on this path must not be accepted by `server`.
+pub fn is_loop(server: Uuid, path: &[Uuid]) -> bool {
+    path.len() > MAX_HOPS || path.contains(&server)
+}
+
+// Media SSRCs that RTCP NACK, PLI or FIR messages in a compound packet ask
+// the source about.
+pub fn feedback_media_ssrcs(data: &[u8]) -> Vec<u32> {
+    let mut ssrcs = Vec::new();
+    let mut offset = 0;
+    while data.len() >= offset + 4 {
+        let packet = &data[offset..];
+        let length = (u16::from_be_bytes([packet[2], packet[3]]) as usize + 1) * 4;
+        if packet[0] >> 6 != 2 || length > packet.len() {
+            break;
+        }
+        offset += length;
+        if length < 12 {
+            continue;
+        }
+        let media_ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        match (packet[1], packet[0] & 0x1F) {
+            (RTCP_RTPFB, 1) | (RTCP_PSFB, 1) => ssrcs.push(media_ssrc),
+            // FIR names its targets in the FCI entries
+            (RTCP_PSFB, 4) => {
+                ssrcs.extend(packet[12..length].chunks_exact(8).map(|e| u32::from_be_bytes([e[0], e[1], e[2], e[3]])));
+            }
+            _ => {}
+        }
+    }
+    ssrcs
+}
+
+// RFC 4585 Picture Loss Indication asking for a keyframe on `media_ssrc`.
+pub fn build_pli(media_ssrc: u32) -> Vec<u8> {
+    let mut pli = vec![0x81, RTCP_PSFB, 0x00, 0x02];
+    pli.extend_from_slice(&0u32.to_be_bytes());
+    pli.extend_from_slice(&media_ssrc.to_be_bytes());
+    pli
+}
+
+// A local session fed by another server.
+#[derive(Debug)]
+struct Upstream {
+    addr: SocketAddr,
+    session_id: SessionId,
+    // As carried by the last packet received, ending with the upstream server.
+    path: RwLock<Vec<Uuid>>,
+    ssrcs: DashSet<u32>,
+    // Last cookie the upstream challenged us with.
+    cookie: RwLock<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayUpstream {
+    pub addr: SocketAddr,
+    pub session_id: SessionId,
+    pub path: Vec<Uuid>,
+}
+
+pub struct RelayServer {
+    server_id: Uuid,
+    socket: Arc<UdpSocket>,
+    session_manager: Arc<SessionManager>,
+    fanout_engine: Arc<FanoutEngine>,
+    upstreams: DashMap<SessionId, Upstream>,
+    // Upstream session id to the local session relaying it.
+    by_remote: DashMap<SessionId, SessionId>,
+    // Last JOIN from each downstream server, per local session.
+    downstreams: DashMap<(SessionId, SocketAddr), Instant>,
+    cookie_key: RandomState,
+    started: Instant,
+}
+
+impl RelayServer {
+    pub async fn bind(
+        config: &ServerConfig,
+        server_id: Uuid,
+        session_manager: Arc<SessionManager>,
+        fanout_engine: Arc<FanoutEngine>,
+    ) -> anyhow::Result<Self> {
+        let bind_addr: SocketAddr = config.relay_bind_address.parse()?;
+        let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+        info!("Relay listening on {} as server {}", bind_addr, server_id);
+
+        Ok(Self {
+            server_id,
+            socket,
+            session_manager,
+            fanout_engine,
+            upstreams: DashMap::new(),
+            by_remote: DashMap::new(),
+            downstreams: DashMap::new(),
+            cookie_key: RandomState::new(),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn server_id(&self) -> Uuid {
+        self.server_id
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Starts pulling `remote` from the upstream server into `session`.
+    pub async fn attach(&self, session: &Session, upstream: SocketAddr, remote: SessionId) {
+        self.upstreams.insert(
+            session.id,
+            Upstream {
+                addr: upstream,
+                session_id: remote,
+                path: RwLock::new(Vec::new()),
+                ssrcs: DashSet::new(),
+                cookie: RwLock::new(Vec::new()),
+            },
+        );
+        self.by_remote.insert(remote, session.id);
+        // Answered with a CHALLENGE; the cookie goes out with the next JOIN.
+        self.send(RelayKind::Join, remote, &[], upstream).await;
+        info!("Session {} relaying {} from {}", session.id.0, remote.0, upstream);
+    }
+
+    pub fn upstream(&self, session_id: &SessionId) -> Option<RelayUpstream> {
+        self.upstreams.get(session_id).map(|u| RelayUpstream {
+            addr: u.addr,
+            session_id: u.session_id,
+            path: u.path.read().clone(),
+        })
+    }
+
+    // Servers the session's packets came through; empty for local sources.
+    fn path_of(&self, session_id: &SessionId) -> Vec<Uuid> {
+        self.upstreams.get(session_id).map(|u| u.path.read().clone()).unwrap_or_default()
+    }
+
+    fn cookie(&self, session_id: SessionId, addr: SocketAddr, epoch: u64) -> [u8; COOKIE_LEN] {
+        self.cookie_key.hash_one((epoch, session_id.0, addr)).to_be_bytes()
+    }
+
+    fn cookie_epoch(&self) -> u64 {
+        self.started.elapsed().as_secs() / COOKIE_EPOCH.as_secs()
+    }
+
+    fn valid_cookie(&self, session_id: SessionId, addr: SocketAddr, cookie: &[u8]) -> bool {
+        let epoch = self.cookie_epoch();
+        cookie.len() == COOKIE_LEN
+            && (cookie == self.cookie(session_id, addr, epoch)
+                || (epoch > 0 && cookie == self.cookie(session_id, addr, epoch - 1)))
+    }
+
+    async fn send(&self, kind: RelayKind, session_id: SessionId, payload: &[u8], addr: SocketAddr) {
+        let message = RelayMessage { kind, session_id, path: vec![self.server_id], payload };
+        if let Err(e) = self.socket.send_to(&message.encode(), addr).await {
+            warn!("Failed to send relay {:?} to {}: {}", kind, addr, e);
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        tokio::spawn(self.clone().run_maintenance());
+
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).await?;
+            match RelayMessage::parse(&buf[..len]) {
+                Some(message) => self.handle(message, addr).await,
+                None => debug!("Discarding non-relay datagram from {}", addr),
+            }
+        }
+    }
+
+    async fn handle(&self, message: RelayMessage<'_>, addr: SocketAddr) {
+        match message.kind {
+            RelayKind::Media => self.on_media(message, addr).await,
+            RelayKind::Join => self.on_join(message, addr).await,
+            RelayKind::Leave => {
+                if !self.valid_cookie(message.session_id, addr, message.payload) {
+                    debug!("Ignoring relay LEAVE without a valid cookie from {}", addr);
+                    return;
+                }
+                if self.downstreams.remove(&(message.session_id, addr)).is_some() {
+                    if let Some(session) = self.session_manager.get_session(&message.session_id) {
+                        session.remove_subscriber(&addr);
+                    }
+                    info!("Relay {} left session {}", addr, message.session_id.0);
+                }
+            }
+            RelayKind::Feedback => {
+                // Only servers that completed a JOIN may send feedback toward the source
+                if !self.downstreams.contains_key(&(message.session_id, addr)) {
+                    debug!("Ignoring relay feedback for session {} from {}", message.session_id.0, addr);
+                    return;
+                }
+                if let Some(session) = self.session_manager.get_session(&message.session_id) {
+                    self.feedback_upstream(&session, message.payload).await;
+                }
+            }
+            RelayKind::Challenge => self.on_challenge(message, addr).await,
+        }
+    }
+
+    async fn on_challenge(&self, message: RelayMessage<'_>, addr: SocketAddr) {
+        let Some(local) = self.by_remote.get(&message.session_id).map(|id| *id) else {
+            return;
+        };
+        let Some(upstream) = self.upstreams.get(&local).filter(|u| u.addr == addr) else {
+            debug!("Ignoring relay CHALLENGE for session {} from {}", message.session_id.0, addr);
+            return;
+        };
+        *upstream.cookie.write() = message.payload.to_vec();
+        drop(upstream);
+        self.send(RelayKind::Join, message.session_id, message.payload, addr).await;
+    }
+
+    async fn on_media(&self, message: RelayMessage<'_>, addr: SocketAddr) {
+        let Some(local) = self.by_remote.get(&message.session_id).map(|id| *id) else {
+            trace!("Relay media for unknown session {} from {}", message.session_id.0, addr);
+            return;
+        };
+        if self.upstreams.get(&local).is_none_or(|u| u.addr.ip() != addr.ip()) {
+            warn!("Dropping relay media for session {} from {}: not its upstream", local.0, addr);
+            return;
+        }
+        if is_loop(self.server_id, &message.path) {
+            warn!("Dropping relay media for session {} that looped: {:?}", local.0, message.path);
+            return;
+        }
+        let Some(session) = self.session_manager.get_session(&local) else {
+            return;
+        };
+        // The origin forwards its source's sender reports the same way
+        if tcp::is_rtcp(message.payload) {
+            self.fanout_engine.handle_source_rtcp(&session, message.payload).await;
+            return;
+        }
+        let Some(packet) = RtpFanoutServer::parse_rtp_packet(message.payload) else {
+            return;
+        };
+        if let Some(upstream) = self.upstreams.get(&local) {
+            if *upstream.path.read() != message.path {
+                *upstream.path.write() = message.path.clone();
+            }
+            upstream.ssrcs.insert(packet.ssrc);
+        }
+        self.fanout_engine.ingest_to_session(&session, packet.with_source(addr), message.payload).await;
+    }
+
+    async fn on_join(&self, message: RelayMessage<'_>, addr: SocketAddr) {
+        let Some(session) = self.session_manager.get_session(&message.session_id) else {
+            debug!("Relay JOIN from {} for unknown session {}", addr, message.session_id.0);
+            return;
+        };
+        if !self.valid_cookie(session.id, addr, message.payload) {
+            let cookie = self.cookie(session.id, addr, self.cookie_epoch());
+            let challenge =
+                RelayMessage { kind: RelayKind::Challenge, session_id: session.id, path: Vec::new(), payload: &cookie };
+            if let Err(e) = self.socket.send_to(&challenge.encode(), addr).await {
+                warn!("Failed to send relay CHALLENGE to {}: {}", addr, e);
+            }
+            return;
+        }
+        let mut path = self.path_of(&session.id);
+        let requester = message.path.first().copied().unwrap_or_default();
+        if requester == self.server_id || path.contains(&requester) {
+            warn!("Refusing relay JOIN from {} for session {}: it is upstream of us", addr, session.id.0);
+            return;
+        }
+
+        path.push(self.server_id);
+        let header = RelayMessage::header(RelayKind::Media, session.id, &path);
+        let current = session.subscribers.get(&addr).and_then(|s| match &s.transport {
+            SubscriberTransport::Relay { header } => Some(header.clone()),
+            _ => None,
+        });
+        // Re-added only when new or when the path upstream changed.
+        if current.as_deref() != Some(&header) {
+            if current.is_none() {
+                info!("Relay {} joined session {}", addr, session.id.0);
+            }
+            session.add_subscriber_with_transport(addr, SubscriberTransport::Relay { header: Arc::new(header) });
+        }
+        self.downstreams.insert((session.id, addr), Instant::now());
+    }
+
+    // Forwards feedback about a session's media toward its source: up the
+    // relay chain, or to the source address at the origin.
+    pub async fn feedback_upstream(&self, session: &Session, rtcp: &[u8]) {
+        let upstream = self.upstreams.get(&session.id).map(|u| (u.addr, u.session_id));
+        match upstream {
+            Some((addr, remote)) => self.send(RelayKind::Feedback, remote, rtcp, addr).await,
+            None if session.source_addr.port() == 0 => {
+                debug!("No source address to forward feedback for session {}", session.id.0);
+            }
+            None => {
+                if let Err(e) = self.socket.send_to(rtcp, session.source_addr).await {
+                    warn!("Failed to forward feedback to source {}: {}", session.source_addr, e);
+                }
+            }
+        }
+    }
+
+    // Called with RTCP from local subscribers; NACK/PLI/FIR for relayed
+    // media go upstream.
+    pub async fn on_subscriber_feedback(&self, rtcp: &[u8]) {
+        let ssrcs = feedback_media_ssrcs(rtcp);
+        if ssrcs.is_empty() {
+            return;
+        }
+        let targets: Vec<_> = self
+            .upstreams
+            .iter()
+            .filter(|u| ssrcs.iter().any(|ssrc| u.ssrcs.contains(ssrc)))
+            .map(|u| (u.addr, u.session_id))
+            .collect();
+        for (addr, remote) in targets {
+            trace!("Forwarding subscriber feedback for {} to {}", remote.0, addr);
+            self.send(RelayKind::Feedback, remote, rtcp, addr).await;
+        }
+    }
+
+    // Refreshes JOINs upstream, leaves for sessions that are gone and drops
+    // downstream servers that stopped refreshing.
+    async fn run_maintenance(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(RELAY_KEEPALIVE);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+
+            let upstreams: Vec<_> = self
+                .upstreams
+                .iter()
+                .map(|u| (*u.key(), u.addr, u.session_id, u.cookie.read().clone()))
+                .collect();
+            for (local, addr, remote, cookie) in upstreams {
+                if self.session_manager.get_session(&local).is_some() {
+                    self.send(RelayKind::Join, remote, &cookie, addr).await;
+                } else {
+                    self.upstreams.remove(&local);
+                    self.by_remote.remove_if(&remote, |_, id| *id == local);
+                    self.send(RelayKind::Leave, remote, &cookie, addr).await;
+                    info!("Stopped relaying {} from {}", remote.0, addr);
+                }
+            }
+
+            let now = Instant::now();
+            self.downstreams.retain(|(session_id, addr), joined| {
+                if now.duration_since(*joined) < RELAY_TIMEOUT {
+                    return true;
+                }
+                if let Some(session) = self.session_manager.get_session(session_id) {
+                    session.remove_subscriber(addr);
+                }
+                info!("Relay {} of session {} timed out", addr, session_id.0);
+                false
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_header_and_loops() {
+        let (origin, relay) = (Uuid::new_v4(), Uuid::new_v4());
+        let session_id = SessionId::new();
+        let message = RelayMessage { kind: RelayKind::Media, session_id, path: vec![origin, relay], payload: &[0x80, 96] };
+        let encoded = message.encode();
+        assert_eq!(encoded.len(), HEADER_LEN + 32 + 2);
+        assert_eq!(RelayMessage::parse(&encoded), Some(message));
+        assert_eq!(RelayMessage::parse(&encoded[..HEADER_LEN + 8]), None);
+
+        assert!(is_loop(origin, &[origin, relay]));
+        assert!(!is_loop(Uuid::new_v4(), &[origin, relay]));
+        assert!(is_loop(Uuid::new_v4(), &[origin; MAX_HOPS + 1]));
+    }
+
+    #[test]
+    fn test_feedback_media_ssrcs() {
+        // RR, then PLI for 0x1234 and a generic NACK for 0x5678
+        let mut rtcp = vec![0x80, 201, 0, 1, 0, 0, 0, 9];
+        rtcp.extend_from_slice(&[0x81, 206, 0, 2, 0, 0, 0, 9, 0, 0, 0x12, 0x34]);
+        rtcp.extend_from_slice(&[0x81, 205, 0, 3, 0, 0, 0, 9, 0, 0, 0x56, 0x78, 0, 7, 0, 0]);
+        assert_eq!(feedback_media_ssrcs(&rtcp), vec![0x1234, 0x5678]);
+
+        // Receiver reports alone ask nothing of the source
+        assert!(feedback_media_ssrcs(&rtcp[..8]).is_empty());
+        assert_eq!(feedback_media_ssrcs(&build_pli(0x1234)), vec![0x1234]);
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::fanout::FanoutEngine;
+use crate::session::Session;
+use crate::tcp;
+use crate::RtpFanoutServer;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayOptions {
+    // 1.0 keeps the original timing, 2.0 plays twice as fast; 0 sends
+    // everything as fast as possible.
+    pub speed: f64,
+    // Number of passes over the capture; 0 loops until stopped.
+    pub loops: u32,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self { speed: 1.0, loops: 1 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+    // Since the first packet of the capture.
+    pub offset: Duration,
+    pub data: Vec<u8>,
+    pub source: Option<SocketAddr>,
+}
+
+// RTP packets read from an rtpdump, pcap or pcapng file. RTCP and non-RTP
+// traffic is skipped.
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub packets: Vec<CapturedPacket>,
+}
+
+impl Capture {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let mut packets = if data.starts_with(b"#!rtpplay") {
+            parse_rtpdump(data)?
+        } else if data.starts_with(&[0x0A, 0x0D, 0x0D, 0x0A]) {
+            parse_pcapng(data)?
+        } else {
+            parse_pcap(data)?
+        };
+
+        packets.retain(|p| p.data.len() >= 12 && p.data[0] >> 6 == 2 && !tcp::is_rtcp(&p.data));
+        if let Some(first) = packets.iter().map(|p| p.offset).min() {
+            for packet in &mut packets {
+                packet.offset -= first;
+            }
+        }
+        Ok(Self { packets })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.packets.last().map(|p| p.offset).unwrap_or_default()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn parse_rtpdump(data: &[u8]) -> io::Result<Vec<CapturedPacket>> {
+    let line_end = data.iter().position(|&b| b == b'\n').ok_or_else(|| invalid("truncated rtpdump header"))?;
+    let source = std::str::from_utf8(&data[..line_end])
+        .ok()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|address| {
+            let (ip, port) = address.rsplit_once('/')?;
+            Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?))
+        });
+
+    let mut pos = line_end + 1 + 16;
+    let mut packets = Vec::new();
+    while pos + 8 <= data.len() {
+        let length = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
+        let plen = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let offset = u32::from_be_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]);
+        if length < 8 || pos + length > data.len() {
+            return Err(invalid("truncated rtpdump packet"));
+        }
+        // plen is 0 for RTCP
+        if plen > 0 {
+            packets.push(CapturedPacket {
+                offset: Duration::from_millis(offset as u64),
+                data: data[pos + 8..pos + length].to_vec(),
+                source,
+            });
+        }
+        pos += length;
+    }
+    Ok(packets)
+}
+
+fn parse_pcap(data: &[u8]) -> io::Result<Vec<CapturedPacket>> {
+    if data.len() < 24 {
+        return Err(invalid("not an rtpdump, pcap or pcapng file"));
+    }
+    let (big_endian, nanos) = match data[0..4] {
+        [0xD4, 0xC3, 0xB2, 0xA1] => (false, false),
+        [0xA1, 0xB2, 0xC3, 0xD4] => (true, false),
+        [0x4D, 0x3C, 0xB2, 0xA1] => (false, true),
+        [0xA1, 0xB2, 0x3C, 0x4D] => (true, true),
+        _ => return Err(invalid("not an rtpdump, pcap or pcapng file")),
+    };
+    let read_u32 = |pos: usize| {
+        let bytes = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+        if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    };
+    let linktype = read_u32(20) & 0xFFFF;
+
+    let mut pos = 24;
+    let mut packets = Vec::new();
+    while pos + 16 <= data.len() {
+        let seconds = read_u32(pos) as u64;
+        let fraction = read_u32(pos + 4) as u64;
+        let captured = read_u32(pos + 8) as usize;
+        pos += 16;
+        if pos + captured > data.len() {
+            return Err(invalid("truncated pcap record"));
+        }
+        let offset = Duration::from_secs(seconds)
+            + if nanos { Duration::from_nanos(fraction) } else { Duration::from_micros(fraction) };
+        if let Some((source, payload)) = udp_payload(linktype, &data[pos..pos + captured]) {
+            packets.push(CapturedPacket { offset, data: payload.to_vec(), source: Some(source) });
+        }
+        pos += captured;
+    }
+    Ok(packets)
+}
+
+fn parse_pcapng(data: &[u8]) -> io::Result<Vec<CapturedPacket>> {
+    if data.len() < 12 {
+        return Err(invalid("truncated pcapng header"));
+    }
+    let big_endian = data[8..12] == [0x1A, 0x2B, 0x3C, 0x4D];
+    let read_u32 = |pos: usize| {
+        let bytes = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+        if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    };
+    let read_u16 = |pos: usize| {
+        let bytes = [data[pos], data[pos + 1]];
+        if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+    };
+
+    // (link type, timestamp units per second) per interface
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut packets = Vec::new();
+    let mut pos = 0;
+    while pos + 12 <= data.len() {
+        let block_type = read_u32(pos);
+        let length = read_u32(pos + 4) as usize;
+        if length < 12 || pos + length > data.len() {
+            return Err(invalid("truncated pcapng block"));
+        }
+        let block = pos..pos + length;
+
+        match block_type {
+            // Interface description
+            1 if length >= 20 => {
+                let linktype = read_u16(pos + 8) as u32;
+                let mut units = 1_000_000;
+                let mut option = pos + 16;
+                while option + 4 <= block.end - 4 {
+                    let code = read_u16(option);
+                    let option_len = read_u16(option + 2) as usize;
+                    if code == 0 {
+                        break;
+                    }
+                    // if_tsresol: power of ten, or of two with the top bit set
+                    if code == 9 && option_len == 1 {
+                        let resolution = data[option + 4];
+                        units = if resolution & 0x80 == 0 {
+                            10u64.saturating_pow(resolution as u32)
+                        } else {
+                            1u64 << (resolution & 0x7F).min(63)
+                        };
+                    }
+                    option += 4 + option_len.div_ceil(4) * 4;
+                }
+                interfaces.push((linktype, units));
+            }
+            // Enhanced packet
+            6 if length >= 32 => {
+                let interface = read_u32(pos + 8) as usize;
+                let timestamp = ((read_u32(pos + 12) as u64) << 32) | read_u32(pos + 16) as u64;
+                let captured = read_u32(pos + 20) as usize;
+                let Some(&(linktype, units)) = interfaces.get(interface) else {
+                    return Err(invalid("pcapng packet for unknown interface"));
+                };
+                if pos + 28 + captured > block.end {
+                    return Err(invalid("truncated pcapng packet"));
+                }
+                let offset = Duration::from_secs(timestamp / units)
+                    + Duration::from_nanos((timestamp % units) * 1_000_000_000 / units);
+                if let Some((source, payload)) = udp_payload(linktype, &data[pos + 28..pos + 28 + captured]) {
+                    packets.push(CapturedPacket { offset, data: payload.to_vec(), source: Some(source) });
+                }
+            }
+            _ => {}
+        }
+        pos = block.end;
+    }
+    Ok(packets)
+}
+
+// Source address and UDP payload of a captured frame.
+fn udp_payload(linktype: u32, frame: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    let ip = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_ETHERNET => {
+            let mut header = 14;
+            if frame.get(12..14)? == [0x81, 0x00] {
+                header += 4;
+            }
+            frame.get(header..)?
+        }
+        _ => return None,
+    };
+
+    let (source_ip, udp): (IpAddr, &[u8]) = match ip.first()? >> 4 {
+        4 => {
+            let header = ((ip[0] & 0x0F) as usize) * 4;
+            if *ip.get(9)? != 17 {
+                return None;
+            }
+            let octets: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).into(), ip.get(header..)?)
+        }
+        6 => {
+            if *ip.get(6)? != 17 {
+                return None;
+            }
+            let octets: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            (Ipv6Addr::from(octets).into(), ip.get(40..)?)
+        }
+        _ => return None,
+    };
+
+    let port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let length = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+    let payload = udp.get(8..length.max(8).min(udp.len()))?;
+    Some((SocketAddr::new(source_ip, port), payload))
+}
+
+// How far one pass over the capture moves each SSRC's sequence numbers and
+// timestamps, so looped passes continue the stream instead of repeating it.
+fn loop_shifts(capture: &Capture) -> HashMap<u32, (u16, u32)> {
+    let mut streams: HashMap<u32, (u16, u16, u32, u32, Vec<u32>)> = HashMap::new();
+    for packet in &capture.packets {
+        let data = &packet.data;
+        let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        let seq = u16::from_be_bytes([data[2], data[3]]);
+        let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let stream = streams.entry(ssrc).or_insert((seq, seq, timestamp, timestamp, Vec::new()));
+        stream.1 = seq;
+        stream.3 = timestamp;
+        if stream.4.last() != Some(&timestamp) {
+            stream.4.push(timestamp);
+        }
+    }
+
+    streams
+        .into_iter()
+        .map(|(ssrc, (first_seq, last_seq, first_ts, last_ts, frames))| {
+            let span = last_ts.wrapping_sub(first_ts);
+            let frame_interval = if frames.len() > 1 { span / (frames.len() as u32 - 1) } else { 0 };
+            (ssrc, (last_seq.wrapping_sub(first_seq).wrapping_add(1), span.wrapping_add(frame_interval)))
+        })
+        .collect()
+}
+
+// Feeds a capture into a session as if its source were live.
+pub fn spawn(
+    capture: Capture,
+    session: Arc<Session>,
+    fanout_engine: Arc<FanoutEngine>,
+    options: ReplayOptions,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let shifts = loop_shifts(&capture);
+        let mut offsets: HashMap<u32, (u16, u32)> = HashMap::new();
+        // Keep the packet rhythm across the jump back to the start.
+        let gap = capture.duration() / capture.packets.len().max(1) as u32;
+        let mut pass = 0;
+
+        info!("Replaying {} packets into session {} ({:?})", capture.packets.len(), session.id.0, options);
+        loop {
+            let start = tokio::time::Instant::now();
+            for captured in &capture.packets {
+                if options.speed > 0.0 {
+                    tokio::time::sleep_until(start + captured.offset.div_f64(options.speed)).await;
+                } else {
+                    // Unpaced, and fan-out to non-UDP subscribers never awaits
+                    tokio::task::yield_now().await;
+                }
+                let Some(mut packet) = RtpFanoutServer::parse_rtp_packet(&captured.data) else {
+                    continue;
+                };
+                if session.wants_raw() {
+                    packet = packet.with_raw(&captured.data);
+                }
+                if let Some(&(seq, timestamp)) = offsets.get(&packet.ssrc) {
+                    packet.sequence = packet.sequence.wrapping_add(seq);
+                    packet.timestamp = packet.timestamp.wrapping_add(timestamp);
+                    packet.raw = None;
+                }
+                packet.source = captured.source.or(Some(session.source_addr));
+                packet.received_at = Instant::now();
+                fanout_engine.fanout_to_session(&session, &packet).await;
+            }
+
+            pass += 1;
+            if options.loops != 0 && pass >= options.loops {
+                break;
+            }
+            for (ssrc, &(seq, timestamp)) in &shifts {
+                let offset = offsets.entry(*ssrc).or_default();
+                offset.0 = offset.0.wrapping_add(seq);
+                offset.1 = offset.1.wrapping_add(timestamp);
+            }
+            if options.speed > 0.0 {
+                tokio::time::sleep(gap.div_f64(options.speed)).await;
+            }
+        }
+        debug!("Replay into session {} finished after {} passes", session.id.0, pass);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{PacketWriter, PcapngWriter, RecordedPacket, RtpdumpWriter};
+    use std::time::UNIX_EPOCH;
+
+    fn rtp(seq: u16, timestamp: u32) -> Vec<u8> {
+        let mut data = vec![0x80, 0x60];
+        data.extend_from_slice(&seq.to_be_bytes());
+        data.extend_from_slice(&timestamp.to_be_bytes());
+        data.extend_from_slice(&42u32.to_be_bytes());
+        data.extend_from_slice(b"frame");
+        data
+    }
+
+    #[test]
+    fn test_recordings_round_trip() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let source: SocketAddr = "10.1.2.3:4000".parse().unwrap();
+        let recorded: Vec<RecordedPacket> = (0..3)
+            .map(|i| RecordedPacket {
+                data: rtp(100 + i, 3000 * i as u32).into(),
+                arrival: start + Duration::from_millis(40 * i as u64),
+                source: Some(source),
+            })
+            .collect();
+
+        let mut rtpdump = Vec::new();
+        let mut pcapng = Vec::new();
+        {
+            let mut writer = RtpdumpWriter::new(&mut rtpdump, start, source).unwrap();
+            let mut pcap_writer = PcapngWriter::new(&mut pcapng, "10.0.0.1:5004".parse().unwrap()).unwrap();
+            for packet in &recorded {
+                writer.write_packet(packet).unwrap();
+                pcap_writer.write_packet(packet).unwrap();
+            }
+        }
+
+        for file in [rtpdump, pcapng] {
+            let capture = Capture::parse(&file).unwrap();
+            assert_eq!(capture.packets.len(), 3);
+            assert_eq!(capture.packets[2].offset, Duration::from_millis(80));
+            assert_eq!(capture.packets[1].data, rtp(101, 3000));
+            assert_eq!(capture.packets[0].source, Some(source));
+        }
+    }
+
+    #[test]
+    fn test_loop_shifts_continue_stream() {
+        let capture = Capture {
+            packets: [(65534, 0), (65535, 3000), (0, 3000), (1, 6000)]
+                .iter()
+                .map(|&(seq, ts)| CapturedPacket { offset: Duration::ZERO, data: rtp(seq, ts), source: None })
+                .collect(),
+        };
+        assert_eq!(loop_shifts(&capture)[&42], (4, 9000));
+    }
+}
@@ -0,0 +1,136 @@
+// RTP header extension handling (RFC 8285). The raw extension block is kept
+// on the packet so it can be forwarded untouched; elements are decoded on
+// demand.
+
+pub const ONE_BYTE_PROFILE: u16 = 0xBEDE;
+pub const TWO_BYTE_PROFILE_MASK: u16 = 0xFFF0;
+pub const TWO_BYTE_PROFILE: u16 = 0x1000;
+
+pub const SDES_MID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:mid";
+pub const SDES_RTP_STREAM_ID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpExtension {
+    pub profile: u16,
+    pub data: Vec<u8>,
+}
+
+impl RtpExtension {
+    pub fn is_one_byte(&self) -> bool {
+        self.profile == ONE_BYTE_PROFILE
+    }
+
+    pub fn is_two_byte(&self) -> bool {
+        self.profile & TWO_BYTE_PROFILE_MASK == TWO_BYTE_PROFILE
+    }
+
+    pub fn elements(&self) -> Vec<(u8, &[u8])> {
+        let mut elements = Vec::new();
+        let data = &self.data[..];
+        let mut i = 0;
+
+        while i < data.len() {
+            if data[i] == 0 {
+                i += 1;
+                continue;
+            }
+            let (id, len, header) = if self.is_one_byte() {
+                let id = data[i] >> 4;
+                if id == 15 {
+                    break;
+                }
+                (id, (data[i] & 0x0F) as usize + 1, 1)
+            } else if self.is_two_byte() {
+                if i + 1 >= data.len() {
+                    break;
+                }
+                (data[i], data[i + 1] as usize, 2)
+            } else {
+                break;
+            };
+
+            let start = i + header;
+            if start + len > data.len() {
+                break;
+            }
+            elements.push((id, &data[start..start + len]));
+            i = start + len;
+        }
+
+        elements
+    }
+
+    pub fn element(&self, id: u8) -> Option<&[u8]> {
+        self.elements().into_iter().find(|(e, _)| *e == id).map(|(_, v)| v)
+    }
+
+    // Replaces or appends an element, switching to the two-byte form when the
+    // id or value doesn't fit the one-byte form.
+    pub fn set_element(&mut self, id: u8, value: &[u8]) {
+        let mut elements: Vec<(u8, Vec<u8>)> = self
+            .elements()
+            .into_iter()
+            .filter(|(e, _)| *e != id)
+            .map(|(e, v)| (e, v.to_vec()))
+            .collect();
+        elements.push((id, value.to_vec()));
+        *self = Self::from_elements(&elements);
+    }
+
+    pub fn from_elements(elements: &[(u8, Vec<u8>)]) -> Self {
+        let one_byte = elements
+            .iter()
+            .all(|(id, v)| (1..15).contains(id) && !v.is_empty() && v.len() <= 16);
+
+        let mut data = Vec::new();
+        for (id, value) in elements {
+            if one_byte {
+                data.push((id << 4) | (value.len() as u8 - 1));
+            } else {
+                data.push(*id);
+                data.push(value.len() as u8);
+            }
+            data.extend_from_slice(value);
+        }
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+
+        Self {
+            profile: if one_byte { ONE_BYTE_PROFILE } else { TWO_BYTE_PROFILE },
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_byte_elements() {
+        let ext = RtpExtension {
+            profile: ONE_BYTE_PROFILE,
+            data: vec![0x10, 0xAA, 0x00, 0x21, 0x01, 0x02, 0x00, 0x00],
+        };
+        assert_eq!(ext.element(1), Some(&[0xAA][..]));
+        assert_eq!(ext.element(2), Some(&[0x01, 0x02][..]));
+        assert_eq!(ext.element(3), None);
+    }
+
+    #[test]
+    fn test_set_element_roundtrip() {
+        let mut ext = RtpExtension::from_elements(&[(1, vec![0x30])]);
+        ext.set_element(3, &[0x00, 0x07]);
+        ext.set_element(1, &[0x31]);
+        assert!(ext.is_one_byte());
+        assert_eq!(ext.data.len() % 4, 0);
+        assert_eq!(ext.element(1), Some(&[0x31][..]));
+        assert_eq!(ext.element(3), Some(&[0x00, 0x07][..]));
+
+        ext.set_element(20, &[0x01]);
+        assert!(ext.is_two_byte());
+        assert_eq!(ext.element(3), Some(&[0x00, 0x07][..]));
+        assert_eq!(ext.element(20), Some(&[0x01][..]));
+    }
+}
@@ -0,0 +1,678 @@
+use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use tracing::{info, debug, warn};
+
+use crate::config::ServerConfig;
+use crate::filter::SubscriberFilter;
+use crate::multicast::MulticastOptions;
+use crate::session::{Session, SessionId, SessionManager, SubscriberTransport};
+
+const SUPPORTED_METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER";
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+const INTERLEAVED_QUEUE_DEPTH: usize = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtspRequest {
+    pub method: String,
+    pub uri: String,
+    pub cseq: u32,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RtspRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TransportSpec {
+    pub tcp: bool,
+    pub multicast: bool,
+    pub client_port: Option<(u16, u16)>,
+    pub interleaved: Option<(u8, u8)>,
+    pub destination: Option<IpAddr>,
+    pub port: Option<(u16, u16)>,
+}
+
+impl TransportSpec {
+    // Only the first alternative of a comma separated Transport header is
+    // considered; clients list their preferred transport first.
+    pub fn parse(header: &str) -> Option<Self> {
+        let first = header.split(',').next()?.trim();
+        let mut parts = first.split(';');
+        let profile = parts.next()?.trim();
+
+        let mut spec = TransportSpec {
+            tcp: match profile {
+                "RTP/AVP" | "RTP/AVP/UDP" => false,
+                "RTP/AVP/TCP" => true,
+                _ => return None,
+            },
+            ..Default::default()
+        };
+
+        for part in parts {
+            let (key, value) = match part.split_once('=') {
+                Some((k, v)) => (k.trim(), Some(v.trim())),
+                None => (part.trim(), None),
+            };
+            match (key, value) {
+                ("multicast", _) => spec.multicast = true,
+                ("unicast", _) => spec.multicast = false,
+                ("client_port", Some(v)) => spec.client_port = parse_range(v),
+                ("port", Some(v)) => spec.port = parse_range(v),
+                ("interleaved", Some(v)) => {
+                    spec.interleaved = parse_range(v).and_then(|(a, b)| {
+                        Some((u8::try_from(a).ok()?, u8::try_from(b).ok()?))
+                    });
+                }
+                ("destination", Some(v)) => spec.destination = v.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Some(spec)
+    }
+}
+
+fn parse_range(value: &str) -> Option<(u16, u16)> {
+    match value.split_once('-') {
+        Some((a, b)) => Some((a.parse().ok()?, b.parse().ok()?)),
+        None => {
+            let a: u16 = value.parse().ok()?;
+            Some((a, a.saturating_add(1)))
+        }
+    }
+}
+
+// Parses one request from the front of `buf`, returning it together with the
+// number of bytes consumed (headers plus any body). Returns `None` until a
+// complete request is buffered.
+pub fn parse_request(buf: &[u8]) -> Option<(RtspRequest, usize)> {
+    let header_end = buf.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let text = std::str::from_utf8(&buf[..header_end]).ok()?;
+    let mut lines = text.split("\r\n");
+
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let uri = request_line.next()?.to_string();
+    if !request_line.next()?.starts_with("RTSP/1.0") {
+        return None;
+    }
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let mut request = RtspRequest { method, uri, cseq: 0, headers };
+    request.cseq = request.header("CSeq").and_then(|v| v.parse().ok()).unwrap_or(0);
+
+    let body_len: usize = request
+        .header("Content-Length")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    if buf.len() < header_end + body_len {
+        return None;
+    }
+
+    Some((request, header_end + body_len))
+}
+
+pub fn session_id_from_uri(uri: &str) -> Option<SessionId> {
+    let path = uri
+        .strip_prefix("rtsp://")
+        .and_then(|rest| rest.find('/').map(|i| &rest[i..]))
+        .unwrap_or(uri);
+
+    path.split('/')
+        .find_map(|segment| Uuid::parse_str(segment).ok())
+        .map(SessionId)
+}
+
+// The `trackID=N` control DESCRIBE hands out, if the URI names a track.
+pub fn track_from_uri(uri: &str) -> Option<usize> {
+    uri.rsplit('/')
+        .find_map(|segment| segment.strip_prefix("trackID="))
+        .and_then(|track| track.parse().ok())
+}
+
+// Interleaved subscribers all have the connection's address, so the RTP
+// channel goes in the flow label to keep each track its own subscriber.
+fn interleaved_addr(peer: SocketAddr, channel: u8) -> SocketAddr {
+    let ip = match peer.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    SocketAddr::V6(SocketAddrV6::new(ip, peer.port(), channel as u32, 0))
+}
+
+fn response(status: u16, reason: &str, cseq: u32, headers: &[(&str, String)], body: Option<&str>) -> Vec<u8> {
+    let mut out = format!("RTSP/1.0 {} {}\r\nCSeq: {}\r\nServer: rtp-fanout-server/{}\r\n",
+                          status, reason, cseq, env!("CARGO_PKG_VERSION"));
+    for (name, value) in headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    match body {
+        Some(body) => {
+            out.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+            out.push_str(body);
+        }
+        None => out.push_str("\r\n"),
+    }
+    out.into_bytes()
+}
+
+struct MulticastGroup {
+    addr: SocketAddr,
+    members: usize,
+}
+
+struct Setup {
+    session: Arc<Session>,
+    addr: SocketAddr,
+    transport: SubscriberTransport,
+    // The payload types of the track set up, when the session has several.
+    filter: SubscriberFilter,
+    multicast: bool,
+    // Where a UDP client's RTCP comes from; it keeps the session alive.
+    rtcp_addr: Option<SocketAddr>,
+}
+
+struct Connection {
+    peer: SocketAddr,
+    rtsp_session: Option<String>,
+    setups: Vec<Setup>,
+    playing: bool,
+    writer: mpsc::Sender<Vec<u8>>,
+}
+
+pub struct RtspServer {
+    config: ServerConfig,
+    session_manager: Arc<SessionManager>,
+    multicast_groups: DashMap<SessionId, MulticastGroup>,
+    next_multicast_port: AtomicU16,
+    // Port of the socket UDP media is sent from and RTCP is received on.
+    media_port: u16,
+    // Last RTCP seen from each UDP client set up over RTSP.
+    rtcp_seen: DashMap<SocketAddr, Instant>,
+}
+
+// An RTP/RTCP port pair starting at `port`, or the single port when there is
+// no port above it.
+fn port_pair(port: u16) -> String {
+    match port.checked_add(1) {
+        Some(rtcp) => format!("{}-{}", port, rtcp),
+        None => port.to_string(),
+    }
+}
+
+impl RtspServer {
+    pub fn new(config: ServerConfig, session_manager: Arc<SessionManager>, media_port: u16) -> Self {
+        let base_port = config
+            .rtsp_multicast_address
+            .parse::<SocketAddr>()
+            .map(|a| a.port())
+            .unwrap_or(16000);
+
+        Self {
+            config,
+            session_manager,
+            multicast_groups: DashMap::new(),
+            next_multicast_port: AtomicU16::new(base_port),
+            media_port,
+            rtcp_seen: DashMap::new(),
+        }
+    }
+
+    // Called for RTCP arriving on the media socket; only clients with an
+    // RTSP setup are tracked.
+    pub fn on_rtcp(&self, from: SocketAddr) {
+        if let Some(mut seen) = self.rtcp_seen.get_mut(&from) {
+            *seen = Instant::now();
+        }
+    }
+
+    fn last_rtcp(&self, conn: &Connection) -> Option<Instant> {
+        conn.setups
+            .iter()
+            .filter_map(|setup| setup.rtcp_addr)
+            .filter_map(|addr| self.rtcp_seen.get(&addr).map(|seen| *seen))
+            .max()
+    }
+
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        let bind_addr: SocketAddr = self.config.rtsp_bind_address.parse()?;
+        let listener = TcpListener::bind(bind_addr).await?;
+        info!("RTSP server listening on {}", bind_addr);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_connection(stream, peer).await {
+                    debug!("RTSP connection {} closed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(&self, stream: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
+        let (mut reader, mut writer) = stream.into_split();
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(INTERLEAVED_QUEUE_DEPTH);
+
+        let writer_task = tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut conn = Connection {
+            peer,
+            rtsp_session: None,
+            setups: Vec::new(),
+            playing: false,
+            writer: tx,
+        };
+
+        let idle_timeout = Duration::from_secs(self.config.rtsp_session_timeout_secs);
+        let mut buf: Vec<u8> = Vec::with_capacity(4096);
+        let mut chunk = vec![0u8; 4096];
+
+        let mut last_request = Instant::now();
+
+        let result = loop {
+            // RTCP from the client's UDP setups counts as activity too.
+            let last_activity = self.last_rtcp(&conn).map_or(last_request, |t| t.max(last_request));
+            let deadline = tokio::time::Instant::from_std(last_activity + idle_timeout);
+            let n = match tokio::time::timeout_at(deadline, reader.read(&mut chunk)).await {
+                Ok(Ok(0)) => break Ok(()),
+                Ok(Ok(n)) => n,
+                Ok(Err(e)) => break Err(e.into()),
+                Err(_) => {
+                    if self.last_rtcp(&conn).is_some_and(|t| t + idle_timeout > Instant::now()) {
+                        continue;
+                    }
+                    info!("RTSP client {} timed out", peer);
+                    break Ok(());
+                }
+            };
+            last_request = Instant::now();
+            buf.extend_from_slice(&chunk[..n]);
+
+            loop {
+                // Interleaved data from the client (RTCP receiver reports) is
+                // consumed but not forwarded.
+                if buf.first() == Some(&b'$') {
+                    if buf.len() < 4 {
+                        break;
+                    }
+                    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+                    if buf.len() < 4 + len {
+                        break;
+                    }
+                    buf.drain(..4 + len);
+                    continue;
+                }
+
+                match parse_request(&buf) {
+                    Some((request, consumed)) => {
+                        buf.drain(..consumed);
+                        let reply = self.handle_request(&mut conn, &request);
+                        if conn.writer.send(reply).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+
+            if buf.len() > MAX_REQUEST_SIZE {
+                break Err(anyhow::anyhow!("request exceeds {} bytes", MAX_REQUEST_SIZE));
+            }
+        };
+
+        self.teardown(&mut conn);
+        drop(conn);
+        writer_task.abort();
+        result
+    }
+
+    fn handle_request(&self, conn: &mut Connection, request: &RtspRequest) -> Vec<u8> {
+        debug!("RTSP {} {} from {}", request.method, request.uri, conn.peer);
+        let cseq = request.cseq;
+
+        if let (Some(expected), Some(given)) = (&conn.rtsp_session, request.header("Session")) {
+            let given = given.split(';').next().unwrap_or("").trim();
+            if given != expected {
+                return response(454, "Session Not Found", cseq, &[], None);
+            }
+        }
+
+        match request.method.as_str() {
+            "OPTIONS" => response(200, "OK", cseq, &[("Public", SUPPORTED_METHODS.to_string())], None),
+            "DESCRIBE" => self.describe(request),
+            "SETUP" => self.setup(conn, request),
+            "PLAY" => self.play(conn, request),
+            "TEARDOWN" => {
+                self.teardown(conn);
+                response(200, "OK", cseq, &[], None)
+            }
+            "GET_PARAMETER" => {
+                let headers: Vec<(&str, String)> = conn
+                    .rtsp_session
+                    .iter()
+                    .map(|s| ("Session", s.clone()))
+                    .collect();
+                response(200, "OK", cseq, &headers, None)
+            }
+            _ => response(501, "Not Implemented", cseq, &[("Public", SUPPORTED_METHODS.to_string())], None),
+        }
+    }
+
+    fn lookup(&self, uri: &str) -> Option<Arc<Session>> {
+        session_id_from_uri(uri).and_then(|id| self.session_manager.get_session(&id))
+    }
+
+    fn describe(&self, request: &RtspRequest) -> Vec<u8> {
+        let Some(session) = self.lookup(&request.uri) else {
+            return response(404, "Not Found", request.cseq, &[], None);
+        };
+
+        let mut description = self.session_manager.subscriber_description(&session, self.server_ip());
+        description.attributes.push("control:*".to_string());
+        for (track, media) in description.media.iter_mut().enumerate() {
+            media.control = Some(format!("trackID={}", track));
+        }
+        let sdp = description.to_string();
+        let base = format!("{}/", request.uri.trim_end_matches('/'));
+
+        response(200, "OK", request.cseq, &[
+            ("Content-Type", "application/sdp".to_string()),
+            ("Content-Base", base),
+        ], Some(&sdp))
+    }
+
+    fn server_ip(&self) -> IpAddr {
+        self.config
+            .rtsp_bind_address
+            .parse::<SocketAddr>()
+            .map(|a| a.ip())
+            .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+    }
+
+    // A SETUP without a track gets every track. A single-track session is
+    // left unfiltered so payload types its SDP doesn't list still get through.
+    fn track_filter(&self, session: &Session, uri: &str) -> Option<SubscriberFilter> {
+        let Some(track) = track_from_uri(uri) else {
+            return Some(SubscriberFilter::default());
+        };
+        let description = self.session_manager.subscriber_description(session, self.server_ip());
+        let media = description.media.get(track)?;
+        if description.media.len() == 1 {
+            return Some(SubscriberFilter::default());
+        }
+        Some(SubscriberFilter { payload_types: media.payload_types.clone(), ..SubscriberFilter::default() })
+    }
+
+    fn setup(&self, conn: &mut Connection, request: &RtspRequest) -> Vec<u8> {
+        let cseq = request.cseq;
+        let Some(session) = self.lookup(&request.uri) else {
+            return response(404, "Not Found", cseq, &[], None);
+        };
+        let Some(filter) = self.track_filter(&session, &request.uri) else {
+            return response(404, "Not Found", cseq, &[], None);
+        };
+        let Some(spec) = request.header("Transport").and_then(TransportSpec::parse) else {
+            return response(461, "Unsupported Transport", cseq, &[], None);
+        };
+
+        let (setup, transport_reply) = if spec.tcp {
+            let (rtp, rtcp) = spec.interleaved.unwrap_or((0, 1));
+            let setup = Setup {
+                session: session.clone(),
+                addr: interleaved_addr(conn.peer, rtp),
+                transport: SubscriberTransport::Interleaved { channel: rtp, sink: conn.writer.clone() },
+                filter,
+                multicast: false,
+                rtcp_addr: None,
+            };
+            (setup, format!("RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}", rtp, rtcp, session.ssrc))
+        } else if spec.multicast {
+            let group = self.multicast_group(&session.id, &spec);
+            let options = MulticastOptions::from_config(&self.config);
+            let ttl = options.ttl;
+            let setup = Setup {
+                session: session.clone(),
+                addr: group,
+                transport: SubscriberTransport::Multicast(options),
+                // One group carries every track of the session
+                filter: SubscriberFilter::default(),
+                multicast: true,
+                rtcp_addr: None,
+            };
+            (setup, format!("RTP/AVP;multicast;destination={};port={};ttl={}",
+                            group.ip(), port_pair(group.port()), ttl))
+        } else {
+            let Some((rtp, rtcp)) = spec.client_port else {
+                return response(461, "Unsupported Transport", cseq, &[], None);
+            };
+            let setup = Setup {
+                session: session.clone(),
+                addr: SocketAddr::new(conn.peer.ip(), rtp),
+                transport: SubscriberTransport::Udp,
+                filter,
+                multicast: false,
+                rtcp_addr: Some(SocketAddr::new(conn.peer.ip(), rtcp)),
+            };
+            self.rtcp_seen.insert(SocketAddr::new(conn.peer.ip(), rtcp), Instant::now());
+            // RTP and RTCP share the media socket, so both ends of the pair
+            // are the same port.
+            (setup, format!("RTP/AVP;unicast;client_port={}-{};server_port={}-{};ssrc={:08X}",
+                            rtp, rtcp, self.media_port, self.media_port, session.ssrc))
+        };
+
+        if conn.playing {
+            self.activate(&setup);
+        }
+        conn.setups.push(setup);
+
+        let rtsp_session = conn
+            .rtsp_session
+            .get_or_insert_with(|| format!("{:016X}", Uuid::new_v4().as_u64_pair().0))
+            .clone();
+
+        response(200, "OK", cseq, &[
+            ("Transport", transport_reply),
+            ("Session", format!("{};timeout={}", rtsp_session, self.config.rtsp_session_timeout_secs)),
+        ], None)
+    }
+
+    fn play(&self, conn: &mut Connection, request: &RtspRequest) -> Vec<u8> {
+        let Some(rtsp_session) = conn.rtsp_session.clone() else {
+            return response(455, "Method Not Valid in This State", request.cseq, &[], None);
+        };
+
+        if !conn.playing {
+            for setup in &conn.setups {
+                self.activate(setup);
+            }
+            conn.playing = true;
+        }
+
+        response(200, "OK", request.cseq, &[
+            ("Session", rtsp_session),
+            ("Range", "npt=0.000-".to_string()),
+        ], None)
+    }
+
+    fn activate(&self, setup: &Setup) {
+        if setup.multicast {
+            let mut group = self
+                .multicast_groups
+                .entry(setup.session.id)
+                .or_insert(MulticastGroup { addr: setup.addr, members: 0 });
+            group.members += 1;
+            if group.members > 1 {
+                return;
+            }
+        }
+        setup.session.add_subscriber_with_transport(setup.addr, setup.transport.clone());
+        if !setup.filter.is_empty() {
+            setup.session.set_subscriber_filter(&setup.addr, setup.filter.clone());
+        }
+    }
+
+    fn teardown(&self, conn: &mut Connection) {
+        for setup in conn.setups.drain(..) {
+            if let Some(addr) = setup.rtcp_addr {
+                self.rtcp_seen.remove(&addr);
+            }
+            if !conn.playing {
+                continue;
+            }
+            if setup.multicast {
+                let remaining = self.multicast_groups.get_mut(&setup.session.id).map(|mut g| {
+                    g.members = g.members.saturating_sub(1);
+                    g.members
+                });
+                if remaining != Some(0) {
+                    continue;
+                }
+                self.multicast_groups.remove(&setup.session.id);
+            }
+            setup.session.remove_subscriber(&setup.addr);
+        }
+        conn.playing = false;
+        conn.rtsp_session = None;
+    }
+
+    fn multicast_group(&self, id: &SessionId, spec: &TransportSpec) -> SocketAddr {
+        self.multicast_groups
+            .entry(*id)
+            .or_insert_with(|| {
+                let addr = match (spec.destination, spec.port) {
+                    (Some(destination), Some((port, _))) if destination.is_multicast() => {
+                        SocketAddr::new(destination, port)
+                    }
+                    _ => self.allocate_multicast_group(),
+                };
+                MulticastGroup { addr, members: 0 }
+            })
+            .addr
+    }
+
+    fn allocate_multicast_group(&self) -> SocketAddr {
+        let base_ip = self
+            .config
+            .rtsp_multicast_address
+            .parse::<SocketAddr>()
+            .map(|a| a.ip())
+            .unwrap_or_else(|_| {
+                warn!("Invalid rtsp_multicast_address {}", self.config.rtsp_multicast_address);
+                IpAddr::from([239, 255, 42, 1])
+            });
+        let port = self.next_multicast_port.fetch_add(2, Ordering::Relaxed);
+        SocketAddr::new(base_ip, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let raw = b"SETUP rtsp://host/550e8400-e29b-41d4-a716-446655440000/trackID=0 RTSP/1.0\r\n\
+                    CSeq: 3\r\n\
+                    Transport: RTP/AVP;unicast;client_port=8000-8001\r\n\r\n";
+        let (request, consumed) = parse_request(raw).unwrap();
+        assert_eq!(consumed, raw.len());
+        assert_eq!(request.method, "SETUP");
+        assert_eq!(request.cseq, 3);
+        assert_eq!(
+            session_id_from_uri(&request.uri).unwrap().0.to_string(),
+            "550e8400-e29b-41d4-a716-446655440000"
+        );
+
+        let spec = TransportSpec::parse(request.header("transport").unwrap()).unwrap();
+        assert!(!spec.tcp && !spec.multicast);
+        assert_eq!(spec.client_port, Some((8000, 8001)));
+    }
+
+    #[test]
+    fn test_parse_interleaved_transport() {
+        let spec = TransportSpec::parse("RTP/AVP/TCP;unicast;interleaved=2-3").unwrap();
+        assert!(spec.tcp);
+        assert_eq!(spec.interleaved, Some((2, 3)));
+        assert!(TransportSpec::parse("RAW/RAW/UDP;unicast").is_none());
+    }
+
+    #[test]
+    fn test_port_pairs_and_rtcp_keepalive() {
+        assert_eq!(port_pair(16000), "16000-16001");
+        assert_eq!(port_pair(u16::MAX), "65535");
+
+        let config = ServerConfig::default();
+        let server = RtspServer::new(config.clone(), Arc::new(SessionManager::new(config)), 5004);
+        let client: SocketAddr = "10.0.0.2:8001".parse().unwrap();
+        server.on_rtcp(client);
+        assert!(server.rtcp_seen.is_empty());
+
+        let registered = Instant::now() - Duration::from_secs(10);
+        server.rtcp_seen.insert(client, registered);
+        server.on_rtcp(client);
+        assert!(*server.rtcp_seen.get(&client).unwrap() > registered);
+    }
+
+    #[test]
+    fn test_setup_per_track() {
+        let config = ServerConfig::default();
+        let session_manager = Arc::new(SessionManager::new(config.clone()));
+        let offer = crate::sdp::SessionDescription::parse(
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=cam\r\nt=0 0\r\n\
+             m=audio 5006 RTP/AVP 111\r\na=rtpmap:111 opus/48000/2\r\n\
+             m=video 5004 RTP/AVP 96\r\na=rtpmap:96 VP8/90000\r\n",
+        )
+        .unwrap();
+        let session = session_manager
+            .create_session_with_description("10.0.0.1:5004".parse().unwrap(), 0, offer)
+            .unwrap();
+        let server = RtspServer::new(config, session_manager, 5004);
+
+        let (writer, _rx) = mpsc::channel(8);
+        let peer: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut conn = Connection { peer, rtsp_session: None, setups: Vec::new(), playing: true, writer };
+        let setup = |track: usize, channels: &str| RtspRequest {
+            method: "SETUP".to_string(),
+            uri: format!("rtsp://host/{}/trackID={}", session.id.0, track),
+            cseq: 1,
+            headers: vec![("Transport".to_string(), format!("RTP/AVP/TCP;unicast;interleaved={}", channels))],
+        };
+
+        assert!(server.setup(&mut conn, &setup(0, "0-1")).starts_with(b"RTSP/1.0 200"));
+        assert!(server.setup(&mut conn, &setup(1, "2-3")).starts_with(b"RTSP/1.0 200"));
+        assert!(server.setup(&mut conn, &setup(2, "4-5")).starts_with(b"RTSP/1.0 404"));
+
+        assert_eq!(session.subscribers.len(), 2);
+        let audio = session.subscribers.get(&interleaved_addr(peer, 0)).unwrap();
+        assert_eq!(audio.filter.payload_types, vec![111]);
+        let video = session.subscribers.get(&interleaved_addr(peer, 2)).unwrap();
+        assert_eq!(video.filter.payload_types, vec![96]);
+        assert_eq!(track_from_uri("rtsp://host/id/trackID=1"), Some(1));
+        assert_eq!(track_from_uri("rtsp://host/id"), None);
+    }
+}
@@ -0,0 +1,449 @@
+use std::fmt;
+use std::net::IpAddr;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SdpError {
+    #[error("line {0}: expected <type>=<value>")]
+    MalformedLine(usize),
+    #[error("line {0}: invalid media line")]
+    InvalidMedia(usize),
+    #[error("line {0}: invalid {1} attribute")]
+    InvalidAttribute(usize, &'static str),
+    #[error("missing version line")]
+    MissingVersion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MediaKind {
+    Audio,
+    Video,
+    Application,
+}
+
+impl MediaKind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "audio" => Some(Self::Audio),
+            "video" => Some(Self::Video),
+            "application" | "data" => Some(Self::Application),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Audio => "audio",
+            Self::Video => "video",
+            Self::Application => "application",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    fn parse(attr: &str) -> Option<Self> {
+        match attr {
+            "sendrecv" => Some(Self::SendRecv),
+            "sendonly" => Some(Self::SendOnly),
+            "recvonly" => Some(Self::RecvOnly),
+            "inactive" => Some(Self::Inactive),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SendRecv => "sendrecv",
+            Self::SendOnly => "sendonly",
+            Self::RecvOnly => "recvonly",
+            Self::Inactive => "inactive",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpMap {
+    pub payload_type: u8,
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub channels: Option<u16>,
+    pub fmtp: Option<String>,
+    pub rtcp_fb: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SsrcAttribute {
+    pub ssrc: u32,
+    pub cname: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extmap {
+    pub id: u8,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaDescription {
+    pub kind: MediaKind,
+    pub port: u16,
+    pub protocol: String,
+    pub payload_types: Vec<u8>,
+    pub formats: Vec<RtpMap>,
+    pub connection: Option<String>,
+    pub rtcp_mux: bool,
+    pub direction: Option<Direction>,
+    pub mid: Option<String>,
+    pub control: Option<String>,
+    pub ssrcs: Vec<SsrcAttribute>,
+    pub extmaps: Vec<Extmap>,
+    pub attributes: Vec<String>,
+}
+
+impl MediaDescription {
+    pub fn new(kind: MediaKind) -> Self {
+        Self {
+            kind,
+            port: 0,
+            protocol: "RTP/AVP".to_string(),
+            payload_types: Vec::new(),
+            formats: Vec::new(),
+            connection: None,
+            rtcp_mux: false,
+            direction: None,
+            mid: None,
+            control: None,
+            ssrcs: Vec::new(),
+            extmaps: Vec::new(),
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn format(&self, payload_type: u8) -> Option<&RtpMap> {
+        self.formats.iter().find(|f| f.payload_type == payload_type)
+    }
+
+    pub fn extmap_id(&self, uri: &str) -> Option<u8> {
+        self.extmaps.iter().find(|e| e.uri == uri).map(|e| e.id)
+    }
+
+    fn format_mut(&mut self, payload_type: u8) -> Option<&mut RtpMap> {
+        self.formats.iter_mut().find(|f| f.payload_type == payload_type)
+    }
+
+    fn ssrc_mut(&mut self, ssrc: u32) -> &mut SsrcAttribute {
+        if let Some(i) = self.ssrcs.iter().position(|s| s.ssrc == ssrc) {
+            return &mut self.ssrcs[i];
+        }
+        self.ssrcs.push(SsrcAttribute { ssrc, cname: None });
+        self.ssrcs.last_mut().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDescription {
+    pub origin: String,
+    pub name: String,
+    pub connection: Option<String>,
+    pub attributes: Vec<String>,
+    pub media: Vec<MediaDescription>,
+}
+
+impl SessionDescription {
+    pub fn new(name: &str, address: IpAddr) -> Self {
+        let family = if address.is_ipv4() { "IP4" } else { "IP6" };
+        Self {
+            origin: format!("- 0 1 IN {} {}", family, address),
+            name: name.to_string(),
+            connection: None,
+            attributes: Vec::new(),
+            media: Vec::new(),
+        }
+    }
+
+    pub fn parse(sdp: &str) -> Result<Self, SdpError> {
+        let mut description = SessionDescription {
+            origin: String::new(),
+            name: "-".to_string(),
+            connection: None,
+            attributes: Vec::new(),
+            media: Vec::new(),
+        };
+        let mut seen_version = false;
+
+        for (index, raw) in sdp.lines().enumerate() {
+            let line_no = index + 1;
+            let line = raw.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let (kind, value) = line
+                .split_once('=')
+                .filter(|(k, _)| k.len() == 1)
+                .ok_or(SdpError::MalformedLine(line_no))?;
+
+            match (kind, description.media.last_mut()) {
+                ("v", _) => seen_version = true,
+                ("o", _) => description.origin = value.to_string(),
+                ("s", _) => description.name = value.to_string(),
+                ("m", _) => description.media.push(parse_media_line(value, line_no)?),
+                ("c", Some(media)) => media.connection = Some(value.to_string()),
+                ("c", None) => description.connection = Some(value.to_string()),
+                ("a", Some(media)) => parse_media_attribute(media, value, line_no)?,
+                ("a", None) => description.attributes.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        if !seen_version {
+            return Err(SdpError::MissingVersion);
+        }
+        Ok(description)
+    }
+
+    pub fn media_of_kind(&self, kind: MediaKind) -> Option<&MediaDescription> {
+        self.media.iter().find(|m| m.kind == kind)
+    }
+
+    pub fn ssrcs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.media.iter().flat_map(|m| m.ssrcs.iter().map(|s| s.ssrc))
+    }
+
+    pub fn cname(&self) -> Option<&str> {
+        self.media
+            .iter()
+            .flat_map(|m| m.ssrcs.iter())
+            .find_map(|s| s.cname.as_deref())
+    }
+}
+
+fn parse_media_line(value: &str, line_no: usize) -> Result<MediaDescription, SdpError> {
+    let mut parts = value.split_whitespace();
+    let kind = parts.next().and_then(MediaKind::parse).ok_or(SdpError::InvalidMedia(line_no))?;
+    let port = parts
+        .next()
+        .and_then(|p| p.split('/').next())
+        .and_then(|p| p.parse().ok())
+        .ok_or(SdpError::InvalidMedia(line_no))?;
+    let protocol = parts.next().ok_or(SdpError::InvalidMedia(line_no))?;
+
+    let mut media = MediaDescription::new(kind);
+    media.port = port;
+    media.protocol = protocol.to_string();
+    media.payload_types = parts.filter_map(|pt| pt.parse().ok()).collect();
+    Ok(media)
+}
+
+fn parse_media_attribute(media: &mut MediaDescription, value: &str, line_no: usize) -> Result<(), SdpError> {
+    let (name, arg) = match value.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (value, None),
+    };
+
+    if let Some(direction) = Direction::parse(name) {
+        media.direction = Some(direction);
+        return Ok(());
+    }
+
+    match (name, arg) {
+        ("rtcp-mux", _) => media.rtcp_mux = true,
+        ("mid", Some(mid)) => media.mid = Some(mid.to_string()),
+        ("control", Some(control)) => media.control = Some(control.to_string()),
+        ("rtpmap", Some(arg)) => {
+            let rtpmap = parse_rtpmap(arg).ok_or(SdpError::InvalidAttribute(line_no, "rtpmap"))?;
+            media.formats.retain(|f| f.payload_type != rtpmap.payload_type);
+            media.formats.push(rtpmap);
+        }
+        ("fmtp", Some(arg)) => {
+            let (pt, params) = arg.split_once(' ').ok_or(SdpError::InvalidAttribute(line_no, "fmtp"))?;
+            let pt: u8 = pt.parse().map_err(|_| SdpError::InvalidAttribute(line_no, "fmtp"))?;
+            if let Some(format) = media.format_mut(pt) {
+                format.fmtp = Some(params.trim().to_string());
+            }
+        }
+        ("rtcp-fb", Some(arg)) => {
+            let (pt, feedback) = arg.split_once(' ').ok_or(SdpError::InvalidAttribute(line_no, "rtcp-fb"))?;
+            // Wildcard feedback applies to every format and stays on the section
+            if pt == "*" {
+                media.attributes.push(value.to_string());
+            } else if let Some(format) = pt.parse().ok().and_then(|pt| media.format_mut(pt)) {
+                format.rtcp_fb.push(feedback.trim().to_string());
+            }
+        }
+        ("extmap", Some(arg)) => {
+            let mut parts = arg.split_whitespace();
+            let id = parts
+                .next()
+                .and_then(|id| id.split('/').next())
+                .and_then(|id| id.parse().ok())
+                .ok_or(SdpError::InvalidAttribute(line_no, "extmap"))?;
+            let uri = parts.next().ok_or(SdpError::InvalidAttribute(line_no, "extmap"))?;
+            media.extmaps.push(Extmap { id, uri: uri.to_string() });
+        }
+        ("ssrc", Some(arg)) => {
+            let (ssrc, attr) = match arg.split_once(' ') {
+                Some((ssrc, attr)) => (ssrc, Some(attr)),
+                None => (arg, None),
+            };
+            let ssrc: u32 = ssrc.parse().map_err(|_| SdpError::InvalidAttribute(line_no, "ssrc"))?;
+            let entry = media.ssrc_mut(ssrc);
+            if let Some(cname) = attr.and_then(|a| a.strip_prefix("cname:")) {
+                entry.cname = Some(cname.to_string());
+            }
+        }
+        _ => media.attributes.push(value.to_string()),
+    }
+    Ok(())
+}
+
+fn parse_rtpmap(arg: &str) -> Option<RtpMap> {
+    let (pt, encoding) = arg.split_once(' ')?;
+    let mut parts = encoding.trim().split('/');
+    Some(RtpMap {
+        payload_type: pt.parse().ok()?,
+        encoding: parts.next()?.to_string(),
+        clock_rate: parts.next()?.parse().ok()?,
+        channels: parts.next().and_then(|c| c.parse().ok()),
+        fmtp: None,
+        rtcp_fb: Vec::new(),
+    })
+}
+
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v=0\r\no={}\r\ns={}\r\n", self.origin, self.name)?;
+        if let Some(connection) = &self.connection {
+            write!(f, "c={}\r\n", connection)?;
+        }
+        write!(f, "t=0 0\r\n")?;
+        for attribute in &self.attributes {
+            write!(f, "a={}\r\n", attribute)?;
+        }
+        for media in &self.media {
+            write!(f, "{}", media)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MediaDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m={} {} {}", self.kind.as_str(), self.port, self.protocol)?;
+        for pt in &self.payload_types {
+            write!(f, " {}", pt)?;
+        }
+        write!(f, "\r\n")?;
+
+        if let Some(connection) = &self.connection {
+            write!(f, "c={}\r\n", connection)?;
+        }
+        if let Some(mid) = &self.mid {
+            write!(f, "a=mid:{}\r\n", mid)?;
+        }
+        if let Some(direction) = self.direction {
+            write!(f, "a={}\r\n", direction.as_str())?;
+        }
+        if self.rtcp_mux {
+            write!(f, "a=rtcp-mux\r\n")?;
+        }
+        for extmap in &self.extmaps {
+            write!(f, "a=extmap:{} {}\r\n", extmap.id, extmap.uri)?;
+        }
+        for format in &self.formats {
+            write!(f, "a=rtpmap:{} {}/{}", format.payload_type, format.encoding, format.clock_rate)?;
+            if let Some(channels) = format.channels {
+                write!(f, "/{}", channels)?;
+            }
+            write!(f, "\r\n")?;
+            if let Some(fmtp) = &format.fmtp {
+                write!(f, "a=fmtp:{} {}\r\n", format.payload_type, fmtp)?;
+            }
+            for feedback in &format.rtcp_fb {
+                write!(f, "a=rtcp-fb:{} {}\r\n", format.payload_type, feedback)?;
+            }
+        }
+        for attribute in &self.attributes {
+            write!(f, "a={}\r\n", attribute)?;
+        }
+        for ssrc in &self.ssrcs {
+            match &ssrc.cname {
+                Some(cname) => write!(f, "a=ssrc:{} cname:{}\r\n", ssrc.ssrc, cname)?,
+                None => write!(f, "a=ssrc:{}\r\n", ssrc.ssrc)?,
+            }
+        }
+        if let Some(control) = &self.control {
+            write!(f, "a=control:{}\r\n", control)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+        s=rover-cam-front\r\n\
+        t=0 0\r\n\
+        a=group:BUNDLE 0\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96 97\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=mid:0\r\n\
+        a=sendonly\r\n\
+        a=rtcp-mux\r\n\
+        a=extmap:3 http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01\r\n\
+        a=rtpmap:96 H264/90000\r\n\
+        a=fmtp:96 packetization-mode=1;profile-level-id=42e01f\r\n\
+        a=rtcp-fb:96 nack pli\r\n\
+        a=rtcp-fb:* transport-cc\r\n\
+        a=rtpmap:97 rtx/90000\r\n\
+        a=fmtp:97 apt=96\r\n\
+        a=ssrc:1234 cname:rover-17\r\n\
+        a=ssrc:1234 msid:stream track\r\n";
+
+    #[test]
+    fn test_parse_offer() {
+        let sdp = SessionDescription::parse(OFFER).unwrap();
+        assert_eq!(sdp.name, "rover-cam-front");
+        assert_eq!(sdp.attributes, vec!["group:BUNDLE 0".to_string()]);
+
+        let video = sdp.media_of_kind(MediaKind::Video).unwrap();
+        assert_eq!(video.payload_types, vec![96, 97]);
+        assert_eq!(video.direction, Some(Direction::SendOnly));
+        assert!(video.rtcp_mux);
+        assert_eq!(video.mid.as_deref(), Some("0"));
+        assert_eq!(video.format(96).unwrap().encoding, "H264");
+        assert_eq!(video.format(97).unwrap().fmtp.as_deref(), Some("apt=96"));
+        assert_eq!(video.format(96).unwrap().rtcp_fb, vec!["nack pli".to_string()]);
+        assert!(video.attributes.contains(&"rtcp-fb:* transport-cc".to_string()));
+        assert_eq!(video.extmaps[0].id, 3);
+        assert_eq!(sdp.ssrcs().collect::<Vec<_>>(), vec![1234]);
+        assert_eq!(sdp.cname(), Some("rover-17"));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let sdp = SessionDescription::parse(OFFER).unwrap();
+        let reparsed = SessionDescription::parse(&sdp.to_string()).unwrap();
+        assert_eq!(sdp, reparsed);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(SessionDescription::parse("o=- 0 1 IN IP4 127.0.0.1\r\n"), Err(SdpError::MissingVersion));
+        assert_eq!(SessionDescription::parse("v=0\r\nm=video\r\n"), Err(SdpError::InvalidMedia(2)));
+        assert_eq!(
+            SessionDescription::parse("v=0\r\nm=audio 0 RTP/AVP 0\r\na=rtpmap:x\r\n"),
+            Err(SdpError::InvalidAttribute(3, "rtpmap"))
+        );
+    }
+}
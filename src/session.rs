@@ -1,13 +1,41 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant};
-use dashmap::DashMap;
-use parking_lot::RwLock;
+use dashmap::{DashMap, DashSet};
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 use tracing::{info, debug, warn};
 
+use crate::audio_level::{self, AudioLevelMeter, AUDIO_LEVEL_URI};
+use crate::bwe::{TwccFeedback, TRANSPORT_CC_URI};
+use crate::codec::{Codec, FreezeChange, MediaInspector, MediaStreamStats, MediaType};
 use crate::config::ServerConfig;
+use crate::container::{self, PayloadFormat};
+use crate::group::SessionGroupId;
+use crate::jitter::{ReorderBuffer, ReorderMode};
+use crate::events::{EventBus, ServerEvent};
+use crate::fec::{self, FecConfig, FecProtector, FecScheme, FecStats};
+use crate::filter::{PacketKind, SubscriberFilter};
+use crate::latch::{LatchRegistry, LatchToken};
+use crate::metrics::MetricsCollector;
+use crate::multicast::MulticastOptions;
+use crate::policer::{Policer, RateLimit};
+use crate::recording::{Recorder, RecordingFormat, RecordingOptions, RecordingStatus};
+use crate::ports::{IngestPorts, PortAllocator};
+use crate::rtp::{SDES_MID_URI, SDES_RTP_STREAM_ID_URI};
+use crate::sdp::{Direction, Extmap, MediaDescription, MediaKind, RtpMap, SessionDescription, SsrcAttribute};
+use crate::simulcast::{LayerSelector, LayerSpec, LayerTarget, SimulcastLayers};
+use crate::svc::{SvcLayer, SvcState, AV1_DEPENDENCY_DESCRIPTOR_URI};
+use crate::tap::{self, Tap, TapId, TapOptions, TapReceiver, TapStatus, TapTarget};
+use crate::RtpPacket;
+
+const FREEZE_CHECK_MIN_INTERVAL: Duration = Duration::from_millis(50);
+// Subscribers waiting on the same layer share one keyframe request.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SessionId(pub Uuid);
@@ -24,7 +52,84 @@ impl Default for SessionId {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SessionError {
+    #[error("maximum session limit reached ({0})")]
+    SessionLimit(usize),
+    #[error("SSRC {ssrc} already belongs to session {}", .existing.0)]
+    SsrcCollision { ssrc: u32, existing: SessionId },
+    #[error("MID {mid} from {source_addr} already belongs to session {}", .existing.0)]
+    MidCollision { mid: String, source_addr: SocketAddr, existing: SessionId },
+    #[error("session {} not found", .0.0)]
+    NotFound(SessionId),
+    #[error("session group {} not found", .0.0)]
+    GroupNotFound(SessionGroupId),
+    #[error("session {} is already being recorded", .0.0)]
+    AlreadyRecording(SessionId),
+    #[error("session {} is not being recorded", .0.0)]
+    NotRecording(SessionId),
+    #[error("failed to start recording: {0}")]
+    Recording(String),
+    #[error("tap {} not found", .0.0)]
+    TapNotFound(TapId),
+    #[error("failed to start tap: {0}")]
+    Tap(String),
+}
+
+// Sessions are normally found by SSRC alone. Sessions created with
+// `demux_by_source` are keyed by (source address, SSRC) instead, so unrelated
+// sources may reuse an SSRC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DemuxKey {
+    Ssrc(u32),
+    Source(SocketAddr, u32),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    pub ssrcs: Vec<u32>,
+    pub mids: Vec<String>,
+    pub demux_by_source: bool,
+    pub description: Option<SessionDescription>,
+    pub simulcast: Vec<LayerSpec>,
+    // Overrides the configured per-session ingress limits.
+    pub rate_limit: Option<RateLimit>,
+    // Overrides the configured FEC mode for the session's subscribers.
+    pub fec: Option<FecConfig>,
+    // Overrides the configured reorder latency.
+    pub reorder: Option<ReorderMode>,
+    // As given in CreateSession; detected from the media otherwise.
+    pub media_type: Option<MediaType>,
+    // Fixed id, e.g. for a session restored from the cluster directory.
+    pub id: Option<SessionId>,
+}
+
+impl SessionOptions {
+    // Every a=ssrc and a=mid in the description becomes part of the session.
+    pub fn from_description(description: SessionDescription) -> Self {
+        Self {
+            ssrcs: description.ssrcs().collect(),
+            mids: description.media.iter().filter_map(|m| m.mid.clone()).collect(),
+            demux_by_source: false,
+            simulcast: LayerSpec::from_description(&description),
+            description: Some(description),
+            rate_limit: None,
+            fec: None,
+            reorder: None,
+            media_type: None,
+            id: None,
+        }
+    }
+}
+
+// Media-level attributes that belong to the source's transport.
+const TRANSPORT_ATTRIBUTES: &[&str] = &[
+    "ice-ufrag", "ice-pwd", "ice-options", "ice-lite", "candidate", "end-of-candidates",
+    "remote-candidates", "fingerprint", "setup", "connection", "tls-id", "crypto", "rtcp",
+    "rtcp-rsize", "msid", "sctp-port", "max-message-size",
+];
+
+#[derive(Debug)]
 pub struct Session {
     pub id: SessionId,
     pub source_addr: SocketAddr,
@@ -34,14 +139,84 @@ pub struct Session {
     pub last_activity: RwLock<Instant>,
     pub packet_count: std::sync::atomic::AtomicU64,
     pub byte_count: std::sync::atomic::AtomicU64,
+    pub tasks: parking_lot::Mutex<Vec<tokio::task::AbortHandle>>,
+    pub description: RwLock<Option<SessionDescription>>,
+    // CNAME from the source's RTCP SDES, for sessions without one in SDP.
+    pub rtcp_cname: RwLock<Option<String>>,
+    pub ingest_ports: RwLock<Option<IngestPorts>>,
+    pub ssrcs: DashSet<u32>,
+    pub mids: DashSet<String>,
+    pub demux_by_source: bool,
+    pub simulcast: SimulcastLayers,
+    pub svc: SvcState,
+    pub policer: Option<Mutex<Policer>>,
+    pub suspended_until: RwLock<Option<Instant>>,
+    pub fec: Option<FecConfig>,
+    pub reorder: Mutex<ReorderBuffer>,
+    // Serializes forwarding of packets released by the reorder buffer.
+    pub reorder_output: tokio::sync::Mutex<()>,
+    pub recorder: RwLock<Option<Recorder>>,
+    pub replay: Mutex<Option<tokio::task::AbortHandle>>,
+    pub declared_media_type: Option<MediaType>,
+    pub media: Mutex<MediaInspector>,
+    pub audio_level: Mutex<AudioLevelMeter>,
+    pub taps: DashMap<TapId, Tap>,
+    // Shared with the session manager, so removed subscribers' tokens go too.
+    pub latches: Arc<LatchRegistry>,
+    keyframe_requests: Mutex<HashMap<u32, Instant>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Subscriber {
     pub addr: SocketAddr,
     pub joined_at: Instant,
     pub last_seq: u16,
     pub packet_count: std::sync::atomic::AtomicU64,
+    pub latch_token: Option<LatchToken>,
+    pub latched: bool,
+    pub transport: SubscriberTransport,
+    pub layer_selector: Arc<Mutex<LayerSelector>>,
+    pub fec: Option<Arc<Mutex<FecProtector>>>,
+    pub filter: SubscriberFilter,
+    // Added by a session group; leaving the group only removes these.
+    pub from_group: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum SubscriberTransport {
+    Udp,
+    // RTSP RTP/AVP/TCP: packets are framed as `$ <channel> <len>` and handed to
+    // the connection's writer task.
+    Interleaved { channel: u8, sink: mpsc::Sender<Vec<u8>> },
+    // RFC 4571: packets are prefixed with a two-byte length. The sink is
+    // bounded so a stalled reader only loses its own packets.
+    Tcp { sink: mpsc::Sender<Vec<u8>> },
+    // One copy is sent to the group regardless of how many receivers joined it.
+    Multicast(MulticastOptions),
+    // A downstream server; packets are prefixed with the relay tunnel header.
+    Relay { header: Arc<Vec<u8>> },
+}
+
+impl Subscriber {
+    pub fn new(addr: SocketAddr, transport: SubscriberTransport) -> Self {
+        Self {
+            addr,
+            joined_at: Instant::now(),
+            last_seq: 0,
+            packet_count: std::sync::atomic::AtomicU64::new(0),
+            latch_token: None,
+            latched: false,
+            transport,
+            layer_selector: Arc::new(Mutex::new(LayerSelector::default())),
+            fec: None,
+            filter: SubscriberFilter::default(),
+            from_group: false,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.latch_token.is_none() || self.latched
+    }
 }
 
 impl Session {
@@ -56,16 +231,57 @@ impl Session {
             last_activity: RwLock::new(now),
             packet_count: std::sync::atomic::AtomicU64::new(0),
             byte_count: std::sync::atomic::AtomicU64::new(0),
+            tasks: parking_lot::Mutex::new(Vec::new()),
+            description: RwLock::new(None),
+            rtcp_cname: RwLock::new(None),
+            ingest_ports: RwLock::new(None),
+            // 0 stands for "not known yet" and is never claimed.
+            ssrcs: DashSet::from_iter(Some(ssrc).filter(|s| *s != 0)),
+            mids: DashSet::new(),
+            demux_by_source: false,
+            simulcast: SimulcastLayers::default(),
+            svc: SvcState::default(),
+            policer: None,
+            suspended_until: RwLock::new(None),
+            fec: None,
+            reorder: Mutex::new(ReorderBuffer::new(ReorderMode::Bypass, 0)),
+            reorder_output: tokio::sync::Mutex::new(()),
+            recorder: RwLock::new(None),
+            replay: Mutex::new(None),
+            declared_media_type: None,
+            media: Mutex::new(MediaInspector::default()),
+            audio_level: Mutex::new(AudioLevelMeter::default()),
+            latches: Arc::new(LatchRegistry::new()),
+            keyframe_requests: Mutex::new(HashMap::new()),
+            taps: DashMap::new(),
+        }
+    }
+
+    pub fn demux_key(&self, ssrc: u32) -> DemuxKey {
+        if self.demux_by_source {
+            DemuxKey::Source(self.source_addr, ssrc)
+        } else {
+            DemuxKey::Ssrc(ssrc)
+        }
+    }
+
+    // The SSRC FlexFEC repair packets protecting `ssrc` are sent on.
+    pub fn repair_ssrc(&self, ssrc: u32) -> Option<u32> {
+        match self.fec?.scheme {
+            FecScheme::FlexFec { .. } => Some(fec::flexfec_ssrc(ssrc)),
+            FecScheme::UlpfecRed { .. } => None,
         }
     }
 
     pub fn add_subscriber(&self, addr: SocketAddr) -> bool {
-        let subscriber = Subscriber {
-            addr,
-            joined_at: Instant::now(),
-            last_seq: 0,
-            packet_count: std::sync::atomic::AtomicU64::new(0),
-        };
+        self.add_subscriber_with_transport(addr, SubscriberTransport::Udp)
+    }
+
+    pub fn add_subscriber_with_transport(&self, addr: SocketAddr, transport: SubscriberTransport) -> bool {
+        let mut subscriber = Subscriber::new(addr, transport);
+        if let Some(config) = self.fec {
+            subscriber.fec = self.fec_encoder(&subscriber.transport, config);
+        }
 
         self.subscribers.insert(addr, subscriber);
         *self.last_activity.write() = Instant::now();
@@ -75,13 +291,155 @@ impl Session {
         true
     }
 
+    // A subscriber the session already has, added directly, is left as it is.
+    pub fn add_group_subscriber(&self, addr: SocketAddr, transport: SubscriberTransport) -> bool {
+        if self.subscribers.contains_key(&addr) {
+            return false;
+        }
+        self.add_subscriber_with_transport(addr, transport);
+        if let Some(mut subscriber) = self.subscribers.get_mut(&addr) {
+            subscriber.from_group = true;
+        }
+        true
+    }
+
+    pub fn remove_group_subscriber(&self, addr: &SocketAddr) -> bool {
+        if !self.subscribers.get(addr).is_some_and(|s| s.from_group) {
+            return false;
+        }
+        self.remove_subscriber(addr)
+    }
+
+    pub fn add_multicast_egress(&self, group: SocketAddr, options: MulticastOptions) -> bool {
+        if !group.ip().is_multicast() {
+            warn!("Refusing multicast egress to non-multicast address {}", group);
+            return false;
+        }
+        self.add_subscriber_with_transport(group, SubscriberTransport::Multicast(options))
+    }
+
+    pub fn add_latching_subscriber(&self, claimed_addr: SocketAddr, token: LatchToken) -> bool {
+        let mut subscriber = Subscriber::new(claimed_addr, SubscriberTransport::Udp);
+        subscriber.latch_token = Some(token);
+        if let Some(config) = self.fec {
+            subscriber.fec = self.fec_encoder(&subscriber.transport, config);
+        }
+
+        self.subscribers.insert(claimed_addr, subscriber);
+        *self.last_activity.write() = Instant::now();
+
+        info!("Added latching subscriber {} to session {} (awaiting punch)",
+              claimed_addr, self.id.0);
+        true
+    }
+
+    pub fn relatch_subscriber(
+        &self,
+        old_addr: &SocketAddr,
+        new_addr: SocketAddr,
+        transport: Option<SubscriberTransport>,
+    ) -> bool {
+        if self.subscribers.contains_key(&new_addr) {
+            warn!("Refusing to latch {} onto {} in session {}: already a subscriber", old_addr, new_addr, self.id.0);
+            return false;
+        }
+        let Some((_, mut subscriber)) = self.subscribers.remove(old_addr) else {
+            return false;
+        };
+
+        subscriber.addr = new_addr;
+        subscriber.latched = true;
+        if let Some(transport) = transport {
+            if !matches!(transport, SubscriberTransport::Udp | SubscriberTransport::Multicast(_)) {
+                subscriber.fec = None;
+            }
+            subscriber.transport = transport;
+        }
+        self.subscribers.insert(new_addr, subscriber);
+        *self.last_activity.write() = Instant::now();
+
+        info!("Latched subscriber {} -> {} in session {}", old_addr, new_addr, self.id.0);
+        true
+    }
+
+    pub fn set_subscriber_layer(&self, addr: &SocketAddr, target: LayerTarget, max_bitrate_bps: Option<u64>) -> bool {
+        let Some(subscriber) = self.subscribers.get(addr) else {
+            return false;
+        };
+        let mut selector = subscriber.layer_selector.lock();
+        selector.target = target;
+        selector.max_bitrate_bps = max_bitrate_bps;
+        true
+    }
+
+    pub fn set_subscriber_svc_ceiling(&self, addr: &SocketAddr, max_spatial: Option<u8>, max_temporal: Option<u8>) -> bool {
+        let Some(subscriber) = self.subscribers.get(addr) else {
+            return false;
+        };
+        let mut selector = subscriber.layer_selector.lock();
+        selector.max_spatial = max_spatial;
+        selector.max_temporal = max_temporal;
+        true
+    }
+
+    // FEC only makes sense where packets can be lost.
+    fn fec_encoder(&self, transport: &SubscriberTransport, config: FecConfig) -> Option<Arc<Mutex<FecProtector>>> {
+        match transport {
+            SubscriberTransport::Udp | SubscriberTransport::Multicast(_) => {
+                Some(Arc::new(Mutex::new(FecProtector::new(config))))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set_subscriber_fec(&self, addr: &SocketAddr, config: Option<FecConfig>) -> bool {
+        let Some(mut subscriber) = self.subscribers.get_mut(addr) else {
+            return false;
+        };
+        subscriber.fec = config.and_then(|config| self.fec_encoder(&subscriber.transport, config));
+        true
+    }
+
+    // Only packets matching the filter are sent to this subscriber.
+    pub fn set_subscriber_filter(&self, addr: &SocketAddr, filter: SubscriberFilter) -> bool {
+        let Some(mut subscriber) = self.subscribers.get_mut(addr) else {
+            return false;
+        };
+        debug!("Subscriber {} of session {} filter: {:?}", addr, self.id.0, filter);
+        subscriber.filter = filter;
+        true
+    }
+
+    pub fn packet_kind(&self, packet: &RtpPacket, codec: Codec) -> Option<PacketKind> {
+        PacketKind::classify(self.description.read().as_ref(), packet.payload_type, codec)
+    }
+
+    pub fn fec_stats(&self) -> FecStats {
+        let mut stats = FecStats::default();
+        for subscriber in self.subscribers.iter() {
+            if let Some(fec) = &subscriber.fec {
+                stats.merge(&fec.lock().stats());
+            }
+        }
+        stats
+    }
+
+    pub fn update_bandwidth_estimate(&self, addr: &SocketAddr, bitrate_bps: u64) {
+        if let Some(subscriber) = self.subscribers.get(addr) {
+            subscriber.layer_selector.lock().estimated_bps = Some(bitrate_bps);
+        }
+    }
+
     pub fn remove_subscriber(&self, addr: &SocketAddr) -> bool {
-        let removed = self.subscribers.remove(addr).is_some();
-        if removed {
-            *self.last_activity.write() = Instant::now();
-            debug!("Removed subscriber {} from session {}", addr, self.id.0);
+        let Some((_, subscriber)) = self.subscribers.remove(addr) else {
+            return false;
+        };
+        if let Some(token) = subscriber.latch_token {
+            self.latches.remove(&token);
         }
-        removed
+        *self.last_activity.write() = Instant::now();
+        debug!("Removed subscriber {} from session {}", addr, self.id.0);
+        true
     }
 
     pub fn is_expired(&self, timeout: Duration) -> bool {
@@ -89,40 +447,637 @@ impl Session {
         last.elapsed() > timeout
     }
 
+    // Ingress policing with the `suspend` action stops forwarding the session
+    // for a while.
+    // Returns false, leaving the end unchanged, if already suspended.
+    pub fn suspend(&self, duration: Duration, now: Instant) -> bool {
+        let mut until = self.suspended_until.write();
+        if until.is_some_and(|until| now < until) {
+            return false;
+        }
+        *until = Some(now + duration);
+        true
+    }
+
+    pub fn is_suspended(&self, now: Instant) -> bool {
+        self.suspended_until.read().is_some_and(|until| now < until)
+    }
+
+    pub fn cname(&self) -> Option<String> {
+        let declared = self.description.read().as_ref().and_then(|d| d.cname().map(str::to_string));
+        declared.or_else(|| self.rtcp_cname.read().clone())
+    }
+
+    // Returns true when the CNAME is new for this session.
+    pub fn learn_cname(&self, cname: String) -> bool {
+        let mut learned = self.rtcp_cname.write();
+        if learned.as_ref() == Some(&cname) {
+            return false;
+        }
+        *learned = Some(cname);
+        true
+    }
+
     pub fn record_activity(&self) {
         *self.last_activity.write() = Instant::now();
     }
+
+    pub fn set_description(&self, description: SessionDescription) {
+        *self.description.write() = Some(description);
+    }
+
+    // Codec and clock rate of a payload type, from the source SDP. Sessions
+    // without one use what was sniffed from the payloads, or assume H.264.
+    pub fn media_format(&self, payload_type: u8) -> (Codec, u32) {
+        self.declared_format(payload_type)
+            .or_else(|| self.media.lock().detected(payload_type))
+            .unwrap_or((Codec::H264, 90000))
+    }
+
+    fn is_auxiliary(&self, payload_type: u8) -> bool {
+        let description = self.description.read();
+        description
+            .as_ref()
+            .and_then(|d| d.media.iter().find_map(|m| m.format(payload_type)))
+            .is_some_and(|f| Codec::is_auxiliary_encoding(&f.encoding))
+    }
+
+    fn declared_format(&self, payload_type: u8) -> Option<(Codec, u32)> {
+        let description = self.description.read();
+        let format = description
+            .as_ref()?
+            .media
+            .iter()
+            .find_map(|m| m.format(payload_type))
+            .map(|f| (Codec::from_encoding(&f.encoding), f.clock_rate));
+        Some(format.unwrap_or((Codec::Unknown, 90000)))
+    }
+
+    // Runs the packet through payload inspection and returns its format.
+    pub fn inspect(&self, packet: &RtpPacket) -> (Codec, u32) {
+        // Data sessions aren't sniffed for a codec.
+        let declared = self.declared_format(packet.payload_type).or_else(|| {
+            (self.declared_media_type == Some(MediaType::Data)).then_some((Codec::Unknown, 90000))
+        });
+        match declared {
+            Some(format) if self.is_auxiliary(packet.payload_type) => format,
+            _ => self.media.lock().observe(packet, declared, Instant::now()),
+        }
+    }
+
+    // Feeds the RFC 6464 level, when the SDP negotiated the extension.
+    pub fn observe_audio_level(&self, packet: &RtpPacket) {
+        let Some(extension) = packet.extension.as_ref() else {
+            return;
+        };
+        let id = self
+            .description
+            .read()
+            .as_ref()
+            .and_then(|d| d.media.iter().find_map(|m| m.extmap_id(AUDIO_LEVEL_URI)));
+        if let Some(level) = id.and_then(|id| extension.element(id)).and_then(audio_level::parse_audio_level) {
+            self.audio_level.lock().observe(level, Instant::now());
+        }
+    }
+
+    // Smoothed dBov; None when the source isn't sending levels.
+    pub fn audio_level_dbov(&self) -> Option<f64> {
+        self.audio_level.lock().level_dbov(Instant::now())
+    }
+
+    // Taps and recordings want the datagram as it arrived; everyone else
+    // makes do with the parsed packet.
+    pub fn wants_raw(&self) -> bool {
+        !self.taps.is_empty() || self.recorder.read().is_some()
+    }
+
+    // Offers an ingress packet to the session's taps, dropping finished ones.
+    pub fn mirror(&self, packet: &RtpPacket, data: &Arc<[u8]>) {
+        let now = Instant::now();
+        let finished: Vec<TapId> = self
+            .taps
+            .iter()
+            .filter(|tap| !tap.mirror(packet, data, now))
+            .map(|tap| *tap.key())
+            .collect();
+        for id in finished {
+            if self.taps.remove(&id).is_some() {
+                debug!("Tap {} on session {} finished", id.0, self.id.0);
+            }
+        }
+    }
+
+    // True when no keyframe was requested for `ssrc` recently; the request
+    // is then counted as made.
+    pub fn keyframe_request_due(&self, ssrc: u32, now: Instant) -> bool {
+        let mut requests = self.keyframe_requests.lock();
+        match requests.get(&ssrc) {
+            Some(at) if now.saturating_duration_since(*at) < KEYFRAME_REQUEST_INTERVAL => false,
+            _ => {
+                requests.insert(ssrc, now);
+                true
+            }
+        }
+    }
+
+    pub fn tap_statuses(&self) -> Vec<TapStatus> {
+        let now = Instant::now();
+        self.taps.retain(|_, tap| !tap.is_finished(now));
+        self.taps.iter().map(|tap| tap.status(now)).collect()
+    }
+
+    pub fn media_stats(&self) -> Vec<MediaStreamStats> {
+        self.media.lock().stats(Instant::now())
+    }
+
+    // As declared at creation, else from the SDP, else from the media seen.
+    pub fn media_type(&self) -> Option<MediaType> {
+        if let Some(media_type) = self.declared_media_type {
+            return Some(media_type);
+        }
+        let kinds: Vec<MediaKind> = match self.description.read().as_ref() {
+            Some(description) => description.media.iter().map(|m| m.kind).collect(),
+            None => Vec::new(),
+        };
+        if kinds.contains(&MediaKind::Video) {
+            Some(MediaType::Video)
+        } else if kinds.contains(&MediaKind::Audio) {
+            Some(MediaType::Audio)
+        } else if kinds.contains(&MediaKind::Application) {
+            Some(MediaType::Data)
+        } else {
+            self.media.lock().media_type()
+        }
+    }
+
+    // Every payload type in the source SDP; empty without one.
+    pub fn payload_formats(&self) -> Vec<PayloadFormat> {
+        self.description
+            .read()
+            .iter()
+            .flat_map(|d| d.media.iter().flat_map(|m| m.formats.iter()))
+            .map(|f| PayloadFormat {
+                payload_type: f.payload_type,
+                codec: Codec::from_encoding(&f.encoding),
+                clock_rate: f.clock_rate,
+                channels: f.channels.unwrap_or(1),
+            })
+            .collect()
+    }
+
+    // True when the packet belongs to one of the session's simulcast layers.
+    pub fn observe_layer(&self, packet: &RtpPacket) -> bool {
+        if self.simulcast.is_empty() {
+            return false;
+        }
+        let rid_ext = self
+            .description
+            .read()
+            .as_ref()
+            .and_then(|d| d.media.iter().find_map(|m| m.extmap_id(SDES_RTP_STREAM_ID_URI)));
+        self.simulcast.observe(packet, rid_ext)
+    }
+
+    pub fn svc_layer(&self, packet: &RtpPacket, codec: Codec) -> Option<SvcLayer> {
+        let dd_ext = match codec {
+            Codec::Av1 => self
+                .description
+                .read()
+                .as_ref()
+                .and_then(|d| d.media.iter().find_map(|m| m.extmap_id(AV1_DEPENDENCY_DESCRIPTOR_URI))),
+            _ => None,
+        };
+        self.svc.layer_of(packet, codec, dd_ext)
+    }
+
+    // The description offered to subscribers: the source's codecs and streams
+    // with the server as the sending side, without the source's transport
+    // (ICE, DTLS, candidates and bundling describe the source's leg, not the
+    // plain RTP the server sends). Sessions created without an SDP advertise
+    // a single dynamic video track.
+    pub fn subscriber_description(&self, server_ip: IpAddr) -> SessionDescription {
+        let mut description = SessionDescription::new(&format!("rtp-fanout {}", self.id.0), server_ip);
+        let family = if server_ip.is_ipv4() { "IP4 0.0.0.0" } else { "IP6 ::" };
+        description.connection = Some(format!("IN {}", family));
+
+        match self.description.read().as_ref() {
+            Some(source) => {
+                description.attributes = source
+                    .attributes
+                    .iter()
+                    .filter(|a| a.starts_with("group:") && !a.starts_with("group:BUNDLE"))
+                    .cloned()
+                    .collect();
+                description.media = source.media.clone();
+            }
+            None => {
+                let mut media = MediaDescription::new(MediaKind::Video);
+                media.payload_types.push(96);
+                media.formats.push(RtpMap {
+                    payload_type: 96,
+                    encoding: "H264".to_string(),
+                    clock_rate: 90000,
+                    channels: None,
+                    fmtp: None,
+                    rtcp_fb: Vec::new(),
+                });
+                media.ssrcs.push(SsrcAttribute { ssrc: self.ssrc, cname: None });
+                description.media.push(media);
+            }
+        }
+
+        for media in &mut description.media {
+            media.port = 0;
+            media.protocol = if media.protocol.ends_with("AVPF") { "RTP/AVPF" } else { "RTP/AVP" }.to_string();
+            media.connection = None;
+            media.rtcp_mux = false;
+            media.direction = Some(Direction::SendOnly);
+            media.attributes.retain(|a| {
+                let name = a.split(':').next().unwrap_or_default();
+                !TRANSPORT_ATTRIBUTES.contains(&name)
+            });
+        }
+        description
+    }
+
+    // Background tasks feeding or draining this session (e.g. multicast
+    // ingest) are aborted when the session is removed.
+    // Finished tasks are dropped here so repeated replays don't pile up.
+    pub fn attach_task(&self, handle: tokio::task::AbortHandle) {
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(handle);
+    }
+
+    pub fn abort_tasks(&self) {
+        for handle in self.tasks.lock().drain(..) {
+            handle.abort();
+        }
+    }
 }
 
 pub struct SessionManager {
     config: ServerConfig,
     sessions: DashMap<SessionId, Arc<Session>>,
-    ssrc_index: DashMap<u32, SessionId>,
+    ssrc_index: DashMap<DemuxKey, SessionId>,
+    mid_index: DashMap<(SocketAddr, String), SessionId>,
+    latches: Arc<LatchRegistry>,
+    ports: Option<PortAllocator>,
+    events: EventBus,
 }
 
 impl SessionManager {
     pub fn new(config: ServerConfig) -> Self {
+        let ports = config.enable_session_ports.then(|| {
+            PortAllocator::new(
+                config.session_port_range_start,
+                config.session_port_range_end,
+                config.session_port_pairs,
+            )
+        });
+
         Self {
             config,
             sessions: DashMap::with_capacity(1024),
             ssrc_index: DashMap::new(),
+            mid_index: DashMap::new(),
+            latches: Arc::new(LatchRegistry::new()),
+            ports,
+            events: EventBus::new(),
+        }
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn start_recording(&self, id: &SessionId, format: RecordingFormat) -> Result<(), SessionError> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound(*id))?;
+        let mut recorder = session.recorder.write();
+        if recorder.is_some() {
+            return Err(SessionError::AlreadyRecording(*id));
+        }
+
+        // pcapng packets are addressed to where the source actually sends.
+        let mut local: SocketAddr = self
+            .config
+            .bind_address
+            .parse()
+            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+        if let Some(ports) = session.ingest_ports.read().as_ref() {
+            local.set_port(ports.rtp);
+        }
+
+        let mut options = RecordingOptions::from_config(&self.config, format);
+        options.payload_formats = session.payload_formats();
+        if format.is_container() && container::recordable_codecs(&options.payload_formats).is_empty() {
+            return Err(SessionError::Recording(format!(
+                "session has no H.264, H.265 or Opus stream to record as {}",
+                format.extension()
+            )));
+        }
+        let started = Recorder::start(*id, session.source_addr, local, options)
+            .map_err(|e| SessionError::Recording(e.to_string()))?;
+        *recorder = Some(started);
+        Ok(())
+    }
+
+    // Returns once everything queued has been written.
+    pub async fn stop_recording(&self, id: &SessionId) -> Result<RecordingStatus, SessionError> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound(*id))?;
+        let recorder = session.recorder.write().take().ok_or(SessionError::NotRecording(*id))?;
+        let status = recorder.stop().await;
+        info!("Stopped recording session {} ({} packets, {} files)", id.0, status.packets, status.files.len());
+        Ok(status)
+    }
+
+    pub fn recording_status(&self, id: &SessionId) -> Option<RecordingStatus> {
+        let session = self.get_session(id)?;
+        let recorder = session.recorder.read();
+        recorder.as_ref().map(|r| r.status())
+    }
+
+    // Mirrors the session's ingress to a debug destination. Stream taps return
+    // the receiving end to the caller.
+    pub fn start_tap(
+        &self,
+        id: &SessionId,
+        target: TapTarget,
+        options: TapOptions,
+    ) -> Result<(TapId, Option<TapReceiver>), SessionError> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound(*id))?;
+        let (tap, receiver) = Tap::new(target, options.clamp(&self.config));
+        let receiver = match target {
+            TapTarget::Udp(destination) => {
+                tap::spawn_udp(receiver, destination).map_err(|e| SessionError::Tap(e.to_string()))?;
+                None
+            }
+            TapTarget::Stream => Some(receiver),
+        };
+
+        let tap_id = tap.id;
+        info!("Tapping session {} to {:?} ({:?})", id.0, target, tap.options);
+        session.taps.insert(tap_id, tap);
+        Ok((tap_id, receiver))
+    }
+
+    pub fn stop_tap(&self, id: &SessionId, tap_id: &TapId) -> Result<TapStatus, SessionError> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound(*id))?;
+        let (_, tap) = session.taps.remove(tap_id).ok_or(SessionError::TapNotFound(*tap_id))?;
+        info!("Stopped tap {} on session {}", tap_id.0, id.0);
+        Ok(tap.status(Instant::now()))
+    }
+
+    pub fn sessions_with_cname(&self, cname: &str) -> Vec<Arc<Session>> {
+        self.sessions
+            .iter()
+            .filter(|entry| entry.value().cname().as_deref() == Some(cname))
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    pub fn reordering_sessions(&self) -> Vec<Arc<Session>> {
+        self.sessions
+            .iter()
+            .filter(|entry| !entry.value().reorder.lock().is_bypassed())
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    // Transport-cc feedback also tells FEC encoders which losses were
+    // repairable.
+    pub fn on_transport_feedback(&self, addr: &SocketAddr, feedback: &[TwccFeedback]) {
+        for session in self.sessions.iter() {
+            let Some(subscriber) = session.subscribers.get(addr) else {
+                continue;
+            };
+            if let Some(fec) = &subscriber.fec {
+                let mut fec = fec.lock();
+                for report in feedback {
+                    fec.on_feedback(report);
+                }
+            }
         }
     }
 
-    pub fn create_session(&self, source_addr: SocketAddr, ssrc: u32) -> Option<Arc<Session>> {
+    pub fn create_session(&self, source_addr: SocketAddr, ssrc: u32) -> Result<Arc<Session>, SessionError> {
+        self.create_session_with_options(source_addr, ssrc, SessionOptions::default())
+    }
+
+    pub fn create_session_with_description(
+        &self,
+        source_addr: SocketAddr,
+        ssrc: u32,
+        description: SessionDescription,
+    ) -> Result<Arc<Session>, SessionError> {
+        self.create_session_with_options(source_addr, ssrc, SessionOptions::from_description(description))
+    }
+
+    // `ssrc` may be 0 when the options list the session's SSRCs.
+    pub fn create_session_with_options(
+        &self,
+        source_addr: SocketAddr,
+        ssrc: u32,
+        options: SessionOptions,
+    ) -> Result<Arc<Session>, SessionError> {
         if self.sessions.len() >= self.config.max_sessions {
             warn!("Maximum session limit reached ({})", self.config.max_sessions);
-            return None;
+            return Err(SessionError::SessionLimit(self.config.max_sessions));
         }
 
-        let id = SessionId::new();
-        let session = Arc::new(Session::new(id, source_addr, ssrc));
-        
+        let primary = match ssrc {
+            0 => options.ssrcs.first().copied().unwrap_or(0),
+            ssrc => ssrc,
+        };
+
+        let id = options.id.unwrap_or_default();
+        let mut session = Session::new(id, source_addr, primary);
+        session.demux_by_source = options.demux_by_source;
+        session.declared_media_type = options.media_type;
+        for ssrc in &options.ssrcs {
+            session.ssrcs.insert(*ssrc);
+        }
+        for mid in &options.mids {
+            session.mids.insert(mid.clone());
+        }
+        for ssrc in options.simulcast.iter().filter_map(|layer| layer.ssrc) {
+            session.ssrcs.insert(ssrc);
+        }
+        session.simulcast = SimulcastLayers::new(options.simulcast);
+        session.fec = options.fec.or_else(|| FecConfig::from_config(&self.config));
+        session.latches = self.latches.clone();
+        let reorder = options.reorder.unwrap_or_else(|| ReorderMode::from_config(&self.config));
+        session.reorder = Mutex::new(ReorderBuffer::new(reorder, self.config.reorder_max_packets));
+        let rate_limit = options.rate_limit.unwrap_or_else(|| RateLimit::session(&self.config));
+        if !rate_limit.is_unlimited() {
+            session.policer = Some(Mutex::new(Policer::new(rate_limit, Instant::now())));
+        }
+        if let Some(description) = options.description {
+            session.set_description(description);
+        }
+        let session = Arc::new(session);
+
+        self.index_session(&session)?;
         self.sessions.insert(id, session.clone());
-        self.ssrc_index.insert(ssrc, id);
-        
-        info!("Created session {} for SSRC {} from {}", id.0, ssrc, source_addr);
-        Some(session)
+
+        info!("Created session {} for SSRC {} from {} ({} SSRCs, {} MIDs)",
+              id.0, primary, source_addr, session.ssrcs.len(), session.mids.len());
+        Ok(session)
+    }
+
+    // Claims every SSRC and MID of the session in the demux indexes, undoing
+    // any partial claim on collision.
+    fn index_session(&self, session: &Session) -> Result<(), SessionError> {
+        let ssrcs: Vec<u32> = session.ssrcs.iter().map(|s| *s).collect();
+        let mids: Vec<String> = session.mids.iter().map(|m| m.clone()).collect();
+
+        for (i, ssrc) in ssrcs.iter().enumerate() {
+            if let Err(e) = self.claim_stream(session, *ssrc) {
+                for claimed in &ssrcs[..i] {
+                    self.unclaim_stream(session, *claimed);
+                }
+                return Err(e);
+            }
+        }
+
+        for (i, mid) in mids.iter().enumerate() {
+            let key = (session.source_addr, mid.clone());
+            match self.mid_index.entry(key) {
+                dashmap::mapref::entry::Entry::Occupied(existing) => {
+                    let existing = *existing.get();
+                    for claimed in &mids[..i] {
+                        self.mid_index.remove(&(session.source_addr, claimed.clone()));
+                    }
+                    for ssrc in &ssrcs {
+                        self.unclaim_stream(session, *ssrc);
+                    }
+                    warn!("MID {} from {} collides with session {}", mid, session.source_addr, existing.0);
+                    return Err(SessionError::MidCollision {
+                        mid: mid.clone(),
+                        source_addr: session.source_addr,
+                        existing,
+                    });
+                }
+                dashmap::mapref::entry::Entry::Vacant(slot) => {
+                    slot.insert(session.id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn claim_ssrc(&self, session: &Session, ssrc: u32) -> Result<(), SessionError> {
+        match self.ssrc_index.entry(session.demux_key(ssrc)) {
+            dashmap::mapref::entry::Entry::Occupied(existing) if *existing.get() != session.id => {
+                let existing = *existing.get();
+                warn!("SSRC {} collides with session {}", ssrc, existing.0);
+                Err(SessionError::SsrcCollision { ssrc, existing })
+            }
+            dashmap::mapref::entry::Entry::Occupied(_) => Ok(()),
+            dashmap::mapref::entry::Entry::Vacant(slot) => {
+                slot.insert(session.id);
+                Ok(())
+            }
+        }
+    }
+
+    // A stream's SSRC plus, for FlexFEC sessions, the repair SSRC derived
+    // from it, which mustn't clash with any stream either.
+    fn claim_stream(&self, session: &Session, ssrc: u32) -> Result<(), SessionError> {
+        let Some(repair) = session.repair_ssrc(ssrc) else {
+            return self.claim_ssrc(session, ssrc);
+        };
+        // The mapping is its own inverse: a stream whose repair SSRC is a
+        // stream of this session is that stream's repair SSRC.
+        if session.ssrcs.contains(&repair) {
+            warn!("SSRC {} collides with FlexFEC repair of session {}", ssrc, session.id.0);
+            return Err(SessionError::SsrcCollision { ssrc, existing: session.id });
+        }
+        self.claim_ssrc(session, ssrc)?;
+        let claimed = self.claim_ssrc(session, repair);
+        if claimed.is_err() {
+            self.ssrc_index.remove_if(&session.demux_key(ssrc), |_, owner| *owner == session.id);
+        }
+        claimed
+    }
+
+    fn unclaim_stream(&self, session: &Session, ssrc: u32) {
+        for ssrc in std::iter::once(ssrc).chain(session.repair_ssrc(ssrc)) {
+            self.ssrc_index.remove_if(&session.demux_key(ssrc), |_, owner| *owner == session.id);
+        }
+    }
+
+    pub fn add_session_ssrc(&self, id: &SessionId, ssrc: u32) -> Result<(), SessionError> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound(*id))?;
+        self.claim_stream(&session, ssrc)?;
+        session.ssrcs.insert(ssrc);
+        debug!("Session {} now owns SSRC {}", id.0, ssrc);
+        Ok(())
+    }
+
+    // Finds the session a packet belongs to: (source, SSRC) first, then SSRC
+    // alone, then the MID header extension for SSRCs not yet signalled. A
+    // match by MID teaches the session the new SSRC.
+    pub fn resolve(&self, packet: &RtpPacket) -> Option<Arc<Session>> {
+        if let Some(source) = packet.source {
+            if let Some(session) = self.lookup(&DemuxKey::Source(source, packet.ssrc)) {
+                return Some(session);
+            }
+        }
+        if let Some(session) = self.lookup(&DemuxKey::Ssrc(packet.ssrc)) {
+            return Some(session);
+        }
+
+        let source = packet.source?;
+        let extension = packet.extension.as_ref()?;
+        for (ext_id, value) in extension.elements() {
+            let Ok(mid) = std::str::from_utf8(value) else {
+                continue;
+            };
+            let Some(session) = self
+                .mid_index
+                .get(&(source, mid.to_string()))
+                .and_then(|id| self.get_session(&id))
+            else {
+                continue;
+            };
+
+            // Without a negotiated extmap there is no telling which element
+            // carries the MID.
+            let mid_ext = session
+                .description
+                .read()
+                .as_ref()
+                .and_then(|d| d.media.iter().find_map(|m| m.extmap_id(SDES_MID_URI)));
+            if mid_ext != Some(ext_id) {
+                continue;
+            }
+
+            if self.claim_stream(&session, packet.ssrc).is_ok() {
+                session.ssrcs.insert(packet.ssrc);
+                info!("Learned SSRC {} for MID {} in session {}", packet.ssrc, mid, session.id.0);
+            }
+            return Some(session);
+        }
+
+        None
+    }
+
+    // The session whose source sent this sender report, matched by the
+    // sender SSRC and the source's address.
+    pub fn source_of_rtcp(&self, data: &[u8], from: SocketAddr) -> Option<Arc<Session>> {
+        if !crate::tcp::is_sender_report(data) {
+            return None;
+        }
+        let ssrc = crate::tcp::sender_ssrc(data)?;
+        self.lookup(&DemuxKey::Source(from, ssrc))
+            .or_else(|| self.lookup(&DemuxKey::Ssrc(ssrc)))
+            .filter(|session| session.source_addr.ip() == from.ip())
+    }
+
+    fn lookup(&self, key: &DemuxKey) -> Option<Arc<Session>> {
+        self.ssrc_index.get(key).and_then(|id| self.get_session(&id))
     }
 
     pub fn get_session(&self, id: &SessionId) -> Option<Arc<Session>> {
@@ -130,14 +1085,22 @@ impl SessionManager {
     }
 
     pub fn get_session_by_ssrc(&self, ssrc: u32) -> Option<Arc<Session>> {
-        self.ssrc_index
-            .get(&ssrc)
-            .and_then(|id| self.get_session(&id))
+        self.lookup(&DemuxKey::Ssrc(ssrc))
     }
 
     pub fn remove_session(&self, id: &SessionId) -> bool {
         if let Some((_, session)) = self.sessions.remove(id) {
-            self.ssrc_index.remove(&session.ssrc);
+            for ssrc in session.ssrcs.iter() {
+                self.unclaim_stream(&session, *ssrc);
+            }
+            for mid in session.mids.iter() {
+                self.mid_index.remove_if(&(session.source_addr, mid.clone()), |_, owner| owner == id);
+            }
+            self.latches.remove_session(id);
+            session.abort_tasks();
+            // The writer finishes the current file on its own.
+            session.recorder.write().take();
+            self.release_ingest_ports(&session);
             info!("Removed session {}", id.0);
             true
         } else {
@@ -145,6 +1108,85 @@ impl SessionManager {
         }
     }
 
+    pub fn allocate_ingest_ports(&self, session: &Session) -> Option<IngestPorts> {
+        let ports = self.ports.as_ref()?.allocate()?;
+        *session.ingest_ports.write() = Some(ports);
+        Some(ports)
+    }
+
+    pub fn release_ingest_ports(&self, session: &Session) {
+        if let (Some(allocator), Some(ports)) = (&self.ports, session.ingest_ports.write().take()) {
+            allocator.release(ports);
+        }
+    }
+
+    pub fn add_latching_subscriber(
+        &self,
+        id: &SessionId,
+        claimed_addr: SocketAddr,
+    ) -> Option<LatchToken> {
+        let session = self.get_session(id)?;
+        let token = LatchToken::new();
+
+        session.add_latching_subscriber(claimed_addr, token);
+        self.latches.register(token, *id, claimed_addr);
+        Some(token)
+    }
+
+    pub fn latch_subscriber(&self, token: &LatchToken, observed_addr: SocketAddr) -> Option<SessionId> {
+        self.latch(token, observed_addr, None)
+    }
+
+    pub fn latch_stream_subscriber(
+        &self,
+        token: &LatchToken,
+        peer_addr: SocketAddr,
+        transport: SubscriberTransport,
+    ) -> Option<SessionId> {
+        self.latch(token, peer_addr, Some(transport))
+    }
+
+    fn latch(
+        &self,
+        token: &LatchToken,
+        observed_addr: SocketAddr,
+        transport: Option<SubscriberTransport>,
+    ) -> Option<SessionId> {
+        let binding = self.latches.lookup(token)?;
+        let Some(session) = self.get_session(&binding.session_id) else {
+            self.latches.remove(token);
+            return None;
+        };
+
+        if binding.addr == observed_addr {
+            let Some(mut subscriber) = session.subscribers.get_mut(&observed_addr) else {
+                self.latches.remove(token);
+                return None;
+            };
+            subscriber.latched = true;
+            if let Some(transport) = transport {
+                subscriber.transport = transport;
+            }
+            return Some(binding.session_id);
+        }
+
+        // Another subscriber already receives at that address; the token
+        // stays valid for a punch from elsewhere.
+        if session.subscribers.contains_key(&observed_addr) {
+            debug!("Not latching token {} onto existing subscriber {}", token, observed_addr);
+            return None;
+        }
+
+        if !session.relatch_subscriber(&binding.addr, observed_addr, transport) {
+            debug!("Dropping stale latch token {} for session {}", token, binding.session_id.0);
+            self.latches.remove(token);
+            return None;
+        }
+
+        self.latches.update(token, observed_addr);
+        Some(binding.session_id)
+    }
+
     pub fn cleanup_expired_sessions(&self) {
         let timeout = Duration::from_secs(self.config.session_timeout_secs);
         let expired: Vec<_> = self
@@ -159,6 +1201,136 @@ impl SessionManager {
         }
     }
 
+    // Reports video streams that stopped delivering frames, and recovered ones.
+    pub fn check_frozen_video(&self, threshold: Duration) {
+        let now = Instant::now();
+        let mut frozen = 0;
+        for session in self.sessions.iter() {
+            let mut media = session.media.lock();
+            for change in media.check_freezes(now, threshold) {
+                let event = match change {
+                    FreezeChange::Frozen { ssrc, stalled } => {
+                        warn!("Video SSRC {} of session {} frozen", ssrc, session.id.0);
+                        MetricsCollector::record_video_freeze();
+                        ServerEvent::VideoFrozen {
+                            session_id: session.id,
+                            ssrc,
+                            stalled_ms: stalled.as_millis() as u64,
+                        }
+                    }
+                    FreezeChange::Resumed { ssrc, frozen_for } => {
+                        info!("Video SSRC {} of session {} resumed after {:?}", ssrc, session.id.0, frozen_for);
+                        ServerEvent::VideoResumed {
+                            session_id: session.id,
+                            ssrc,
+                            frozen_ms: frozen_for.as_millis() as u64,
+                        }
+                    }
+                };
+                self.events.publish(event);
+            }
+            frozen += media.frozen_streams();
+        }
+        MetricsCollector::update_frozen_streams(frozen);
+    }
+
+    pub async fn run_freeze_detector(self: Arc<Self>, threshold: Duration) {
+        let mut interval = tokio::time::interval((threshold / 4).max(FREEZE_CHECK_MIN_INTERVAL));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            self.check_frozen_video(threshold);
+        }
+    }
+
+    // The session's subscriber description plus the extensions this server
+    // adds on egress.
+    pub fn subscriber_description(&self, session: &Session, server_ip: IpAddr) -> SessionDescription {
+        let mut description = session.subscriber_description(server_ip);
+        if self.config.enable_twcc {
+            let id = self.config.twcc_extension_id;
+            for media in &mut description.media {
+                media.extmaps.retain(|e| e.id != id && e.uri != TRANSPORT_CC_URI);
+                media.extmaps.push(Extmap { id, uri: TRANSPORT_CC_URI.to_string() });
+            }
+        }
+        if let Some(config) = session.fec {
+            Self::describe_fec(&mut description, session.ssrc, config);
+        }
+        description
+    }
+
+    // Announces the repair format in each media section carrying a media
+    // SSRC, and the first video section for the primary SSRC if no section
+    // lists it.
+    fn describe_fec(description: &mut SessionDescription, ssrc: u32, config: FecConfig) {
+        let mut sections: Vec<(usize, Vec<u32>)> = description
+            .media
+            .iter()
+            .enumerate()
+            .map(|(i, media)| (i, Self::media_ssrcs(media)))
+            .filter(|(_, ssrcs)| !ssrcs.is_empty())
+            .collect();
+        if !sections.iter().any(|(_, ssrcs)| ssrcs.contains(&ssrc)) {
+            if let Some(i) = description.media.iter().position(|m| m.kind == MediaKind::Video) {
+                match sections.iter_mut().find(|(index, _)| *index == i) {
+                    Some((_, ssrcs)) => ssrcs.push(ssrc),
+                    None => sections.push((i, vec![ssrc])),
+                }
+            }
+        }
+        for (i, ssrcs) in sections {
+            Self::describe_media_fec(&mut description.media[i], &ssrcs, config);
+        }
+    }
+
+    // A section's SSRCs, less those an ssrc-group marks as repair streams or
+    // further simulcast layers.
+    fn media_ssrcs(media: &MediaDescription) -> Vec<u32> {
+        let secondary: Vec<u32> = media
+            .attributes
+            .iter()
+            .filter_map(|a| a.strip_prefix("ssrc-group:"))
+            .flat_map(|group| group.split_whitespace().skip(2))
+            .filter_map(|ssrc| ssrc.parse().ok())
+            .collect();
+        let mut ssrcs: Vec<u32> = media.ssrcs.iter().map(|s| s.ssrc).filter(|s| !secondary.contains(s)).collect();
+        ssrcs.dedup();
+        ssrcs
+    }
+
+    fn describe_media_fec(media: &mut MediaDescription, ssrcs: &[u32], config: FecConfig) {
+        let clock_rate = media.formats.first().map(|f| f.clock_rate).unwrap_or(90000);
+        let add_format = |media: &mut MediaDescription, payload_type: u8, encoding: &str, fmtp: Option<String>| {
+            media.payload_types.push(payload_type);
+            media.formats.push(RtpMap {
+                payload_type,
+                encoding: encoding.to_string(),
+                clock_rate,
+                channels: None,
+                fmtp,
+                rtcp_fb: Vec::new(),
+            });
+        };
+
+        match config.scheme {
+            FecScheme::FlexFec { payload_type } => {
+                add_format(media, payload_type, "flexfec-03", Some("repair-window=10000000".to_string()));
+                for &ssrc in ssrcs {
+                    let repair_ssrc = fec::flexfec_ssrc(ssrc);
+                    let cname = media.ssrcs.iter().find(|s| s.ssrc == ssrc).and_then(|s| s.cname.clone());
+                    media.ssrcs.push(SsrcAttribute { ssrc: repair_ssrc, cname });
+                    media.attributes.push(format!("ssrc-group:FEC-FR {} {}", ssrc, repair_ssrc));
+                }
+            }
+            FecScheme::UlpfecRed { red_payload_type, ulpfec_payload_type } => {
+                let primary = media.payload_types.first().copied();
+                add_format(media, red_payload_type, "red", primary.map(|pt| format!("{}/{}", pt, pt)));
+                add_format(media, ulpfec_payload_type, "ulpfec", None);
+            }
+        }
+    }
+
     pub fn session_count(&self) -> usize {
         self.sessions.len()
     }
@@ -197,4 +1369,187 @@ mod tests {
         assert!(session.add_subscriber(addr));
         assert_eq!(session.subscribers.len(), 1);
     }
+
+    #[test]
+    fn test_session_description_from_sdp() {
+        let manager = SessionManager::new(ServerConfig::default());
+        let offer = SessionDescription::parse(
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.2\r\ns=cam\r\nt=0 0\r\n\
+             a=group:BUNDLE 0\r\n\
+             m=audio 5004 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=sendonly\r\na=rtcp-mux\r\n\
+             a=ice-ufrag:abcd\r\na=ice-pwd:secretsecretsecret\r\na=fingerprint:sha-256 AB:CD\r\n\
+             a=setup:actpass\r\na=candidate:1 1 udp 2130706431 10.0.0.2 5004 typ host\r\n\
+             a=msid:stream track\r\na=rtpmap:111 opus/48000/2\r\na=ssrc:777 cname:rover\r\n",
+        )
+        .unwrap();
+
+        let session = manager
+            .create_session_with_description("10.0.0.2:5004".parse().unwrap(), 0, offer)
+            .unwrap();
+        assert_eq!(session.ssrc, 777);
+
+        let answer = session.subscriber_description("192.0.2.1".parse().unwrap());
+        let audio = answer.media_of_kind(MediaKind::Audio).unwrap();
+        assert_eq!(audio.port, 0);
+        assert_eq!(audio.format(111).unwrap().channels, Some(2));
+        assert_eq!(answer.cname(), Some("rover"));
+
+        // Only the codecs and streams survive, not the source's WebRTC transport
+        assert_eq!(audio.protocol, "RTP/AVPF");
+        assert!(audio.attributes.is_empty());
+        assert!(answer.attributes.is_empty());
+        assert!(!answer.to_string().contains("a=rtcp-mux"));
+    }
+
+    #[test]
+    fn test_ingest_ports_released_with_session() {
+        let config = ServerConfig {
+            enable_session_ports: true,
+            session_port_range_start: 40000,
+            session_port_range_end: 40003,
+            ..ServerConfig::default()
+        };
+        let manager = SessionManager::new(config);
+        let addr: SocketAddr = "127.0.0.1:5004".parse().unwrap();
+
+        let first = manager.create_session(addr, 1).unwrap();
+        let second = manager.create_session(addr, 2).unwrap();
+        assert_eq!(manager.allocate_ingest_ports(&first).unwrap().rtcp, Some(40001));
+        assert!(manager.allocate_ingest_ports(&second).is_some());
+
+        let third = manager.create_session(addr, 3).unwrap();
+        assert!(manager.allocate_ingest_ports(&third).is_none());
+
+        manager.remove_session(&first.id);
+        assert_eq!(manager.allocate_ingest_ports(&third).unwrap().rtp, 40000);
+    }
+
+    #[test]
+    fn test_ssrc_collision() {
+        let manager = SessionManager::new(ServerConfig::default());
+        let first = manager.create_session("10.0.0.1:5004".parse().unwrap(), 4242).unwrap();
+
+        let err = manager.create_session("10.0.0.2:5004".parse().unwrap(), 4242).unwrap_err();
+        assert_eq!(err, SessionError::SsrcCollision { ssrc: 4242, existing: first.id });
+        assert_eq!(manager.get_session_by_ssrc(4242).unwrap().id, first.id);
+        assert_eq!(manager.session_count(), 1);
+
+        let options = SessionOptions { demux_by_source: true, ..SessionOptions::default() };
+        let second = manager
+            .create_session_with_options("10.0.0.2:5004".parse().unwrap(), 4242, options)
+            .unwrap();
+        assert_ne!(first.id, second.id);
+    }
+
+    #[test]
+    fn test_flexfec_repair_ssrc_collision() {
+        let manager = SessionManager::new(ServerConfig::default());
+        let flexfec = FecConfig { scheme: FecScheme::FlexFec { payload_type: 118 }, group_size: 10 };
+        let options = SessionOptions { fec: Some(flexfec), ..SessionOptions::default() };
+        let first = manager.create_session_with_options("10.0.0.1:5004".parse().unwrap(), 1, options).unwrap();
+        let repair = fec::flexfec_ssrc(1);
+
+        let err = manager.create_session("10.0.0.2:5004".parse().unwrap(), repair).unwrap_err();
+        assert_eq!(err, SessionError::SsrcCollision { ssrc: repair, existing: first.id });
+        let err = manager.add_session_ssrc(&first.id, repair).unwrap_err();
+        assert_eq!(manager.get_session_by_ssrc(repair).unwrap().id, first.id);
+        assert_eq!(err, SessionError::SsrcCollision { ssrc: repair, existing: first.id });
+        assert!(!first.ssrcs.contains(&repair));
+
+        manager.remove_session(&first.id);
+        assert!(manager.create_session("10.0.0.2:5004".parse().unwrap(), repair).is_ok());
+    }
+
+    #[test]
+    fn test_multi_ssrc_session() {
+        let manager = SessionManager::new(ServerConfig::default());
+        let options = SessionOptions {
+            ssrcs: vec![100, 101, 102],
+            ..SessionOptions::default()
+        };
+        let session = manager
+            .create_session_with_options("10.0.0.1:5004".parse().unwrap(), 0, options)
+            .unwrap();
+        assert_eq!(session.ssrc, 100);
+        assert_eq!(manager.get_session_by_ssrc(102).unwrap().id, session.id);
+
+        assert!(manager.create_session("10.0.0.1:5004".parse().unwrap(), 101).is_err());
+        manager.remove_session(&session.id);
+        assert!(manager.get_session_by_ssrc(101).is_none());
+        assert!(manager.create_session("10.0.0.1:5004".parse().unwrap(), 101).is_ok());
+
+        // Sessions without any SSRC yet don't contend for SSRC 0.
+        let relayed = manager.create_session("10.0.0.3:5004".parse().unwrap(), 0).unwrap();
+        assert!(relayed.ssrcs.is_empty());
+        assert!(manager.create_session("10.0.0.4:5004".parse().unwrap(), 0).is_ok());
+        assert!(manager.get_session_by_ssrc(0).is_none());
+    }
+
+    #[test]
+    fn test_mid_learning_requires_extmap() {
+        let manager = SessionManager::new(ServerConfig::default());
+        let source: SocketAddr = "10.0.0.2:5004".parse().unwrap();
+        let options = SessionOptions { mids: vec!["0".to_string()], ..SessionOptions::default() };
+        let session = manager.create_session_with_options(source, 0, options).unwrap();
+
+        let packet = |ssrc: u32, ext_id: u8| {
+            RtpPacket {
+                payload: vec![0; 10],
+                timestamp: 0,
+                sequence: 1,
+                ssrc,
+                marker: false,
+                payload_type: 96,
+                extension: Some(crate::rtp::RtpExtension::from_elements(&[(ext_id, b"0".to_vec())])),
+                source: Some(source),
+                received_at: Instant::now(),
+                raw: None,
+            }
+        };
+        assert!(manager.resolve(&packet(900, 3)).is_none());
+
+        let offer = SessionDescription::parse(
+            "v=0\r\no=- 1 1 IN IP4 10.0.0.2\r\ns=cam\r\nt=0 0\r\n\
+             m=video 5004 RTP/AVP 96\r\na=mid:0\r\na=rtpmap:96 VP8/90000\r\n\
+             a=extmap:4 urn:ietf:params:rtp-hdrext:sdes:mid\r\n",
+        )
+        .unwrap();
+        session.set_description(offer);
+        assert!(manager.resolve(&packet(900, 3)).is_none());
+        assert_eq!(manager.resolve(&packet(900, 4)).unwrap().id, session.id);
+        assert_eq!(manager.get_session_by_ssrc(900).unwrap().id, session.id);
+    }
+
+    #[test]
+    fn test_subscriber_relatch() {
+        let manager = SessionManager::new(ServerConfig::default());
+        let session = manager
+            .create_session("127.0.0.1:5004".parse().unwrap(), 12345)
+            .unwrap();
+
+        let private: SocketAddr = "10.0.0.5:6000".parse().unwrap();
+        let token = manager.add_latching_subscriber(&session.id, private).unwrap();
+        assert!(!session.subscribers.get(&private).unwrap().is_ready());
+
+        let public: SocketAddr = "203.0.113.7:41000".parse().unwrap();
+        assert_eq!(manager.latch_subscriber(&token, public), Some(session.id));
+        assert!(session.subscribers.get(&private).is_none());
+        assert!(session.subscribers.get(&public).unwrap().is_ready());
+
+        let remapped: SocketAddr = "203.0.113.7:41022".parse().unwrap();
+        assert_eq!(manager.latch_subscriber(&token, remapped), Some(session.id));
+        assert_eq!(session.subscribers.len(), 1);
+        assert!(session.subscribers.contains_key(&remapped));
+
+        // A punch from another subscriber's address doesn't take it over
+        let other: SocketAddr = "198.51.100.9:5000".parse().unwrap();
+        session.add_subscriber(other);
+        assert_eq!(manager.latch_subscriber(&token, other), None);
+        assert_eq!(session.subscribers.len(), 2);
+        assert!(session.subscribers.get(&remapped).unwrap().is_ready());
+
+        assert!(session.remove_subscriber(&remapped));
+        assert!(session.latches.is_empty());
+        assert_eq!(manager.latch_subscriber(&token, remapped), None);
+    }
 }
@@ -0,0 +1,501 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, RwLock};
+use tracing::debug;
+
+use crate::metrics::MetricsCollector;
+use crate::sdp::{MediaKind, SessionDescription};
+use crate::svc::{SvcCeiling, SvcLayer, SvcState};
+use crate::RtpPacket;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// One simulcast encoding as signalled by the source: a RID (RFC 8851), an
+// SSRC from `a=ssrc-group:SIM`, or both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerSpec {
+    pub rid: Option<String>,
+    pub ssrc: Option<u32>,
+}
+
+impl LayerSpec {
+    pub fn from_description(description: &SessionDescription) -> Vec<LayerSpec> {
+        let Some(media) = description.media_of_kind(MediaKind::Video) else {
+            return Vec::new();
+        };
+
+        let mut rids = Vec::new();
+        let mut ssrcs = Vec::new();
+        for attribute in &media.attributes {
+            if let Some(simulcast) = attribute.strip_prefix("simulcast:") {
+                let mut parts = simulcast.split_whitespace();
+                while let Some(direction) = parts.next() {
+                    let Some(streams) = parts.next() else {
+                        break;
+                    };
+                    if direction == "send" {
+                        rids = streams
+                            .split(';')
+                            .filter_map(|alternatives| alternatives.split(',').next())
+                            .map(|rid| rid.trim_start_matches('~').to_string())
+                            .collect();
+                    }
+                }
+            } else if let Some(group) = attribute.strip_prefix("ssrc-group:SIM ") {
+                ssrcs = group.split_whitespace().filter_map(|s| s.parse().ok()).collect();
+            }
+        }
+
+        (0..rids.len().max(ssrcs.len()))
+            .map(|i| LayerSpec {
+                rid: rids.get(i).cloned(),
+                ssrc: ssrcs.get(i).copied(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateMeter {
+    window_start: Instant,
+    bytes: u64,
+    pub(crate) bitrate_bps: u64,
+}
+
+impl RateMeter {
+    pub(crate) fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            bytes: 0,
+            bitrate_bps: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, bytes: usize, now: Instant) {
+        self.bytes += bytes as u64;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            self.bitrate_bps = (self.bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+            self.bytes = 0;
+            self.window_start = now;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SimulcastLayer {
+    rid: Option<String>,
+    ssrc: Option<u32>,
+    meter: Mutex<RateMeter>,
+    // The meter's last measurement, read by layer selection on every packet.
+    bitrate_bps: AtomicU64,
+}
+
+impl SimulcastLayer {
+    fn record(&self, packet: &RtpPacket) {
+        let mut meter = self.meter.lock();
+        meter.record(packet.payload.len(), packet.received_at);
+        self.bitrate_bps.store(meter.bitrate_bps, Ordering::Relaxed);
+    }
+
+    fn bitrate_bps(&self) -> u64 {
+        self.bitrate_bps.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerStats {
+    pub rid: Option<String>,
+    pub ssrc: Option<u32>,
+    pub bitrate_bps: u64,
+}
+
+// The layers of a simulcast session, in signalling order. Empty for regular
+// sessions, which skip layer selection entirely.
+#[derive(Debug, Default)]
+pub struct SimulcastLayers {
+    layers: RwLock<Vec<SimulcastLayer>>,
+}
+
+impl SimulcastLayers {
+    pub fn new(specs: Vec<LayerSpec>) -> Self {
+        let layers = specs
+            .into_iter()
+            .map(|spec| SimulcastLayer {
+                rid: spec.rid,
+                ssrc: spec.ssrc,
+                meter: Mutex::new(RateMeter::new()),
+                bitrate_bps: AtomicU64::new(0),
+            })
+            .collect();
+        Self {
+            layers: RwLock::new(layers),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.read().is_empty()
+    }
+
+    // Accounts the packet to its layer, learning the SSRC of a RID-only layer
+    // from the rtp-stream-id extension. Returns false for packets outside
+    // every layer.
+    pub fn observe(&self, packet: &RtpPacket, rid_ext: Option<u8>) -> bool {
+        {
+            let layers = self.layers.read();
+            if let Some(layer) = layers.iter().find(|l| l.ssrc == Some(packet.ssrc)) {
+                layer.record(packet);
+                return true;
+            }
+        }
+
+        let rid = rid_ext
+            .zip(packet.extension.as_ref())
+            .and_then(|(id, ext)| ext.element(id))
+            .and_then(|value| std::str::from_utf8(value).ok());
+        let Some(rid) = rid else {
+            return false;
+        };
+
+        let mut layers = self.layers.write();
+        match layers.iter_mut().find(|l| l.ssrc.is_none() && l.rid.as_deref() == Some(rid)) {
+            Some(layer) => {
+                debug!("Simulcast layer {} is SSRC {}", rid, packet.ssrc);
+                layer.ssrc = Some(packet.ssrc);
+                layer.record(packet);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> Vec<LayerStats> {
+        self.layers
+            .read()
+            .iter()
+            .map(|layer| LayerStats {
+                rid: layer.rid.clone(),
+                ssrc: layer.ssrc,
+                bitrate_bps: layer.bitrate_bps(),
+            })
+            .collect()
+    }
+
+    // The SSRC a subscriber should receive. `Auto` takes the best layer that
+    // fits the available bandwidth, or the best layer when nothing is known.
+    // Runs for every packet of every subscriber, so it works off the cached
+    // bitrates without allocating.
+    pub fn choose(&self, target: &LayerTarget, available_bps: Option<u64>) -> Option<u32> {
+        let layers = self.layers.read();
+
+        if let LayerTarget::Layer(name) = target {
+            let by_rid = layers.iter().find(|l| l.rid.as_deref() == Some(name.as_str()));
+            let by_index = || name.parse::<usize>().ok().and_then(|i| layers.get(i));
+            if let Some(ssrc) = by_rid.or_else(by_index).and_then(|l| l.ssrc) {
+                return Some(ssrc);
+            }
+        }
+
+        // (ssrc, bitrate) of the layers measured so far
+        let measured = || {
+            layers
+                .iter()
+                .filter_map(|l| Some((l.ssrc?, l.bitrate_bps())))
+                .filter(|(_, bps)| *bps > 0)
+        };
+        if measured().next().is_none() {
+            return layers.iter().find_map(|l| l.ssrc);
+        }
+
+        let chosen = match available_bps {
+            Some(available) => measured()
+                .filter(|(_, bps)| *bps <= available)
+                .max_by_key(|(_, bps)| *bps)
+                .or_else(|| measured().min_by_key(|(_, bps)| *bps)),
+            None => measured().max_by_key(|(_, bps)| *bps),
+        };
+        chosen.map(|(ssrc, _)| ssrc)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum LayerTarget {
+    #[default]
+    Auto,
+    // A RID, or the layer's position in the signalled order.
+    Layer(String),
+}
+
+impl LayerTarget {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "" | "auto" => LayerTarget::Auto,
+            layer => LayerTarget::Layer(layer.to_string()),
+        }
+    }
+}
+
+// Keeps the stream a subscriber sees continuous while the packets behind it
+// change: sequence numbers and timestamps carry on from the last packet sent
+// and the SSRC stays fixed.
+#[derive(Debug, Default)]
+pub struct StreamRewriter {
+    seq_offset: u16,
+    ts_offset: u32,
+    last: Option<(u16, u32, Instant)>,
+}
+
+impl StreamRewriter {
+    // Anchors `packet` directly after the last packet sent, advancing the
+    // timestamp by the wall-clock time in between.
+    pub fn rebase(&mut self, packet: &RtpPacket, clock_rate: u32) {
+        let Some((seq, ts, sent_at)) = self.last else {
+            return;
+        };
+        let elapsed = packet.received_at.saturating_duration_since(sent_at);
+        let ticks = ((elapsed.as_secs_f64() * clock_rate as f64) as u32).max(1);
+
+        self.seq_offset = seq.wrapping_add(1).wrapping_sub(packet.sequence);
+        self.ts_offset = ts.wrapping_add(ticks).wrapping_sub(packet.timestamp);
+    }
+
+    // Closes the gap left by a packet that is not forwarded.
+    pub fn skip(&mut self) {
+        self.seq_offset = self.seq_offset.wrapping_sub(1);
+    }
+
+    pub fn rewrite(&mut self, packet: &RtpPacket, ssrc: u32) -> RtpPacket {
+        let mut out = packet.clone();
+        out.sequence = packet.sequence.wrapping_add(self.seq_offset);
+        out.timestamp = packet.timestamp.wrapping_add(self.ts_offset);
+        out.ssrc = ssrc;
+        out.raw = None;
+        self.last = Some((out.sequence, out.timestamp, packet.received_at));
+        out
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LayerSelector {
+    pub target: LayerTarget,
+    pub max_bitrate_bps: Option<u64>,
+    pub estimated_bps: Option<u64>,
+    pub max_spatial: Option<u8>,
+    pub max_temporal: Option<u8>,
+    current: Option<u32>,
+    // Simulcast: the layer to switch to at its next keyframe. Scalable
+    // streams: the SSRC whose next keyframe adds spatial layers.
+    pending: Option<u32>,
+    // Scalable streams: the layers currently forwarded, which trail the
+    // ceiling upwards until a decodable point.
+    forwarded: Option<SvcCeiling>,
+    rewriter: StreamRewriter,
+}
+
+impl LayerSelector {
+    pub fn available_bps(&self) -> Option<u64> {
+        match (self.max_bitrate_bps, self.estimated_bps) {
+            (Some(max), Some(estimate)) => Some(max.min(estimate)),
+            (max, estimate) => max.or(estimate),
+        }
+    }
+
+    pub fn current_layer(&self) -> Option<u32> {
+        self.current
+    }
+
+    pub fn awaiting_keyframe(&self) -> Option<u32> {
+        self.pending
+    }
+
+    // Returns the packet to send, rewritten onto `output_ssrc`, or None when
+    // it belongs to a layer this subscriber isn't receiving. A new layer is
+    // only taken at the start of one of its keyframes.
+    pub fn select(
+        &mut self,
+        packet: &RtpPacket,
+        layers: &SimulcastLayers,
+        keyframe: bool,
+        clock_rate: u32,
+        output_ssrc: u32,
+    ) -> Option<RtpPacket> {
+        let desired = layers.choose(&self.target, self.available_bps());
+        self.pending = desired.filter(|ssrc| Some(*ssrc) != self.current);
+
+        if keyframe && self.pending == Some(packet.ssrc) {
+            debug!("Switching simulcast layer {:?} -> {}", self.current, packet.ssrc);
+            self.current = self.pending.take();
+            self.rewriter.rebase(packet, clock_rate);
+            MetricsCollector::record_simulcast_switch();
+        }
+
+        (self.current == Some(packet.ssrc)).then(|| self.rewriter.rewrite(packet, output_ssrc))
+    }
+
+    pub fn svc_ceiling(&self, svc: &SvcState) -> SvcCeiling {
+        let configured = SvcCeiling {
+            spatial: self.max_spatial.unwrap_or(u8::MAX),
+            temporal: self.max_temporal.unwrap_or(u8::MAX),
+        };
+        match self.available_bps() {
+            Some(available) => configured.min(svc.ceiling_for(available)),
+            None => configured,
+        }
+    }
+
+    // Scalable streams: drops packets above the subscriber's ceiling and
+    // closes the sequence gaps they leave. The marker moves to the end of
+    // the highest spatial layer still forwarded. Lowering the ceiling takes
+    // effect at once; spatial layers are only added at a keyframe and
+    // temporal ones at a switching point of the layer.
+    pub fn filter_layers(
+        &mut self,
+        packet: &RtpPacket,
+        layer: Option<SvcLayer>,
+        svc: &SvcState,
+        keyframe: bool,
+    ) -> Option<RtpPacket> {
+        let Some(layer) = layer else {
+            return Some(self.rewriter.rewrite(packet, packet.ssrc));
+        };
+
+        let target = self.svc_ceiling(svc);
+        let ceiling = self.forwarded.get_or_insert(target);
+        *ceiling = ceiling.min(target);
+        if keyframe && layer.start_of_frame && layer.spatial == 0 {
+            *ceiling = target;
+        } else if layer.temporal > ceiling.temporal
+            && layer.temporal <= target.temporal
+            && layer.switch_up
+            && layer.start_of_frame
+        {
+            ceiling.temporal = layer.temporal;
+        }
+        let ceiling = *ceiling;
+        self.pending = (ceiling.spatial < target.spatial).then_some(packet.ssrc);
+
+        if !ceiling.admits(&layer) {
+            self.rewriter.skip();
+            return None;
+        }
+
+        let mut out = self.rewriter.rewrite(packet, packet.ssrc);
+        if layer.end_of_frame && layer.spatial == ceiling.spatial {
+            out.marker = true;
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(ssrc: u32, sequence: u16, timestamp: u32) -> RtpPacket {
+        RtpPacket {
+            payload: vec![0; 100],
+            timestamp,
+            sequence,
+            ssrc,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: None,
+            received_at: Instant::now(),
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn test_layers_from_description() {
+        let sdp = "v=0\r\no=- 1 1 IN IP4 10.0.0.1\r\ns=-\r\nm=video 5004 RTP/AVP 96\r\n\
+                   a=rtpmap:96 VP8/90000\r\na=rid:h send\r\na=rid:l send\r\n\
+                   a=simulcast:send h;~l\r\na=ssrc-group:SIM 11 22\r\n";
+        let description = SessionDescription::parse(sdp).unwrap();
+        let specs = LayerSpec::from_description(&description);
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0], LayerSpec { rid: Some("h".to_string()), ssrc: Some(11) });
+        assert_eq!(specs[1], LayerSpec { rid: Some("l".to_string()), ssrc: Some(22) });
+    }
+
+    #[test]
+    fn test_switch_on_keyframe_keeps_stream_continuous() {
+        let layers = SimulcastLayers::new(vec![
+            LayerSpec { rid: Some("h".to_string()), ssrc: Some(1) },
+            LayerSpec { rid: Some("l".to_string()), ssrc: Some(2) },
+        ]);
+        let mut selector = LayerSelector {
+            target: LayerTarget::Layer("h".to_string()),
+            ..Default::default()
+        };
+
+        assert!(selector.select(&packet(2, 500, 9000), &layers, true, 90000, 7).is_none());
+        let first = selector.select(&packet(1, 100, 1000), &layers, true, 90000, 7).unwrap();
+        assert_eq!((first.ssrc, first.sequence), (7, 100));
+
+        selector.target = LayerTarget::Layer("l".to_string());
+        // Delta frame on the new layer: keep forwarding the old one
+        assert!(selector.select(&packet(2, 501, 9000), &layers, false, 90000, 7).is_none());
+        let second = selector.select(&packet(1, 101, 1000), &layers, false, 90000, 7).unwrap();
+        assert_eq!(second.sequence, 101);
+        assert_eq!(selector.awaiting_keyframe(), Some(2));
+
+        let switched = selector.select(&packet(2, 502, 12000), &layers, true, 90000, 7).unwrap();
+        assert_eq!((switched.ssrc, switched.sequence), (7, 102));
+        assert_eq!(selector.awaiting_keyframe(), None);
+        assert!(switched.timestamp.wrapping_sub(second.timestamp) >= 1);
+        assert!(selector.select(&packet(1, 102, 4000), &layers, true, 90000, 7).is_none());
+        assert_eq!(selector.select(&packet(2, 503, 12000), &layers, false, 90000, 7).unwrap().sequence, 103);
+    }
+
+    #[test]
+    fn test_svc_filter_keeps_sequence_contiguous() {
+        let svc = SvcState::default();
+        let mut selector = LayerSelector {
+            max_temporal: Some(0),
+            ..Default::default()
+        };
+        let layer = |temporal| {
+            Some(SvcLayer { spatial: 0, temporal, start_of_frame: true, end_of_frame: true, switch_up: false })
+        };
+
+        let sent: Vec<u16> = [(10, 0), (11, 1), (12, 0), (13, 2), (14, 0)]
+            .into_iter()
+            .filter_map(|(seq, temporal)| selector.filter_layers(&packet(5, seq, 0), layer(temporal), &svc, false))
+            .map(|p| p.sequence)
+            .collect();
+        assert_eq!(sent, vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn test_svc_ceiling_raised_at_decodable_points() {
+        let svc = SvcState::default();
+        let mut selector = LayerSelector { max_spatial: Some(0), max_temporal: Some(0), ..Default::default() };
+        let layer = |spatial, temporal, switch_up| {
+            Some(SvcLayer { spatial, temporal, start_of_frame: true, end_of_frame: true, switch_up })
+        };
+        let forwards = |selector: &mut LayerSelector, spatial, temporal, switch_up, keyframe| {
+            selector
+                .filter_layers(&packet(5, 0, 0), layer(spatial, temporal, switch_up), &svc, keyframe)
+                .is_some()
+        };
+        assert!(forwards(&mut selector, 0, 0, false, false));
+
+        selector.max_spatial = Some(1);
+        selector.max_temporal = Some(1);
+        // Neither a keyframe nor a switching point
+        assert!(!forwards(&mut selector, 1, 0, false, false));
+        assert!(!forwards(&mut selector, 0, 1, false, false));
+        // The temporal layer is joined at its switching point
+        assert!(forwards(&mut selector, 0, 1, true, false));
+        assert!(forwards(&mut selector, 0, 1, false, false));
+        assert!(!forwards(&mut selector, 1, 0, false, false));
+        // The spatial layer waits for a keyframe
+        assert!(forwards(&mut selector, 0, 0, false, true));
+        assert!(forwards(&mut selector, 1, 0, false, false));
+
+        // Lowering is immediate
+        selector.max_spatial = Some(0);
+        assert!(!forwards(&mut selector, 1, 0, false, false));
+    }
+}
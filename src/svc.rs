@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::codec::{BitReader, Codec};
+use crate::simulcast::RateMeter;
+use crate::RtpPacket;
+
+pub const AV1_DEPENDENCY_DESCRIPTOR_URI: &str =
+    "https://aomediacodec.github.io/av1-rtp-spec/#dependency-descriptor-rtp-header-extension";
+
+// Spatial/temporal layer of a packet in a scalable stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SvcLayer {
+    pub spatial: u8,
+    pub temporal: u8,
+    // First packet of this layer's part of the picture.
+    pub start_of_frame: bool,
+    // Last packet of this layer's part of the picture.
+    pub end_of_frame: bool,
+    // A receiver can start decoding this temporal layer from this frame.
+    pub switch_up: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SvcCeiling {
+    pub spatial: u8,
+    pub temporal: u8,
+}
+
+impl SvcCeiling {
+    pub const UNLIMITED: SvcCeiling = SvcCeiling { spatial: u8::MAX, temporal: u8::MAX };
+
+    pub fn admits(&self, layer: &SvcLayer) -> bool {
+        layer.spatial <= self.spatial && layer.temporal <= self.temporal
+    }
+
+    pub fn min(self, other: SvcCeiling) -> SvcCeiling {
+        SvcCeiling {
+            spatial: self.spatial.min(other.spatial),
+            temporal: self.temporal.min(other.temporal),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SvcLayerStats {
+    pub spatial: u8,
+    pub temporal: u8,
+    pub bitrate_bps: u64,
+}
+
+// Template structure of an AV1 dependency descriptor; sent with keyframes and
+// needed to map later packets' template ids to layers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Av1Templates {
+    id_offset: u8,
+    layers: Vec<(u8, u8)>,
+    // Templates with a Switch indication for some decode target.
+    switches: Vec<bool>,
+}
+
+fn parse_av1_templates(data: &[u8]) -> Option<Av1Templates> {
+    let mut reader = BitReader::new(data.get(3..)?);
+    let structure_present = reader.read(1)? == 1;
+    if !structure_present {
+        return None;
+    }
+    // active_decode_targets_present, custom_dtis, custom_fdiffs, custom_chains
+    reader.read(4)?;
+
+    let id_offset = reader.read(6)? as u8;
+    let decode_targets = reader.read(5)? + 1;
+
+    let mut layers = Vec::new();
+    let (mut spatial, mut temporal) = (0u8, 0u8);
+    loop {
+        layers.push((spatial, temporal));
+        match reader.read(2)? {
+            1 => temporal += 1,
+            2 => {
+                temporal = 0;
+                spatial += 1;
+            }
+            3 => break,
+            _ => {}
+        }
+        if layers.len() >= 64 {
+            break;
+        }
+    }
+    let switches = layers
+        .iter()
+        .map(|_| (0..decode_targets).any(|_| reader.read(2) == Some(2)))
+        .collect();
+    Some(Av1Templates { id_offset, layers, switches })
+}
+
+fn parse_vp9(payload: &[u8]) -> Option<SvcLayer> {
+    let first = *payload.first()?;
+    if first & 0x20 == 0 {
+        // No layer indices: not a scalable stream
+        return None;
+    }
+
+    let mut i = 1;
+    if first & 0x80 != 0 {
+        i += if payload.get(i)? & 0x80 != 0 { 2 } else { 1 };
+    }
+    // TID|U|SID|D
+    let layer = *payload.get(i)?;
+    Some(SvcLayer {
+        spatial: (layer >> 1) & 0x07,
+        temporal: layer >> 5,
+        start_of_frame: first & 0x08 != 0,
+        end_of_frame: first & 0x04 != 0,
+        switch_up: layer & 0x10 != 0,
+    })
+}
+
+// Per-session view of the scalable stream: the latest AV1 template
+// structure and the bitrate of every layer seen.
+#[derive(Debug, Default)]
+pub struct SvcState {
+    av1_templates: Mutex<Option<Av1Templates>>,
+    meters: Mutex<HashMap<(u8, u8), RateMeter>>,
+}
+
+impl SvcState {
+    pub fn layer_of(&self, packet: &RtpPacket, codec: Codec, dd_ext: Option<u8>) -> Option<SvcLayer> {
+        let layer = match codec {
+            Codec::Vp9 => parse_vp9(&packet.payload),
+            Codec::Av1 => {
+                let data = dd_ext
+                    .zip(packet.extension.as_ref())
+                    .and_then(|(id, ext)| ext.element(id))?;
+                self.parse_av1(data)
+            }
+            _ => None,
+        }?;
+
+        self.meters
+            .lock()
+            .entry((layer.spatial, layer.temporal))
+            .or_insert_with(RateMeter::new)
+            .record(packet.payload.len(), packet.received_at);
+        Some(layer)
+    }
+
+    fn parse_av1(&self, data: &[u8]) -> Option<SvcLayer> {
+        let first = *data.first()?;
+        if data.len() < 3 {
+            return None;
+        }
+
+        let mut templates = self.av1_templates.lock();
+        if let Some(structure) = parse_av1_templates(data) {
+            if templates.as_ref() != Some(&structure) {
+                debug!("AV1 template structure with {} templates", structure.layers.len());
+            }
+            *templates = Some(structure);
+        }
+
+        let structure = templates.as_ref()?;
+        let index = ((first & 0x3F) + 64 - structure.id_offset) % 64;
+        let (spatial, temporal) = *structure.layers.get(index as usize)?;
+        Some(SvcLayer {
+            spatial,
+            temporal,
+            start_of_frame: first & 0x80 != 0,
+            end_of_frame: first & 0x40 != 0,
+            switch_up: structure.switches.get(index as usize).copied().unwrap_or(false),
+        })
+    }
+
+    // Set once any layered packet has been seen.
+    pub fn is_scalable(&self) -> bool {
+        !self.meters.lock().is_empty()
+    }
+
+    pub fn stats(&self) -> Vec<SvcLayerStats> {
+        let mut stats: Vec<SvcLayerStats> = self
+            .meters
+            .lock()
+            .iter()
+            .map(|(&(spatial, temporal), meter)| SvcLayerStats {
+                spatial,
+                temporal,
+                bitrate_bps: meter.bitrate_bps,
+            })
+            .collect();
+        stats.sort_by_key(|l| (l.spatial, l.temporal));
+        stats
+    }
+
+    // Highest operating point whose cumulative bitrate (every layer at or
+    // below it) fits `available_bps`; the base layer if none does.
+    pub fn ceiling_for(&self, available_bps: u64) -> SvcCeiling {
+        let stats = self.stats();
+        let cumulative = |ceiling: SvcCeiling| -> u64 {
+            stats
+                .iter()
+                .filter(|l| l.spatial <= ceiling.spatial && l.temporal <= ceiling.temporal)
+                .map(|l| l.bitrate_bps)
+                .sum()
+        };
+
+        stats
+            .iter()
+            .map(|l| SvcCeiling { spatial: l.spatial, temporal: l.temporal })
+            .filter(|c| cumulative(*c) <= available_bps)
+            .max_by_key(|c| (cumulative(*c), c.spatial, c.temporal))
+            .unwrap_or(SvcCeiling { spatial: 0, temporal: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_vp9_layer_indices() {
+        // I|L|B|E, 7-bit picture id, TID=2 U=1 SID=1 D=0
+        let layer = parse_vp9(&[0xAC, 0x12, 0x52, 0x00]).unwrap();
+        let expected = SvcLayer { spatial: 1, temporal: 2, start_of_frame: true, end_of_frame: true, switch_up: true };
+        assert_eq!(layer, expected);
+        assert_eq!(parse_vp9(&[0x88, 0x00]), None);
+    }
+
+    #[test]
+    fn test_av1_template_mapping() {
+        // start/end, template 0, frame 1, then a structure with offset 0,
+        // one decode target and templates (0,0) (0,1) (1,0), only the second
+        // a switch point
+        let keyframe = [0xC0, 0x00, 0x01, 0x80, 0x00, 0x6F, 0xB0];
+        let state = SvcState::default();
+        let packet = |ext: &[u8]| RtpPacket {
+            payload: vec![0; 10],
+            timestamp: 0,
+            sequence: 0,
+            ssrc: 1,
+            marker: false,
+            payload_type: 45,
+            extension: Some(crate::rtp::RtpExtension::from_elements(&[(3, ext.to_vec())])),
+            source: None,
+            received_at: Instant::now(),
+            raw: None,
+        };
+
+        let base = state.layer_of(&packet(&keyframe), Codec::Av1, Some(3)).unwrap();
+        assert_eq!((base.spatial, base.temporal), (0, 0));
+        let upper = state.layer_of(&packet(&[0x02, 0x00, 0x02]), Codec::Av1, Some(3)).unwrap();
+        assert_eq!((upper.spatial, upper.temporal), (1, 0));
+        let temporal = state.layer_of(&packet(&[0x01, 0x00, 0x03]), Codec::Av1, Some(3)).unwrap();
+        assert_eq!((temporal.spatial, temporal.temporal, temporal.switch_up), (0, 1, true));
+        assert!(!base.switch_up);
+    }
+}
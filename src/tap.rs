@@ -0,0 +1,301 @@
+// Read-only debug taps on a live session. Every ingress packet is offered to
+// the session's taps before session policing and fan-out; a tap that can't keep up
+// loses packets instead of slowing the session down.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::config::ServerConfig;
+use crate::RtpPacket;
+
+const TAP_QUEUE_DEPTH: usize = 1024;
+const TAP_MAGIC: &[u8; 4] = b"RTAP";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TapId(pub Uuid);
+
+impl TapId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Default for TapId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapTarget {
+    Udp(SocketAddr),
+    // Handed to the caller, e.g. a streaming control-API response.
+    Stream,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TapOptions {
+    // Fraction of packets mirrored, evenly spread.
+    pub sample_rate: f64,
+    pub max_packets_per_second: u32,
+    pub duration: Duration,
+}
+
+impl TapOptions {
+    // Keeps the request within the server's caps; zero limits mean the cap.
+    pub fn clamp(self, config: &ServerConfig) -> Self {
+        let max_duration = Duration::from_secs(config.tap_max_duration_secs);
+        let cap = |value: u32, max: u32| if value == 0 { max } else { value.min(max) };
+        Self {
+            sample_rate: if self.sample_rate > 0.0 { self.sample_rate.min(1.0) } else { 1.0 },
+            max_packets_per_second: cap(self.max_packets_per_second, config.tap_max_packets_per_second),
+            duration: if self.duration.is_zero() { max_duration } else { self.duration.min(max_duration) },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TappedPacket {
+    pub source: Option<SocketAddr>,
+    pub arrival: SystemTime,
+    pub data: Arc<[u8]>,
+}
+
+impl TappedPacket {
+    // UDP framing: "RTAP", arrival in microseconds since the Unix epoch (u64),
+    // source address family (4, 6 or 0 when unknown), address, port, then the
+    // RTP packet as received.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() + 31);
+        out.extend_from_slice(TAP_MAGIC);
+        let micros = self.arrival.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        out.extend_from_slice(&micros.to_be_bytes());
+        match self.source {
+            Some(SocketAddr::V4(addr)) => {
+                out.push(4);
+                out.extend_from_slice(&addr.ip().octets());
+                out.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            Some(SocketAddr::V6(addr)) => {
+                out.push(6);
+                out.extend_from_slice(&addr.ip().octets());
+                out.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&self.data);
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TapStatus {
+    pub id: TapId,
+    pub target: TapTarget,
+    pub options: TapOptions,
+    pub remaining: Duration,
+    pub mirrored: u64,
+    pub sampled_out: u64,
+    pub rate_limited: u64,
+    // Queue overflow while the destination fell behind.
+    pub dropped: u64,
+}
+
+#[derive(Debug)]
+struct TapState {
+    sample_credit: f64,
+    window_start: Instant,
+    window_packets: u32,
+    mirrored: u64,
+    sampled_out: u64,
+    rate_limited: u64,
+    dropped: u64,
+}
+
+#[derive(Debug)]
+pub struct Tap {
+    pub id: TapId,
+    pub target: TapTarget,
+    pub options: TapOptions,
+    deadline: Instant,
+    tx: mpsc::Sender<TappedPacket>,
+    state: Mutex<TapState>,
+}
+
+// The consuming end of a tap. Ends when the tap expires or is stopped.
+#[derive(Debug)]
+pub struct TapReceiver {
+    rx: mpsc::Receiver<TappedPacket>,
+    deadline: Instant,
+}
+
+impl TapReceiver {
+    pub async fn recv(&mut self) -> Option<TappedPacket> {
+        let deadline = tokio::time::Instant::from_std(self.deadline);
+        tokio::time::timeout_at(deadline, self.rx.recv()).await.ok().flatten()
+    }
+}
+
+impl Tap {
+    pub fn new(target: TapTarget, options: TapOptions) -> (Self, TapReceiver) {
+        let (tx, rx) = mpsc::channel(TAP_QUEUE_DEPTH);
+        let now = Instant::now();
+        let deadline = now + options.duration;
+        let tap = Self {
+            id: TapId::new(),
+            target,
+            options,
+            deadline,
+            tx,
+            state: Mutex::new(TapState {
+                sample_credit: 0.0,
+                window_start: now,
+                window_packets: 0,
+                mirrored: 0,
+                sampled_out: 0,
+                rate_limited: 0,
+                dropped: 0,
+            }),
+        };
+        (tap, TapReceiver { rx, deadline })
+    }
+
+    pub fn is_finished(&self, now: Instant) -> bool {
+        now >= self.deadline || self.tx.is_closed()
+    }
+
+    // Offers a packet to the tap; false once the tap is finished.
+    pub fn mirror(&self, packet: &RtpPacket, data: &Arc<[u8]>, now: Instant) -> bool {
+        if self.is_finished(now) {
+            return false;
+        }
+        let mut state = self.state.lock();
+
+        state.sample_credit += self.options.sample_rate;
+        if state.sample_credit < 1.0 {
+            state.sampled_out += 1;
+            return true;
+        }
+        state.sample_credit -= 1.0;
+
+        if now.saturating_duration_since(state.window_start) >= Duration::from_secs(1) {
+            state.window_start = now;
+            state.window_packets = 0;
+        }
+        if state.window_packets >= self.options.max_packets_per_second {
+            state.rate_limited += 1;
+            return true;
+        }
+        state.window_packets += 1;
+
+        let arrival = SystemTime::now()
+            .checked_sub(packet.received_at.elapsed())
+            .unwrap_or_else(SystemTime::now);
+        let tapped = TappedPacket { source: packet.source, arrival, data: data.clone() };
+        match self.tx.try_send(tapped) {
+            Ok(()) => state.mirrored += 1,
+            Err(mpsc::error::TrySendError::Full(_)) => state.dropped += 1,
+            Err(mpsc::error::TrySendError::Closed(_)) => return false,
+        }
+        true
+    }
+
+    pub fn status(&self, now: Instant) -> TapStatus {
+        let state = self.state.lock();
+        TapStatus {
+            id: self.id,
+            target: self.target,
+            options: self.options,
+            remaining: self.deadline.saturating_duration_since(now),
+            mirrored: state.mirrored,
+            sampled_out: state.sampled_out,
+            rate_limited: state.rate_limited,
+            dropped: state.dropped,
+        }
+    }
+}
+
+// Sends a tap's packets to a UDP debug destination until the tap ends.
+pub fn spawn_udp(mut receiver: TapReceiver, destination: SocketAddr) -> io::Result<tokio::task::JoinHandle<()>> {
+    let bind: SocketAddr = if destination.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+    let socket = StdUdpSocket::bind(bind)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+
+    Ok(tokio::spawn(async move {
+        while let Some(packet) = receiver.recv().await {
+            if let Err(e) = socket.send_to(&packet.encode(), destination).await {
+                warn!("Failed to mirror packet to tap {}: {}", destination, e);
+            }
+        }
+        debug!("Tap to {} finished", destination);
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(sequence: u16) -> RtpPacket {
+        RtpPacket {
+            payload: vec![0; 10],
+            timestamp: 0,
+            sequence,
+            ssrc: 1,
+            marker: false,
+            payload_type: 96,
+            extension: None,
+            source: Some("10.0.0.1:5004".parse().unwrap()),
+            received_at: Instant::now(),
+            raw: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sampling_and_rate_cap() {
+        let options = TapOptions { sample_rate: 0.5, max_packets_per_second: 3, duration: Duration::from_secs(10) };
+        let (tap, mut receiver) = Tap::new(TapTarget::Stream, options);
+        let now = Instant::now();
+        for sequence in 0..10 {
+            assert!(tap.mirror(&packet(sequence), &Arc::from([0x80, sequence as u8]), now));
+        }
+
+        let status = tap.status(now);
+        assert_eq!((status.mirrored, status.sampled_out, status.rate_limited), (3, 5, 2));
+        let first = receiver.recv().await.unwrap();
+        assert_eq!(&first.data[..], [0x80, 1]);
+        assert_eq!(&first.encode()[..4], b"RTAP");
+        assert_eq!(first.encode()[12], 4);
+
+        drop(tap);
+        for _ in 0..2 {
+            assert!(receiver.recv().await.is_some());
+        }
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_options_clamped_to_server_caps() {
+        let config = ServerConfig::default();
+        let requested = TapOptions { sample_rate: 0.0, max_packets_per_second: 1_000_000, duration: Duration::from_secs(60) };
+        let options = requested.clamp(&config);
+        assert_eq!(options.sample_rate, 1.0);
+        assert_eq!(options.max_packets_per_second, config.tap_max_packets_per_second);
+        assert_eq!(options.duration, Duration::from_secs(60));
+
+        let (tap, _) = Tap::new(TapTarget::Stream, TapOptions { duration: Duration::ZERO, ..options });
+        assert!(!tap.mirror(&packet(0), &Arc::from([]), Instant::now()));
+    }
+}
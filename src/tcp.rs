@@ -0,0 +1,250 @@
+use std::sync::Arc;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+use tracing::{info, debug, trace, warn};
+
+use crate::config::ServerConfig;
+use crate::fanout::FanoutEngine;
+use crate::latch::{self, LatchToken};
+use crate::session::{SessionManager, SubscriberTransport};
+use crate::RtpFanoutServer;
+
+// RFC 5761 demultiplexing: RTCP packet types (SR/RR/SDES/BYE/APP as well as
+// RTPFB/PSFB feedback) fall in 192-223 of the second byte, which RTP only
+// reaches with payload types 64-95 and the marker bit, and those are avoided.
+pub fn is_rtcp(data: &[u8]) -> bool {
+    data.len() >= 2 && (192..=223).contains(&data[1])
+}
+
+// Compound RTCP from a sender starts with a sender report.
+pub fn is_sender_report(data: &[u8]) -> bool {
+    data.len() >= 8 && data[1] == 200
+}
+
+pub fn sender_ssrc(data: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(4..8)?.try_into().ok()?))
+}
+
+// The CNAME item of the first SDES chunk in a compound RTCP packet.
+pub fn sdes_cname(data: &[u8]) -> Option<String> {
+    let mut offset = 0;
+    while offset + 4 <= data.len() {
+        let length = (u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize + 1) * 4;
+        let packet = data.get(offset..offset + length)?;
+        if packet[1] == 202 && packet[0] & 0x1F > 0 {
+            // Items after the chunk's SSRC, up to the null item
+            let mut i = 8;
+            while let (Some(&kind), Some(&len)) = (packet.get(i), packet.get(i + 1)) {
+                if kind == 0 {
+                    break;
+                }
+                let value = packet.get(i + 2..i + 2 + len as usize)?;
+                if kind == 1 {
+                    return String::from_utf8(value.to_vec()).ok();
+                }
+                i += 2 + len as usize;
+            }
+        }
+        offset += length;
+    }
+    None
+}
+
+async fn read_frame(reader: &mut BufReader<OwnedReadHalf>, buf: &mut Vec<u8>) -> std::io::Result<bool> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    buf.resize(len, 0);
+    reader.read_exact(buf).await?;
+    Ok(true)
+}
+
+pub struct TcpTransport {
+    config: ServerConfig,
+    session_manager: Arc<SessionManager>,
+    fanout_engine: Arc<FanoutEngine>,
+}
+
+impl TcpTransport {
+    pub fn new(
+        config: ServerConfig,
+        session_manager: Arc<SessionManager>,
+        fanout_engine: Arc<FanoutEngine>,
+    ) -> Self {
+        Self {
+            config,
+            session_manager,
+            fanout_engine,
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        let bind_addr: SocketAddr = self.config.tcp_bind_address.parse()?;
+        let listener = TcpListener::bind(bind_addr).await?;
+        info!("RTP/TCP listener binding to {}", bind_addr);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            stream.set_nodelay(true).ok();
+            let transport = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = transport.handle_connection(stream, peer).await {
+                    debug!("RTP/TCP connection {} closed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    // The first frame decides the role of a connection: a latch punch turns
+    // it into a subscriber, anything else is treated as RTP/RTCP ingest.
+    async fn handle_connection(&self, stream: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut frame = Vec::with_capacity(1500);
+
+        if !read_frame(&mut reader, &mut frame).await? {
+            return Ok(());
+        }
+
+        match latch::parse_punch(&frame) {
+            Some(token) => self.serve_subscriber(token, reader, writer, peer).await,
+            None => {
+                info!("RTP/TCP ingest connection from {}", peer);
+                loop {
+                    self.ingest_frame(&frame, peer).await;
+                    if !read_frame(&mut reader, &mut frame).await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn ingest_frame(&self, frame: &[u8], peer: SocketAddr) {
+        if is_rtcp(frame) {
+            match self.session_manager.source_of_rtcp(frame, peer) {
+                Some(session) => self.fanout_engine.handle_source_rtcp(&session, frame).await,
+                None => trace!("Ignoring RTCP frame ({} bytes) from {}", frame.len(), peer),
+            }
+            return;
+        }
+
+        match RtpFanoutServer::parse_rtp_packet(frame) {
+            Some(packet) => self.fanout_engine.ingest(packet.with_source(peer), frame).await,
+            None => debug!("Discarding malformed RTP/TCP frame from {}", peer),
+        }
+    }
+
+    async fn serve_subscriber(
+        &self,
+        token: LatchToken,
+        mut reader: BufReader<OwnedReadHalf>,
+        mut writer: OwnedWriteHalf,
+        peer: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(self.config.tcp_send_queue_depth.max(1));
+        let transport = SubscriberTransport::Tcp { sink: tx.clone() };
+
+        let Some(session_id) = self.session_manager.latch_stream_subscriber(&token, peer, transport) else {
+            warn!("RTP/TCP subscriber {} presented unknown latch token", peer);
+            return Ok(());
+        };
+        info!("RTP/TCP subscriber {} latched to session {}", peer, session_id.0);
+
+        tx.send(FanoutEngine::frame_rfc4571(&latch::build_punch(token))).await.ok();
+        drop(tx);
+
+        let writer_task = async {
+            while let Some(frame) = rx.recv().await {
+                writer.write_all(&frame).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+
+        // Subscribers may send RTCP receiver reports or keepalive punches
+        // upstream; they are drained so the peer never blocks on its send buffer.
+        let reader_task = async {
+            let mut frame = Vec::with_capacity(1500);
+            while read_frame(&mut reader, &mut frame).await? {
+                trace!("Received {} byte frame from TCP subscriber {}", frame.len(), peer);
+            }
+            Ok::<_, std::io::Error>(())
+        };
+
+        let result = tokio::select! {
+            r = writer_task => r,
+            r = reader_task => r,
+        };
+
+        if let Some(session) = self.session_manager.get_session(&session_id) {
+            session.remove_subscriber(&peer);
+        }
+        info!("RTP/TCP subscriber {} disconnected from session {}", peer, session_id.0);
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtcp_demux() {
+        let sender_report = [0x80, 200, 0x00, 0x06];
+        let transport_cc = [0x8F, 205, 0x00, 0x05];
+        let pli = [0x81, 206, 0x00, 0x02];
+        let rtp = [0x80, 0x60, 0x00, 0x01];
+        let rtp_marker = [0x80, 0xE0, 0x00, 0x01];
+        assert!(is_rtcp(&sender_report));
+        assert!(is_rtcp(&transport_cc));
+        assert!(is_rtcp(&pli));
+        assert!(!is_rtcp(&rtp));
+        assert!(!is_rtcp(&rtp_marker));
+    }
+
+    #[test]
+    fn test_sdes_cname() {
+        // SR without report blocks, then SDES with CNAME "cam"
+        let mut compound = vec![0x80, 200, 0x00, 0x06];
+        compound.extend_from_slice(&42u32.to_be_bytes());
+        compound.extend_from_slice(&[0; 20]);
+        compound.extend_from_slice(&[0x81, 202, 0x00, 0x03]);
+        compound.extend_from_slice(&42u32.to_be_bytes());
+        compound.extend_from_slice(&[1, 3, b'c', b'a', b'm', 0]);
+        compound.extend_from_slice(&[0; 2]);
+        assert!(is_sender_report(&compound));
+        assert_eq!(sender_ssrc(&compound), Some(42));
+        assert_eq!(sdes_cname(&compound).as_deref(), Some("cam"));
+        assert_eq!(sdes_cname(&compound[..28]), None);
+    }
+
+    #[tokio::test]
+    async fn test_ingested_sender_report_reaches_subscribers() {
+        let session_manager = Arc::new(SessionManager::new(ServerConfig::default()));
+        let fanout_engine =
+            Arc::new(FanoutEngine::new(session_manager.clone(), Arc::new(crossbeam::queue::SegQueue::new())));
+        let transport = TcpTransport::new(ServerConfig::default(), session_manager.clone(), fanout_engine);
+
+        let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let session = session_manager.create_session(peer, 42).unwrap();
+        let subscriber = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        session.add_subscriber(subscriber.local_addr().unwrap());
+
+        let mut sender_report = vec![0x80, 200, 0x00, 0x06];
+        sender_report.extend_from_slice(&42u32.to_be_bytes());
+        sender_report.extend_from_slice(&[0; 20]);
+        transport.ingest_frame(&sender_report, peer).await;
+
+        let mut buf = [0u8; 1500];
+        let (len, _) = tokio::time::timeout(std::time::Duration::from_secs(2), subscriber.recv_from(&mut buf))
+            .await
+            .expect("sender report not forwarded")
+            .unwrap();
+        assert_eq!(&buf[..len], &sender_report[..]);
+    }
+}
@@ -1,7 +1,13 @@
-use std::sync::Arc;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::net::UdpSocket;
 use rtp_fanout_server::config::ServerConfig;
-use rtp_fanout_server::session::{SessionManager, SessionId, Session};
+use rtp_fanout_server::recording::{PacketWriter, RecordedPacket, RtpdumpWriter};
+use rtp_fanout_server::relay::{RelayKind, RelayMessage};
+use rtp_fanout_server::replay::ReplayOptions;
+use rtp_fanout_server::session::{SessionManager, SessionId, Session, SessionOptions};
+use rtp_fanout_server::RtpFanoutServer;
 
 #[tokio::test]
 async fn test_server_creation() {
@@ -64,7 +70,7 @@ fn test_session_manager() {
     let ssrc = 12345u32;
     
     let session = manager.create_session(addr, ssrc);
-    assert!(session.is_some());
+    assert!(session.is_ok());
     
     let retrieved = manager.get_session_by_ssrc(ssrc);
     assert!(retrieved.is_some());
@@ -76,3 +82,360 @@ fn test_session_manager() {
 async fn test_session_lifecycle() {
     // Integration test for session creation/deletion
 }
+
+// An rtpdump capture of `count` packets, 20ms apart, from SSRC 42.
+fn write_capture(count: u16) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("replay-{}.rtpdump", SessionId::new().0));
+    let source: SocketAddr = "192.168.1.100:5004".parse().unwrap();
+    let start = SystemTime::now();
+
+    let file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+    let mut writer = RtpdumpWriter::new(file, start, source).unwrap();
+    for i in 0..count {
+        let mut data = vec![0x80, 0x60];
+        data.extend_from_slice(&(100 + i).to_be_bytes());
+        data.extend_from_slice(&(3000 * i as u32).to_be_bytes());
+        data.extend_from_slice(&42u32.to_be_bytes());
+        data.extend_from_slice(&[i as u8; 100]);
+        writer
+            .write_packet(&RecordedPacket {
+                data: data.into(),
+                arrival: start + Duration::from_millis(20 * i as u64),
+                source: Some(source),
+            })
+            .unwrap();
+    }
+    writer.finish().unwrap();
+    path
+}
+
+async fn replay_server() -> RtpFanoutServer {
+    let config = ServerConfig {
+        bind_address: "127.0.0.1:0".to_string(),
+        enable_session_ports: false,
+        ..ServerConfig::default()
+    };
+    RtpFanoutServer::new(config).await.unwrap()
+}
+
+async fn receive_sequences(socket: &UdpSocket, count: usize) -> Vec<u16> {
+    let mut buf = [0u8; 1500];
+    let mut sequences = Vec::new();
+    for _ in 0..count {
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+            .await
+            .expect("replayed packet not received")
+            .unwrap();
+        assert_eq!(len, 112);
+        sequences.push(u16::from_be_bytes([buf[2], buf[3]]));
+    }
+    sequences
+}
+
+#[tokio::test]
+async fn test_replay_reaches_subscriber() {
+    let server = replay_server().await;
+    let session = server
+        .create_session("192.168.1.100:5004".parse().unwrap(), 42, SessionOptions::default())
+        .unwrap();
+    let subscriber = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    session.add_subscriber(subscriber.local_addr().unwrap());
+
+    let capture = write_capture(5);
+    let started = std::time::Instant::now();
+    server
+        .start_replay(&session.id, &capture, ReplayOptions { speed: 4.0, loops: 1 })
+        .unwrap();
+
+    assert_eq!(receive_sequences(&subscriber, 5).await, vec![100, 101, 102, 103, 104]);
+    // 80ms of capture at 4x
+    assert!(started.elapsed() >= Duration::from_millis(20));
+    assert_eq!(session.packet_count.load(std::sync::atomic::Ordering::Relaxed), 5);
+    std::fs::remove_file(capture).ok();
+}
+
+#[tokio::test]
+async fn test_looped_replay_continues_sequence() {
+    let server = replay_server().await;
+    let session = server
+        .create_session("192.168.1.100:5004".parse().unwrap(), 42, SessionOptions::default())
+        .unwrap();
+    let subscriber = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    session.add_subscriber(subscriber.local_addr().unwrap());
+
+    let capture = write_capture(3);
+    server
+        .start_replay(&session.id, &capture, ReplayOptions { speed: 0.0, loops: 2 })
+        .unwrap();
+
+    assert_eq!(receive_sequences(&subscriber, 6).await, vec![100, 101, 102, 103, 104, 105]);
+    std::fs::remove_file(capture).ok();
+}
+
+async fn relay_server() -> RtpFanoutServer {
+    let config = ServerConfig {
+        bind_address: "127.0.0.1:0".to_string(),
+        enable_session_ports: false,
+        relay_bind_address: "127.0.0.1:0".to_string(),
+        ..ServerConfig::default()
+    };
+    let server = RtpFanoutServer::new(config).await.unwrap();
+    tokio::spawn(server.relay().unwrap().clone().run());
+    server
+}
+
+#[tokio::test]
+async fn test_relay_cascades_and_refuses_loops() {
+    let origin = relay_server().await;
+    let edge = relay_server().await;
+    let source = origin
+        .create_session("192.168.1.100:5004".parse().unwrap(), 42, SessionOptions::default())
+        .unwrap();
+
+    let origin_relay = origin.relay().unwrap().local_addr().unwrap();
+
+    // A JOIN that doesn't echo a cookie is only answered with a challenge
+    let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let join = RelayMessage { kind: RelayKind::Join, session_id: source.id, path: Vec::new(), payload: &[] };
+    stranger.send_to(&join.encode(), origin_relay).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(1), stranger.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(RelayMessage::parse(&buf[..len]).unwrap().kind, RelayKind::Challenge);
+    assert!(source.subscribers.is_empty());
+
+    let relayed = edge
+        .create_relay_session(origin_relay, source.id, SessionOptions::default())
+        .await
+        .unwrap();
+    let subscriber = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    relayed.add_subscriber(subscriber.local_addr().unwrap());
+
+    while source.subscribers.is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let capture = write_capture(3);
+    origin
+        .start_replay(&source.id, &capture, ReplayOptions { speed: 0.0, loops: 1 })
+        .unwrap();
+    assert_eq!(receive_sequences(&subscriber, 3).await, vec![100, 101, 102]);
+
+    let path = edge.relay().unwrap().upstream(&relayed.id).unwrap().path;
+    assert_eq!(path, vec![origin.relay().unwrap().server_id()]);
+
+    // Pulling the relayed session back into the origin would form a loop
+    let edge_relay = edge.relay().unwrap().local_addr().unwrap();
+    origin
+        .create_relay_session(edge_relay, relayed.id, SessionOptions::default())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(relayed.subscribers.len(), 1);
+    std::fs::remove_file(capture).ok();
+}
+
+#[tokio::test]
+async fn test_cluster_places_sessions_across_nodes() {
+    let directory = std::env::temp_dir().join(format!("rtp-fanout-cluster-{}.json", SessionId::new().0));
+    let node = || {
+        let config = ServerConfig {
+            bind_address: "127.0.0.1:0".to_string(),
+            enable_session_ports: false,
+            cluster_directory: directory.display().to_string(),
+            ..ServerConfig::default()
+        };
+        RtpFanoutServer::new(config)
+    };
+    let (a, b) = (node().await.unwrap(), node().await.unwrap());
+    a.sync_cluster().await.unwrap();
+    b.sync_cluster().await.unwrap();
+
+    let source: SocketAddr = "192.168.1.100:5004".parse().unwrap();
+    let (first, _) = a.create_cluster_session(source, 1, SessionOptions::default()).await.unwrap();
+    let (second, owner) = a.create_cluster_session(source, 2, SessionOptions::default()).await.unwrap();
+    assert_ne!(first.owner, second.owner);
+    assert!(b.cluster_nodes().await.unwrap().iter().any(|n| n.id == owner.id));
+
+    // Each node serves its own session once synced, and both see both
+    b.sync_cluster().await.unwrap();
+    assert_eq!(a.session_manager().session_count(), 1);
+    assert_eq!(b.session_manager().session_count(), 1);
+    assert_eq!(b.cluster_sessions().await.unwrap().len(), 2);
+    assert_eq!(b.cluster_session(&first.id).await.unwrap(), Some(first.clone()));
+
+    let served_by_b = if b.session_manager().get_session(&first.id).is_some() { &first } else { &second };
+    assert!(a.delete_cluster_session(&served_by_b.id).await.unwrap());
+    b.sync_cluster().await.unwrap();
+    assert_eq!(b.session_manager().session_count(), 0);
+    std::fs::remove_file(&directory).ok();
+    std::fs::remove_file(directory.with_extension("json.lock")).ok();
+}
+
+fn rtp_datagram(ssrc: u32, sequence: u16) -> Vec<u8> {
+    let mut packet = vec![0x80, 96];
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(&(sequence as u32 * 3000).to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.extend_from_slice(&[0u8; 100]);
+    packet
+}
+
+#[tokio::test]
+async fn test_standalone_transport_feedback_reaches_bwe() {
+    let config = ServerConfig {
+        bind_address: "127.0.0.1:0".to_string(),
+        enable_twcc: true,
+        ..ServerConfig::default()
+    };
+    let server = std::sync::Arc::new(RtpFanoutServer::new(config).await.unwrap());
+    let media_addr = server.local_addr().unwrap();
+    tokio::spawn({
+        let server = server.clone();
+        async move { server.run().await }
+    });
+
+    let source = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let subscriber = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let subscriber_addr = subscriber.local_addr().unwrap();
+    let session = server
+        .create_session(source.local_addr().unwrap(), 42, SessionOptions::default())
+        .unwrap();
+    session.add_subscriber(subscriber_addr);
+
+    source.send_to(&rtp_datagram(42, 1), media_addr).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(2), subscriber.recv_from(&mut buf))
+        .await
+        .expect("media not forwarded")
+        .unwrap();
+    let forwarded = RtpFanoutServer::parse_rtp_packet(&buf[..len]).unwrap();
+    let ext = forwarded.extension.unwrap();
+    let twcc = ext.element(5).unwrap();
+    let transport_seq = u16::from_be_bytes([twcc[0], twcc[1]]);
+
+    // A lone 24-byte transport-cc report (FMT 15) marking the packet lost,
+    // followed by a lone PLI; neither may be taken for RTP.
+    let mut feedback = vec![0x8F, 205, 0x00, 0x05];
+    feedback.extend_from_slice(&1u32.to_be_bytes());
+    feedback.extend_from_slice(&42u32.to_be_bytes());
+    feedback.extend_from_slice(&transport_seq.to_be_bytes());
+    feedback.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00]);
+    subscriber.send_to(&feedback, media_addr).await.unwrap();
+    let mut pli = vec![0x81, 206, 0x00, 0x02];
+    pli.extend_from_slice(&1u32.to_be_bytes());
+    pli.extend_from_slice(&42u32.to_be_bytes());
+    subscriber.send_to(&pli, media_addr).await.unwrap();
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.bandwidth_estimate(&subscriber_addr).is_none_or(|e| e.loss_fraction < 1.0) {
+        assert!(std::time::Instant::now() < deadline, "transport-cc feedback not applied");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // The receive loop survived both
+    source.send_to(&rtp_datagram(42, 2), media_addr).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(2), subscriber.recv_from(&mut buf))
+        .await
+        .expect("receive loop stopped")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_lone_pli_propagates_upstream() {
+    let origin = relay_server().await;
+    let edge = std::sync::Arc::new(relay_server().await);
+    let edge_media = edge.local_addr().unwrap();
+    tokio::spawn({
+        let edge = edge.clone();
+        async move { edge.run().await }
+    });
+
+    let source = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let upstream = origin
+        .create_session(source.local_addr().unwrap(), 42, SessionOptions::default())
+        .unwrap();
+    let origin_relay = origin.relay().unwrap().local_addr().unwrap();
+    let relayed = edge
+        .create_relay_session(origin_relay, upstream.id, SessionOptions::default())
+        .await
+        .unwrap();
+    let subscriber = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    relayed.add_subscriber(subscriber.local_addr().unwrap());
+    while upstream.subscribers.is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // Media has to flow first so the edge knows SSRC 42 comes from upstream
+    let capture = write_capture(1);
+    origin
+        .start_replay(&upstream.id, &capture, ReplayOptions { speed: 0.0, loops: 1 })
+        .unwrap();
+    receive_sequences(&subscriber, 1).await;
+
+    let mut pli = vec![0x81, 206, 0x00, 0x02];
+    pli.extend_from_slice(&1u32.to_be_bytes());
+    pli.extend_from_slice(&42u32.to_be_bytes());
+    subscriber.send_to(&pli, edge_media).await.unwrap();
+
+    let mut buf = [0u8; 1500];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(2), source.recv_from(&mut buf))
+        .await
+        .expect("PLI not relayed to the source")
+        .unwrap();
+    assert_eq!(&buf[..len], &pli[..]);
+    std::fs::remove_file(capture).ok();
+}
+
+#[tokio::test]
+async fn test_source_sender_reports_reach_subscribers_and_group_by_cname() {
+    let origin = std::sync::Arc::new(relay_server().await);
+    let origin_media = origin.local_addr().unwrap();
+    tokio::spawn({
+        let origin = origin.clone();
+        async move { origin.run().await }
+    });
+    let edge = relay_server().await;
+
+    let source = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let upstream = origin
+        .create_session(source.local_addr().unwrap(), 42, SessionOptions::default())
+        .unwrap();
+    let group = origin.session_groups().create_group("rover", Some("rover".to_string()));
+    assert!(group.members.is_empty());
+
+    let direct = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    upstream.add_subscriber(direct.local_addr().unwrap());
+    let origin_relay = origin.relay().unwrap().local_addr().unwrap();
+    let relayed = edge
+        .create_relay_session(origin_relay, upstream.id, SessionOptions::default())
+        .await
+        .unwrap();
+    let downstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    relayed.add_subscriber(downstream.local_addr().unwrap());
+    while upstream.subscribers.len() < 2 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // SR with no report blocks, then an SDES chunk carrying CNAME "rover"
+    let mut compound = vec![0x80, 200, 0x00, 0x06];
+    compound.extend_from_slice(&42u32.to_be_bytes());
+    compound.extend_from_slice(&[0u8; 20]);
+    compound.extend_from_slice(&[0x81, 202, 0x00, 0x03]);
+    compound.extend_from_slice(&42u32.to_be_bytes());
+    compound.extend_from_slice(&[1, 5, b'r', b'o', b'v', b'e', b'r', 0]);
+    source.send_to(&compound, origin_media).await.unwrap();
+
+    let mut buf = [0u8; 1500];
+    for subscriber in [&direct, &downstream] {
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), subscriber.recv_from(&mut buf))
+            .await
+            .expect("sender report not forwarded")
+            .unwrap();
+        assert_eq!(&buf[..len], &compound[..]);
+    }
+    assert_eq!(upstream.cname().as_deref(), Some("rover"));
+    assert!(group.members.contains(&upstream.id));
+    assert_eq!(relayed.cname().as_deref(), Some("rover"));
+}